@@ -1,6 +1,9 @@
-use criterion::{criterion_group, criterion_main, Bencher, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, Bencher, Criterion};
 
-use eddiner::response::{Ch5ResponsesFormatter, Res};
+use eddiner::{
+    response::{write_sjis_dat, Ch5ResponsesFormatter, Res, SjisDat},
+    thread::{Ch5ThreadFormatter, Thread},
+};
 
 fn format_responses_string(responses: &[Res], thread_title: &str, default_name: &str) -> String {
     let mut builder = String::new();
@@ -58,6 +61,7 @@ fn generate_responses() -> Vec<Res> {
             authed_token: None,
             timestamp: 0,
             is_abone: 0,
+            res_no: i + 1,
         };
         responses.push(res);
     }
@@ -78,11 +82,98 @@ fn generate_dat_string_shift_jis(b: &mut Bencher<'_>) {
     });
 }
 
+/// Written straight into a buffer reused between iterations
+fn write_sjis_dat_reused(b: &mut Bencher<'_>) {
+    let responses = generate_responses();
+    let mut buffer = Vec::new();
+    b.iter(|| {
+        buffer.clear();
+        write_sjis_dat(&mut buffer, &responses, "スレタイ", "デフォ名無し");
+    });
+}
+
+/// A dat requested for the first time, encoded response by response
+fn encode_sjis_dat(b: &mut Bencher<'_>) {
+    let responses = generate_responses();
+    b.iter(|| {
+        let mut dat = SjisDat::default();
+        dat.append(&responses, "スレタイ", "デフォ名無し");
+        let _ = dat.as_bytes().to_vec();
+    });
+}
+
+/// A dat requested again after one more response was posted
+fn append_sjis_dat(b: &mut Bencher<'_>) {
+    let responses = generate_responses();
+    let (old, new) = responses.split_at(responses.len() - 1);
+    let mut dat = SjisDat::default();
+    dat.append(old, "スレタイ", "デフォ名無し");
+    b.iter_batched(
+        || dat.clone(),
+        |mut dat| {
+            dat.append(new, "スレタイ", "デフォ名無し");
+            let _ = dat.as_bytes().to_vec();
+        },
+        BatchSize::SmallInput,
+    );
+}
+
+fn generate_threads() -> Vec<Thread> {
+    (0..1000)
+        .map(|i| Thread {
+            title: format!("スレタイ{i}"),
+            response_count: i % 1000 + 1,
+            thread_number: (1666666666 + i).to_string(),
+            last_modified: (1666666666 + i).to_string(),
+            board_id: 1,
+            non_auth_thread: 0,
+            archived: 0,
+            active: 1,
+            metadent: None,
+            no_pool: 0,
+            modulo: 0,
+        })
+        .collect()
+}
+
+/// subject.txt as it was rendered before, parsing the template on every request
+fn subject_txt_fresh_env(b: &mut Bencher<'_>) {
+    let threads = generate_threads();
+    b.iter(|| {
+        let mut env = minijinja::Environment::new();
+        env.add_template(
+            "subject.txt",
+            include_str!("../src/routes/templates/subject.txt"),
+        )
+        .unwrap();
+        let tmpl = env.get_template("subject.txt").unwrap();
+        let _ = tmpl
+            .render(minijinja::context!(threads => &threads))
+            .unwrap();
+    });
+}
+
+/// subject.txt rendered by the templates parsed once per isolate
+fn subject_txt_shared_env(b: &mut Bencher<'_>) {
+    let threads = generate_threads();
+    b.iter(|| {
+        let _ = threads.format_threads().unwrap();
+    });
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("dat-format");
     group.sample_size(10000);
     group.bench_function("dat_string", generate_dat_string);
     group.bench_function("dat_string_encoding", generate_dat_string_shift_jis);
+    group.bench_function("sjis_dat_write", write_sjis_dat_reused);
+    group.bench_function("sjis_dat_encode", encode_sjis_dat);
+    group.bench_function("sjis_dat_append", append_sjis_dat);
+    group.finish();
+
+    let mut group = c.benchmark_group("subject-txt");
+    group.bench_function("subject_txt_fresh_env", subject_txt_fresh_env);
+    group.bench_function("subject_txt_shared_env", subject_txt_shared_env);
     group.finish();
 }
 
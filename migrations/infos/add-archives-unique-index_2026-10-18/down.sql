@@ -0,0 +1 @@
+DROP INDEX archives_board_id_thread_number_idx;
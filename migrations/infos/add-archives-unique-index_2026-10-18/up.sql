@@ -0,0 +1,15 @@
+-- One record per archived thread, so that the archiver can be re-run (see src/archiver.rs)
+DELETE FROM
+    archives
+WHERE
+    rowid NOT IN (
+        SELECT
+            MAX(rowid)
+        FROM
+            archives
+        GROUP BY
+            board_id,
+            thread_number
+    );
+
+CREATE UNIQUE INDEX archives_board_id_thread_number_idx ON archives(board_id, thread_number);
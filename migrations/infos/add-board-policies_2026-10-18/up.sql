@@ -0,0 +1,13 @@
+-- Boards without a row use the defaults of `BoardPolicy`
+CREATE TABLE IF NOT EXISTS board_policies (
+    board_id INTEGER PRIMARY KEY,
+    max_subject_len INTEGER NOT NULL DEFAULT 96,
+    max_name_len INTEGER NOT NULL DEFAULT 64,
+    max_mail_len INTEGER NOT NULL DEFAULT 64,
+    max_body_len INTEGER NOT NULL DEFAULT 4096,
+    max_body_lines INTEGER NOT NULL DEFAULT 32,
+    thread_stopper INTEGER NOT NULL DEFAULT 1000,
+    thread_cooldown_secs INTEGER NOT NULL DEFAULT 120,
+    min_res_span_secs INTEGER NOT NULL DEFAULT 5,
+    n_live_threads INTEGER NOT NULL DEFAULT 60
+);
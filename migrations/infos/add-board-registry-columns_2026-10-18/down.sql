@@ -0,0 +1,7 @@
+DROP INDEX boards_board_key_idx;
+
+ALTER TABLE
+    boards DROP COLUMN retired;
+
+ALTER TABLE
+    boards DROP COLUMN default_name;
@@ -0,0 +1,14 @@
+-- Boards are loaded from this table instead of BOARD_KEYS and the per-board vars.
+-- NULL until it is imported from the per-board var of the board (see board_registry.rs).
+ALTER TABLE
+    boards
+ADD
+    COLUMN default_name TEXT;
+
+-- 1 once retired; the row is kept so that the ID is never reused
+ALTER TABLE
+    boards
+ADD
+    COLUMN retired INTEGER NOT NULL DEFAULT 0;
+
+CREATE UNIQUE INDEX boards_board_key_idx ON boards(board_key);
@@ -0,0 +1,7 @@
+-- Extra SETTING.TXT keys of each board, set by the operator
+CREATE TABLE IF NOT EXISTS board_settings (
+    board_id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (board_id, key)
+);
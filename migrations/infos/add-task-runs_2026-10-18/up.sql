@@ -0,0 +1,14 @@
+-- Outcome of every run of the scheduled tasks (see src/scheduled_tasks.rs)
+CREATE TABLE IF NOT EXISTS task_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    task_name TEXT NOT NULL,
+    -- unix time in milliseconds
+    started_at INTEGER NOT NULL,
+    duration_millis INTEGER NOT NULL,
+    succeeded INTEGER NOT NULL,
+    -- JSON object of what the run did, e.g. {"archived": 3}
+    counts TEXT NOT NULL,
+    error TEXT
+);
+
+CREATE INDEX task_runs_task_name_idx ON task_runs(task_name, started_at);
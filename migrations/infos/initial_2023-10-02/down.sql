@@ -0,0 +1,7 @@
+DROP TABLE caps;
+
+DROP TABLE archives;
+
+DROP TABLE authed_cookies;
+
+DROP TABLE boards;
@@ -0,0 +1,4 @@
+DROP INDEX responses_res_no_idx;
+
+ALTER TABLE
+    responses DROP COLUMN res_no;
@@ -0,0 +1,21 @@
+ALTER TABLE
+    responses
+ADD
+    COLUMN res_no INTEGER;
+
+-- Number the existing responses of each thread in insertion order
+UPDATE
+    responses
+SET
+    res_no = (
+        SELECT
+            COUNT(*)
+        FROM
+            responses AS r
+        WHERE
+            r.thread_id = responses.thread_id
+            AND r.board_id = responses.board_id
+            AND r.id <= responses.id
+    );
+
+CREATE UNIQUE INDEX responses_res_no_idx ON responses(board_id, thread_id, res_no);
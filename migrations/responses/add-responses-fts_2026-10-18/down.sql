@@ -0,0 +1,7 @@
+DROP TRIGGER responses_fts_update;
+
+DROP TRIGGER responses_fts_delete;
+
+DROP TRIGGER responses_fts_insert;
+
+DROP TABLE responses_fts;
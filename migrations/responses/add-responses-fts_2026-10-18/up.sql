@@ -0,0 +1,49 @@
+-- Full-text index of response bodies (see src/search.rs), reading the bodies from `responses`
+CREATE VIRTUAL TABLE IF NOT EXISTS responses_fts USING fts5(
+    body,
+    content = 'responses',
+    content_rowid = 'id',
+    tokenize = 'trigram'
+);
+
+INSERT INTO
+    responses_fts (responses_fts)
+VALUES
+    ('rebuild');
+
+CREATE TRIGGER IF NOT EXISTS responses_fts_insert
+AFTER
+INSERT
+    ON responses BEGIN
+INSERT INTO
+    responses_fts (rowid, body)
+VALUES
+    (new.id, new.body);
+
+END;
+
+CREATE TRIGGER IF NOT EXISTS responses_fts_delete
+AFTER
+    DELETE ON responses BEGIN
+INSERT INTO
+    responses_fts (responses_fts, rowid, body)
+VALUES
+    ('delete', old.id, old.body);
+
+END;
+
+CREATE TRIGGER IF NOT EXISTS responses_fts_update
+AFTER
+UPDATE
+    OF body ON responses BEGIN
+INSERT INTO
+    responses_fts (responses_fts, rowid, body)
+VALUES
+    ('delete', old.id, old.body);
+
+INSERT INTO
+    responses_fts (rowid, body)
+VALUES
+    (new.id, new.body);
+
+END;
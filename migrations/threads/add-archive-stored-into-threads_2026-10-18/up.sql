@@ -0,0 +1,5 @@
+-- 1 once the dat of the archived thread is stored in R2 and recorded in `archives`
+ALTER TABLE
+    threads
+ADD
+    COLUMN archive_stored INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,10 @@
+-- Responses moves between shards in progress (see src/rebalance.rs)
+CREATE TABLE IF NOT EXISTS shard_moves (
+    board_id INTEGER NOT NULL,
+    thread_number TEXT NOT NULL,
+    from_modulo INTEGER NOT NULL,
+    to_modulo INTEGER NOT NULL,
+    -- `active` of the thread before the move
+    active INTEGER NOT NULL,
+    PRIMARY KEY (board_id, thread_number)
+);
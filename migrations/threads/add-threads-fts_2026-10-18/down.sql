@@ -0,0 +1,7 @@
+DROP TRIGGER threads_fts_update;
+
+DROP TRIGGER threads_fts_delete;
+
+DROP TRIGGER threads_fts_insert;
+
+DROP TABLE threads_fts;
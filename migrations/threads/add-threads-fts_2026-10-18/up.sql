@@ -0,0 +1,50 @@
+-- Full-text index of thread titles (see src/search.rs).
+-- `threads` has no INTEGER PRIMARY KEY, whose rowid may change on VACUUM,
+-- so the titles are copied instead of using an external content table.
+CREATE VIRTUAL TABLE IF NOT EXISTS threads_fts USING fts5(
+    title,
+    thread_number UNINDEXED,
+    tokenize = 'trigram'
+);
+
+INSERT INTO
+    threads_fts (title, thread_number)
+SELECT
+    title,
+    thread_number
+FROM
+    threads;
+
+CREATE TRIGGER IF NOT EXISTS threads_fts_insert
+AFTER
+INSERT
+    ON threads BEGIN
+INSERT INTO
+    threads_fts (title, thread_number)
+VALUES
+    (new.title, new.thread_number);
+
+END;
+
+CREATE TRIGGER IF NOT EXISTS threads_fts_delete
+AFTER
+    DELETE ON threads BEGIN
+DELETE FROM
+    threads_fts
+WHERE
+    thread_number = old.thread_number;
+
+END;
+
+CREATE TRIGGER IF NOT EXISTS threads_fts_update
+AFTER
+UPDATE
+    OF title ON threads BEGIN
+UPDATE
+    threads_fts
+SET
+    title = new.title
+WHERE
+    thread_number = old.thread_number;
+
+END;
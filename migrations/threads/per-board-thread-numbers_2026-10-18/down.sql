@@ -0,0 +1,59 @@
+DROP INDEX threads_board_id_thread_number_idx;
+
+CREATE UNIQUE INDEX threads_thread_number_idx ON threads(thread_number);
+
+DROP TRIGGER threads_fts_update;
+
+DROP TRIGGER threads_fts_delete;
+
+DROP TRIGGER threads_fts_insert;
+
+DROP TABLE threads_fts;
+
+CREATE VIRTUAL TABLE IF NOT EXISTS threads_fts USING fts5(
+    title,
+    thread_number UNINDEXED,
+    tokenize = 'trigram'
+);
+
+INSERT INTO
+    threads_fts (title, thread_number)
+SELECT
+    title,
+    thread_number
+FROM
+    threads;
+
+CREATE TRIGGER IF NOT EXISTS threads_fts_insert
+AFTER
+INSERT
+    ON threads BEGIN
+INSERT INTO
+    threads_fts (title, thread_number)
+VALUES
+    (new.title, new.thread_number);
+
+END;
+
+CREATE TRIGGER IF NOT EXISTS threads_fts_delete
+AFTER
+    DELETE ON threads BEGIN
+DELETE FROM
+    threads_fts
+WHERE
+    thread_number = old.thread_number;
+
+END;
+
+CREATE TRIGGER IF NOT EXISTS threads_fts_update
+AFTER
+UPDATE
+    OF title ON threads BEGIN
+UPDATE
+    threads_fts
+SET
+    title = new.title
+WHERE
+    thread_number = old.thread_number;
+
+END;
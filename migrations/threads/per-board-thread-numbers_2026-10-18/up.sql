@@ -0,0 +1,66 @@
+-- Thread numbers are unique per board (see BbsRepository::create_thread), so threads of
+-- different boards may share one
+DROP INDEX threads_thread_number_idx;
+
+CREATE UNIQUE INDEX threads_board_id_thread_number_idx ON threads(board_id, thread_number);
+
+-- threads_fts told threads apart by the number alone
+DROP TRIGGER threads_fts_update;
+
+DROP TRIGGER threads_fts_delete;
+
+DROP TRIGGER threads_fts_insert;
+
+DROP TABLE threads_fts;
+
+CREATE VIRTUAL TABLE threads_fts USING fts5(
+    title,
+    board_id UNINDEXED,
+    thread_number UNINDEXED,
+    tokenize = 'trigram'
+);
+
+INSERT INTO
+    threads_fts (title, board_id, thread_number)
+SELECT
+    title,
+    board_id,
+    thread_number
+FROM
+    threads;
+
+CREATE TRIGGER threads_fts_insert
+AFTER
+INSERT
+    ON threads BEGIN
+INSERT INTO
+    threads_fts (title, board_id, thread_number)
+VALUES
+    (new.title, new.board_id, new.thread_number);
+
+END;
+
+CREATE TRIGGER threads_fts_delete
+AFTER
+    DELETE ON threads BEGIN
+DELETE FROM
+    threads_fts
+WHERE
+    board_id = old.board_id
+    AND thread_number = old.thread_number;
+
+END;
+
+CREATE TRIGGER threads_fts_update
+AFTER
+UPDATE
+    OF title ON threads BEGIN
+UPDATE
+    threads_fts
+SET
+    title = new.title
+WHERE
+    board_id = old.board_id
+    AND thread_number = old.thread_number;
+
+END;
@@ -0,0 +1,437 @@
+use worker::{async_trait::async_trait, Bucket};
+
+use crate::{
+    board_config::BoardConfig, repositories::bbs_repository::BbsRepository,
+    response::Ch5ResponsesFormatter, thread::Thread,
+};
+
+/// Threads stored per cron tick when `ARCHIVE_BATCH_SIZE` is not set
+const DEFAULT_ARCHIVE_BATCH_SIZE: usize = 10;
+
+/// Where the dat files of archived threads are stored, i.e. `ARCHIVE_BUCKET`
+#[async_trait(?Send)]
+pub(crate) trait DatArchive {
+    /// Overwrites the object if it already exists
+    async fn put_dat(&self, key: &str, dat: String) -> anyhow::Result<()>;
+
+    async fn get_dat(&self, key: &str) -> anyhow::Result<Option<String>>;
+}
+
+#[async_trait(?Send)]
+impl DatArchive for Bucket {
+    async fn put_dat(&self, key: &str, dat: String) -> anyhow::Result<()> {
+        self.put(key, dat)
+            .execute()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to put {key}: {e}"))?;
+        Ok(())
+    }
+
+    async fn get_dat(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Ok(object) = self.get(key).execute().await else {
+            return Err(anyhow::anyhow!("failed to get {key}"));
+        };
+        let Some(object) = object else {
+            return Ok(None);
+        };
+        let Some(body) = object.body() else {
+            return Err(anyhow::anyhow!("{key} has no body"));
+        };
+        let Ok(dat) = body.text().await else {
+            return Err(anyhow::anyhow!("failed to read {key}"));
+        };
+        Ok(Some(dat))
+    }
+}
+
+/// Read by `Route::KakoDat`
+pub(crate) fn dat_key(board_key: &str, thread_id: &str) -> String {
+    format!("{board_key}/dat/{thread_id}.dat")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ArchiveConfig {
+    /// Threads stored (and purged) per run, over every board
+    pub batch_size: usize,
+    /// Deletes the thread and its responses from D1 once the dat is stored
+    pub purge: bool,
+}
+
+impl ArchiveConfig {
+    /// From `ARCHIVE_BATCH_SIZE` and `ARCHIVE_PURGE` ("true" to purge)
+    pub fn parse(batch_size: Option<&str>, purge: Option<&str>) -> anyhow::Result<ArchiveConfig> {
+        let batch_size = match batch_size {
+            Some(x) => x
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("ARCHIVE_BATCH_SIZE must be a number: {x}"))?,
+            None => DEFAULT_ARCHIVE_BATCH_SIZE,
+        };
+        Ok(ArchiveConfig {
+            batch_size,
+            purge: purge.map(|x| x.trim() == "true").unwrap_or(false),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ArchiveReport {
+    /// `board_key/thread_number` of the threads stored in this run
+    pub stored: Vec<String>,
+    pub purged: Vec<String>,
+}
+
+/// Stores the dat of archived threads to the archive and records them in `archives`,
+/// then purges the stored threads from D1 if `config.purge` is set.
+///
+/// A thread is marked as stored only after both writes succeed, and purged only after
+/// it is marked, so a run interrupted at any point is simply redone by the next one.
+/// A thread whose responses are only on another shard is skipped until the integrity
+/// check fixes its `modulo`.
+pub(crate) async fn run_archiver(
+    repo: &BbsRepository<'_>,
+    archive: &dyn DatArchive,
+    boards: &[BoardConfig<'_>],
+    config: ArchiveConfig,
+) -> anyhow::Result<ArchiveReport> {
+    let mut report = ArchiveReport::default();
+
+    for board in boards {
+        let remaining = config.batch_size - report.stored.len();
+        if remaining == 0 {
+            break;
+        }
+        for thread in repo.get_threads_to_store(board.board_id, remaining).await? {
+            let responses = repo
+                .get_responses(
+                    board.board_id,
+                    &thread.thread_number,
+                    thread.modulo as usize,
+                )
+                .await?;
+            if responses.is_empty() {
+                let found_on = shards_with_responses(repo, board.board_id, &thread).await?;
+                if !found_on.is_empty() {
+                    // The `modulo` is wrong; left to the integrity check, which points the
+                    // thread to the shard, so that it's stored by a later run
+                    log!(
+                        "archiver: {}/{} has its responses on shards {found_on:?}",
+                        board.board_key,
+                        thread.thread_number
+                    );
+                    continue;
+                }
+                // Nothing to store, but it must not be picked up again in every run, and it
+                // is still listed by the kako index once purged
+                log!(
+                    "archiver: {}/{} has no responses",
+                    board.board_key,
+                    thread.thread_number
+                );
+                repo.upsert_archive(&thread).await?;
+                repo.mark_archive_stored(board.board_id, &thread.thread_number)
+                    .await?;
+                continue;
+            }
+            let dat = responses.format_responses(&thread.title, &board.default_name);
+
+            archive
+                .put_dat(&dat_key(board.board_key, &thread.thread_number), dat)
+                .await?;
+            repo.upsert_archive(&thread).await?;
+            repo.mark_archive_stored(board.board_id, &thread.thread_number)
+                .await?;
+            report
+                .stored
+                .push(format!("{}/{}", board.board_key, thread.thread_number));
+        }
+    }
+
+    if !config.purge {
+        return Ok(report);
+    }
+    for board in boards {
+        let remaining = config.batch_size - report.purged.len();
+        if remaining == 0 {
+            break;
+        }
+        for thread in repo.get_stored_threads(board.board_id, remaining).await? {
+            repo.delete_responses(
+                board.board_id,
+                &thread.thread_number,
+                thread.modulo as usize,
+            )
+            .await?;
+            repo.delete_thread(board.board_id, &thread.thread_number)
+                .await?;
+            report
+                .purged
+                .push(format!("{}/{}", board.board_key, thread.thread_number));
+        }
+    }
+
+    Ok(report)
+}
+
+/// Shards other than the thread's own which hold its responses
+async fn shards_with_responses(
+    repo: &BbsRepository<'_>,
+    board_id: usize,
+    thread: &Thread,
+) -> anyhow::Result<Vec<usize>> {
+    let thread_ids = [thread.thread_number.clone()];
+    let mut found_on = Vec::new();
+    for modulo in (0..repo.n_responses_db()).filter(|x| *x != thread.modulo as usize) {
+        if !repo
+            .count_responses(board_id, modulo, &thread_ids)
+            .await?
+            .is_empty()
+        {
+            found_on.push(modulo);
+        }
+    }
+    Ok(found_on)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use crate::board_policy::BoardPolicy;
+    use crate::repositories::{
+        bbs_repository::{CreatingRes, CreatingThread, ThreadStatus, THREAD_STOPPER},
+        bbs_storage::BbsStorage,
+        sqlite_storage::SqliteStorage,
+    };
+    use crate::thread::MetadentType;
+
+    #[derive(Default)]
+    struct MemoryArchive {
+        objects: RefCell<HashMap<String, String>>,
+    }
+
+    #[async_trait(?Send)]
+    impl DatArchive for MemoryArchive {
+        async fn put_dat(&self, key: &str, dat: String) -> anyhow::Result<()> {
+            self.objects.borrow_mut().insert(key.to_string(), dat);
+            Ok(())
+        }
+
+        async fn get_dat(&self, key: &str) -> anyhow::Result<Option<String>> {
+            Ok(self.objects.borrow().get(key).cloned())
+        }
+    }
+
+    fn board() -> BoardConfig<'static> {
+        BoardConfig {
+            board_id: 1,
+            board_key: "liveedge",
+            title: "エッヂ".to_string(),
+            default_name: "エッヂの名無し".to_string(),
+            policy: BoardPolicy::default_for(1),
+        }
+    }
+
+    async fn create_archived_thread(storage: &SqliteStorage, unix_time: &str) {
+        let repo = BbsRepository::new(storage);
+        let thread = CreatingThread {
+            title: "過去ログ",
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id: 1,
+            metadent: MetadentType::None,
+        };
+        repo.create_thread(thread.clone()).await.unwrap();
+        let modulo = unix_time.parse::<usize>().unwrap() % storage.n_responses_db();
+        repo.create_response(
+            CreatingRes {
+                body: "レス",
+                ..CreatingRes::from(&thread)
+            },
+            modulo,
+            THREAD_STOPPER,
+        )
+        .await
+        .unwrap();
+        storage
+            .execute_on_threads_db(&format!(
+                "UPDATE threads SET archived = 1, active = 0 WHERE thread_number = '{unix_time}'"
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parse_archive_config() {
+        assert_eq!(
+            ArchiveConfig::parse(None, None).unwrap(),
+            ArchiveConfig {
+                batch_size: DEFAULT_ARCHIVE_BATCH_SIZE,
+                purge: false
+            }
+        );
+        assert_eq!(
+            ArchiveConfig::parse(Some("3"), Some("true")).unwrap(),
+            ArchiveConfig {
+                batch_size: 3,
+                purge: true
+            }
+        );
+        assert!(ArchiveConfig::parse(Some("many"), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_archiver() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let archive = MemoryArchive::default();
+        create_archived_thread(&storage, "1820000000").await;
+        create_archived_thread(&storage, "1820000001").await;
+        let config = ArchiveConfig {
+            batch_size: 1,
+            purge: false,
+        };
+
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert_eq!(report.stored, vec!["liveedge/1820000000"]);
+        assert_eq!(
+            archive.objects.borrow()["liveedge/dat/1820000000.dat"],
+            "エッヂの名無し<><>2099/09/09(水) 00:00:00.000 ID:abcdefghi<> 本文<>過去ログ\n\
+            エッヂの名無し<><>2099/09/09(水) 00:00:00.000 ID:abcdefghi<> レス<>\n"
+        );
+
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert_eq!(report.stored, vec!["liveedge/1820000001"]);
+        // Everything is stored, and the threads are kept without purging
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert_eq!(report, ArchiveReport::default());
+        assert_eq!(archive.objects.borrow().len(), 2);
+        assert!(storage.get_thread(1, "1820000000").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_run_archiver_rerun_after_interruption() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let archive = MemoryArchive::default();
+        create_archived_thread(&storage, "1820000000").await;
+        // Interrupted after the dat and the record were written
+        let thread = storage.get_thread(1, "1820000000").await.unwrap().unwrap();
+        archive
+            .put_dat(&dat_key("liveedge", "1820000000"), "途中".to_string())
+            .await
+            .unwrap();
+        repo.upsert_archive(&thread).await.unwrap();
+
+        let config = ArchiveConfig {
+            batch_size: 10,
+            purge: false,
+        };
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert_eq!(report.stored, vec!["liveedge/1820000000"]);
+        assert!(archive.objects.borrow()["liveedge/dat/1820000000.dat"].contains("レス"));
+        // The record was overwritten, not duplicated
+        assert_eq!(
+            storage
+                .count_on_infos_db("SELECT COUNT(*) FROM archives")
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_archiver_purge() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let archive = MemoryArchive::default();
+        create_archived_thread(&storage, "1820000000").await;
+        let config = ArchiveConfig {
+            batch_size: 10,
+            purge: true,
+        };
+
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert_eq!(report.stored, vec!["liveedge/1820000000"]);
+        assert_eq!(report.purged, vec!["liveedge/1820000000"]);
+        assert!(storage.get_thread(1, "1820000000").await.unwrap().is_none());
+        assert!(storage
+            .get_responses(1, "1820000000", 1820000000 % 3)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(repo
+            .get_threads(1, ThreadStatus::Archived)
+            .await
+            .unwrap()
+            .iter()
+            .all(|th| th.thread_number != "1820000000"));
+        assert_eq!(archive.objects.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_archiver_purge_thread_without_responses() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let archive = MemoryArchive::default();
+        create_archived_thread(&storage, "1820000010").await;
+        repo.delete_responses(1, "1820000010", 0).await.unwrap();
+        let config = ArchiveConfig {
+            batch_size: 10,
+            purge: true,
+        };
+
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert!(report.stored.is_empty());
+        assert_eq!(report.purged, vec!["liveedge/1820000010"]);
+        assert!(archive.objects.borrow().is_empty());
+        let archives = repo.get_archives(1, None, 10, 0).await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].thread_number, "1820000010");
+    }
+
+    #[tokio::test]
+    async fn test_run_archiver_skips_thread_with_responses_on_another_shard() {
+        let storage = SqliteStorage::new_in_memory(2).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let archive = MemoryArchive::default();
+        create_archived_thread(&storage, "1820000020").await;
+        repo.update_thread_modulo(1, "1820000020", 1).await.unwrap();
+        let config = ArchiveConfig {
+            batch_size: 10,
+            purge: true,
+        };
+
+        let report = run_archiver(&repo, &archive, &[board()], config)
+            .await
+            .unwrap();
+        assert_eq!(report, ArchiveReport::default());
+        assert!(repo.get_archives(1, None, 10, 0).await.unwrap().is_empty());
+        assert!(repo.get_thread(1, "1820000020").await.unwrap().is_some());
+        assert_eq!(
+            storage
+                .get_responses(1, "1820000020", 0)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+}
@@ -1,9 +1,154 @@
 use serde::{Deserialize, Serialize};
 
+/// Board keys that would be shadowed by the other routes
+const RESERVED_BOARD_KEYS: &[&str] = &["admin", "auth", "test"];
+const MAX_BOARD_KEY_LEN: usize = 32;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Board {
     pub id: usize,
     pub board_key: Option<String>,
     pub name: String,
+    /// NULL in rows which were created without one
+    pub local_rule: Option<String>,
+    /// NULL until it is imported from the per-board var (see `LegacyBoard`)
+    pub default_name: Option<String>,
+    pub retired: u32,
+}
+
+/// Body of `POST /admin/boards`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreatingBoard {
+    /// Assigned by the database if omitted. Set it to keep the ID of an existing board,
+    /// e.g. one that was configured by `BOARD_KEYS`, whose threads refer to it.
+    pub id: Option<usize>,
+    pub board_key: String,
+    pub name: String,
+    pub default_name: String,
+    #[serde(default)]
     pub local_rule: String,
 }
+
+impl CreatingBoard {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.board_key.is_empty() || self.board_key.len() > MAX_BOARD_KEY_LEN {
+            return Err("board_key must be 1 to 32 characters");
+        }
+        if !self
+            .board_key
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        {
+            return Err("board_key must consist of lowercase letters and digits");
+        }
+        if RESERVED_BOARD_KEYS.contains(&self.board_key.as_str()) {
+            return Err("board_key is reserved");
+        }
+        if self.name.trim().is_empty() || self.default_name.trim().is_empty() {
+            return Err("name and default_name must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// Body of `PATCH /admin/boards/:id`. Omitted fields are kept.
+///
+/// `board_key` is not changeable since it is a part of every URL of the board.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UpdatingBoard {
+    pub name: Option<String>,
+    pub default_name: Option<String>,
+    pub local_rule: Option<String>,
+    pub retired: Option<bool>,
+}
+
+impl UpdatingBoard {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let is_blank = |x: &Option<String>| x.as_ref().is_some_and(|x| x.trim().is_empty());
+        if is_blank(&self.name) || is_blank(&self.default_name) {
+            return Err("name and default_name must not be empty");
+        }
+        Ok(())
+    }
+}
+
+/// An extra `KEY=value` line of SETTING.TXT
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BoardSetting {
+    pub key: String,
+    pub value: String,
+}
+
+impl BoardSetting {
+    /// `derived_keys` are rendered from the board and its policy, and can't be overridden
+    pub fn validate(&self, derived_keys: &[&str]) -> Result<(), &'static str> {
+        if self.key.is_empty()
+            || !self
+                .key
+                .bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+        {
+            return Err("key must consist of uppercase letters, digits and underscores");
+        }
+        if derived_keys.contains(&self.key.as_str()) {
+            return Err("key is derived from the board and its policy");
+        }
+        if self.value.contains(['\r', '\n']) {
+            return Err("value must be a single line");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creating_board(board_key: &str) -> CreatingBoard {
+        CreatingBoard {
+            id: None,
+            board_key: board_key.to_string(),
+            name: "エッヂ".to_string(),
+            default_name: "エッヂの名無し".to_string(),
+            local_rule: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_validate_creating_board() {
+        assert!(creating_board("liveedge").validate().is_ok());
+        assert!(creating_board("news4vip").validate().is_ok());
+        assert!(creating_board("").validate().is_err());
+        assert!(creating_board("LiveEdge").validate().is_err());
+        assert!(creating_board("live/edge").validate().is_err());
+        assert!(creating_board("test").validate().is_err());
+        assert!(creating_board(&"a".repeat(33)).validate().is_err());
+        assert!(CreatingBoard {
+            name: " ".to_string(),
+            ..creating_board("liveedge")
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_board_setting() {
+        let setting = |key: &str, value: &str| BoardSetting {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        let derived_keys = ["BBS_TITLE"];
+        assert!(setting("BBS_BG_COLOR", "#000000")
+            .validate(&derived_keys)
+            .is_ok());
+        assert!(setting("bbs_bg_color", "#000000")
+            .validate(&derived_keys)
+            .is_err());
+        assert!(setting("BBS_TITLE", "エッヂ")
+            .validate(&derived_keys)
+            .is_err());
+        assert!(setting("BBS_SLIP", "verbose\nBBS_TITLE=x")
+            .validate(&derived_keys)
+            .is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use crate::repositories::bbs_repository::THREAD_STOPPER;
+
+/// Limits of a board, from `board_policies`.
+///
+/// Boards without a row use the defaults, which are the limits of a live board.
+/// Missing fields of a row (or of `PUT /admin/boards/:id/policy`) are also the defaults.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct BoardPolicy {
+    pub board_id: usize,
+    /// In characters
+    pub max_subject_len: usize,
+    pub max_name_len: usize,
+    pub max_mail_len: usize,
+    pub max_body_len: usize,
+    pub max_body_lines: usize,
+    /// A thread stops accepting responses once it has this many
+    pub thread_stopper: u32,
+    /// Seconds an authed token has to wait after creating a thread before creating another
+    pub thread_cooldown_secs: u64,
+    /// Posts from the same IP or authed token within this many seconds are rejected
+    pub min_res_span_secs: u64,
+    /// Threads kept alive (not archived) by the scheduled maintenance
+    pub n_live_threads: usize,
+}
+
+impl Default for BoardPolicy {
+    fn default() -> Self {
+        BoardPolicy {
+            board_id: 0,
+            max_subject_len: 96,
+            max_name_len: 64,
+            max_mail_len: 64,
+            max_body_len: 4096,
+            max_body_lines: 32,
+            thread_stopper: THREAD_STOPPER,
+            thread_cooldown_secs: 120,
+            min_res_span_secs: 5,
+            n_live_threads: 60,
+        }
+    }
+}
+
+impl BoardPolicy {
+    pub fn default_for(board_id: usize) -> BoardPolicy {
+        BoardPolicy {
+            board_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if [
+            self.max_subject_len,
+            self.max_name_len,
+            self.max_mail_len,
+            self.max_body_len,
+        ]
+        .contains(&0)
+        {
+            return Err("length limits must be positive");
+        }
+        if self.thread_stopper == 0 {
+            return Err("thread_stopper must be positive");
+        }
+        if self.n_live_threads == 0 {
+            return Err("n_live_threads must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_policy() {
+        let policy = serde_json::from_str::<BoardPolicy>(
+            r#"{"thread_stopper": 2000, "thread_cooldown_secs": 0}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy,
+            BoardPolicy {
+                thread_stopper: 2000,
+                thread_cooldown_secs: 0,
+                ..Default::default()
+            }
+        );
+        assert!(policy.validate().is_ok());
+        assert!(BoardPolicy {
+            max_body_len: 0,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use crate::{
+    board::{Board, CreatingBoard, UpdatingBoard},
+    board_config::BoardConfig,
+    board_policy::BoardPolicy,
+    repositories::bbs_repository::BbsRepository,
+    utils::get_current_millis,
+};
+
+/// Boards are reloaded after this, so that the changes made through another isolate are
+/// picked up without redeploying
+const BOARD_REGISTRY_TTL_MILLIS: u64 = 60 * 1000;
+
+/// Of the boards which neither have one in `boards` nor in their per-board var
+const FALLBACK_DEFAULT_NAME: &str = "名無しさん";
+
+/// A board configured by `BOARD_KEYS` and its per-board var (`<board_key> = "title,default name"`),
+/// which is how boards were configured before the `boards` table.
+///
+/// They are imported into `boards` while the vars are still set, so that the boards keep
+/// working across the deploy without recreating them by hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LegacyBoard {
+    /// The position in `BOARD_KEYS`, from 1, which the threads of the board refer to
+    pub id: usize,
+    pub board_key: String,
+    pub title: Option<String>,
+    pub default_name: Option<String>,
+}
+
+impl LegacyBoard {
+    /// `var` looks up the per-board var of a board key
+    pub fn parse_all(board_keys: &str, var: impl Fn(&str) -> Option<String>) -> Vec<LegacyBoard> {
+        board_keys
+            .split(',')
+            .enumerate()
+            .filter(|(_, key)| !key.is_empty())
+            .map(|(i, key)| {
+                let info = var(key);
+                let mut info = info.as_deref().unwrap_or_default().splitn(2, ',');
+                let mut next = || info.next().filter(|x| !x.is_empty()).map(str::to_string);
+                LegacyBoard {
+                    id: i + 1,
+                    board_key: key.to_string(),
+                    title: next(),
+                    default_name: next(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Creates the legacy boards missing in `boards`, and fills `default_name` of the existing
+/// ones which don't have it yet. Boards are never overwritten otherwise, so the changes made
+/// by `/admin/boards` are kept. Returns whether anything was written.
+async fn import_legacy_boards(
+    repo: &BbsRepository<'_>,
+    boards: &[Board],
+    legacy_boards: &[LegacyBoard],
+) -> bool {
+    let mut imported = false;
+    for legacy in legacy_boards {
+        let existing = boards
+            .iter()
+            .find(|b| b.board_key.as_deref() == Some(legacy.board_key.as_str()));
+        let result = match existing {
+            Some(board) if board.default_name.is_none() && legacy.default_name.is_some() => {
+                let updating = UpdatingBoard {
+                    default_name: legacy.default_name.clone(),
+                    ..Default::default()
+                };
+                repo.update_board(board.id, &updating).await.map(|_| ())
+            }
+            Some(_) => continue,
+            None if boards.iter().any(|b| b.id == legacy.id) => {
+                log!(
+                    "board {} of BOARD_KEYS is not imported since its ID {} is taken",
+                    legacy.board_key,
+                    legacy.id
+                );
+                continue;
+            }
+            None => {
+                let board = CreatingBoard {
+                    id: Some(legacy.id),
+                    board_key: legacy.board_key.clone(),
+                    name: legacy
+                        .title
+                        .clone()
+                        .unwrap_or_else(|| legacy.board_key.clone()),
+                    default_name: legacy
+                        .default_name
+                        .clone()
+                        .unwrap_or_else(|| FALLBACK_DEFAULT_NAME.to_string()),
+                    local_rule: String::new(),
+                };
+                repo.create_board(&board).await.map(|_| ())
+            }
+        };
+        match result {
+            Ok(()) => imported = true,
+            Err(e) => log!("failed to import board {}: {e}", legacy.board_key),
+        }
+    }
+    imported
+}
+
+/// The boards being served, i.e. the rows of `boards` which have a key and are not retired,
+/// with their policies
+#[derive(Debug, Default)]
+pub(crate) struct BoardRegistry {
+    boards: Vec<Board>,
+    board_keys: HashMap<String, usize>,
+    policies: HashMap<usize, BoardPolicy>,
+}
+
+impl BoardRegistry {
+    /// Boards without a policy get the default one
+    pub fn new(boards: Vec<Board>, policies: Vec<BoardPolicy>) -> BoardRegistry {
+        let mut boards = boards
+            .into_iter()
+            .filter(|b| b.retired == 0 && b.board_key.is_some())
+            .collect::<Vec<_>>();
+        boards.sort_by_key(|b| b.id);
+        let board_keys = boards
+            .iter()
+            .filter_map(|b| Some((b.board_key.clone()?, b.id)))
+            .collect();
+        let policies = policies.into_iter().map(|p| (p.board_id, p)).collect();
+        BoardRegistry {
+            boards,
+            board_keys,
+            policies,
+        }
+    }
+
+    /// Board key to board ID, for `analyze_route`
+    pub fn board_keys(&self) -> &HashMap<String, usize> {
+        &self.board_keys
+    }
+
+    pub fn get(&self, board_id: usize) -> Option<BoardConfig<'_>> {
+        self.boards
+            .iter()
+            .find(|b| b.id == board_id)
+            .and_then(|b| self.board_config(b))
+    }
+
+    /// Ordered by ID
+    pub fn board_configs(&self) -> Vec<BoardConfig<'_>> {
+        self.boards
+            .iter()
+            .filter_map(|b| self.board_config(b))
+            .collect()
+    }
+
+    fn board_config<'a>(&self, board: &'a Board) -> Option<BoardConfig<'a>> {
+        Some(BoardConfig {
+            board_id: board.id,
+            board_key: board.board_key.as_deref()?,
+            title: board.name.clone(),
+            default_name: board
+                .default_name
+                .clone()
+                .unwrap_or_else(|| FALLBACK_DEFAULT_NAME.to_string()),
+            policy: self
+                .policies
+                .get(&board.id)
+                .cloned()
+                .unwrap_or_else(|| BoardPolicy::default_for(board.id)),
+        })
+    }
+}
+
+type CachedRegistry = Option<(u64, Arc<BoardRegistry>)>;
+
+fn cached_registry() -> &'static RwLock<CachedRegistry> {
+    static BOARD_REGISTRY: OnceLock<RwLock<CachedRegistry>> = OnceLock::new();
+    BOARD_REGISTRY.get_or_init(|| RwLock::new(None))
+}
+
+/// Returns the registry cached in this isolate, or loads it from `boards` after importing
+/// `legacy_boards`
+pub(crate) async fn load_board_registry(
+    repo: &BbsRepository<'_>,
+    legacy_boards: &[LegacyBoard],
+) -> anyhow::Result<Arc<BoardRegistry>> {
+    let now = get_current_millis();
+    if let Ok(cached) = cached_registry().read() {
+        if let Some((loaded_at, registry)) = cached.as_ref() {
+            if now.saturating_sub(*loaded_at) < BOARD_REGISTRY_TTL_MILLIS {
+                return Ok(registry.clone());
+            }
+        }
+    }
+
+    let (boards, policies) = tokio::join!(repo.get_boards(), repo.get_board_policies());
+    let mut boards = boards?;
+    if import_legacy_boards(repo, &boards, legacy_boards).await {
+        boards = repo.get_boards().await?;
+    }
+    let registry = Arc::new(BoardRegistry::new(boards, policies?));
+    if let Ok(mut cached) = cached_registry().write() {
+        *cached = Some((now, registry.clone()));
+    }
+    Ok(registry)
+}
+
+/// Called after the boards are changed, so that this isolate sees the change immediately
+pub(crate) fn invalidate_board_registry() {
+    if let Ok(mut cached) = cached_registry().write() {
+        *cached = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        board::{CreatingBoard, UpdatingBoard},
+        repositories::sqlite_storage::SqliteStorage,
+    };
+
+    fn creating_board(id: Option<usize>, board_key: &str) -> CreatingBoard {
+        CreatingBoard {
+            id,
+            board_key: board_key.to_string(),
+            name: format!("{board_key}板"),
+            default_name: "名無し".to_string(),
+            local_rule: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_board_registry() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        // Keeps the ID which was assigned by the position in BOARD_KEYS
+        let board = repo
+            .create_board(&creating_board(Some(3), "news"))
+            .await
+            .unwrap();
+        assert_eq!(board.id, 3);
+        let board = repo
+            .create_board(&creating_board(None, "retired"))
+            .await
+            .unwrap();
+        assert_eq!(board.id, 4);
+        assert!(repo
+            .create_board(&creating_board(None, "news"))
+            .await
+            .is_err());
+        repo.update_board(
+            4,
+            &UpdatingBoard {
+                retired: Some(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        let policy = BoardPolicy {
+            board_id: 3,
+            thread_stopper: 2000,
+            ..Default::default()
+        };
+        repo.upsert_board_policy(&policy).await.unwrap();
+
+        let registry = BoardRegistry::new(
+            repo.get_boards().await.unwrap(),
+            repo.get_board_policies().await.unwrap(),
+        );
+        assert_eq!(
+            registry.board_keys(),
+            &HashMap::from([("liveedge".to_string(), 1), ("news".to_string(), 3)])
+        );
+        let liveedge = registry.get(1).unwrap();
+        assert_eq!(liveedge.board_key, "liveedge");
+        assert_eq!(liveedge.title, "なんでも実況エッヂ");
+        // Not imported from the per-board var
+        assert_eq!(liveedge.default_name, FALLBACK_DEFAULT_NAME);
+        assert_eq!(liveedge.policy, BoardPolicy::default_for(1));
+        assert_eq!(registry.get(3).unwrap().policy, policy);
+        assert!(registry.get(4).is_none());
+        assert_eq!(
+            registry
+                .board_configs()
+                .iter()
+                .map(|b| b.board_id)
+                .collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_board() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+
+        let updating = UpdatingBoard {
+            default_name: Some("エッヂの名無し".to_string()),
+            ..Default::default()
+        };
+        repo.update_board(1, &updating).await.unwrap().unwrap();
+        let board = repo
+            .update_board(
+                1,
+                &UpdatingBoard {
+                    name: Some("エッヂ".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(board.name, "エッヂ");
+        // Omitted fields are kept
+        assert_eq!(board.default_name.as_deref(), Some("エッヂの名無し"));
+        assert!(board.local_rule.unwrap().contains("ローカルルール"));
+        assert!(repo
+            .update_board(2, &UpdatingBoard::default())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_import_legacy_boards() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let legacy_boards = LegacyBoard::parse_all("liveedge,news,,vip", |key| match key {
+            "liveedge" => Some("エッヂ,エッヂの名無し".to_string()),
+            "news" => Some("ニュース,名無しさん@ニュース".to_string()),
+            _ => None,
+        });
+        assert_eq!(
+            legacy_boards
+                .iter()
+                .map(|b| (b.id, b.board_key.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, "liveedge"), (2, "news"), (4, "vip")]
+        );
+
+        let boards = repo.get_boards().await.unwrap();
+        assert!(import_legacy_boards(&repo, &boards, &legacy_boards).await);
+        let registry = BoardRegistry::new(repo.get_boards().await.unwrap(), Vec::new());
+        let liveedge = registry.get(1).unwrap();
+        // Only default_name is filled in an existing board
+        assert_eq!(liveedge.title, "なんでも実況エッヂ");
+        assert_eq!(liveedge.default_name, "エッヂの名無し");
+        let news = registry.get(2).unwrap();
+        assert_eq!(
+            (
+                news.board_key,
+                news.title.as_str(),
+                news.default_name.as_str()
+            ),
+            ("news", "ニュース", "名無しさん@ニュース")
+        );
+        let vip = registry.get(4).unwrap();
+        assert_eq!(
+            (vip.board_key, vip.title.as_str(), vip.default_name.as_str()),
+            ("vip", "vip", FALLBACK_DEFAULT_NAME)
+        );
+
+        // Nothing to import anymore, and a taken ID is not reused
+        let boards = repo.get_boards().await.unwrap();
+        assert!(!import_legacy_boards(&repo, &boards, &legacy_boards).await);
+        let taken = LegacyBoard::parse_all("other", |_| None);
+        assert!(!import_legacy_boards(&repo, &boards, &taken).await);
+        assert_eq!(repo.get_boards().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_board_without_local_rule() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        storage
+            .execute_on_infos_db(
+                "INSERT INTO boards (id, name, board_key, local_rule) VALUES (2, 'ニュース', 'news', NULL)",
+            )
+            .unwrap();
+
+        let boards = repo.get_boards().await.unwrap();
+        assert_eq!(boards[1].local_rule, None);
+        let registry = BoardRegistry::new(boards, Vec::new());
+        assert_eq!(registry.get(2).unwrap().board_key, "news");
+    }
+
+    #[tokio::test]
+    async fn test_load_board_registry() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        invalidate_board_registry();
+        let registry = load_board_registry(&repo, &[]).await.unwrap();
+        assert_eq!(registry.board_keys().len(), 1);
+
+        repo.create_board(&creating_board(None, "news"))
+            .await
+            .unwrap();
+        // Cached until invalidated
+        let registry = load_board_registry(&repo, &[]).await.unwrap();
+        assert_eq!(registry.board_keys().len(), 1);
+        invalidate_board_registry();
+        let registry = load_board_registry(&repo, &[]).await.unwrap();
+        assert_eq!(registry.board_keys().len(), 2);
+    }
+}
@@ -0,0 +1,260 @@
+use sha1::{Digest, Sha1};
+use worker::{Headers, Response};
+
+use crate::{thread::Thread, utils::response_shift_jis_text_plain_with_cache};
+
+/// IMF-fixdate, the format of dates sent by this worker
+const IMF_FIXDATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+/// The obsolete formats which recipients still have to accept (RFC 7231 7.1.1.1)
+const RFC850_DATE: &str = "%A, %d-%b-%y %H:%M:%S GMT";
+const ASCTIME_DATE: &str = "%a %b %e %H:%M:%S %Y";
+/// JST, which some clients still send as it was accepted before the HTTP formats
+const LEGACY_JST_DATE: &str = "%Y/%m/%d %H:%M:%S";
+
+pub(crate) fn format_http_date(unix_secs: u64) -> String {
+    chrono::DateTime::from_timestamp(unix_secs as i64, 0)
+        .unwrap_or_default()
+        .format(IMF_FIXDATE)
+        .to_string()
+}
+
+pub(crate) fn parse_http_date(date: &str) -> Option<u64> {
+    let date = date.trim();
+    let timestamp = [IMF_FIXDATE, RFC850_DATE, ASCTIME_DATE]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(date, format).ok())
+        .map(|x| x.and_utc().timestamp())
+        .or_else(|| {
+            chrono::NaiveDateTime::parse_from_str(date, LEGACY_JST_DATE)
+                .ok()
+                .map(|x| x.and_utc().timestamp() - 32400) // fix local time
+        })?;
+    u64::try_from(timestamp).ok()
+}
+
+/// `ETag` and `Last-Modified` of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Validators {
+    /// A strong entity tag including the quotes
+    pub etag: String,
+    /// Unix time in seconds
+    pub last_modified: Option<u64>,
+}
+
+impl Validators {
+    /// The dat changes only when a response is added, which bumps both
+    pub fn for_thread(thread: &Thread) -> Validators {
+        Validators {
+            etag: format!("\"{}-{}\"", thread.last_modified, thread.response_count),
+            last_modified: thread.last_modified.parse().ok(),
+        }
+    }
+
+    /// subject.txt lists the threads in this order with their response counts
+    pub fn for_threads(threads: &[Thread]) -> Validators {
+        let mut hasher = Sha1::new();
+        for thread in threads {
+            hasher.update(format!(
+                "{}:{}:{}\n",
+                thread.thread_number, thread.last_modified, thread.response_count
+            ));
+        }
+        Validators {
+            etag: format!("\"{:x}\"", hasher.finalize()),
+            last_modified: threads
+                .iter()
+                .filter_map(|x| x.last_modified.parse().ok())
+                .max(),
+        }
+    }
+
+    /// For a body without a timestamp, e.g. SETTING.TXT
+    pub fn for_body(body: &str) -> Validators {
+        Validators {
+            etag: format!("\"{:x}\"", Sha1::digest(body)),
+            last_modified: None,
+        }
+    }
+
+    /// `ETag`, and `Last-Modified` if known
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![("ETag", self.etag.clone())];
+        if let Some(last_modified) = self.last_modified {
+            headers.push(("Last-Modified", format_http_date(last_modified)));
+        }
+        headers
+    }
+
+    pub fn append_to(&self, headers: &mut Headers) {
+        for (name, value) in self.headers() {
+            let _ = headers.append(name, &value);
+        }
+    }
+
+    /// 304 with the validators and `cache_control`, which a 304 has to repeat
+    pub fn not_modified(&self, cache_control: &str) -> worker::Result<Response> {
+        let mut resp = Response::empty()?.with_status(304);
+        self.append_to(resp.headers_mut());
+        let _ = resp.headers_mut().append("Cache-Control", cache_control);
+        Ok(resp)
+    }
+}
+
+/// 304 if `conditions` match, otherwise `body` with the validators
+pub(crate) fn response_shift_jis_text_plain_conditional(
+    body: &str,
+    ttl: usize,
+    validators: &Validators,
+    conditions: &Conditions,
+) -> worker::Result<Response> {
+    if conditions.is_not_modified(validators) {
+        return validators.not_modified(&format!("s-maxage={ttl}"));
+    }
+    let mut resp = response_shift_jis_text_plain_with_cache(body, ttl)?;
+    validators.append_to(resp.headers_mut());
+    Ok(resp)
+}
+
+/// The conditional headers of a request
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Conditions {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub if_range: Option<String>,
+}
+
+impl Conditions {
+    pub fn from_headers(headers: &Headers) -> Conditions {
+        let get = |name: &str| headers.get(name).ok().flatten();
+        Conditions {
+            if_none_match: get("If-None-Match"),
+            if_modified_since: get("If-Modified-Since"),
+            if_range: get("If-Range"),
+        }
+    }
+
+    /// `If-None-Match` takes precedence over `If-Modified-Since` (RFC 7232 6)
+    pub fn is_not_modified(&self, validators: &Validators) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == validators.etag);
+        }
+        match (
+            self.if_modified_since.as_deref().and_then(parse_http_date),
+            validators.last_modified,
+        ) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Whether `Range` applies, i.e. there is no `If-Range` or it still matches.
+    /// Otherwise the whole body is sent, since the client's copy is outdated.
+    pub fn is_range_applicable(&self, validators: &Validators) -> bool {
+        match self.if_range.as_deref().map(str::trim) {
+            None => true,
+            // Weak tags never match in If-Range
+            Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == validators.etag,
+            Some(date) => {
+                validators.last_modified.is_some()
+                    && parse_http_date(date) == validators.last_modified
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thread(thread_number: &str, last_modified: &str, response_count: u32) -> Thread {
+        Thread {
+            title: "スレ".to_string(),
+            response_count,
+            thread_number: thread_number.to_string(),
+            last_modified: last_modified.to_string(),
+            board_id: 1,
+            non_auth_thread: 0,
+            archived: 0,
+            active: 1,
+            metadent: None,
+            no_pool: 0,
+            modulo: 0,
+        }
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(784111777)
+        );
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(784111777));
+        // The legacy JST format is still accepted
+        assert_eq!(parse_http_date("1994/11/06 17:49:37"), Some(784111777));
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let validators = Validators::for_thread(&thread("1700000000", "1700000100", 5));
+        assert_eq!(validators.etag, "\"1700000100-5\"");
+        let since = |date: u64| Conditions {
+            if_modified_since: Some(format_http_date(date)),
+            ..Conditions::default()
+        };
+        assert!(since(1700000100).is_not_modified(&validators));
+        assert!(!since(1700000099).is_not_modified(&validators));
+
+        let none_match = |tags: &str| Conditions {
+            if_none_match: Some(tags.to_string()),
+            // Ignored when If-None-Match is present
+            if_modified_since: Some(format_http_date(1700000100)),
+            ..Conditions::default()
+        };
+        assert!(none_match("\"1700000000-1\", W/\"1700000100-5\"").is_not_modified(&validators));
+        assert!(none_match("*").is_not_modified(&validators));
+        assert!(!none_match("\"1700000000-1\"").is_not_modified(&validators));
+
+        assert!(!Conditions::default().is_not_modified(&validators));
+        // Without Last-Modified, only the ETag is compared
+        assert!(!since(1700000100).is_not_modified(&Validators::for_body("")));
+    }
+
+    #[test]
+    fn test_is_range_applicable() {
+        let validators = Validators::for_thread(&thread("1700000000", "1700000100", 5));
+        let if_range = |x: &str| Conditions {
+            if_range: Some(x.to_string()),
+            ..Conditions::default()
+        };
+        assert!(Conditions::default().is_range_applicable(&validators));
+        assert!(if_range("\"1700000100-5\"").is_range_applicable(&validators));
+        assert!(!if_range("W/\"1700000100-5\"").is_range_applicable(&validators));
+        assert!(!if_range("\"1700000000-1\"").is_range_applicable(&validators));
+        assert!(if_range(&format_http_date(1700000100)).is_range_applicable(&validators));
+        assert!(!if_range(&format_http_date(1700000000)).is_range_applicable(&validators));
+    }
+
+    #[test]
+    fn test_validators_for_threads() {
+        let threads = vec![
+            thread("1700000000", "1700000200", 10),
+            thread("1700000001", "1700000100", 3),
+        ];
+        let validators = Validators::for_threads(&threads);
+        assert_eq!(validators.last_modified, Some(1700000200));
+
+        // A new response changes the tag even if it's not the latest thread
+        let mut updated = threads.clone();
+        updated[1].response_count = 4;
+        assert_ne!(Validators::for_threads(&updated).etag, validators.etag);
+        assert_eq!(Validators::for_threads(&threads), validators);
+    }
+}
@@ -0,0 +1,87 @@
+use worker::{D1Database, Env};
+
+/// Bindings used as the responses databases when `RESPONSE_SHARDS` is not set
+const DEFAULT_RESPONSE_SHARDS: [&str; 3] = ["DB_RESPONSES", "DB_RESPONSES_2", "DB_RESPONSES_3"];
+
+pub(crate) struct DbOrchestrator {
+    pub infos_db: D1Database,
+    pub threads_db: D1Database,
+    pub responses_db: Vec<D1Database>,
+}
+
+impl DbOrchestrator {
+    /// Binds `DB`, `DB_THREADS` and the responses databases listed in `RESPONSE_SHARDS`
+    /// (comma separated binding names, in modulo order).
+    pub fn from_env(env: &Env) -> anyhow::Result<DbOrchestrator> {
+        let shards = env.var("RESPONSE_SHARDS").ok().map(|x| x.to_string());
+        let shards = parse_response_shards(shards.as_deref())?;
+
+        let d1 = |binding: &str| {
+            env.d1(binding)
+                .map_err(|_| anyhow::anyhow!("D1 binding `{binding}` is not configured"))
+        };
+
+        Ok(DbOrchestrator {
+            infos_db: d1("DB")?,
+            threads_db: d1("DB_THREADS")?,
+            responses_db: shards
+                .iter()
+                .map(|binding| d1(binding))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        })
+    }
+
+    pub fn get_responses_db(&self, modulo: usize) -> anyhow::Result<&D1Database> {
+        self.responses_db.get(modulo).ok_or_else(|| {
+            anyhow::anyhow!(
+                "responses database {modulo} is out of range ({} configured)",
+                self.responses_db.len()
+            )
+        })
+    }
+}
+
+/// Order matters: the index of a binding is the `modulo` stored in `threads`,
+/// so shards may only be appended.
+fn parse_response_shards(var: Option<&str>) -> anyhow::Result<Vec<String>> {
+    let Some(var) = var else {
+        return Ok(DEFAULT_RESPONSE_SHARDS.map(ToOwned::to_owned).to_vec());
+    };
+
+    let shards = var
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .collect::<Vec<_>>();
+    if shards.iter().any(|x| x.is_empty()) {
+        return Err(anyhow::anyhow!(
+            "RESPONSE_SHARDS contains an empty binding name"
+        ));
+    }
+    for (i, shard) in shards.iter().enumerate() {
+        if shards[..i].contains(shard) {
+            return Err(anyhow::anyhow!("RESPONSE_SHARDS lists `{shard}` twice"));
+        }
+    }
+
+    Ok(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response_shards() {
+        assert_eq!(
+            parse_response_shards(None).unwrap(),
+            vec!["DB_RESPONSES", "DB_RESPONSES_2", "DB_RESPONSES_3"]
+        );
+        assert_eq!(
+            parse_response_shards(Some("DB_RESPONSES, DB_RESPONSES_2")).unwrap(),
+            vec!["DB_RESPONSES", "DB_RESPONSES_2"]
+        );
+        assert!(parse_response_shards(Some("")).is_err());
+        assert!(parse_response_shards(Some("DB_RESPONSES,,DB_RESPONSES_2")).is_err());
+        assert!(parse_response_shards(Some("DB_RESPONSES,DB_RESPONSES")).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use worker::Cache;
+
+/// Ranges of the thread pages which every post makes stale, i.e. the last `n` responses
+const STALE_RANGES: [&str; 3] = ["l10", "l50", "l100"];
+
+/// URLs of the responses cached by `main` which a new post makes stale: the board's
+/// subject.txt and index, and if `thread_id` is given, the dat, the whole-thread pages and
+/// the pages of `STALE_RANGES`.
+///
+/// Other ranges, e.g. `100-`, are not listed since their URLs are unbounded; they still
+/// expire by `s-maxage`.
+pub(crate) fn stale_urls_after_post(
+    origin: &str,
+    board_key: &str,
+    thread_id: Option<&str>,
+) -> Vec<String> {
+    let mut urls = vec![
+        format!("{origin}/{board_key}/subject.txt"),
+        format!("{origin}/{board_key}/"),
+        format!("{origin}/{board_key}"),
+    ];
+    if let Some(thread_id) = thread_id {
+        urls.push(format!("{origin}/{board_key}/dat/{thread_id}.dat"));
+        for prefix in ["", "/test/read.cgi"] {
+            urls.push(format!("{origin}{prefix}/{board_key}/{thread_id}/"));
+            urls.push(format!("{origin}{prefix}/{board_key}/{thread_id}"));
+            for range in STALE_RANGES {
+                urls.push(format!("{origin}{prefix}/{board_key}/{thread_id}/{range}/"));
+                urls.push(format!("{origin}{prefix}/{board_key}/{thread_id}/{range}"));
+            }
+        }
+    }
+    urls
+}
+
+/// Deletes the responses from the cache of this data center, which is the one the poster
+/// reads from next. Other data centers still serve theirs until `s-maxage` passes.
+pub(crate) async fn purge_cached_urls(urls: &[String]) {
+    let cache = Cache::default();
+    for url in urls {
+        if let Err(e) = cache.delete(url, false).await {
+            log!("failed to purge {url} from the cache: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::repositories::bbs_repository::ResRange;
+    use crate::routes::{analyze_route, Route};
+
+    #[test]
+    fn test_stale_urls_after_post() {
+        let board_keys = HashMap::from([("liveedge".to_string(), 1)]);
+        let origin = "https://bbs.eddibb.cc";
+
+        let urls = stale_urls_after_post(origin, "liveedge", None);
+        assert_eq!(urls[0], "https://bbs.eddibb.cc/liveedge/subject.txt");
+        assert!(urls.iter().all(|url| !url.contains("1700000000")));
+
+        // Every URL is one of the cached routes of the board or the thread
+        let urls = stale_urls_after_post(origin, "liveedge", Some("1700000000"));
+        assert_eq!(urls.len(), 20);
+        for url in &urls {
+            let path = url.strip_prefix(origin).unwrap();
+            match analyze_route(path, &board_keys) {
+                Route::SubjectTxt { board_id: 1, .. } | Route::BoardIndex { board_id: 1, .. } => {}
+                Route::Dat {
+                    board_id: 1,
+                    thread_id: "1700000000",
+                    ..
+                }
+                | Route::ThreadWebUI {
+                    board_id: 1,
+                    thread_id: "1700000000",
+                    range: ResRange::All | ResRange::Last(10 | 50 | 100),
+                    ..
+                } => {}
+                route => panic!("{url} is routed to {route:?}"),
+            }
+        }
+    }
+}
@@ -31,6 +31,7 @@ fn get_cached_lwt_per_cookie() -> &'static Mutex<HashMap<String, NaiveDateTime>>
 fn reject_common(
     key: &str,
     lwt_map: &'static Mutex<HashMap<String, NaiveDateTime>>,
+    min_span_secs: u64,
 ) -> Result<bool> {
     let mut lock = lwt_map
         .lock()
@@ -40,7 +41,7 @@ fn reject_common(
         Entry::Occupied(mut lwt) => {
             let diff = now - *lwt.get();
             *lwt.get_mut() = now;
-            Ok(diff.num_seconds() < 5)
+            Ok(diff.num_seconds() < min_span_secs as i64)
         }
         Entry::Vacant(e) => {
             e.insert(now);
@@ -49,10 +50,10 @@ fn reject_common(
     }
 }
 
-pub(crate) fn maybe_reject_cookie(cookie: &str) -> Result<bool> {
-    reject_common(cookie, get_cached_lwt_per_cookie())
+pub(crate) fn maybe_reject_cookie(cookie: &str, min_span_secs: u64) -> Result<bool> {
+    reject_common(cookie, get_cached_lwt_per_cookie(), min_span_secs)
 }
 
-pub(crate) fn maybe_reject_ip(ip: &str) -> Result<bool> {
-    reject_common(ip, get_cached_lwt_per_ip())
+pub(crate) fn maybe_reject_ip(ip: &str, min_span_secs: u64) -> Result<bool> {
+    reject_common(ip, get_cached_lwt_per_ip(), min_span_secs)
 }
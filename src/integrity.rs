@@ -0,0 +1,481 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{repositories::bbs_repository::BbsRepository, utils::get_unix_timestamp_sec};
+
+/// Number of fixes applied per run when not specified
+pub(crate) const DEFAULT_REPAIR_LIMIT: usize = 50;
+
+/// Threads created within this many seconds are skipped, because a thread is inserted
+/// before its first response and may be rolled back
+const INTEGRITY_GRACE_SECS: u64 = 10 * 60;
+
+/// A row of `threads`, without the contents
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ThreadLocation {
+    pub board_id: usize,
+    pub thread_number: String,
+    pub modulo: usize,
+}
+
+/// Responses of a thread on a responses shard
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ResponseGroup {
+    pub board_id: usize,
+    pub thread_id: String,
+    pub n_responses: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct ResponsesOnShard {
+    pub board_id: usize,
+    pub thread_id: String,
+    pub modulo: usize,
+    pub n_responses: usize,
+}
+
+/// A thread without responses on the shard of its `modulo`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct BrokenThread {
+    pub board_id: usize,
+    pub thread_number: String,
+    pub modulo: usize,
+    /// Shards which have responses of the thread
+    pub found_on: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum IntegrityFix {
+    DeleteResponses {
+        board_id: usize,
+        thread_id: String,
+        modulo: usize,
+    },
+    UpdateModulo {
+        board_id: usize,
+        thread_number: String,
+        from: usize,
+        to: usize,
+    },
+    DeleteThread {
+        board_id: usize,
+        thread_number: String,
+    },
+}
+
+/// Inconsistencies between `threads` and the responses shards.
+///
+/// Threads being moved between shards (see `rebalance`) are not inspected.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct IntegrityReport {
+    pub n_shards: usize,
+    pub n_threads: usize,
+    /// Responses whose thread doesn't exist
+    pub orphaned_responses: Vec<ResponsesOnShard>,
+    /// Responses of a thread on another shard than its `modulo`
+    pub stray_responses: Vec<ResponsesOnShard>,
+    pub broken_threads: Vec<BrokenThread>,
+    /// What the fix mode does, or did. Stray responses outnumbering the ones on the shard of
+    /// the thread, and broken threads found on several shards are left to be checked by hand.
+    pub fixes: Vec<IntegrityFix>,
+    /// Fixes applied in this run, the first ones of `fixes`
+    pub applied: usize,
+}
+
+/// Whether the scheduled handler only logs the report or also applies the fixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IntegrityMode {
+    Report,
+    Fix,
+}
+
+impl IntegrityMode {
+    /// From `INTEGRITY_CHECK` ("report" or "fix"); the check is disabled without it
+    pub fn parse(var: Option<&str>) -> anyhow::Result<Option<IntegrityMode>> {
+        match var.map(str::trim) {
+            None | Some("") => Ok(None),
+            Some("report") => Ok(Some(IntegrityMode::Report)),
+            Some("fix") => Ok(Some(IntegrityMode::Fix)),
+            Some(x) => Err(anyhow::anyhow!(
+                "INTEGRITY_CHECK must be \"report\" or \"fix\": {x}"
+            )),
+        }
+    }
+}
+
+fn is_recent(thread_number: &str, now: u64) -> bool {
+    thread_number
+        .parse::<u64>()
+        .is_ok_and(|created_at| created_at.saturating_add(INTEGRITY_GRACE_SECS) > now)
+}
+
+/// Reads every thread and one grouped count per responses shard, and lists the fixes
+pub(crate) async fn check_integrity(repo: &BbsRepository<'_>) -> anyhow::Result<IntegrityReport> {
+    let n_shards = repo.n_responses_db();
+    let threads = repo.get_thread_locations().await?;
+    let mut groups = Vec::with_capacity(n_shards);
+    for modulo in 0..n_shards {
+        groups.push(repo.get_response_groups(modulo).await?);
+    }
+    let moving = repo
+        .get_shard_moves()
+        .await?
+        .into_iter()
+        .map(|m| (m.board_id, m.thread_number))
+        .collect::<HashSet<_>>();
+
+    Ok(inspect(
+        &threads,
+        &groups,
+        &moving,
+        get_unix_timestamp_sec(),
+    ))
+}
+
+fn inspect(
+    threads: &[ThreadLocation],
+    groups: &[Vec<ResponseGroup>],
+    moving: &HashSet<(usize, String)>,
+    now: u64,
+) -> IntegrityReport {
+    let mut report = IntegrityReport {
+        n_shards: groups.len(),
+        n_threads: threads.len(),
+        ..Default::default()
+    };
+
+    let modulo_of = threads
+        .iter()
+        .map(|t| ((t.board_id, t.thread_number.as_str()), t.modulo))
+        .collect::<HashMap<_, _>>();
+    // Responses of each thread on each shard
+    let mut found = HashMap::<(usize, &str), BTreeMap<usize, usize>>::new();
+    for (modulo, groups) in groups.iter().enumerate() {
+        for group in groups {
+            found
+                .entry((group.board_id, group.thread_id.as_str()))
+                .or_default()
+                .insert(modulo, group.n_responses);
+        }
+    }
+    let is_skipped = |board_id: usize, thread_number: &str| {
+        is_recent(thread_number, now) || moving.contains(&(board_id, thread_number.to_string()))
+    };
+
+    for (modulo, groups) in groups.iter().enumerate() {
+        for group in groups {
+            let key = (group.board_id, group.thread_id.as_str());
+            if is_skipped(key.0, key.1) {
+                continue;
+            }
+            let responses = ResponsesOnShard {
+                board_id: group.board_id,
+                thread_id: group.thread_id.clone(),
+                modulo,
+                n_responses: group.n_responses,
+            };
+            match modulo_of.get(&key) {
+                None => {
+                    report.fixes.push(IntegrityFix::DeleteResponses {
+                        board_id: group.board_id,
+                        thread_id: group.thread_id.clone(),
+                        modulo,
+                    });
+                    report.orphaned_responses.push(responses);
+                }
+                Some(&thread_modulo) if thread_modulo != modulo => {
+                    // Leftovers of a move, unless the shard of the thread has fewer responses
+                    let n_on_thread_shard = found[&key].get(&thread_modulo).copied().unwrap_or(0);
+                    if n_on_thread_shard >= group.n_responses {
+                        report.fixes.push(IntegrityFix::DeleteResponses {
+                            board_id: group.board_id,
+                            thread_id: group.thread_id.clone(),
+                            modulo,
+                        });
+                    }
+                    report.stray_responses.push(responses);
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    for thread in threads {
+        if is_skipped(thread.board_id, &thread.thread_number) {
+            continue;
+        }
+        let found_on = found
+            .get(&(thread.board_id, thread.thread_number.as_str()))
+            .map(|x| x.keys().copied().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if found_on.contains(&thread.modulo) {
+            continue;
+        }
+        match found_on.as_slice() {
+            [] => report.fixes.push(IntegrityFix::DeleteThread {
+                board_id: thread.board_id,
+                thread_number: thread.thread_number.clone(),
+            }),
+            [to] => report.fixes.push(IntegrityFix::UpdateModulo {
+                board_id: thread.board_id,
+                thread_number: thread.thread_number.clone(),
+                from: thread.modulo,
+                to: *to,
+            }),
+            _ => {}
+        }
+        report.broken_threads.push(BrokenThread {
+            board_id: thread.board_id,
+            thread_number: thread.thread_number.clone(),
+            modulo: thread.modulo,
+            found_on,
+        });
+    }
+
+    report
+}
+
+/// Checks the integrity and applies up to `limit` of the fixes.
+///
+/// Every fix is re-derived from the current state by the next run, so a run interrupted
+/// at any point is simply continued by the next one.
+pub(crate) async fn repair_integrity(
+    repo: &BbsRepository<'_>,
+    limit: usize,
+) -> anyhow::Result<IntegrityReport> {
+    let mut report = check_integrity(repo).await?;
+    for fix in report.fixes.iter().take(limit) {
+        log!("integrity: {fix:?}");
+        match fix {
+            IntegrityFix::DeleteResponses {
+                board_id,
+                thread_id,
+                modulo,
+            } => repo.delete_responses(*board_id, thread_id, *modulo).await?,
+            IntegrityFix::UpdateModulo {
+                board_id,
+                thread_number,
+                to,
+                ..
+            } => {
+                repo.update_thread_modulo(*board_id, thread_number, *to)
+                    .await?
+            }
+            IntegrityFix::DeleteThread {
+                board_id,
+                thread_number,
+            } => repo.delete_thread(*board_id, thread_number).await?,
+        }
+        report.applied += 1;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repositories::{
+            bbs_repository::{CreatingRes, CreatingThread},
+            bbs_storage::BbsStorage,
+            sqlite_storage::SqliteStorage,
+        },
+        thread::MetadentType,
+    };
+
+    /// Creates a thread with `n_responses` responses on the `modulo`-th shard
+    async fn create_thread_on(
+        storage: &SqliteStorage,
+        board_id: usize,
+        unix_time: &str,
+        modulo: usize,
+        n_responses: usize,
+    ) {
+        let thread = CreatingThread {
+            title: "スレ",
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id,
+            metadent: MetadentType::None,
+        };
+        storage
+            .insert_thread(&thread, unix_time, modulo)
+            .await
+            .unwrap();
+        let res = CreatingRes::from(&thread);
+        for _ in 0..n_responses {
+            storage
+                .insert_response(&res, modulo, 1000)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    /// The initial response of 1696233330 is seeded into every shard
+    fn seeded_strays(n_shards: usize) -> Vec<IntegrityFix> {
+        (1..n_shards)
+            .map(|modulo| IntegrityFix::DeleteResponses {
+                board_id: 1,
+                thread_id: "1696233330".to_string(),
+                modulo,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_check_integrity() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        // Healthy
+        create_thread_on(&storage, 1, "1720000000", 0, 2).await;
+        // Its thread row is gone
+        create_thread_on(&storage, 1, "1720000001", 1, 2).await;
+        repo.delete_thread(1, "1720000001").await.unwrap();
+        // Its responses are on another shard
+        create_thread_on(&storage, 1, "1720000002", 2, 3).await;
+        repo.update_thread_modulo(1, "1720000002", 0).await.unwrap();
+        // Its responses are gone
+        create_thread_on(&storage, 1, "1720000003", 0, 1).await;
+        repo.delete_responses(1, "1720000003", 0).await.unwrap();
+        // Still being created
+        storage
+            .execute_on_threads_db(&format!(
+                "INSERT INTO threads (thread_number, title, response_count, last_modified, board_id)
+                VALUES ('{0}', 'スレ', 1, '{0}', 1)",
+                get_unix_timestamp_sec()
+            ))
+            .unwrap();
+
+        let report = check_integrity(&repo).await.unwrap();
+        assert_eq!(report.n_threads, 5);
+        assert_eq!(
+            report.orphaned_responses,
+            vec![ResponsesOnShard {
+                board_id: 1,
+                thread_id: "1720000001".to_string(),
+                modulo: 1,
+                n_responses: 2,
+            }]
+        );
+        assert_eq!(
+            report
+                .stray_responses
+                .iter()
+                .map(|x| (x.thread_id.as_str(), x.modulo))
+                .collect::<Vec<_>>(),
+            vec![("1696233330", 1), ("1696233330", 2), ("1720000002", 2)]
+        );
+        assert_eq!(
+            report.broken_threads,
+            vec![
+                BrokenThread {
+                    board_id: 1,
+                    thread_number: "1720000002".to_string(),
+                    modulo: 0,
+                    found_on: vec![2],
+                },
+                BrokenThread {
+                    board_id: 1,
+                    thread_number: "1720000003".to_string(),
+                    modulo: 0,
+                    found_on: vec![],
+                },
+            ]
+        );
+
+        let [seeded_1, seeded_2] = seeded_strays(3).try_into().unwrap();
+        assert_eq!(
+            report.fixes,
+            vec![
+                seeded_1,
+                IntegrityFix::DeleteResponses {
+                    board_id: 1,
+                    thread_id: "1720000001".to_string(),
+                    modulo: 1,
+                },
+                seeded_2,
+                IntegrityFix::UpdateModulo {
+                    board_id: 1,
+                    thread_number: "1720000002".to_string(),
+                    from: 0,
+                    to: 2,
+                },
+                IntegrityFix::DeleteThread {
+                    board_id: 1,
+                    thread_number: "1720000003".to_string(),
+                },
+            ]
+        );
+        assert_eq!(report.applied, 0);
+    }
+
+    #[tokio::test]
+    async fn test_repair_integrity() {
+        let storage = SqliteStorage::new_in_memory(2).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread_on(&storage, 1, "1720000010", 1, 2).await;
+        repo.delete_thread(1, "1720000010").await.unwrap();
+        create_thread_on(&storage, 1, "1720000011", 1, 3).await;
+        repo.update_thread_modulo(1, "1720000011", 0).await.unwrap();
+
+        let report = repair_integrity(&repo, 1).await.unwrap();
+        assert_eq!(report.fixes.len(), 3);
+        assert_eq!(report.applied, 1);
+        let report = repair_integrity(&repo, DEFAULT_REPAIR_LIMIT).await.unwrap();
+        assert_eq!(report.applied, 2);
+
+        let report = check_integrity(&repo).await.unwrap();
+        assert!(report.fixes.is_empty());
+        assert!(report.orphaned_responses.is_empty());
+        assert!(report.broken_threads.is_empty());
+        let thread = repo.get_thread(1, "1720000011").await.unwrap().unwrap();
+        assert_eq!(thread.modulo, 1);
+        assert_eq!(
+            repo.get_responses(1, "1720000011", 1).await.unwrap().len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_threads_sharing_number() {
+        let thread = |board_id: usize| ThreadLocation {
+            board_id,
+            thread_number: "1720000020".to_string(),
+            modulo: 0,
+        };
+        let group = |board_id: usize| ResponseGroup {
+            board_id,
+            thread_id: "1720000020".to_string(),
+            n_responses: 1,
+        };
+        // Thread numbers are unique per board, and responses are told apart by the board
+        let report = inspect(
+            &[thread(1), thread(2)],
+            &[vec![group(1), group(2)]],
+            &HashSet::new(),
+            1720000020 + INTEGRITY_GRACE_SECS,
+        );
+        assert!(report.broken_threads.is_empty());
+        assert!(report.orphaned_responses.is_empty());
+        assert!(report.fixes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_integrity_mode() {
+        assert_eq!(IntegrityMode::parse(None).unwrap(), None);
+        assert_eq!(
+            IntegrityMode::parse(Some("fix")).unwrap(),
+            Some(IntegrityMode::Fix)
+        );
+        assert!(IntegrityMode::parse(Some("repair")).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{response::Res, thread::Thread};
+
+/// Threads per page of the kako index
+pub(crate) const KAKO_PAGE_SIZE: usize = 100;
+/// Threads listed in `kako/subject.txt`, newest first
+pub(crate) const KAKO_SUBJECT_LIMIT: usize = 5000;
+/// Months of the kako index are in JST, like the dates of the responses
+const JST_OFFSET_SECS: i64 = 9 * 3600;
+
+/// A row of `archives`, i.e. a thread whose dat is stored in R2
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Archive {
+    pub thread_number: String,
+    pub title: String,
+    pub response_count: u32,
+    pub board_id: u32,
+    pub last_modified: String,
+}
+
+impl From<&Archive> for Thread {
+    fn from(archive: &Archive) -> Self {
+        Thread {
+            title: archive.title.clone(),
+            response_count: archive.response_count,
+            thread_number: archive.thread_number.clone(),
+            last_modified: archive.last_modified.clone(),
+            board_id: archive.board_id,
+            non_auth_thread: 0,
+            archived: 1,
+            active: 0,
+            metadent: None,
+            no_pool: 0,
+            modulo: 0,
+        }
+    }
+}
+
+/// Number of archived threads created in `month` (`YYYY-MM`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ArchiveMonth {
+    pub month: String,
+    pub n_threads: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KakoMonth {
+    year: i32,
+    month: u32,
+}
+
+impl KakoMonth {
+    /// Parses `YYYY-MM`
+    pub fn parse(s: &str) -> Option<KakoMonth> {
+        let (year, month) = s.split_once('-')?;
+        if year.len() != 4 || month.len() != 2 {
+            return None;
+        }
+        let month = KakoMonth {
+            year: year.parse().ok()?,
+            month: month.parse().ok()?,
+        };
+        month.first_day()?;
+        Some(month)
+    }
+
+    fn first_day(&self) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(self.year, self.month, 1)
+    }
+
+    fn next(&self) -> KakoMonth {
+        if self.month == 12 {
+            KakoMonth {
+                year: self.year + 1,
+                month: 1,
+            }
+        } else {
+            KakoMonth {
+                year: self.year,
+                month: self.month + 1,
+            }
+        }
+    }
+
+    /// Thread numbers created in the month, `from..to`
+    pub fn thread_number_range(&self) -> (u32, u32) {
+        let start_of = |month: KakoMonth| {
+            month
+                .first_day()
+                .and_then(|x| x.and_hms_opt(0, 0, 0))
+                .map(|x| x.and_utc().timestamp() - JST_OFFSET_SECS)
+                .unwrap_or(0)
+                .clamp(0, u32::MAX as i64) as u32
+        };
+        (start_of(*self), start_of(self.next()))
+    }
+}
+
+impl std::fmt::Display for KakoMonth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// Reads back a dat rendered by `Ch5ResponsesFormatter`, returning the thread title and
+/// the responses numbered by line. Malformed lines are skipped but keep their number.
+pub(crate) fn parse_dat(dat: &str, thread_id: &str) -> (String, Vec<Res>) {
+    let mut title = String::new();
+    let mut responses = Vec::new();
+    for (i, line) in dat.lines().enumerate() {
+        let fields = line.split("<>").collect::<Vec<_>>();
+        let [name, mail, date_id, body, line_title] = fields[..] else {
+            continue;
+        };
+        if i == 0 {
+            title = line_title.to_string();
+        }
+        let (date, author_id) = match date_id.split_once(" ID:") {
+            Some((date, author_id)) => (date, Some(author_id.to_string())),
+            None => (date_id.trim(), None),
+        };
+        let is_abone = name == "あぼーん" && mail == "あぼーん" && date.is_empty();
+        responses.push(Res {
+            name: Some(name.to_string()),
+            mail: Some(mail.to_string()),
+            date: date.to_string(),
+            author_id,
+            body: body.strip_prefix(' ').unwrap_or(body).to_string(),
+            thread_id: thread_id.to_string(),
+            ip_addr: String::new(),
+            authed_token: None,
+            timestamp: 0,
+            is_abone: is_abone as u32,
+            res_no: i as u32 + 1,
+        });
+    }
+    (title, responses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repositories::{bbs_repository::BbsRepository, sqlite_storage::SqliteStorage},
+        response::Ch5ResponsesFormatter,
+    };
+
+    fn res(res_no: u32, name: Option<&str>, body: &str) -> Res {
+        Res {
+            name: name.map(ToOwned::to_owned),
+            mail: None,
+            date: "2099/09/09(水) 00:00:00.000".to_string(),
+            author_id: Some("abcdefghi".to_string()),
+            body: body.to_string(),
+            thread_id: "1700000000".to_string(),
+            ip_addr: "127.0.0.1".to_string(),
+            authed_token: None,
+            timestamp: 0,
+            is_abone: 0,
+            res_no,
+        }
+    }
+
+    #[test]
+    fn test_kako_month() {
+        assert_eq!(KakoMonth::parse("2024-13"), None);
+        assert_eq!(KakoMonth::parse("2024-1"), None);
+        assert_eq!(KakoMonth::parse("abcd-01"), None);
+        let month = KakoMonth::parse("2023-12").unwrap();
+        assert_eq!(month.to_string(), "2023-12");
+        // 2023-12-01T00:00:00+09:00 and 2024-01-01T00:00:00+09:00
+        assert_eq!(month.thread_number_range(), (1701356400, 1704034800));
+    }
+
+    #[test]
+    fn test_parse_dat() {
+        let responses = vec![
+            res(1, None, "本文<br>二行目"),
+            res(3, Some("コテハン"), "三レス目"),
+        ];
+        let dat = responses.format_responses("スレタイ", "名無し");
+
+        let (title, parsed) = parse_dat(&dat, "1700000000");
+        assert_eq!(title, "スレタイ");
+        assert_eq!(
+            parsed
+                .iter()
+                .map(|r| (
+                    r.res_no,
+                    r.name.as_deref().unwrap(),
+                    r.author_id.as_deref(),
+                    r.body.as_str(),
+                    r.is_abone
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, "名無し", Some("abcdefghi"), "本文<br>二行目", 0),
+                (2, "あぼーん", None, "あぼーん", 1),
+                (3, "コテハン", Some("abcdefghi"), "三レス目", 0),
+            ]
+        );
+        assert_eq!(parsed[0].date, "2099/09/09(水) 00:00:00.000");
+    }
+
+    #[tokio::test]
+    async fn test_get_archives() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        // 2023-11-30T23:59:59+09:00, 2023-12-01T00:00:00+09:00 and a day later
+        for (thread_number, board_id) in [
+            ("1701356399", 1),
+            ("1701356400", 1),
+            ("1701442800", 1),
+            ("1701442801", 2),
+        ] {
+            let archive = Archive {
+                thread_number: thread_number.to_string(),
+                title: format!("スレ{thread_number}"),
+                response_count: 1000,
+                board_id,
+                last_modified: thread_number.to_string(),
+            };
+            repo.upsert_archive(&Thread::from(&archive)).await.unwrap();
+        }
+
+        assert_eq!(
+            repo.get_archive_months(1).await.unwrap(),
+            vec![
+                ArchiveMonth {
+                    month: "2023-12".to_string(),
+                    n_threads: 2
+                },
+                ArchiveMonth {
+                    month: "2023-11".to_string(),
+                    n_threads: 1
+                },
+            ]
+        );
+        let thread_numbers = |archives: Vec<Archive>| {
+            archives
+                .into_iter()
+                .map(|x| x.thread_number)
+                .collect::<Vec<_>>()
+        };
+        let december = KakoMonth::parse("2023-12");
+        assert_eq!(
+            thread_numbers(repo.get_archives(1, december, 10, 0).await.unwrap()),
+            vec!["1701442800", "1701356400"]
+        );
+        assert_eq!(
+            thread_numbers(repo.get_archives(1, december, 1, 1).await.unwrap()),
+            vec!["1701356400"]
+        );
+        assert_eq!(
+            thread_numbers(repo.get_archives(1, None, 10, 0).await.unwrap()),
+            vec!["1701442800", "1701356400", "1701356399"]
+        );
+    }
+}
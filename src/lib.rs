@@ -1,35 +1,71 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 
-use board_config::BoardConfig;
+use archiver::{ArchiveConfig, DatArchive};
+use board_registry::{load_board_registry, BoardRegistry, LegacyBoard};
+use conditional::Conditions;
 use cookie::Cookie;
-use repositories::bbs_repository::BbsRepository;
+use db_orchestrator::DbOrchestrator;
+use integrity::IntegrityMode;
+use repositories::{bbs_repository::BbsRepository, d1_storage::D1Storage};
 use routes::{
     analyze_route,
     auth::{route_auth_get, route_auth_post},
     auth_code::{route_auth_code_get, route_auth_code_post},
     bbs_cgi::route_bbs_cgi,
-    dat_routing::{route_dat, DatRoutingThreadInfo},
+    dat_routing::{route_dat, DatRangePolicy, DatRoutingThreadInfo},
     head_txt::route_head_txt,
+    kako_subject_txt::route_kako_subject_txt,
+    search_json::route_search_json,
     subject_txt::route_subject_txt,
     webui,
 };
+use scheduled_tasks::{run_due_tasks, ArchiverTask, IntegrityTask, MaintenanceTask, ScheduledTask};
 use utils::response_shift_jis_text_plain_with_cache;
 use worker::*;
 
+/// `console_log!` which also works off Workers (e.g. `cargo test`), where it prints to stderr
+macro_rules! log {
+    ($($t:tt)*) => {{
+        #[cfg(target_arch = "wasm32")]
+        worker::console_log!($($t)*);
+        #[cfg(not(target_arch = "wasm32"))]
+        eprintln!($($t)*);
+    }};
+}
+
+mod archiver;
 mod authed_cookie;
 mod board;
 pub(crate) mod board_config;
+mod board_policy;
+mod board_registry;
 mod cap;
+mod conditional;
+mod db_orchestrator;
+mod edge_cache;
 mod grecaptcha;
 pub(crate) mod inmemory_cache;
+mod integrity;
+mod kako;
+mod maintenance;
+mod migrations;
+mod rebalance;
 pub mod response;
 pub mod routes;
-mod thread;
+mod scheduled_tasks;
+mod search;
+mod templates;
+pub mod thread;
 mod tinker;
 mod turnstile;
 mod utils;
 pub(crate) mod repositories {
     pub(crate) mod bbs_repository;
+    pub(crate) mod bbs_storage;
+    pub(crate) mod d1_storage;
+    pub(crate) mod responses_cache;
+    #[cfg(test)]
+    pub(crate) mod sqlite_storage;
 }
 pub(crate) mod services {
     pub(crate) mod auth_verification;
@@ -74,6 +110,31 @@ fn get_tinker_token_cookies(req: &Request) -> Option<String> {
     None
 }
 
+/// `q` and 1-origin `page` of the search routes
+fn get_search_params(req: &Request) -> Result<(String, usize)> {
+    let url = req.url()?;
+    let get = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.to_string())
+    };
+    let q = get("q").unwrap_or_default();
+    let page = get("page").and_then(|x| x.parse().ok()).unwrap_or(1);
+    Ok((q, page))
+}
+
+/// `month` (`YYYY-MM`) and 1-origin `page` of the kako routes
+fn get_kako_params(req: &Request) -> Result<(Option<String>, usize)> {
+    let url = req.url()?;
+    let get = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.to_string())
+    };
+    let page = get("page").and_then(|x| x.parse().ok()).unwrap_or(1);
+    Ok((get("month"), page))
+}
+
 /// Returns true if --var=WEBUI:false is passed
 fn check_webui_disabled(env: &Env) -> bool {
     match env.var("WEBUI") {
@@ -82,52 +143,14 @@ fn check_webui_disabled(env: &Env) -> bool {
     }
 }
 
-fn get_board_keys(env: &Env) -> Option<HashMap<String, usize>> {
+/// Boards still configured by `BOARD_KEYS` and the per-board vars, to be imported
+fn get_legacy_boards(env: &Env) -> Vec<LegacyBoard> {
     let Ok(board_keys) = env.var("BOARD_KEYS") else {
-        return None;
+        return Vec::new();
     };
-    Some(
-        board_keys
-            .to_string()
-            .split(',')
-            .enumerate()
-            .map(|(id, key)| (key.to_string(), id + 1))
-            .collect::<HashMap<_, _>>(),
-    )
-}
-
-fn get_board_info<'a>(env: &Env, board_id: usize, board_key: &'a str) -> Option<BoardConfig<'a>> {
-    let Ok(board_info) = env.var(board_key) else {
-        return None;
-    };
-    let board_info = board_info.to_string();
-    let info_split = board_info.split(',').collect::<Vec<_>>();
-    if info_split.len() < 2 {
-        None
-    } else {
-        Some(BoardConfig {
-            board_id,
-            board_key,
-            title: info_split[0].to_string(),
-            default_name: info_split[1].to_string(),
-        })
-    }
-}
-
-struct DbOrchestrator {
-    infos_db: D1Database,
-    threads_db: D1Database,
-    responses_db: Vec<D1Database>,
-}
-
-impl DbOrchestrator {
-    pub fn get_responses_db(&self, modulo: usize) -> &D1Database {
-        &self.responses_db[if modulo >= self.responses_db.len() {
-            0
-        } else {
-            modulo
-        }]
-    }
+    LegacyBoard::parse_all(&board_keys.to_string(), |key| {
+        env.var(key).ok().map(|x| x.to_string())
+    })
 }
 
 #[event(fetch)]
@@ -136,38 +159,48 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
     let token_cookie = get_token_cookies(&req);
     let ua = req.headers().get("User-Agent").ok().flatten();
 
-    let (infos_db, threads_db, responses_db) = (
-        env.d1("DB").unwrap(),
-        env.d1("DB_THREADS").unwrap(),
-        vec![
-            env.d1("DB_RESPONSES").unwrap(),
-            env.d1("DB_RESPONSES_2").unwrap(),
-            env.d1("DB_RESPONSES_3").unwrap(),
-        ],
-    );
-    let dbo = DbOrchestrator {
-        infos_db,
-        threads_db,
-        responses_db,
+    let dbo = match DbOrchestrator::from_env(&env) {
+        Ok(dbo) => dbo,
+        Err(e) => {
+            console_error!("{e}");
+            return Response::error(format!("internal server error: {e}"), 500);
+        }
     };
 
-    let repo = BbsRepository::new(&dbo);
-    let Some(board_keys) = get_board_keys(&env) else {
-        return Response::error(
-            "internal server error: failed to load environment settings",
-            500,
-        );
+    let storage = D1Storage::new(&dbo);
+    let repo = BbsRepository::new(&storage);
+
+    let path = req.path();
+    // Admin routes stay available so that the migrations can be applied
+    let is_admin = path.starts_with("/admin/");
+    if !is_admin {
+        match migrations::check_schema(&dbo).await {
+            Ok(pending) if pending.is_empty() => {}
+            Ok(pending) => {
+                console_error!("schema is out of date: {}", pending.join(", "));
+                return Response::error("service unavailable - schema is out of date", 503);
+            }
+            Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+        }
+    }
+
+    let board_registry = match load_board_registry(&repo, &get_legacy_boards(&env)).await {
+        Ok(board_registry) => board_registry,
+        // `boards` may be out of date until the migrations are applied
+        Err(_) if is_admin => Arc::new(BoardRegistry::default()),
+        Err(e) => {
+            console_error!("{e}");
+            return Response::error("internal server error - failed to load boards", 500);
+        }
     };
+    let route = analyze_route(&path, board_registry.board_keys());
 
-    match analyze_route(req.path().as_str(), &board_keys) {
+    match route {
         routes::Route::Index => {
             if check_webui_disabled(&env) {
                 return webui::webui_disabled(SITE_TITLE);
             }
-            let board_infos = board_keys
-                .iter()
-                .filter_map(|(board_key, board_id)| get_board_info(&env, *board_id, board_key))
-                .collect::<Vec<_>>();
+            let board_infos = board_registry.board_configs();
 
             webui::route_index(SITE_TITLE, SITE_NAME, SITE_DESCRIPTION, &board_infos)
                 .map_err(|e| Error::RustError(format!("Error in index.rs {}", e)))
@@ -220,7 +253,7 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
             route_bbs_cgi(
                 &mut req,
                 &env,
-                &board_keys,
+                &board_registry,
                 ua,
                 &repo,
                 token_cookie.as_deref(),
@@ -229,7 +262,7 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
             .await
         }
         routes::Route::Dat {
-            board_key,
+            board_key: _,
             thread_id,
             board_id,
         } => {
@@ -244,7 +277,7 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
                 return Response::error("internal server error - failed to parse url", 500);
             };
 
-            let Some(board_conf) = get_board_info(&env, board_id, board_key) else {
+            let Some(board_conf) = board_registry.get(board_id) else {
                 return Response::error("internal server error - failed to load board info", 500);
             };
             let result = route_dat(
@@ -257,6 +290,12 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
                 &repo,
                 &bucket,
                 host_url,
+                &DatRangePolicy::parse(
+                    env.var("DAT_RANGE_IGNORED_UAS")
+                        .ok()
+                        .map(|x| x.to_string())
+                        .as_deref(),
+                ),
             )
             .await?;
             // NOTE: cache putting is not used here because it's already cached in route_dat
@@ -276,20 +315,64 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
                 return Response::error("internal server error - bucket", 500);
             };
 
-            let log = bucket
-                .get(format!("{board_key}/dat/{thread_id}.dat"))
-                .execute()
-                .await?;
+            let log_text = match bucket
+                .get_dat(&archiver::dat_key(board_key, thread_id))
+                .await
+            {
+                Ok(Some(log_text)) => log_text,
+                Ok(None) => return Response::error("Not found - dat", 404),
+                Err(_) => return Response::error("Internal server error - dat bucket", 500),
+            };
+            let mut result = response_shift_jis_text_plain_with_cache(&log_text, 86400)?;
+            if let Ok(result) = result.cloned() {
+                if result.status_code() == 200 {
+                    let _ = cache.put(&req, result).await;
+                }
+            }
+
+            Ok(result)
+        }
+        routes::Route::KakoIndex {
+            board_key: _,
+            board_id,
+        } => {
+            if check_webui_disabled(&env) {
+                return webui::webui_disabled(SITE_TITLE);
+            }
+
+            if let Ok(Some(s)) = cache.get(&req, false).await {
+                return Ok(s);
+            }
 
-            let Some(log) = log else {
-                return Response::error("Not found - dat", 404);
+            let host_url = match utils::get_host_url(&req) {
+                Ok(url) => url,
+                Err(res) => return res,
             };
-            let Some(log_body) = log.body() else {
-                return Response::error("Internal server error - dat bucket", 500);
+            let Some(board_config) = board_registry.get(board_id) else {
+                return Response::error("internal server error - failed to load board info", 500);
             };
+            let (month, page) = get_kako_params(&req)?;
+            let mut resp =
+                webui::route_kako_index(&host_url, &board_config, &repo, month.as_deref(), page)
+                    .await?;
+            if let Ok(result) = resp.cloned() {
+                if result.status_code() == 200 {
+                    let _ = cache.put(&req, result).await;
+                }
+            }
 
-            let log_text = log_body.text().await?;
-            let mut result = response_shift_jis_text_plain_with_cache(&log_text, 86400)?;
+            Ok(resp)
+        }
+        routes::Route::KakoSubjectTxt {
+            board_key: _,
+            board_id,
+        } => {
+            if let Ok(Some(s)) = cache.get(&req, false).await {
+                return Ok(s);
+            }
+
+            let (month, _) = get_kako_params(&req)?;
+            let mut result = route_kako_subject_txt(&repo, board_id, month.as_deref()).await?;
             if let Ok(result) = result.cloned() {
                 if result.status_code() == 200 {
                     let _ = cache.put(&req, result).await;
@@ -298,14 +381,68 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
 
             Ok(result)
         }
+        routes::Route::KakoThreadWebUI {
+            board_key: _,
+            board_id,
+            thread_id,
+        } => {
+            if check_webui_disabled(&env) {
+                return webui::webui_disabled(SITE_TITLE);
+            }
+
+            if let Ok(Some(s)) = cache.get(&req, false).await {
+                return Ok(s);
+            }
+
+            let Some(bucket) = env.bucket("ARCHIVE_BUCKET").ok() else {
+                return Response::error("internal server error - bucket", 500);
+            };
+            let host_url = match utils::get_host_url(&req) {
+                Ok(url) => url,
+                Err(res) => return res,
+            };
+            let Ok(thread_id) = thread_id.parse::<u64>() else {
+                return Response::error("Not found", 404);
+            };
+            let Some(board_config) = board_registry.get(board_id) else {
+                return Response::error("internal server error - failed to load board info", 500);
+            };
+            let mut resp =
+                webui::route_kako_thread(thread_id, &board_config, &bucket, &host_url).await?;
+            if let Ok(result) = resp.cloned() {
+                if result.status_code() == 200 {
+                    let _ = cache.put(&req, result).await;
+                }
+            }
+
+            Ok(resp)
+        }
         routes::Route::SettingTxt {
-            board_key,
+            board_key: _,
             board_id,
         } => {
-            let Some(board_conf) = get_board_info(&env, board_id, board_key) else {
+            if let Ok(Some(s)) = cache.get(&req, false).await {
+                return Ok(s);
+            }
+
+            let Some(board_conf) = board_registry.get(board_id) else {
                 return Response::error("internal server error - failed to load board info", 500);
             };
-            routes::setting_txt::route_setting_txt(&board_conf)
+            let Ok(extras) = repo.get_board_settings(board_id).await else {
+                return Response::error("internal server error - failed to load settings", 500);
+            };
+            let mut result = routes::setting_txt::route_setting_txt(
+                &board_conf,
+                &extras,
+                &Conditions::from_headers(req.headers()),
+            )?;
+            if let Ok(result) = result.cloned() {
+                if result.status_code() == 200 {
+                    let _ = cache.put(&req, result).await;
+                }
+            }
+
+            Ok(result)
         }
         routes::Route::SubjectTxt {
             board_key: _,
@@ -314,7 +451,9 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
             if let Ok(Some(s)) = cache.get(&req, false).await {
                 return Ok(s);
             }
-            let mut result = route_subject_txt(&repo, board_id).await?;
+            let mut result =
+                route_subject_txt(&repo, board_id, &Conditions::from_headers(req.headers()))
+                    .await?;
 
             if let Ok(result) = result.cloned() {
                 if result.status_code() == 200 {
@@ -327,9 +466,9 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
         routes::Route::HeadTxt {
             board_key: _,
             board_id,
-        } => route_head_txt(board_id, &repo).await,
+        } => route_head_txt(board_id, &repo, &Conditions::from_headers(req.headers())).await,
         routes::Route::BoardIndex {
-            board_key,
+            board_key: _,
             board_id,
         } => {
             if check_webui_disabled(&env) {
@@ -344,7 +483,7 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
                 Ok(url) => url,
                 Err(res) => return res,
             };
-            let Some(board_config) = get_board_info(&env, board_id, board_key) else {
+            let Some(board_config) = board_registry.get(board_id) else {
                 return Response::error("internal server error - failed to load board info", 500);
             };
             let mut resp = webui::route_board(&host_url, &board_config, &repo).await?;
@@ -356,10 +495,58 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
 
             Ok(resp)
         }
+        routes::Route::Search {
+            board_key: _,
+            board_id,
+        } => {
+            if check_webui_disabled(&env) {
+                return webui::webui_disabled(SITE_TITLE);
+            }
+
+            if let Ok(Some(s)) = cache.get(&req, false).await {
+                return Ok(s);
+            }
+
+            let host_url = match utils::get_host_url(&req) {
+                Ok(url) => url,
+                Err(res) => return res,
+            };
+            let Some(board_config) = board_registry.get(board_id) else {
+                return Response::error("internal server error - failed to load board info", 500);
+            };
+            let (q, page) = get_search_params(&req)?;
+            let mut resp = webui::route_search(&host_url, &board_config, &repo, &q, page).await?;
+            if let Ok(result) = resp.cloned() {
+                if result.status_code() == 200 {
+                    let _ = cache.put(&req, result).await;
+                }
+            }
+
+            Ok(resp)
+        }
+        routes::Route::SearchJson {
+            board_key: _,
+            board_id,
+        } => {
+            if let Ok(Some(s)) = cache.get(&req, false).await {
+                return Ok(s);
+            }
+
+            let (q, page) = get_search_params(&req)?;
+            let mut resp = route_search_json(board_id, &repo, &q, page).await?;
+            if let Ok(result) = resp.cloned() {
+                if result.status_code() == 200 {
+                    let _ = cache.put(&req, result).await;
+                }
+            }
+
+            Ok(resp)
+        }
         routes::Route::ThreadWebUI {
-            board_key,
+            board_key: _,
             thread_id,
             board_id,
+            range,
         } => {
             // TODO(kenmo-melon): これだと/liveedge/hogehogeのようなURLにもアクセスできるが、
             // DBにたくさんアクセスする羽目になるよりはマシ？
@@ -377,11 +564,12 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
             let Ok(thread_id) = thread_id.parse::<u64>() else {
                 return Response::error("Not found", 404);
             };
-            let Some(board_config) = get_board_info(&env, board_id, board_key) else {
+            let Some(board_config) = board_registry.get(board_id) else {
                 return Response::error("internal server error - failed to load board info", 500);
             };
 
-            let mut resp = webui::route_thread(thread_id, &board_config, &repo, &host_url).await?;
+            let mut resp =
+                webui::route_thread(thread_id, range, &board_config, &repo, &host_url).await?;
             if let Ok(result) = resp.cloned() {
                 if result.status_code() == 200 {
                     let _ = cache.put(&req, result).await;
@@ -389,71 +577,66 @@ async fn main(mut req: Request, env: Env, _ctx: Context) -> Result<Response> {
             }
             Ok(resp)
         }
+        routes::Route::Admin(admin_route) => {
+            routes::admin::route_admin(&mut req, &env, admin_route, &dbo, &repo).await
+        }
         _ => Response::error(format!("Not found - other route {}", req.path()), 404),
     }
 }
 
 #[event(scheduled)]
 async fn scheduled(_req: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
-    let dbo = DbOrchestrator {
-        infos_db: env.d1("DB").unwrap(),
-        threads_db: env.d1("DB_THREADS").unwrap(),
-        responses_db: vec![
-            env.d1("DB_RESPONSES").unwrap(),
-            env.d1("DB_RESPONSES_2").unwrap(),
-            env.d1("DB_RESPONSES_3").unwrap(),
-        ],
+    let dbo = match DbOrchestrator::from_env(&env) {
+        Ok(dbo) => dbo,
+        Err(e) => {
+            console_error!("{e}");
+            return;
+        }
     };
 
-    dbo.threads_db
-        .prepare("UPDATE threads SET archived = 1 WHERE active = 0")
-        .run()
-        .await
-        .unwrap();
-
-    dbo.threads_db
-        .prepare(
-            "UPDATE threads SET archived = 1, active = 0 WHERE thread_number IN (
-        SELECT thread_number
-        FROM threads WHERE board_id = 1 AND archived = 0
-        ORDER BY CAST(last_modified AS INTEGER) DESC LIMIT 3000 OFFSET 60
-    )",
-        )
-        .run()
-        .await
-        .unwrap();
-
-    let repo = BbsRepository::new(&dbo);
-    let threads = repo
-        .get_threads(1, repositories::bbs_repository::ThreadStatus::Unarchived)
-        .await
-        .unwrap();
-
-    let targets = threads
-        .iter()
-        .filter(|x| x.response_count >= 100)
-        .collect::<Vec<_>>();
-
-    console_debug!("targets.len(): {}", targets.len());
-
-    for th in targets {
-        let (responses, thread) = tokio::join!(
-            repo.get_responses(1, &th.thread_number, th.modulo as usize),
-            repo.get_thread(1, &th.thread_number),
-        );
-        let thread = thread.unwrap().unwrap();
-        let responses = responses.unwrap();
-        console_log!("thread_number: {}", th.thread_number);
-        console_log!("thread.response_count: {}", thread.response_count);
-        console_log!("responses.len(): {}", responses.len());
-        if thread.response_count != responses.len() as u32 {
-            dbo.threads_db
-                .prepare("UPDATE threads SET response_count = ? WHERE thread_number = ?")
-                .bind(&[responses.len().into(), th.thread_number.clone().into()])
-                .unwrap()
-                .run()
-                .await
-                .unwrap();
+    match migrations::check_schema(&dbo).await {
+        Ok(pending) if pending.is_empty() => {}
+        Ok(pending) => {
+            console_error!("schema is out of date: {}", pending.join(", "));
+            return;
+        }
+        Err(e) => {
+            console_error!("failed to check schema: {e}");
+            return;
+        }
+    }
+
+    let storage = D1Storage::new(&dbo);
+    let repo = BbsRepository::new(&storage);
+    let board_registry = match load_board_registry(&repo, &get_legacy_boards(&env)).await {
+        Ok(board_registry) => board_registry,
+        Err(e) => {
+            console_error!("failed to load boards: {e}");
+            return;
         }
+    };
+    let boards = board_registry.board_configs();
+    let var = |name: &str| env.var(name).ok().map(|x| x.to_string());
+    // The archiver is disabled without the bucket
+    let bucket = env.bucket("ARCHIVE_BUCKET").ok();
+    let mut tasks: Vec<Box<dyn ScheduledTask>> = vec![Box::new(MaintenanceTask)];
+    match IntegrityMode::parse(var("INTEGRITY_CHECK").as_deref()) {
+        Ok(Some(mode)) => tasks.push(Box::new(IntegrityTask { mode })),
+        Ok(None) => {}
+        Err(e) => console_error!("{e}"),
     }
+    if let Some(bucket) = &bucket {
+        match ArchiveConfig::parse(
+            var("ARCHIVE_BATCH_SIZE").as_deref(),
+            var("ARCHIVE_PURGE").as_deref(),
+        ) {
+            Ok(config) => tasks.push(Box::new(ArchiverTask {
+                archive: bucket,
+                config,
+            })),
+            Err(e) => console_error!("{e}"),
+        }
+    }
+
+    run_due_tasks(&repo, &boards, &tasks).await;
 }
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::{
+    board_config::BoardConfig,
+    repositories::bbs_repository::{BbsRepository, ThreadStatus},
+};
+
+/// A row of the grouped `MAX(res_no)` over a responses shard
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ResponseCount {
+    pub thread_id: String,
+    pub response_count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResponseCountFix {
+    pub thread_number: String,
+    /// `response_count` when the responses were counted
+    pub from: u32,
+    pub to: u32,
+}
+
+/// What `run_scheduled_maintenance` did to a board
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct MaintenanceReport {
+    /// Stopped threads and threads beyond the live thread limit
+    pub archived: usize,
+    /// Threads whose `response_count` was repaired
+    pub repaired: usize,
+}
+
+/// Maintenance run by the scheduled (cron) handler for each board.
+///
+/// - Archives stopped threads and threads beyond `n_live_threads` of the board's policy
+/// - Repairs `response_count` of threads which drifted from their actual responses
+pub(crate) async fn run_scheduled_maintenance(
+    repo: &BbsRepository<'_>,
+    board: &BoardConfig<'_>,
+) -> anyhow::Result<MaintenanceReport> {
+    let board_id = board.board_id;
+    let mut report = MaintenanceReport {
+        archived: repo.archive_inactive_threads(board_id).await?,
+        repaired: 0,
+    };
+    report.archived += repo
+        .archive_threads_beyond(board_id, board.policy.n_live_threads)
+        .await?;
+
+    report.repaired = reconcile_response_counts(repo, board).await?;
+
+    Ok(report)
+}
+
+/// Repairs `response_count` of the live threads which drifted from the last `res_no` of
+/// their responses.
+///
+/// Runs one grouped count per responses shard and one batch of updates, however many
+/// threads there are. Returns the number of threads repaired.
+async fn reconcile_response_counts(
+    repo: &BbsRepository<'_>,
+    board: &BoardConfig<'_>,
+) -> anyhow::Result<usize> {
+    let threads = repo
+        .get_threads(board.board_id, ThreadStatus::Unarchived)
+        .await?;
+
+    let mut threads_by_shard = HashMap::<usize, Vec<_>>::new();
+    for th in &threads {
+        threads_by_shard
+            .entry(th.modulo as usize)
+            .or_default()
+            .push(th);
+    }
+
+    let mut fixes = Vec::new();
+    for (modulo, threads) in threads_by_shard {
+        let thread_ids = threads
+            .iter()
+            .map(|th| th.thread_number.clone())
+            .collect::<Vec<_>>();
+        let counts = repo
+            .count_responses(board.board_id, modulo, &thread_ids)
+            .await?
+            .into_iter()
+            .map(|c| (c.thread_id, c.response_count))
+            .collect::<HashMap<_, _>>();
+        for th in threads {
+            let actual = counts.get(&th.thread_number).copied().unwrap_or(0);
+            if th.response_count != actual {
+                log!(
+                    "{}/{}: response_count {} -> {}",
+                    board.board_key,
+                    th.thread_number,
+                    th.response_count,
+                    actual
+                );
+                fixes.push(ResponseCountFix {
+                    thread_number: th.thread_number.clone(),
+                    from: th.response_count,
+                    to: actual,
+                });
+            }
+        }
+    }
+
+    repo.fix_response_counts(board.board_id, &fixes).await?;
+    Ok(fixes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        board_policy::BoardPolicy,
+        repositories::{
+            bbs_repository::{CreatingRes, CreatingThread, THREAD_STOPPER},
+            sqlite_storage::SqliteStorage,
+        },
+        thread::MetadentType,
+    };
+
+    fn board(
+        board_id: usize,
+        board_key: &'static str,
+        n_live_threads: usize,
+    ) -> BoardConfig<'static> {
+        BoardConfig {
+            board_id,
+            board_key,
+            title: "板".to_string(),
+            default_name: "名無し".to_string(),
+            policy: BoardPolicy {
+                n_live_threads,
+                ..BoardPolicy::default_for(board_id)
+            },
+        }
+    }
+
+    async fn create_thread(repo: &BbsRepository<'_>, board_id: usize, unix_time: &str) {
+        repo.create_thread(CreatingThread {
+            title: "スレ",
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id,
+            metadent: MetadentType::None,
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn create_response(repo: &BbsRepository<'_>, thread_id: &str, modulo: usize) {
+        repo.create_response(
+            CreatingRes {
+                unix_time: thread_id,
+                body: "レス",
+                name: "",
+                mail: "",
+                date_time: "2099/09/09(水) 00:00:00.000",
+                author_ch5id: "abcdefghi",
+                authed_token: "token",
+                ip_addr: "127.0.0.1",
+                thread_id,
+                board_id: 1,
+            },
+            modulo,
+            THREAD_STOPPER,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_archive_threads_beyond_live_limit() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let board = board(1, "liveedge", 10);
+        for i in 0..board.policy.n_live_threads + 5 {
+            create_thread(&repo, 1, &(1800000000 + i).to_string()).await;
+        }
+
+        let report = run_scheduled_maintenance(&repo, &board).await.unwrap();
+
+        let live = repo.get_threads(1, ThreadStatus::Unarchived).await.unwrap();
+        assert_eq!(live.len(), board.policy.n_live_threads);
+        // The initial thread and the 5 oldest threads are archived
+        let archived = repo.get_threads(1, ThreadStatus::Archived).await.unwrap();
+        assert_eq!(archived.len(), 6);
+        assert!(archived
+            .iter()
+            .all(|th| th.thread_number.as_str() < "1800000006"));
+        assert_eq!(
+            report,
+            MaintenanceReport {
+                archived: 6,
+                repaired: 0
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_per_board() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let liveedge = board(1, "liveedge", 10);
+        let news = board(2, "news", 3);
+        for i in 0..5 {
+            create_thread(&repo, 1, &(1830000000 + i).to_string()).await;
+            create_thread(&repo, 2, &(1830000010 + i).to_string()).await;
+        }
+        // Stopped
+        storage
+            .execute_on_threads_db(
+                "UPDATE threads SET active = 0 WHERE thread_number = '1830000014'",
+            )
+            .unwrap();
+
+        let report = run_scheduled_maintenance(&repo, &news).await.unwrap();
+        assert_eq!(report.archived, 2);
+        let live = repo.get_threads(2, ThreadStatus::Unarchived).await.unwrap();
+        assert_eq!(
+            live.iter()
+                .map(|th| th.thread_number.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1830000011", "1830000012", "1830000013"]
+        );
+        // The other board is untouched
+        assert!(repo
+            .get_threads(1, ThreadStatus::Archived)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let report = run_scheduled_maintenance(&repo, &liveedge).await.unwrap();
+        assert_eq!(report, MaintenanceReport::default());
+        assert_eq!(
+            repo.get_threads(2, ThreadStatus::Unarchived)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repair_response_count() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let thread_id = "1810000000";
+        create_thread(&repo, 1, thread_id).await;
+        let modulo = repo.get_thread(1, thread_id).await.unwrap().unwrap().modulo as usize;
+        for _ in 0..119 {
+            create_response(&repo, thread_id, modulo).await;
+        }
+        storage
+            .execute_on_threads_db(
+                "UPDATE threads SET response_count = 150 WHERE thread_number = '1810000000'",
+            )
+            .unwrap();
+
+        let report = run_scheduled_maintenance(&repo, &board(1, "liveedge", 60))
+            .await
+            .unwrap();
+
+        let thread = repo.get_thread(1, thread_id).await.unwrap().unwrap();
+        assert_eq!(thread.response_count, 120);
+        assert_eq!(report.repaired, 1);
+    }
+
+    #[tokio::test]
+    async fn test_response_count_with_gap_is_kept() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let thread_id = "1810000002";
+        create_thread(&repo, 1, thread_id).await;
+        for _ in 0..2 {
+            create_response(&repo, thread_id, 0).await;
+        }
+        // 2 was rolled back after 3 was posted
+        storage
+            .execute_on_responses_db(
+                0,
+                "DELETE FROM responses WHERE thread_id = '1810000002' AND res_no = 2",
+            )
+            .unwrap();
+
+        let report = run_scheduled_maintenance(&repo, &board(1, "liveedge", 60))
+            .await
+            .unwrap();
+
+        let thread = repo.get_thread(1, thread_id).await.unwrap().unwrap();
+        assert_eq!(thread.response_count, 3);
+        assert_eq!(report.repaired, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stale_fix_is_skipped() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let thread_id = "1810000001";
+        create_thread(&repo, 1, thread_id).await;
+        create_response(&repo, thread_id, 0).await;
+
+        // Counted as 1 before the response above was posted
+        repo.fix_response_counts(
+            1,
+            &[ResponseCountFix {
+                thread_number: thread_id.to_string(),
+                from: 1,
+                to: 5,
+            }],
+        )
+        .await
+        .unwrap();
+        let thread = repo.get_thread(1, thread_id).await.unwrap().unwrap();
+        assert_eq!(thread.response_count, 2);
+    }
+}
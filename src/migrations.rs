@@ -0,0 +1,514 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+use worker::{async_trait::async_trait, D1Database};
+
+use crate::DbOrchestrator;
+
+/// Kind of database a migration is applied to. Responses migrations are applied to every shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DbKind {
+    Infos,
+    Threads,
+    Responses,
+}
+
+/// A directory under `migrations/{db}/{name}/`, whose `up.sql` is applied in registry order
+pub(crate) struct Migration {
+    pub db: DbKind,
+    pub name: &'static str,
+    pub up: &'static str,
+    /// Returns a row if the change is already in the schema, i.e. it was applied by hand
+    /// before the database was versioned. Then it is only recorded instead of being applied.
+    pub applied_check: Option<&'static str>,
+}
+
+macro_rules! migration {
+    ($db:expr, $dir:literal, $name:literal, $applied_check:expr) => {
+        Migration {
+            db: $db,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $dir, "/", $name, "/up.sql")),
+            applied_check: $applied_check,
+        }
+    };
+}
+
+/// Every migration, oldest first. Append new ones at the end.
+pub(crate) const MIGRATIONS: &[Migration] = &[
+    migration!(
+        DbKind::Infos,
+        "infos",
+        "initial_2023-10-02",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'boards'")
+    ),
+    migration!(
+        DbKind::Threads,
+        "threads",
+        "initial_2023-10-02",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'threads'")
+    ),
+    migration!(
+        DbKind::Responses,
+        "responses",
+        "initial_2023-10-02",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'responses'")
+    ),
+    migration!(
+        DbKind::Threads,
+        "threads",
+        "add-attributes-into-threads_2023-12-24",
+        Some("SELECT 1 FROM pragma_table_info('threads') WHERE name = 'modulo'")
+    ),
+    migration!(
+        DbKind::Responses,
+        "responses",
+        "add-res-no-into-responses_2026-10-18",
+        Some("SELECT 1 FROM pragma_table_info('responses') WHERE name = 'res_no'")
+    ),
+    migration!(
+        DbKind::Threads,
+        "threads",
+        "add-shard-moves_2026-10-18",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'shard_moves'")
+    ),
+    migration!(
+        DbKind::Threads,
+        "threads",
+        "add-threads-fts_2026-10-18",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'threads_fts'")
+    ),
+    migration!(
+        DbKind::Responses,
+        "responses",
+        "add-responses-fts_2026-10-18",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'responses_fts'")
+    ),
+    migration!(
+        DbKind::Infos,
+        "infos",
+        "add-archives-unique-index_2026-10-18",
+        Some(
+            "SELECT 1 FROM sqlite_master WHERE type = 'index' \
+            AND name = 'archives_board_id_thread_number_idx'"
+        )
+    ),
+    migration!(
+        DbKind::Threads,
+        "threads",
+        "add-archive-stored-into-threads_2026-10-18",
+        Some("SELECT 1 FROM pragma_table_info('threads') WHERE name = 'archive_stored'")
+    ),
+    migration!(
+        DbKind::Infos,
+        "infos",
+        "add-board-registry-columns_2026-10-18",
+        Some("SELECT 1 FROM pragma_table_info('boards') WHERE name = 'retired'")
+    ),
+    migration!(
+        DbKind::Infos,
+        "infos",
+        "add-board-policies_2026-10-18",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'board_policies'")
+    ),
+    migration!(
+        DbKind::Infos,
+        "infos",
+        "add-board-settings_2026-10-18",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'board_settings'")
+    ),
+    migration!(
+        DbKind::Infos,
+        "infos",
+        "add-task-runs_2026-10-18",
+        Some("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'task_runs'")
+    ),
+    migration!(
+        DbKind::Threads,
+        "threads",
+        "per-board-thread-numbers_2026-10-18",
+        Some(
+            "SELECT 1 FROM sqlite_master WHERE type = 'index' \
+            AND name = 'threads_board_id_thread_number_idx'"
+        )
+    ),
+];
+
+pub(crate) const SCHEMA_MIGRATIONS_EXISTS_QUERY: &str =
+    "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'";
+pub(crate) const CREATE_SCHEMA_MIGRATIONS_QUERY: &str =
+    "CREATE TABLE IF NOT EXISTS schema_migrations (
+    name TEXT PRIMARY KEY,
+    applied_at INTEGER NOT NULL
+)";
+pub(crate) const RECORD_MIGRATION_QUERY: &str =
+    "INSERT INTO schema_migrations (name, applied_at) VALUES (?, ?)";
+
+pub(crate) fn migrations_for(db: DbKind) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |m| m.db == db)
+}
+
+/// The operations the runner needs from a database
+#[async_trait(?Send)]
+pub(crate) trait SchemaDb {
+    /// Names in `schema_migrations`, empty if the table does not exist yet
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<String>>;
+
+    /// Returns true if `sql` returns any row
+    async fn exists(&self, sql: &str) -> anyhow::Result<bool>;
+
+    /// Runs the statements and records `name` in `schema_migrations`, atomically
+    async fn apply(&self, name: &str, statements: Vec<String>) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DbStatus {
+    pub label: String,
+    pub db: DbKind,
+    pub applied: Vec<String>,
+    pub pending: Vec<&'static str>,
+}
+
+pub(crate) async fn db_status(
+    label: &str,
+    kind: DbKind,
+    db: &dyn SchemaDb,
+) -> anyhow::Result<DbStatus> {
+    let applied = db.applied_migrations().await?;
+    let pending = migrations_for(kind)
+        .filter(|m| !applied.iter().any(|x| x == m.name))
+        .map(|m| m.name)
+        .collect();
+    Ok(DbStatus {
+        label: label.to_string(),
+        db: kind,
+        applied,
+        pending,
+    })
+}
+
+/// Applies the pending migrations of the database in order and returns their names.
+/// Stops at the first failure; the migrations before it stay applied.
+pub(crate) async fn apply_pending(
+    kind: DbKind,
+    db: &dyn SchemaDb,
+) -> anyhow::Result<Vec<&'static str>> {
+    let applied = db.applied_migrations().await?;
+
+    let mut newly_applied = Vec::new();
+    for m in migrations_for(kind).filter(|m| !applied.iter().any(|x| x == m.name)) {
+        let already_in_schema = match m.applied_check {
+            Some(check) => db.exists(check).await?,
+            None => false,
+        };
+
+        let mut statements = vec![CREATE_SCHEMA_MIGRATIONS_QUERY.to_string()];
+        if !already_in_schema {
+            statements.extend(split_statements(m.up));
+        }
+        db.apply(m.name, statements)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to apply {}: {e}", m.name))?;
+        log!(
+            "migration {} {}",
+            m.name,
+            if already_in_schema {
+                "recorded"
+            } else {
+                "applied"
+            }
+        );
+        newly_applied.push(m.name);
+    }
+
+    Ok(newly_applied)
+}
+
+/// Every database of the orchestrator with its label
+pub(crate) fn databases(dbo: &DbOrchestrator) -> Vec<(String, DbKind, &D1Database)> {
+    let mut dbs = vec![
+        ("infos".to_string(), DbKind::Infos, &dbo.infos_db),
+        ("threads".to_string(), DbKind::Threads, &dbo.threads_db),
+    ];
+    for (i, db) in dbo.responses_db.iter().enumerate() {
+        dbs.push((format!("responses_{i}"), DbKind::Responses, db));
+    }
+    dbs
+}
+
+/// Applies the pending migrations of every database. Returns `label/name` of the applied ones.
+pub(crate) async fn apply_all(dbo: &DbOrchestrator) -> anyhow::Result<Vec<String>> {
+    let mut applied = Vec::new();
+    for (label, kind, db) in databases(dbo) {
+        let names = apply_pending(kind, db)
+            .await
+            .map_err(|e| anyhow::anyhow!("{label}: {e}"))?;
+        applied.extend(names.into_iter().map(|name| format!("{label}/{name}")));
+    }
+    Ok(applied)
+}
+
+pub(crate) async fn status(dbo: &DbOrchestrator) -> anyhow::Result<Vec<DbStatus>> {
+    let mut statuses = Vec::new();
+    for (label, kind, db) in databases(dbo) {
+        statuses.push(db_status(&label, kind, db).await?);
+    }
+    Ok(statuses)
+}
+
+/// Set once every database is found up to date, so that the check runs once per isolate
+static SCHEMA_UP_TO_DATE: AtomicBool = AtomicBool::new(false);
+
+/// Returns the pending migrations (`label/name`) of every database, empty if up to date
+pub(crate) async fn check_schema(dbo: &DbOrchestrator) -> anyhow::Result<Vec<String>> {
+    if SCHEMA_UP_TO_DATE.load(Ordering::Relaxed) {
+        return Ok(Vec::new());
+    }
+
+    let pending = status(dbo)
+        .await?
+        .into_iter()
+        .flat_map(|s| {
+            s.pending
+                .into_iter()
+                .map(move |name| format!("{}/{name}", s.label))
+        })
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        SCHEMA_UP_TO_DATE.store(true, Ordering::Relaxed);
+    }
+    Ok(pending)
+}
+
+/// Splits a migration file into statements, so that they can be run in one atomic batch.
+///
+/// `;` inside string literals, comments and `BEGIN ... END` / `CASE ... END` blocks
+/// (e.g. trigger bodies) does not end the statement.
+pub(crate) fn split_statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut chars = sql.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' | '"' => {
+                current.push(c);
+                for d in chars.by_ref() {
+                    current.push(d);
+                    // A doubled quote is an escaped quote; it closes and reopens the literal
+                    if d == c {
+                        break;
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for d in chars.by_ref() {
+                    if d == '\n' {
+                        current.push('\n');
+                        break;
+                    }
+                }
+            }
+            ';' if depth == 0 => {
+                if !current.trim().is_empty() {
+                    statements.push(current.trim().to_string());
+                }
+                current.clear();
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = String::from(c);
+                while let Some(&d) = chars.peek() {
+                    if d.is_ascii_alphanumeric() || d == '_' {
+                        word.push(d);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match word.to_ascii_uppercase().as_str() {
+                    "BEGIN" | "CASE" => depth += 1,
+                    "END" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+                current.push_str(&word);
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.trim().is_empty() {
+        statements.push(current.trim().to_string());
+    }
+    statements
+}
+
+#[async_trait(?Send)]
+impl SchemaDb for D1Database {
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<String>> {
+        #[derive(serde::Deserialize)]
+        struct Row {
+            name: String,
+        }
+
+        if !self.exists(SCHEMA_MIGRATIONS_EXISTS_QUERY).await? {
+            return Ok(Vec::new());
+        }
+        let stmt = self.prepare("SELECT name FROM schema_migrations");
+        let Ok(rows) = stmt.all().await.and_then(|res| res.results::<Row>()) else {
+            return Err(anyhow::anyhow!("failed to fetch schema_migrations"));
+        };
+
+        Ok(rows.into_iter().map(|x| x.name).collect())
+    }
+
+    async fn exists(&self, sql: &str) -> anyhow::Result<bool> {
+        let Ok(row) = self.prepare(sql).first::<serde_json::Value>(None).await else {
+            return Err(anyhow::anyhow!("failed to run `{sql}`"));
+        };
+
+        Ok(row.is_some())
+    }
+
+    async fn apply(&self, name: &str, statements: Vec<String>) -> anyhow::Result<()> {
+        let mut stmts = statements
+            .iter()
+            .map(|sql| self.prepare(sql))
+            .collect::<Vec<_>>();
+        let Ok(record) = self.prepare(RECORD_MIGRATION_QUERY).bind(&[
+            name.into(),
+            (crate::utils::get_unix_timestamp_sec() as f64).into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind migration name"));
+        };
+        stmts.push(record);
+
+        // A D1 batch is a transaction, so a failed migration leaves nothing behind
+        self.batch(stmts)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("{e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rusqlite::Connection;
+
+    use super::*;
+
+    fn threads_migrations() -> Vec<&'static str> {
+        migrations_for(DbKind::Threads).map(|m| m.name).collect()
+    }
+
+    #[tokio::test]
+    async fn test_apply_pending_to_empty_db() {
+        let db = Mutex::new(Connection::open_in_memory().unwrap());
+        assert_eq!(
+            db_status("threads", DbKind::Threads, &db)
+                .await
+                .unwrap()
+                .pending,
+            threads_migrations()
+        );
+
+        assert_eq!(
+            apply_pending(DbKind::Threads, &db).await.unwrap(),
+            threads_migrations()
+        );
+        assert!(db_status("threads", DbKind::Threads, &db)
+            .await
+            .unwrap()
+            .pending
+            .is_empty());
+        assert!(db.exists("SELECT 1 FROM threads").await.unwrap());
+        assert!(apply_pending(DbKind::Threads, &db)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_apply_pending_to_unversioned_db() {
+        // Set up by hand as the README used to say, up to the modulo column
+        let db = Mutex::new(Connection::open_in_memory().unwrap());
+        for m in migrations_for(DbKind::Threads).take(2) {
+            db.lock().unwrap().execute_batch(m.up).unwrap();
+        }
+
+        // The existing changes are only recorded, so their non-idempotent statements
+        // (CREATE INDEX, ALTER TABLE) are not run again
+        assert_eq!(
+            apply_pending(DbKind::Threads, &db).await.unwrap(),
+            threads_migrations()
+        );
+        assert!(db
+            .exists("SELECT 1 FROM sqlite_master WHERE name = 'shard_moves'")
+            .await
+            .unwrap());
+        assert!(db_status("threads", DbKind::Threads, &db)
+            .await
+            .unwrap()
+            .pending
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_migration_is_not_recorded() {
+        let db = Mutex::new(Connection::open_in_memory().unwrap());
+        let statements = vec![
+            CREATE_SCHEMA_MIGRATIONS_QUERY.to_string(),
+            "CREATE TABLE a (x TEXT)".to_string(),
+            "INSERT INTO no_such_table VALUES (1)".to_string(),
+        ];
+        assert!(db.apply("broken", statements).await.is_err());
+        assert!(db.applied_migrations().await.unwrap().is_empty());
+        assert!(!db
+            .exists("SELECT 1 FROM sqlite_master WHERE name = 'a'")
+            .await
+            .unwrap());
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let sql = "-- comment; not a statement
+CREATE TABLE a (x TEXT DEFAULT 'a;b', y TEXT DEFAULT 'it''s;');
+CREATE TRIGGER t AFTER INSERT ON a BEGIN
+    UPDATE a SET x = CASE WHEN y = ';' THEN 1 ELSE 2 END;
+    DELETE FROM a;
+END;
+
+INSERT INTO a (x) VALUES ('end;')";
+        let statements = split_statements(sql);
+        assert_eq!(statements.len(), 3);
+        assert_eq!(
+            statements[0],
+            "CREATE TABLE a (x TEXT DEFAULT 'a;b', y TEXT DEFAULT 'it''s;')"
+        );
+        assert!(statements[1].starts_with("CREATE TRIGGER t"));
+        assert!(statements[1].ends_with("END"));
+        assert_eq!(statements[2], "INSERT INTO a (x) VALUES ('end;')");
+    }
+
+    #[test]
+    fn test_split_migration_files() {
+        for m in MIGRATIONS {
+            assert!(!split_statements(m.up).is_empty(), "{}", m.name);
+        }
+    }
+
+    #[test]
+    fn test_migration_names_are_unique_per_db() {
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert!(
+                !MIGRATIONS[..i]
+                    .iter()
+                    .any(|x| x.db == m.db && x.name == m.name),
+                "{}",
+                m.name
+            );
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use serde::{Deserialize, Serialize};
+
+use crate::repositories::bbs_repository::BbsRepository;
+
+/// Number of threads moved per run when not specified
+pub(crate) const DEFAULT_REBALANCE_LIMIT: usize = 5;
+
+/// A thread whose responses are moved from `from_modulo` to `to_modulo`.
+///
+/// It is recorded in `shard_moves` before anything is moved, so an interrupted run
+/// can be resumed. `active` keeps the state of the thread before the move, because
+/// the thread is stopped while its responses are in flight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ShardMove {
+    pub board_id: usize,
+    pub thread_number: String,
+    pub from_modulo: usize,
+    pub to_modulo: usize,
+    pub active: u32,
+}
+
+/// Threads grouped by their current shard and the shard they belong to
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ShardCount {
+    pub modulo: usize,
+    pub target_modulo: usize,
+    pub n_threads: usize,
+    pub n_responses: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct ShardStat {
+    pub modulo: usize,
+    /// Threads currently on this shard
+    pub n_threads: usize,
+    pub n_responses: usize,
+    /// Threads on this shard which belong to another one
+    pub n_misplaced: usize,
+    /// Threads on this shard after rebalancing
+    pub n_threads_after: usize,
+}
+
+/// Dry-run report of how threads are distributed over the responses databases
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ShardReport {
+    pub n_shards: usize,
+    pub shards: Vec<ShardStat>,
+    pub n_misplaced: usize,
+    /// Moves interrupted in a previous run
+    pub pending_moves: Vec<ShardMove>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RebalanceReport {
+    pub resumed: usize,
+    pub moved: Vec<ShardMove>,
+    pub remaining: usize,
+}
+
+pub(crate) async fn shard_report(repo: &BbsRepository<'_>) -> anyhow::Result<ShardReport> {
+    let n_shards = repo.n_responses_db();
+    let counts = repo.get_shard_counts(n_shards).await?;
+
+    let n_stats = counts
+        .iter()
+        .map(|x| x.modulo + 1)
+        .max()
+        .unwrap_or(0)
+        .max(n_shards);
+    let mut shards = (0..n_stats)
+        .map(|modulo| ShardStat {
+            modulo,
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    for count in &counts {
+        let stat = &mut shards[count.modulo];
+        stat.n_threads += count.n_threads;
+        stat.n_responses += count.n_responses;
+        if count.modulo != count.target_modulo {
+            stat.n_misplaced += count.n_threads;
+        }
+        shards[count.target_modulo].n_threads_after += count.n_threads;
+    }
+
+    Ok(ShardReport {
+        n_shards,
+        n_misplaced: shards.iter().map(|x| x.n_misplaced).sum(),
+        shards,
+        pending_moves: repo.get_shard_moves().await?,
+    })
+}
+
+/// Moves up to `limit` threads to `thread_number % (number of shards)`,
+/// finishing the moves left by an interrupted run first.
+pub(crate) async fn rebalance_shards(
+    repo: &BbsRepository<'_>,
+    limit: usize,
+) -> anyhow::Result<RebalanceReport> {
+    let n_shards = repo.n_responses_db();
+
+    let mut moves = repo.get_shard_moves().await?;
+    moves.truncate(limit);
+    let resumed = moves.len();
+    if moves.len() < limit {
+        let misplaced = repo
+            .get_misplaced_threads(n_shards, limit - moves.len())
+            .await?;
+        moves.extend(misplaced);
+    }
+
+    for m in &moves {
+        move_thread(repo, m).await?;
+    }
+
+    Ok(RebalanceReport {
+        resumed,
+        moved: moves,
+        remaining: shard_report(repo).await?.n_misplaced,
+    })
+}
+
+/// Every step is idempotent, so a move can be re-run from the start at any point.
+async fn move_thread(repo: &BbsRepository<'_>, m: &ShardMove) -> anyhow::Result<()> {
+    log!(
+        "moving {}/{} from {} to {}",
+        m.board_id,
+        m.thread_number,
+        m.from_modulo,
+        m.to_modulo
+    );
+    repo.begin_shard_move(m).await?;
+    repo.copy_responses(m).await?;
+    repo.update_thread_modulo(m.board_id, &m.thread_number, m.to_modulo)
+        .await?;
+    // Responses posted before the thread was stopped may have landed after the first copy
+    repo.copy_responses(m).await?;
+    repo.delete_responses(m.board_id, &m.thread_number, m.from_modulo)
+        .await?;
+    repo.finish_shard_move(m).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repositories::{
+            bbs_repository::{CreatingRes, CreatingThread},
+            bbs_storage::BbsStorage,
+            sqlite_storage::SqliteStorage,
+        },
+        thread::MetadentType,
+    };
+
+    /// Creates a thread as if there were only `n_shards` responses databases
+    async fn create_thread_on(storage: &SqliteStorage, unix_time: &str, n_shards: usize) {
+        let thread = CreatingThread {
+            title: "スレ",
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id: 1,
+            metadent: MetadentType::None,
+        };
+        let modulo = unix_time.parse::<usize>().unwrap() % n_shards;
+        storage
+            .insert_thread(&thread, unix_time, modulo)
+            .await
+            .unwrap();
+        let res = CreatingRes::from(&thread);
+        for _ in 0..3 {
+            let inserted = storage
+                .insert_response(&res, modulo, 1000)
+                .await
+                .unwrap()
+                .unwrap();
+            storage
+                .update_thread_by_response(&res, inserted.res_no, 1000, modulo)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn assert_on_target_shard(storage: &SqliteStorage, thread_id: &str) {
+        let thread = storage.get_thread(1, thread_id).await.unwrap().unwrap();
+        let target = thread_id.parse::<usize>().unwrap() % storage.n_responses_db();
+        assert_eq!(thread.modulo as usize, target);
+        assert_eq!(thread.active, 1);
+        for modulo in 0..storage.n_responses_db() {
+            let responses = storage.get_responses(1, thread_id, modulo).await.unwrap();
+            if modulo == target {
+                assert_eq!(
+                    responses.iter().map(|r| r.res_no).collect::<Vec<_>>(),
+                    vec![1, 2, 3]
+                );
+            } else {
+                assert!(responses.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shard_report() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        // 1800000002 (0 -> 2) and 1800000003 (1 -> 0) are misplaced
+        for unix_time in ["1800000000", "1800000001", "1800000002", "1800000003"] {
+            create_thread_on(&storage, unix_time, 2).await;
+        }
+
+        let report = shard_report(&repo).await.unwrap();
+        assert_eq!(report.n_shards, 3);
+        // Including the initial thread 1696233330, which is on shard 0 and belongs there
+        assert_eq!(
+            report
+                .shards
+                .iter()
+                .map(|x| (x.n_threads, x.n_misplaced, x.n_threads_after))
+                .collect::<Vec<_>>(),
+            vec![(3, 1, 3), (2, 1, 1), (0, 0, 1)]
+        );
+        assert_eq!(report.n_misplaced, 2);
+        assert!(report.pending_moves.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_shards() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let thread_ids = ["1800000000", "1800000001", "1800000002", "1800000003"];
+        for unix_time in thread_ids {
+            create_thread_on(&storage, unix_time, 2).await;
+        }
+
+        let report = rebalance_shards(&repo, 1).await.unwrap();
+        assert_eq!(report.moved.len(), 1);
+        assert_eq!(report.remaining, 1);
+        let report = rebalance_shards(&repo, 10).await.unwrap();
+        assert_eq!(report.moved.len(), 1);
+        assert_eq!(report.remaining, 0);
+
+        for thread_id in thread_ids {
+            assert_on_target_shard(&storage, thread_id).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_shards_resumes_interrupted_move() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread_on(&storage, "1800000002", 2).await;
+
+        // Interrupted right after the thread was switched to the new shard
+        let m = repo.get_misplaced_threads(3, 10).await.unwrap().remove(0);
+        repo.begin_shard_move(&m).await.unwrap();
+        repo.copy_responses(&m).await.unwrap();
+        repo.update_thread_modulo(1, "1800000002", m.to_modulo)
+            .await
+            .unwrap();
+        assert_eq!(
+            shard_report(&repo).await.unwrap().pending_moves,
+            vec![m.clone()]
+        );
+
+        let report = rebalance_shards(&repo, 10).await.unwrap();
+        assert_eq!(report.resumed, 1);
+        assert_eq!(report.moved, vec![m]);
+        assert_eq!(report.remaining, 0);
+        assert_on_target_shard(&storage, "1800000002").await;
+        assert!(repo.get_shard_moves().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_archive_skips_thread_being_moved() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread_on(&storage, "1800000005", 2).await;
+
+        let m = repo.get_misplaced_threads(3, 10).await.unwrap().remove(0);
+        repo.begin_shard_move(&m).await.unwrap();
+        // A cron run in the middle of the move
+        assert_eq!(repo.archive_inactive_threads(1).await.unwrap(), 0);
+        // Only the initial thread 1696233330
+        assert_eq!(repo.archive_threads_beyond(1, 0).await.unwrap(), 1);
+        repo.finish_shard_move(&m).await.unwrap();
+
+        let thread = repo.get_thread(1, "1800000005").await.unwrap().unwrap();
+        assert_eq!((thread.active, thread.archived), (1, 0));
+    }
+}
@@ -1,90 +1,116 @@
-use std::{
-    collections::HashMap,
-    sync::{atomic::AtomicU64, OnceLock, RwLock},
-};
+use std::sync::Arc;
 
-use tokio::join;
-use worker::{console_log, Date};
+use crate::{
+    authed_cookie::AuthedCookie,
+    kako::{Archive, ArchiveMonth, KakoMonth},
+    rebalance::{ShardCount, ShardMove},
+    repositories::{
+        bbs_storage::BbsStorage,
+        responses_cache::{responses_cache, CacheLookup, CachedThread},
+    },
+    response::Res,
+    search::SearchQuery,
+    thread::{MetadentType, Thread},
+    utils::get_current_millis,
+};
 
-use crate::{authed_cookie::AuthedCookie, response::Res, thread::MetadentType, DbOrchestrator};
+/// Default of `BoardPolicy::thread_stopper`
+pub(crate) const THREAD_STOPPER: u32 = 1000;
 
-const RESPONSES_CACHE_EXPIRE_TIME: u64 = 1000; // same as s-maxage=1
-const CLEAR_RESPONSES_CACHE_INTERVAL: u64 = 1000 * 60 * 5;
+/// Seconds from the creation time tried for a free thread number
+const THREAD_NUMBER_WINDOW_SECS: u64 = 5;
+/// Digits of a thread number that `analyze_route` accepts
+const THREAD_NUMBER_LEN: usize = 10;
 
-type ResponsesCacheMap = HashMap<(usize, String, usize), (u64, Vec<Res>)>;
+pub struct BbsRepository<'a> {
+    storage: &'a dyn BbsStorage,
+}
 
-fn get_responses_cache_map() -> &'static RwLock<ResponsesCacheMap> {
-    static RESPONSES_CACHE: OnceLock<RwLock<ResponsesCacheMap>> = OnceLock::new();
-    RESPONSES_CACHE.get_or_init(|| RwLock::new(HashMap::new()))
+impl<'a> BbsRepository<'a> {
+    pub fn new(storage: &'a dyn BbsStorage) -> BbsRepository<'a> {
+        BbsRepository { storage }
+    }
 }
 
-fn put_responses_cache(board_id: usize, thread_id: &str, modulo: usize, responses: Vec<Res>) {
-    let current_millis = Date::now().as_millis();
-    static LAST_REFRESHED: OnceLock<AtomicU64> = OnceLock::new();
-    let last_refreshed = LAST_REFRESHED.get_or_init(|| AtomicU64::new(current_millis));
+impl BbsRepository<'_> {
+    pub async fn get_board_info(
+        &self,
+        board_id: usize,
+    ) -> anyhow::Result<Option<crate::board::Board>> {
+        self.storage.get_board_info(board_id).await
+    }
 
-    let mut lock = get_responses_cache_map().write().unwrap();
-    if current_millis - last_refreshed.load(std::sync::atomic::Ordering::Relaxed)
-        > CLEAR_RESPONSES_CACHE_INTERVAL
-    {
-        lock.clear();
-        last_refreshed.store(current_millis, std::sync::atomic::Ordering::Relaxed);
-        console_log!("cache refreshed");
+    pub(crate) async fn get_boards(&self) -> anyhow::Result<Vec<crate::board::Board>> {
+        self.storage.get_boards().await
     }
 
-    lock.insert(
-        (board_id, thread_id.to_string(), modulo),
-        (current_millis, responses),
-    );
-}
+    pub(crate) async fn create_board(
+        &self,
+        board: &crate::board::CreatingBoard,
+    ) -> anyhow::Result<crate::board::Board> {
+        self.storage.create_board(board).await
+    }
 
-fn get_responses_cache(board_id: usize, thread_id: &str, modulo: usize) -> Option<Vec<Res>> {
-    let t = {
-        let lock = get_responses_cache_map().read().unwrap();
-        lock.get(&(board_id, thread_id.to_string(), modulo))
-            .cloned()
-    };
-    if let Some((timestamp, responses)) = t {
-        if Date::now().as_millis() - timestamp > RESPONSES_CACHE_EXPIRE_TIME {
-            console_log!("cache expired {board_id}/{thread_id} ({modulo})");
-            None
-        } else {
-            console_log!("cache hit {board_id}/{thread_id} ({modulo})");
-            Some(responses)
-        }
-    } else {
-        None
+    pub(crate) async fn update_board(
+        &self,
+        board_id: usize,
+        board: &crate::board::UpdatingBoard,
+    ) -> anyhow::Result<Option<crate::board::Board>> {
+        self.storage.update_board(board_id, board).await
     }
-}
 
-pub struct BbsRepository<'a> {
-    dbo: &'a DbOrchestrator,
-}
+    pub(crate) async fn get_board_policies(
+        &self,
+    ) -> anyhow::Result<Vec<crate::board_policy::BoardPolicy>> {
+        self.storage.get_board_policies().await
+    }
 
-impl<'a> BbsRepository<'a> {
-    pub fn new(dbo: &'a DbOrchestrator) -> BbsRepository<'a> {
-        BbsRepository { dbo }
+    pub(crate) async fn upsert_board_policy(
+        &self,
+        policy: &crate::board_policy::BoardPolicy,
+    ) -> anyhow::Result<()> {
+        self.storage.upsert_board_policy(policy).await
     }
-}
 
-impl BbsRepository<'_> {
-    pub async fn get_board_info(
+    pub(crate) async fn get_board_settings(
         &self,
         board_id: usize,
-    ) -> anyhow::Result<Option<crate::board::Board>> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare("SELECT * FROM boards WHERE id = ?")
-            .bind(&[board_id.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind id"));
-        };
-        let Ok(board) = stmt.first::<crate::board::Board>(None).await else {
-            return Err(anyhow::anyhow!("failed to fetch board"));
-        };
+    ) -> anyhow::Result<Vec<crate::board::BoardSetting>> {
+        self.storage.get_board_settings(board_id).await
+    }
+
+    pub(crate) async fn replace_board_settings(
+        &self,
+        board_id: usize,
+        settings: &[crate::board::BoardSetting],
+    ) -> anyhow::Result<()> {
+        self.storage
+            .replace_board_settings(board_id, settings)
+            .await
+    }
+
+    pub(crate) async fn get_last_task_runs(
+        &self,
+    ) -> anyhow::Result<Vec<crate::scheduled_tasks::LastTaskRun>> {
+        self.storage.get_last_task_runs().await
+    }
+
+    pub(crate) async fn get_task_runs(
+        &self,
+        limit: usize,
+    ) -> anyhow::Result<Vec<crate::scheduled_tasks::TaskRun>> {
+        self.storage.get_task_runs(limit).await
+    }
 
-        Ok(board)
+    pub(crate) async fn insert_task_run(
+        &self,
+        run: &crate::scheduled_tasks::TaskRun,
+    ) -> anyhow::Result<()> {
+        self.storage.insert_task_run(run).await
+    }
+
+    pub(crate) async fn delete_task_runs_before(&self, started_at: u64) -> anyhow::Result<()> {
+        self.storage.delete_task_runs_before(started_at).await
     }
 
     pub async fn get_thread(
@@ -92,19 +118,7 @@ impl BbsRepository<'_> {
         board_id: usize,
         thread_id: &str,
     ) -> anyhow::Result<Option<crate::thread::Thread>> {
-        let Ok(stmt) = self
-            .dbo
-            .threads_db
-            .prepare("SELECT * FROM threads WHERE thread_number = ? AND board_id = ?")
-            .bind(&[thread_id.into(), board_id.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind thread_number and board_id"));
-        };
-        let Ok(thread) = stmt.first::<crate::thread::Thread>(None).await else {
-            return Err(anyhow::anyhow!("failed to fetch thread"));
-        };
-
-        Ok(thread)
+        self.storage.get_thread(board_id, thread_id).await
     }
 
     pub async fn get_threads(
@@ -112,34 +126,7 @@ impl BbsRepository<'_> {
         board_id: usize,
         status: ThreadStatus,
     ) -> anyhow::Result<Vec<crate::thread::Thread>> {
-        let Ok(stmt) = self
-            .dbo
-            .threads_db
-            .prepare(match status {
-                ThreadStatus::Active => "SELECT * FROM threads WHERE board_id = ? AND active = 1",
-                ThreadStatus::Inactive => {
-                    "SELECT * FROM threads WHERE board_id = ? AND active = 0 AND archived = 0"
-                }
-                ThreadStatus::Archived => {
-                    "SELECT * FROM threads WHERE board_id = ? AND archived = 1"
-                }
-                ThreadStatus::Unarchived => {
-                    "SELECT * FROM threads WHERE board_id = ? AND archived = 0"
-                }
-            })
-            .bind(&[board_id.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind board_id"));
-        };
-        let Ok(threads) = stmt
-            .all()
-            .await
-            .and_then(|res| res.results::<crate::thread::Thread>())
-        else {
-            return Err(anyhow::anyhow!("failed to fetch threads"));
-        };
-
-        Ok(threads)
+        self.storage.get_threads(board_id, status).await
     }
 
     pub async fn get_responses(
@@ -148,24 +135,116 @@ impl BbsRepository<'_> {
         thread_id: &str,
         modulo: usize,
     ) -> anyhow::Result<Vec<Res>> {
-        let cached_resps = get_responses_cache(board_id, thread_id, modulo);
-        if let Some(resps) = cached_resps {
-            return Ok(resps);
+        self.get_responses_in_range(board_id, thread_id, modulo, ResRange::All)
+            .await
+    }
+
+    /// Ranged reads are served from the cache if the thread is there, but only a read of
+    /// every response loads a thread into it. Otherwise the range is read from storage.
+    pub async fn get_responses_in_range(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        range: ResRange,
+    ) -> anyhow::Result<Vec<Res>> {
+        let read = |thread: &mut CachedThread| thread.select(range);
+        if range == ResRange::All {
+            return self
+                .read_cached_thread(board_id, thread_id, modulo, read)
+                .await;
         }
-        let Ok(stmt) = self
-            .dbo
-            .get_responses_db(modulo)
-            .prepare("SELECT * FROM responses WHERE thread_id = ? AND board_id = ?")
-            .bind(&[thread_id.into(), board_id.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind thread_id and board_id"));
-        };
-        let Ok(responses) = stmt.all().await.and_then(|res| res.results::<Res>()) else {
-            return Err(anyhow::anyhow!("failed to fetch responses"));
-        };
+        match self
+            .lookup_cached_thread(board_id, thread_id, modulo, read)
+            .await?
+        {
+            Some(responses) => Ok(responses),
+            None => {
+                self.storage
+                    .get_responses_in_range(board_id, thread_id, modulo, range)
+                    .await
+            }
+        }
+    }
+
+    /// The dat of the thread in Shift_JIS, where only the responses posted since the last
+    /// request are encoded. The bytes are shared with the cache.
+    pub async fn get_sjis_dat(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        thread_title: &str,
+        default_name: &str,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        self.read_cached_thread(board_id, thread_id, modulo, |thread| {
+            thread.sjis_dat(thread_title, default_name)
+        })
+        .await
+    }
+
+    /// Reads the thread from the isolate's cache, loading every response of it on a miss
+    async fn read_cached_thread<T>(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        read: impl Fn(&mut CachedThread) -> T,
+    ) -> anyhow::Result<T> {
+        if let Some(value) = self
+            .lookup_cached_thread(board_id, thread_id, modulo, &read)
+            .await?
+        {
+            return Ok(value);
+        }
+
+        let now = get_current_millis();
+        let responses = self
+            .storage
+            .get_responses_in_range(board_id, thread_id, modulo, ResRange::All)
+            .await?;
+        Ok(responses_cache().lock().unwrap().insert(
+            (board_id, thread_id.to_string(), modulo),
+            responses,
+            now,
+            &read,
+        ))
+    }
 
-        put_responses_cache(board_id, thread_id, modulo, responses.clone());
-        Ok(responses)
+    /// Reads the thread if it is cached, which only fetches the responses newer than the
+    /// ones it holds. `None` if it is not cached or has to be loaded again.
+    async fn lookup_cached_thread<T>(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        read: impl Fn(&mut CachedThread) -> T,
+    ) -> anyhow::Result<Option<T>> {
+        let key = (board_id, thread_id.to_string(), modulo);
+        let now = get_current_millis();
+        let lookup = responses_cache().lock().unwrap().lookup(&key, now, &read);
+        match lookup {
+            CacheLookup::Fresh(value) => Ok(Some(value)),
+            CacheLookup::Stale(last_res_no) => {
+                let responses = self
+                    .storage
+                    .get_responses_in_range(
+                        board_id,
+                        thread_id,
+                        modulo,
+                        ResRange::Since(last_res_no),
+                    )
+                    .await?;
+                Ok(responses_cache().lock().unwrap().append(
+                    &key,
+                    last_res_no,
+                    responses,
+                    now,
+                    &read,
+                ))
+            }
+            CacheLookup::Miss => Ok(None),
+        }
     }
 
     pub async fn get_responses_by_authed_token_and_timestamp(
@@ -173,39 +252,13 @@ impl BbsRepository<'_> {
         authed_token: &str,
         min_timestamp: &str,
     ) -> anyhow::Result<Vec<Res>> {
-        let mut responses = Vec::new();
-        for m in &self.dbo.responses_db {
-            let Ok(stmt) = m
-                .prepare("SELECT * FROM responses WHERE authed_token = ? AND timestamp > ?")
-                .bind(&[authed_token.into(), min_timestamp.into()])
-            else {
-                return Err(anyhow::anyhow!("failed to bind authed_token and timestamp"));
-            };
-            if let Ok(resps) = stmt.all().await.and_then(|res| res.results::<Res>()) {
-                responses.extend(resps);
-            } else {
-                return Err(anyhow::anyhow!("failed to fetch responses"));
-            }
-        }
-
-        Ok(responses)
+        self.storage
+            .get_responses_by_authed_token_and_timestamp(authed_token, min_timestamp)
+            .await
     }
 
     pub async fn get_authed_token(&self, token: &str) -> anyhow::Result<Option<AuthedCookie>> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare("SELECT * FROM authed_cookies WHERE cookie = ?")
-            .bind(&[token.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind token"));
-        };
-
-        if let Ok(authed_cookie) = stmt.first::<AuthedCookie>(None).await {
-            Ok(authed_cookie)
-        } else {
-            Err(anyhow::anyhow!("failed to fetch authed_cookie"))
-        }
+        self.storage.get_authed_token(token).await
     }
 
     pub async fn get_authed_token_by_origin_ip_and_auth_code(
@@ -213,167 +266,168 @@ impl BbsRepository<'_> {
         ip: &str,
         auth_code: &str,
     ) -> anyhow::Result<Option<AuthedCookie>> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare("SELECT * FROM authed_cookies WHERE origin_ip = ? AND auth_code = ?")
-            .bind(&[ip.into(), auth_code.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind ip and auth_code"));
+        self.storage
+            .get_authed_token_by_origin_ip_and_auth_code(ip, auth_code)
+            .await
+    }
+
+    /// Creates the thread and its first response, and returns the thread number.
+    ///
+    /// The number is the creation time, or if it's taken on the board, one of the next
+    /// `THREAD_NUMBER_WINDOW_SECS` seconds, so that threads created in the same second
+    /// don't fail.
+    ///
+    /// The thread row and the response row live in different databases, so they can't
+    /// share one D1 batch. The thread is inserted first and is deleted again if the
+    /// response can't be written. This is best-effort compensation, not a transaction: if
+    /// the isolate dies in between or the delete fails (`WriteError::RollbackFailed`), a
+    /// thread without responses is left behind, which the integrity check deletes
+    /// (`IntegrityFix::DeleteThread`).
+    pub async fn create_thread(&self, thread: CreatingThread<'_>) -> Result<String, WriteError> {
+        let Ok(unix_time) = thread.unix_time.parse::<u64>() else {
+            return Err(WriteError::Failed("invalid unix_time".to_string()));
         };
 
-        if let Ok(authed_cookie) = stmt.first::<AuthedCookie>(None).await {
-            Ok(authed_cookie)
-        } else {
-            Err(anyhow::anyhow!("failed to fetch authed_cookie"))
+        let mut allocated = None;
+        for thread_number in unix_time..unix_time + THREAD_NUMBER_WINDOW_SECS {
+            let thread_number = thread_number.to_string();
+            // Routes only accept 10-digit thread numbers
+            if thread_number.len() != THREAD_NUMBER_LEN {
+                break;
+            }
+            let modulo = self.modulo_of(&thread_number)?;
+            match self
+                .storage
+                .insert_thread(&thread, &thread_number, modulo)
+                .await
+            {
+                Ok(true) => {
+                    allocated = Some((thread_number, modulo));
+                    break;
+                }
+                Ok(false) => continue,
+                Err(e) => return Err(WriteError::Failed(e.to_string())),
+            }
+        }
+        let Some((thread_number, modulo)) = allocated else {
+            return Err(WriteError::ThreadAlreadyExists);
+        };
+
+        let res = CreatingRes {
+            thread_id: &thread_number,
+            ..CreatingRes::from(&thread)
+        };
+        let err = match self
+            .storage
+            .insert_response(&res, modulo, THREAD_STOPPER)
+            .await
+        {
+            Ok(Some(_)) => return Ok(thread_number),
+            // Leftover responses of a thread with the same number
+            Ok(None) => WriteError::Failed("thread is already full".to_string()),
+            Err(e) => WriteError::Failed(e.to_string()),
+        };
+
+        match self
+            .storage
+            .delete_thread(thread.board_id, &thread_number)
+            .await
+        {
+            Ok(_) => Err(err),
+            Err(re) => Err(WriteError::RollbackFailed(format!("{err}; {re}"))),
         }
     }
 
-    pub async fn create_thread(&self, thread: CreatingThread<'_>) -> anyhow::Result<()> {
-        let metadent: Option<&str> = thread.metadent.into();
-        let metadent = metadent.unwrap_or("");
-        let modulo = thread.unix_time.parse::<usize>().unwrap() % self.dbo.responses_db.len();
-        let th_stmt = self
-            .dbo
-            .threads_db
-            .prepare(
-                "INSERT INTO threads
-                (thread_number, title, response_count, board_id, last_modified, authed_cookie, metadent, modulo)
-                VALUES (?, ?, 1, ?, ?, ?, ?, ?)",
-            )
-            .bind(&[
-                thread.unix_time.into(),
-                thread.title.into(),
-                thread.board_id.into(),
-                thread.unix_time.into(),
-                thread.authed_token.into(),
-                metadent.into(),
-                modulo.into(),
-            ]);
-
-        let res_stmt = self
-            .dbo
-            .get_responses_db(modulo)
-            .prepare(
-                "INSERT INTO responses 
-                (name, mail, date, author_id, body, thread_id, ip_addr, authed_token, timestamp, board_id)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            )
-            .bind(&[
-                thread.name.into(),
-                thread.mail.into(),
-                thread.date_time.into(),
-                thread.author_ch5id.into(),
-                thread.body.into(),
-                thread.unix_time.into(),
-                thread.ip_addr.into(),
-                thread.authed_token.into(),
-                thread.unix_time.into(),
-                thread.board_id.into(),
-            ]);
-
-        match (th_stmt, res_stmt) {
-            (Ok(th_stmt), Ok(res_stmt)) => {
-                if let Err(e) = th_stmt.run().await {
-                    if e.to_string().to_lowercase().contains("unique") {
-                        Err(anyhow::anyhow!("thread already exists"))
-                    } else {
-                        Err(anyhow::anyhow!("failed to insert thread"))
-                    }
-                } else if res_stmt.run().await.is_err() {
-                    Err(anyhow::anyhow!("failed to insert response"))
-                } else {
-                    Ok(())
-                }
-            }
-            _ => Err(anyhow::anyhow!("failed to bind in thread creation")),
+    /// Creates the response and bumps the thread it belongs to.
+    ///
+    /// The response is inserted first, taking the next `res_no` of the thread, and is
+    /// deleted again if the thread can't be updated. The thread isn't updated either if
+    /// it's being moved to another shard, so that the response isn't left behind in the
+    /// old one. The stopper (`BoardPolicy::thread_stopper`) is enforced by the insert
+    /// itself, so it holds exactly even under concurrent posts.
+    ///
+    /// As in `create_thread`, the delete is best-effort compensation. A response left
+    /// behind by a dead isolate or a failed delete (`WriteError::RollbackFailed`) is not
+    /// counted in `response_count` until the maintenance task reconciles it.
+    pub async fn create_response(
+        &self,
+        res: CreatingRes<'_>,
+        modulo: usize,
+        thread_stopper: u32,
+    ) -> Result<(), WriteError> {
+        let inserted = match self
+            .storage
+            .insert_response(&res, modulo, thread_stopper)
+            .await
+        {
+            Ok(Some(inserted)) => inserted,
+            Ok(None) => return Err(WriteError::ThreadStopped),
+            Err(e) => return Err(WriteError::Failed(e.to_string())),
+        };
+
+        let err = match self
+            .storage
+            .update_thread_by_response(&res, inserted.res_no, thread_stopper, modulo)
+            .await
+        {
+            Ok(true) => return Ok(()),
+            Ok(false) => self.not_updated_error(&res, modulo).await,
+            Err(e) => WriteError::Failed(e.to_string()),
+        };
+
+        let rolled_back = self.storage.delete_response(inserted.id, modulo).await;
+        // The response may have been cached in the meantime. Its number is taken again by
+        // the next post, unless a concurrent post already took the next one, in which case
+        // the dat fills the gap with あぼーん.
+        responses_cache().lock().unwrap().remove(&(
+            res.board_id,
+            res.thread_id.to_string(),
+            modulo,
+        ));
+        match rolled_back {
+            Ok(_) => Err(err),
+            Err(re) => Err(WriteError::RollbackFailed(format!("{err}; {re}"))),
         }
     }
 
-    pub async fn create_response(&self, res: CreatingRes<'_>, modulo: usize) -> anyhow::Result<()> {
-        let update_th_stmt = self
-            .dbo
-            .threads_db
-            .prepare(
-                "UPDATE threads SET 
-            response_count = response_count + 1,
-            last_modified = ?,
-            active = (
-                CASE
-                    WHEN response_count >= 999 THEN 0
-                    ELSE 1
-                END
-            )
-            WHERE thread_number = ? AND board_id = ?",
-            ) // 999 means thread stopper 1000
-            .bind(&[
-                res.unix_time.into(),
-                res.thread_id.into(),
-                res.board_id.into(),
-            ]);
-
-        let res_stmt = self
-            .dbo
-            .get_responses_db(modulo)
-            .prepare(
-                "INSERT INTO responses 
-                (name, mail, date, author_id, body, thread_id, ip_addr, authed_token, timestamp, board_id) 
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-            )
-            .bind(&[
-                res.name.into(),
-                res.mail.into(),
-                res.date_time.into(),
-                res.author_ch5id.into(),
-                res.body.into(),
-                res.thread_id.into(),
-                res.ip_addr.into(),
-                res.authed_token.into(),
-                res.unix_time.into(),
-                res.board_id.into(),
-            ]);
-
-        match (update_th_stmt, res_stmt) {
-            (Ok(update_th_stmt), Ok(res_stmt)) => {
-                let (th_res, res_res) = join!(update_th_stmt.run(), res_stmt.run(),);
-                if th_res.is_err() {
-                    Err(anyhow::anyhow!("failed to update thread"))
-                } else if res_res.is_err() {
-                    Err(anyhow::anyhow!("failed to insert response"))
-                } else {
-                    Ok(())
-                }
-            }
-            _ => Err(anyhow::anyhow!("failed to bind in response creation")),
+    /// Tells why `update_thread_by_response` didn't update the thread.
+    async fn not_updated_error(&self, res: &CreatingRes<'_>, modulo: usize) -> WriteError {
+        let thread = match self.storage.get_thread(res.board_id, res.thread_id).await {
+            Ok(Some(thread)) => thread,
+            Ok(None) => return WriteError::ThreadNotFound,
+            Err(e) => return WriteError::Failed(e.to_string()),
+        };
+        if thread.archived == 1 {
+            return WriteError::ThreadStopped;
+        }
+        let moving = match self.storage.get_shard_moves().await {
+            Ok(moves) => moves
+                .iter()
+                .any(|m| m.board_id == res.board_id && m.thread_number == res.thread_id),
+            Err(e) => return WriteError::Failed(e.to_string()),
+        };
+        // `modulo` was read before the thread started (or finished) moving to another shard
+        if moving || thread.modulo as usize != modulo {
+            WriteError::ThreadMoving
+        } else if thread.active == 0 {
+            WriteError::ThreadStopped
+        } else {
+            WriteError::Failed("the thread was not updated".to_string())
         }
     }
 
+    fn modulo_of(&self, unix_time: &str) -> Result<usize, WriteError> {
+        let Ok(unix_time) = unix_time.parse::<usize>() else {
+            return Err(WriteError::Failed("invalid unix_time".to_string()));
+        };
+        Ok(unix_time % self.storage.n_responses_db())
+    }
+
     pub async fn create_authed_token(
         &self,
         authed_token: CreatingAuthedToken<'_>,
     ) -> anyhow::Result<()> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare(
-                "INSERT INTO authed_cookies (cookie, origin_ip, authed, auth_code, writed_time) 
-                VALUES (?, ?, ?, ?, ?)",
-            )
-            .bind(&[
-                authed_token.token.into(),
-                authed_token.origin_ip.into(),
-                0.into(),
-                authed_token.auth_code.into(),
-                authed_token.writed_time.into(),
-            ])
-        else {
-            return Err(anyhow::anyhow!("failed to bind authed_token"));
-        };
-        if stmt.run().await.is_err() {
-            Err(anyhow::anyhow!("failed to insert authed_token"))
-        } else {
-            Ok(())
-        }
+        self.storage.create_authed_token(authed_token).await
     }
 
     pub async fn update_authed_token_last_thread_creation(
@@ -381,66 +435,259 @@ impl BbsRepository<'_> {
         token: &str,
         unix_time: &str,
     ) -> anyhow::Result<()> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare("UPDATE authed_cookies SET last_thread_creation = ? WHERE cookie = ?")
-            .bind(&[unix_time.into(), token.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind token"));
-        };
-
-        if stmt.run().await.is_err() {
-            Err(anyhow::anyhow!("failed to update authed_token"))
-        } else {
-            Ok(())
-        }
+        self.storage
+            .update_authed_token_last_thread_creation(token, unix_time)
+            .await
     }
 
     pub async fn update_authed_status(&self, token: &str, authed_time: &str) -> anyhow::Result<()> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare("UPDATE authed_cookies SET authed = ?, authed_time = ? WHERE cookie = ?")
-            .bind(&[1.into(), authed_time.into(), token.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind token"));
-        };
-
-        if stmt.run().await.is_err() {
-            Err(anyhow::anyhow!("failed to update authed_token"))
-        } else {
-            Ok(())
-        }
+        self.storage.update_authed_status(token, authed_time).await
     }
 
     pub async fn get_cap_by_password_hash(
         &self,
         hash: &str,
     ) -> anyhow::Result<Option<crate::cap::Cap>> {
-        let Ok(stmt) = self
-            .dbo
-            .infos_db
-            .prepare("SELECT * FROM caps WHERE cap_password_hash = ?")
-            .bind(&[hash.into()])
-        else {
-            return Err(anyhow::anyhow!("failed to bind hash"));
-        };
+        self.storage.get_cap_by_password_hash(hash).await
+    }
 
-        if let Ok(cap) = stmt.first::<crate::cap::Cap>(None).await {
-            Ok(cap)
-        } else {
-            Err(anyhow::anyhow!("failed to fetch cap"))
+    pub async fn archive_inactive_threads(&self, board_id: usize) -> anyhow::Result<usize> {
+        self.storage.archive_inactive_threads(board_id).await
+    }
+
+    pub async fn archive_threads_beyond(
+        &self,
+        board_id: usize,
+        n_live_threads: usize,
+    ) -> anyhow::Result<usize> {
+        self.storage
+            .archive_threads_beyond(board_id, n_live_threads)
+            .await
+    }
+
+    pub(crate) async fn count_responses(
+        &self,
+        board_id: usize,
+        modulo: usize,
+        thread_ids: &[String],
+    ) -> anyhow::Result<Vec<crate::maintenance::ResponseCount>> {
+        self.storage
+            .count_responses(board_id, modulo, thread_ids)
+            .await
+    }
+
+    pub(crate) async fn fix_response_counts(
+        &self,
+        board_id: usize,
+        fixes: &[crate::maintenance::ResponseCountFix],
+    ) -> anyhow::Result<()> {
+        self.storage.fix_response_counts(board_id, fixes).await
+    }
+
+    pub(crate) fn n_responses_db(&self) -> usize {
+        self.storage.n_responses_db()
+    }
+
+    pub(crate) async fn get_shard_counts(
+        &self,
+        n_shards: usize,
+    ) -> anyhow::Result<Vec<ShardCount>> {
+        self.storage.get_shard_counts(n_shards).await
+    }
+
+    pub(crate) async fn get_misplaced_threads(
+        &self,
+        n_shards: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ShardMove>> {
+        self.storage.get_misplaced_threads(n_shards, limit).await
+    }
+
+    pub(crate) async fn get_shard_moves(&self) -> anyhow::Result<Vec<ShardMove>> {
+        self.storage.get_shard_moves().await
+    }
+
+    pub(crate) async fn begin_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        self.storage.begin_shard_move(shard_move).await
+    }
+
+    pub(crate) async fn copy_responses(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        self.storage.copy_responses(shard_move).await
+    }
+
+    pub(crate) async fn update_thread_modulo(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()> {
+        self.storage
+            .update_thread_modulo(board_id, thread_id, modulo)
+            .await
+    }
+
+    pub(crate) async fn delete_responses(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()> {
+        self.storage
+            .delete_responses(board_id, thread_id, modulo)
+            .await
+    }
+
+    pub(crate) async fn finish_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        self.storage.finish_shard_move(shard_move).await
+    }
+
+    pub(crate) async fn get_thread_locations(
+        &self,
+    ) -> anyhow::Result<Vec<crate::integrity::ThreadLocation>> {
+        self.storage.get_thread_locations().await
+    }
+
+    pub(crate) async fn get_response_groups(
+        &self,
+        modulo: usize,
+    ) -> anyhow::Result<Vec<crate::integrity::ResponseGroup>> {
+        self.storage.get_response_groups(modulo).await
+    }
+
+    pub(crate) async fn delete_thread(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+    ) -> anyhow::Result<()> {
+        self.storage.delete_thread(board_id, thread_id).await
+    }
+
+    pub(crate) async fn get_threads_to_store(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        self.storage.get_threads_to_store(board_id, limit).await
+    }
+
+    pub(crate) async fn get_stored_threads(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        self.storage.get_stored_threads(board_id, limit).await
+    }
+
+    pub(crate) async fn upsert_archive(&self, thread: &Thread) -> anyhow::Result<()> {
+        self.storage.upsert_archive(thread).await
+    }
+
+    pub(crate) async fn mark_archive_stored(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+    ) -> anyhow::Result<()> {
+        self.storage.mark_archive_stored(board_id, thread_id).await
+    }
+
+    pub(crate) async fn get_archive_months(
+        &self,
+        board_id: usize,
+    ) -> anyhow::Result<Vec<ArchiveMonth>> {
+        self.storage.get_archive_months(board_id).await
+    }
+
+    /// Archived threads created in `month`, or all of them if `None`
+    pub(crate) async fn get_archives(
+        &self,
+        board_id: usize,
+        month: Option<KakoMonth>,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Archive>> {
+        let (from, to) = month
+            .map(|x| x.thread_number_range())
+            .unwrap_or((0, u32::MAX));
+        self.storage
+            .get_archives(board_id, from, to, limit, offset)
+            .await
+    }
+
+    pub(crate) async fn search_threads(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        self.storage
+            .search_threads(board_id, query, limit, offset)
+            .await
+    }
+
+    pub(crate) async fn search_responses(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        modulo: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Res>> {
+        self.storage
+            .search_responses(board_id, query, modulo, limit)
+            .await
+    }
+
+    pub(crate) async fn get_threads_by_numbers(
+        &self,
+        board_id: usize,
+        thread_numbers: &[String],
+    ) -> anyhow::Result<Vec<Thread>> {
+        if thread_numbers.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.storage
+            .get_threads_by_numbers(board_id, thread_numbers)
+            .await
+    }
+}
+
+/// Error of the write paths (`create_thread` / `create_response`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteError {
+    ThreadAlreadyExists,
+    ThreadNotFound,
+    /// The thread already has as many responses as the stopper of its board
+    ThreadStopped,
+    /// The responses of the thread are being moved to another shard. Nothing was written,
+    /// so the post can be retried.
+    ThreadMoving,
+    /// Nothing was written
+    Failed(String),
+    /// The write failed and undoing the already written half failed too
+    RollbackFailed(String),
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::ThreadAlreadyExists => write!(f, "thread already exists"),
+            WriteError::ThreadNotFound => write!(f, "thread not found"),
+            WriteError::ThreadStopped => write!(f, "thread stopped"),
+            WriteError::ThreadMoving => write!(f, "thread is being moved"),
+            WriteError::Failed(e) => write!(f, "failed to write: {e}"),
+            WriteError::RollbackFailed(e) => write!(f, "failed to roll back: {e}"),
         }
     }
 }
 
+impl std::error::Error for WriteError {}
+
 #[derive(Debug, Clone, Copy)]
 #[allow(dead_code)] // TODO: Remove this
 pub enum ThreadStatus {
     // Show in the thread list
     Active,
-    // Not show in the thread list and can't be posted (will be archived via eddiner-archiver)
+    // Not show in the thread list and can't be posted (stored to R2 by the archiver)
     Archived,
     // Show in the thread list but can't be posted
     Inactive,
@@ -448,6 +695,65 @@ pub enum ThreadStatus {
     Unarchived,
 }
 
+impl ThreadStatus {
+    pub(crate) fn select_threads_query(&self) -> &'static str {
+        match self {
+            ThreadStatus::Active => "SELECT * FROM threads WHERE board_id = ? AND active = 1",
+            ThreadStatus::Inactive => {
+                "SELECT * FROM threads WHERE board_id = ? AND active = 0 AND archived = 0"
+            }
+            ThreadStatus::Archived => "SELECT * FROM threads WHERE board_id = ? AND archived = 1",
+            ThreadStatus::Unarchived => "SELECT * FROM threads WHERE board_id = ? AND archived = 0",
+        }
+    }
+}
+
+/// Which responses of a thread to fetch, by `res_no`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResRange {
+    All,
+    /// `from..=to`
+    Between(u32, u32),
+    /// The last `n` responses
+    Last(u32),
+    /// `from..`
+    Since(u32),
+}
+
+impl ResRange {
+    /// Binds `thread_id` and `board_id` first, then `bounds()`
+    pub(crate) fn select_responses_query(&self) -> &'static str {
+        match self {
+            ResRange::All => {
+                "SELECT * FROM responses WHERE thread_id = ? AND board_id = ? ORDER BY res_no"
+            }
+            ResRange::Between(_, _) => {
+                "SELECT * FROM responses WHERE thread_id = ? AND board_id = ?
+                AND res_no BETWEEN ? AND ? ORDER BY res_no"
+            }
+            ResRange::Last(_) => {
+                "SELECT * FROM (
+                    SELECT * FROM responses WHERE thread_id = ? AND board_id = ?
+                    ORDER BY res_no DESC LIMIT ?
+                ) ORDER BY res_no"
+            }
+            ResRange::Since(_) => {
+                "SELECT * FROM responses WHERE thread_id = ? AND board_id = ?
+                AND res_no >= ? ORDER BY res_no"
+            }
+        }
+    }
+
+    pub(crate) fn bounds(&self) -> Vec<u32> {
+        match *self {
+            ResRange::All => vec![],
+            ResRange::Between(from, to) => vec![from, to],
+            ResRange::Last(n) => vec![n],
+            ResRange::Since(from) => vec![from],
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CreatingThread<'a> {
     pub title: &'a str,
@@ -477,6 +783,23 @@ pub struct CreatingRes<'a> {
     pub board_id: usize,
 }
 
+impl<'a> From<&CreatingThread<'a>> for CreatingRes<'a> {
+    fn from(thread: &CreatingThread<'a>) -> Self {
+        CreatingRes {
+            unix_time: thread.unix_time,
+            body: thread.body,
+            name: thread.name,
+            mail: thread.mail,
+            date_time: thread.date_time,
+            author_ch5id: thread.author_ch5id,
+            authed_token: thread.authed_token,
+            ip_addr: thread.ip_addr,
+            thread_id: thread.unix_time,
+            board_id: thread.board_id,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CreatingAuthedToken<'a> {
     pub token: &'a str,
@@ -484,3 +807,457 @@ pub struct CreatingAuthedToken<'a> {
     pub writed_time: &'a str,
     pub auth_code: &'a str,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::sqlite_storage::SqliteStorage;
+
+    fn creating_thread(unix_time: &str) -> CreatingThread<'_> {
+        CreatingThread {
+            title: "スレ",
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id: 1,
+            metadent: MetadentType::None,
+        }
+    }
+
+    fn creating_res<'a>(thread_id: &'a str, unix_time: &'a str) -> CreatingRes<'a> {
+        CreatingRes {
+            unix_time,
+            body: "レス",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:01.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            thread_id,
+            board_id: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_thread_duplicated() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        // The next seconds are taken in order
+        for thread_number in 1840000000..1840000000 + THREAD_NUMBER_WINDOW_SECS {
+            assert_eq!(
+                repo.create_thread(creating_thread("1840000000")).await,
+                Ok(thread_number.to_string())
+            );
+            let responses = repo
+                .get_responses(1, &thread_number.to_string(), thread_number as usize % 3)
+                .await
+                .unwrap();
+            assert_eq!(responses.len(), 1);
+        }
+        assert_eq!(
+            repo.create_thread(creating_thread("1840000000")).await,
+            Err(WriteError::ThreadAlreadyExists)
+        );
+
+        // Thread numbers are unique per board
+        let thread = CreatingThread {
+            board_id: 2,
+            ..creating_thread("1840000000")
+        };
+        assert_eq!(
+            repo.create_thread(thread).await,
+            Ok("1840000000".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_thread_keeps_ten_digits() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("9999999999"))
+            .await
+            .unwrap();
+        assert_eq!(
+            repo.create_thread(creating_thread("9999999999")).await,
+            Err(WriteError::ThreadAlreadyExists)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_thread_rolls_back_thread() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let modulo = 1700000001 % 3;
+        storage
+            .execute_on_responses_db(modulo, "DROP TABLE responses")
+            .unwrap();
+
+        let err = repo
+            .create_thread(creating_thread("1700000001"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WriteError::Failed(_)));
+        assert!(storage.get_thread(1, "1700000001").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_create_response_to_missing_thread_rolls_back_response() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+
+        assert_eq!(
+            repo.create_response(creating_res("1700000001", "1700000002"), 0, THREAD_STOPPER)
+                .await,
+            Err(WriteError::ThreadNotFound)
+        );
+        assert!(storage
+            .get_responses(1, "1700000001", 0)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_response_rolls_back_response() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1700000001"))
+            .await
+            .unwrap();
+        let modulo = 1700000001 % 3;
+        storage
+            .execute_on_threads_db(
+                "CREATE TRIGGER broken BEFORE UPDATE ON threads
+                BEGIN SELECT RAISE(ABORT, 'broken'); END",
+            )
+            .unwrap();
+
+        let err = repo
+            .create_response(
+                creating_res("1700000001", "1700000002"),
+                modulo,
+                THREAD_STOPPER,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, WriteError::Failed(_)));
+
+        let thread = storage.get_thread(1, "1700000001").await.unwrap().unwrap();
+        let responses = storage
+            .get_responses(1, "1700000001", modulo)
+            .await
+            .unwrap();
+        assert_eq!(thread.response_count, 1);
+        assert_eq!(responses.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_response_to_thread_being_moved() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1860000000"))
+            .await
+            .unwrap();
+        let m = ShardMove {
+            board_id: 1,
+            thread_number: "1860000000".to_string(),
+            from_modulo: 0,
+            to_modulo: 1,
+            active: 1,
+        };
+
+        // Posted with the modulo read before the move started
+        repo.begin_shard_move(&m).await.unwrap();
+        assert_eq!(
+            repo.create_response(creating_res("1860000000", "1860000001"), 0, THREAD_STOPPER)
+                .await,
+            Err(WriteError::ThreadMoving)
+        );
+        assert_eq!(
+            storage
+                .get_responses(1, "1860000000", 0)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Posted with the modulo read before the move finished
+        repo.copy_responses(&m).await.unwrap();
+        repo.update_thread_modulo(1, "1860000000", 1).await.unwrap();
+        repo.delete_responses(1, "1860000000", 0).await.unwrap();
+        repo.finish_shard_move(&m).await.unwrap();
+        assert_eq!(
+            repo.create_response(creating_res("1860000000", "1860000002"), 0, THREAD_STOPPER)
+                .await,
+            Err(WriteError::ThreadMoving)
+        );
+        assert!(storage
+            .get_responses(1, "1860000000", 0)
+            .await
+            .unwrap()
+            .is_empty());
+
+        repo.create_response(creating_res("1860000000", "1860000003"), 1, THREAD_STOPPER)
+            .await
+            .unwrap();
+        let thread = storage.get_thread(1, "1860000000").await.unwrap().unwrap();
+        assert_eq!(thread.response_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_create_response_to_stopped_thread() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1860000010"))
+            .await
+            .unwrap();
+        storage
+            .execute_on_threads_db(
+                "UPDATE threads SET active = 0 WHERE thread_number = '1860000010'",
+            )
+            .unwrap();
+
+        assert_eq!(
+            repo.create_response(creating_res("1860000010", "1860000011"), 0, THREAD_STOPPER)
+                .await,
+            Err(WriteError::ThreadStopped)
+        );
+        assert_eq!(
+            storage
+                .get_responses(1, "1860000010", 0)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_response_rollback_drops_cached_thread() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1870000000"))
+            .await
+            .unwrap();
+        assert_eq!(
+            repo.get_responses(1, "1870000000", 0).await.unwrap().len(),
+            1
+        );
+
+        storage
+            .execute_on_threads_db(
+                "CREATE TRIGGER broken BEFORE UPDATE ON threads
+                BEGIN SELECT RAISE(ABORT, 'broken'); END",
+            )
+            .unwrap();
+        assert!(repo
+            .create_response(creating_res("1870000000", "1870000001"), 0, THREAD_STOPPER)
+            .await
+            .is_err());
+        storage
+            .execute_on_threads_db("DROP TRIGGER broken")
+            .unwrap();
+
+        // Takes the number of the rolled back response, and is read without waiting for
+        // the cached thread to become stale
+        let res = CreatingRes {
+            body: "書き直し",
+            ..creating_res("1870000000", "1870000002")
+        };
+        repo.create_response(res, 0, THREAD_STOPPER).await.unwrap();
+        let responses = repo.get_responses(1, "1870000000", 0).await.unwrap();
+        assert_eq!(res_nos(&responses), vec![1, 2]);
+        assert_eq!(responses[1].body, "書き直し");
+    }
+
+    #[tokio::test]
+    async fn test_dat_keeps_numbers_after_rollback() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1870000010"))
+            .await
+            .unwrap();
+
+        // 2 is rolled back after a concurrent post took 3
+        let rolled_back = creating_res("1870000010", "1870000011");
+        let committed = CreatingRes {
+            body: "三レス目",
+            ..creating_res("1870000010", "1870000012")
+        };
+        let inserted = storage
+            .insert_response(&rolled_back, 0, THREAD_STOPPER)
+            .await
+            .unwrap()
+            .unwrap();
+        let res_no = storage
+            .insert_response(&committed, 0, THREAD_STOPPER)
+            .await
+            .unwrap()
+            .unwrap()
+            .res_no;
+        assert_eq!((inserted.res_no, res_no), (2, 3));
+        storage
+            .update_thread_by_response(&committed, res_no, THREAD_STOPPER, 0)
+            .await
+            .unwrap();
+        storage.delete_response(inserted.id, 0).await.unwrap();
+
+        let dat = repo
+            .get_sjis_dat(1, "1870000010", 0, "スレ", "名無し")
+            .await
+            .unwrap();
+        let dat = encoding_rs::SHIFT_JIS.decode(&dat).0.into_owned();
+        let lines = dat.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "あぼーん<>あぼーん<> <> あぼーん<>");
+        assert!(lines[2].contains("三レス目"));
+
+        // Archived with the same numbers
+        let (_, parsed) = crate::kako::parse_dat(&dat, "1870000010");
+        assert_eq!(res_nos(&parsed), vec![1, 2, 3]);
+        assert_eq!(parsed[2].body, "三レス目");
+    }
+
+    #[tokio::test]
+    async fn test_thread_stopper() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1700000001"))
+            .await
+            .unwrap();
+        let modulo = 1700000001 % 3;
+        for _ in 1..THREAD_STOPPER {
+            repo.create_response(
+                creating_res("1700000001", "1700000002"),
+                modulo,
+                THREAD_STOPPER,
+            )
+            .await
+            .unwrap();
+        }
+
+        let thread = storage.get_thread(1, "1700000001").await.unwrap().unwrap();
+        assert_eq!(thread.response_count, THREAD_STOPPER);
+        assert_eq!(thread.active, 0);
+        assert_eq!(
+            repo.create_response(
+                creating_res("1700000001", "1700000003"),
+                modulo,
+                THREAD_STOPPER
+            )
+            .await,
+            Err(WriteError::ThreadStopped)
+        );
+
+        let responses = storage
+            .get_responses(1, "1700000001", modulo)
+            .await
+            .unwrap();
+        assert!(responses
+            .iter()
+            .enumerate()
+            .all(|(i, r)| r.res_no == i as u32 + 1));
+        assert_eq!(responses.len(), THREAD_STOPPER as usize);
+    }
+
+    #[tokio::test]
+    async fn test_thread_stopper_of_board() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1710000003"))
+            .await
+            .unwrap();
+        for _ in 1..3 {
+            repo.create_response(creating_res("1710000003", "1710000004"), 0, 3)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            repo.create_response(creating_res("1710000003", "1710000005"), 0, 3)
+                .await,
+            Err(WriteError::ThreadStopped)
+        );
+        let thread = storage.get_thread(1, "1710000003").await.unwrap().unwrap();
+        assert_eq!(thread.response_count, 3);
+        assert_eq!(thread.active, 0);
+    }
+
+    fn res_nos(responses: &[Res]) -> Vec<u32> {
+        responses.iter().map(|r| r.res_no).collect()
+    }
+
+    #[tokio::test]
+    async fn test_get_responses_in_range() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1710000001"))
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            repo.create_response(creating_res("1710000001", "1710000002"), 0, THREAD_STOPPER)
+                .await
+                .unwrap();
+        }
+
+        for (range, expected) in [
+            (ResRange::Between(2, 3), vec![2, 3]),
+            (ResRange::Last(2), vec![4, 5]),
+            (ResRange::Since(4), vec![4, 5]),
+            (ResRange::Last(10), vec![1, 2, 3, 4, 5]),
+            (ResRange::Between(5, 10), vec![5]),
+            (ResRange::All, vec![1, 2, 3, 4, 5]),
+        ] {
+            let responses = repo
+                .get_responses_in_range(1, "1710000001", 0, range)
+                .await
+                .unwrap();
+            assert_eq!(res_nos(&responses), expected, "{range:?}");
+            let responses = storage
+                .get_responses_in_range(1, "1710000001", 0, range)
+                .await
+                .unwrap();
+            assert_eq!(res_nos(&responses), expected, "{range:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ranged_read_does_not_fill_cache() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_thread(creating_thread("1880000000"))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            repo.create_response(creating_res("1880000000", "1880000001"), 0, THREAD_STOPPER)
+                .await
+                .unwrap();
+        }
+        let key = (1, "1880000000".to_string(), 0);
+        let is_cached = || {
+            let lookup =
+                responses_cache()
+                    .lock()
+                    .unwrap()
+                    .lookup(&key, get_current_millis(), |_| ());
+            lookup != CacheLookup::Miss
+        };
+
+        let responses = repo
+            .get_responses_in_range(1, "1880000000", 0, ResRange::Last(1))
+            .await
+            .unwrap();
+        assert_eq!(res_nos(&responses), vec![3]);
+        assert!(!is_cached());
+
+        repo.get_responses(1, "1880000000", 0).await.unwrap();
+        assert!(is_cached());
+    }
+}
@@ -0,0 +1,499 @@
+use serde::Deserialize;
+use worker::async_trait::async_trait;
+
+use crate::{
+    authed_cookie::AuthedCookie,
+    board::{Board, BoardSetting, CreatingBoard, UpdatingBoard},
+    board_policy::BoardPolicy,
+    cap::Cap,
+    integrity::{ResponseGroup, ThreadLocation},
+    kako::{Archive, ArchiveMonth},
+    maintenance::{ResponseCount, ResponseCountFix},
+    rebalance::{ShardCount, ShardMove},
+    repositories::bbs_repository::{
+        CreatingAuthedToken, CreatingRes, CreatingThread, ResRange, ThreadStatus,
+    },
+    response::Res,
+    scheduled_tasks::{LastTaskRun, TaskRun},
+    search::SearchQuery,
+    thread::Thread,
+};
+
+/// Shared by the backends so that numbering and the stopper behave the same.
+///
+/// `res_no` is computed and inserted in one statement, and the unique index on
+/// `(board_id, thread_id, res_no)` rejects the loser of a concurrent race,
+/// so numbers never collide and never exceed `?11`.
+pub(crate) const INSERT_RESPONSE_QUERY: &str = "INSERT INTO responses
+    (name, mail, date, author_id, body, thread_id, ip_addr, authed_token, timestamp, board_id, res_no)
+    SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, last_res_no + 1
+    FROM (
+        SELECT COALESCE(MAX(res_no), 0) AS last_res_no
+        FROM responses WHERE thread_id = ?6 AND board_id = ?10
+    )
+    WHERE last_res_no < ?11
+    RETURNING id, res_no";
+
+/// `MAX` keeps `response_count` monotonic even if concurrent updates arrive out of order.
+///
+/// Nothing is updated if the response was written to another shard than `?6`, or if the
+/// thread was stopped before reaching the stopper (i.e. for a shard move or by archiving),
+/// since the response may be left behind in the old shard.
+pub(crate) const UPDATE_THREAD_BY_RESPONSE_QUERY: &str = "UPDATE threads SET
+    response_count = MAX(response_count, ?1),
+    last_modified = ?2,
+    active = (
+        CASE
+            WHEN ?1 >= ?3 THEN 0
+            ELSE active
+        END
+    )
+    WHERE thread_number = ?4 AND board_id = ?5
+    AND modulo = ?6 AND (active = 1 OR response_count >= ?3)
+    RETURNING response_count";
+
+pub(crate) const GET_SHARD_COUNTS_QUERY: &str = "SELECT
+    modulo,
+    CAST(thread_number AS INTEGER) % ?1 AS target_modulo,
+    COUNT(*) AS n_threads,
+    SUM(response_count) AS n_responses
+    FROM threads
+    GROUP BY modulo, target_modulo
+    ORDER BY modulo, target_modulo";
+
+pub(crate) const GET_MISPLACED_THREADS_QUERY: &str = "SELECT
+    board_id,
+    thread_number,
+    modulo AS from_modulo,
+    CAST(thread_number AS INTEGER) % ?1 AS to_modulo,
+    active
+    FROM threads
+    WHERE CAST(thread_number AS INTEGER) % ?1 != modulo
+    AND NOT EXISTS (
+        SELECT 1 FROM shard_moves
+        WHERE shard_moves.board_id = threads.board_id
+        AND shard_moves.thread_number = threads.thread_number
+    )
+    ORDER BY CAST(thread_number AS INTEGER)
+    LIMIT ?2";
+
+pub(crate) const GET_THREADS_BY_NUMBERS_QUERY: &str = "SELECT * FROM threads
+    WHERE board_id = ?1 AND thread_number IN (SELECT value FROM json_each(?2))";
+
+/// Threads being moved between shards are skipped until the move finishes
+pub(crate) const GET_THREADS_TO_STORE_QUERY: &str = "SELECT * FROM threads
+    WHERE board_id = ?1 AND archived = 1 AND archive_stored = 0
+    AND NOT EXISTS (
+        SELECT 1 FROM shard_moves
+        WHERE shard_moves.board_id = threads.board_id
+        AND shard_moves.thread_number = threads.thread_number
+    )
+    ORDER BY CAST(thread_number AS INTEGER)
+    LIMIT ?2";
+
+pub(crate) const UPSERT_ARCHIVE_QUERY: &str = "INSERT INTO archives
+    (thread_number, title, response_count, board_id, last_modified)
+    VALUES (?1, ?2, ?3, ?4, ?5)
+    ON CONFLICT (board_id, thread_number) DO UPDATE SET
+    title = excluded.title,
+    response_count = excluded.response_count,
+    last_modified = excluded.last_modified";
+
+/// `month` of `ArchiveMonth`, by the creation time (thread number) of the threads
+pub(crate) const GET_ARCHIVE_MONTHS_QUERY: &str = "SELECT
+    strftime('%Y-%m', CAST(thread_number AS INTEGER) + 32400, 'unixepoch') AS month,
+    COUNT(*) AS n_threads
+    FROM archives
+    WHERE board_id = ?
+    GROUP BY month
+    ORDER BY month DESC";
+
+pub(crate) const GET_ARCHIVES_QUERY: &str = "SELECT * FROM archives
+    WHERE board_id = ?1
+    AND CAST(thread_number AS INTEGER) >= ?2 AND CAST(thread_number AS INTEGER) < ?3
+    ORDER BY CAST(thread_number AS INTEGER) DESC
+    LIMIT ?4 OFFSET ?5";
+
+/// `id` is assigned by the database when `?1` is NULL
+pub(crate) const CREATE_BOARD_QUERY: &str = "INSERT INTO boards
+    (id, board_key, name, default_name, local_rule)
+    VALUES (?1, ?2, ?3, ?4, ?5)
+    RETURNING *";
+
+/// NULL parameters keep the current values
+pub(crate) const UPDATE_BOARD_QUERY: &str = "UPDATE boards SET
+    name = COALESCE(?2, name),
+    default_name = COALESCE(?3, default_name),
+    local_rule = COALESCE(?4, local_rule),
+    retired = COALESCE(?5, retired)
+    WHERE id = ?1
+    RETURNING *";
+
+pub(crate) const UPSERT_BOARD_POLICY_QUERY: &str = "INSERT INTO board_policies
+    (board_id, max_subject_len, max_name_len, max_mail_len, max_body_len, max_body_lines,
+    thread_stopper, thread_cooldown_secs, min_res_span_secs, n_live_threads)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+    ON CONFLICT (board_id) DO UPDATE SET
+    max_subject_len = excluded.max_subject_len,
+    max_name_len = excluded.max_name_len,
+    max_mail_len = excluded.max_mail_len,
+    max_body_len = excluded.max_body_len,
+    max_body_lines = excluded.max_body_lines,
+    thread_stopper = excluded.thread_stopper,
+    thread_cooldown_secs = excluded.thread_cooldown_secs,
+    min_res_span_secs = excluded.min_res_span_secs,
+    n_live_threads = excluded.n_live_threads";
+
+/// Threads being moved between shards are only stopped for the move, so they are skipped
+pub(crate) const ARCHIVE_INACTIVE_THREADS_QUERY: &str = "UPDATE threads SET archived = 1
+    WHERE board_id = ? AND active = 0 AND archived = 0
+    AND NOT EXISTS (
+        SELECT 1 FROM shard_moves
+        WHERE shard_moves.board_id = threads.board_id
+        AND shard_moves.thread_number = threads.thread_number
+    )
+    RETURNING thread_number";
+
+/// At most 3000 threads per run. Threads being moved between shards are archived by a
+/// later run.
+pub(crate) const ARCHIVE_THREADS_BEYOND_QUERY: &str = "UPDATE threads SET archived = 1, active = 0
+    WHERE board_id = ?1 AND thread_number IN (
+        SELECT thread_number
+        FROM threads WHERE board_id = ?1 AND archived = 0
+        ORDER BY CAST(last_modified AS INTEGER) DESC LIMIT 3000 OFFSET ?2
+    )
+    AND NOT EXISTS (
+        SELECT 1 FROM shard_moves
+        WHERE shard_moves.board_id = threads.board_id
+        AND shard_moves.thread_number = threads.thread_number
+    )
+    RETURNING thread_number";
+
+/// Counted as the write path sets `response_count`, i.e. by the last `res_no`, so that the
+/// gaps left by rolled back responses are not taken for drift
+pub(crate) const COUNT_RESPONSES_QUERY: &str = "SELECT
+    thread_id,
+    MAX(res_no) AS response_count
+    FROM responses
+    WHERE board_id = ?1 AND thread_id IN (SELECT value FROM json_each(?2))
+    GROUP BY thread_id";
+
+/// Skipped if the thread got a response after the responses were counted
+pub(crate) const FIX_RESPONSE_COUNT_QUERY: &str = "UPDATE threads SET response_count = ?1
+    WHERE board_id = ?2 AND thread_number = ?3 AND response_count = ?4";
+
+pub(crate) const COPY_RESPONSE_QUERY: &str = "INSERT OR IGNORE INTO responses
+    (name, mail, date, author_id, body, thread_id, ip_addr, authed_token, timestamp, board_id, is_abone, res_no)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+
+pub(crate) const GET_THREAD_LOCATIONS_QUERY: &str = "SELECT board_id, thread_number, modulo
+    FROM threads
+    ORDER BY board_id, thread_number";
+
+/// Covered by `responses_res_no_idx`, so the rows themselves are not read
+pub(crate) const GET_RESPONSE_GROUPS_QUERY: &str = "SELECT
+    board_id,
+    thread_id,
+    COUNT(*) AS n_responses
+    FROM responses
+    GROUP BY board_id, thread_id
+    ORDER BY board_id, thread_id";
+
+/// Archiving skips threads being moved, but an archived thread is kept stopped anyway
+pub(crate) const FINISH_SHARD_MOVE_QUERY: &str = "UPDATE threads SET
+    active = CASE WHEN archived = 1 THEN 0 ELSE ? END
+    WHERE board_id = ? AND thread_number = ?";
+
+pub(crate) const GET_LAST_TASK_RUNS_QUERY: &str = "SELECT
+    task_name,
+    MAX(started_at) AS started_at
+    FROM task_runs
+    GROUP BY task_name";
+
+pub(crate) const GET_TASK_RUNS_QUERY: &str = "SELECT
+    task_name, started_at, duration_millis, succeeded, counts, error
+    FROM task_runs
+    ORDER BY started_at DESC, id DESC
+    LIMIT ?";
+
+pub(crate) const INSERT_TASK_RUN_QUERY: &str = "INSERT INTO task_runs
+    (task_name, started_at, duration_millis, succeeded, counts, error)
+    VALUES (?, ?, ?, ?, ?, ?)";
+
+/// A row of `RETURNING thread_number`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub(crate) struct ThreadNumber {
+    pub thread_number: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub(crate) struct InsertedRes {
+    pub id: i64,
+    pub res_no: u32,
+}
+
+/// Storage backend behind `BbsRepository`.
+///
+/// D1 futures are not `Send`, so every implementation is `?Send`.
+#[async_trait(?Send)]
+pub(crate) trait BbsStorage {
+    async fn get_board_info(&self, board_id: usize) -> anyhow::Result<Option<Board>>;
+
+    /// Every board including the retired ones, ordered by ID
+    async fn get_boards(&self) -> anyhow::Result<Vec<Board>>;
+
+    async fn create_board(&self, board: &CreatingBoard) -> anyhow::Result<Board>;
+
+    /// Returns `None` if the board does not exist
+    async fn update_board(
+        &self,
+        board_id: usize,
+        board: &UpdatingBoard,
+    ) -> anyhow::Result<Option<Board>>;
+
+    /// Only the boards whose policy was set
+    async fn get_board_policies(&self) -> anyhow::Result<Vec<BoardPolicy>>;
+
+    async fn upsert_board_policy(&self, policy: &BoardPolicy) -> anyhow::Result<()>;
+
+    /// Ordered by key
+    async fn get_board_settings(&self, board_id: usize) -> anyhow::Result<Vec<BoardSetting>>;
+
+    /// Replaces every extra setting of the board at once
+    async fn replace_board_settings(
+        &self,
+        board_id: usize,
+        settings: &[BoardSetting],
+    ) -> anyhow::Result<()>;
+
+    /// The latest run of each task
+    async fn get_last_task_runs(&self) -> anyhow::Result<Vec<LastTaskRun>>;
+
+    /// Newest first
+    async fn get_task_runs(&self, limit: usize) -> anyhow::Result<Vec<TaskRun>>;
+
+    async fn insert_task_run(&self, run: &TaskRun) -> anyhow::Result<()>;
+
+    async fn delete_task_runs_before(&self, started_at: u64) -> anyhow::Result<()>;
+
+    async fn get_thread(&self, board_id: usize, thread_id: &str) -> anyhow::Result<Option<Thread>>;
+
+    async fn get_threads(
+        &self,
+        board_id: usize,
+        status: ThreadStatus,
+    ) -> anyhow::Result<Vec<Thread>>;
+
+    async fn get_responses(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<Vec<Res>> {
+        self.get_responses_in_range(board_id, thread_id, modulo, ResRange::All)
+            .await
+    }
+
+    /// Responses of the thread in `range`, ordered by `res_no`
+    async fn get_responses_in_range(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        range: ResRange,
+    ) -> anyhow::Result<Vec<Res>>;
+
+    async fn get_responses_by_authed_token_and_timestamp(
+        &self,
+        authed_token: &str,
+        min_timestamp: &str,
+    ) -> anyhow::Result<Vec<Res>>;
+
+    async fn get_authed_token(&self, token: &str) -> anyhow::Result<Option<AuthedCookie>>;
+
+    async fn get_authed_token_by_origin_ip_and_auth_code(
+        &self,
+        ip: &str,
+        auth_code: &str,
+    ) -> anyhow::Result<Option<AuthedCookie>>;
+
+    /// Number of responses databases (shards)
+    fn n_responses_db(&self) -> usize;
+
+    /// Inserts the thread row only. Returns `false` if the thread number is already taken
+    /// on the board.
+    async fn insert_thread(
+        &self,
+        thread: &CreatingThread<'_>,
+        thread_number: &str,
+        modulo: usize,
+    ) -> anyhow::Result<bool>;
+
+    async fn delete_thread(&self, board_id: usize, thread_id: &str) -> anyhow::Result<()>;
+
+    /// Inserts the response row into the `modulo`-th responses database, numbering it
+    /// right after the last response of the thread in the same statement.
+    /// Returns `None` if the thread already has `max_res_no` responses.
+    async fn insert_response(
+        &self,
+        res: &CreatingRes<'_>,
+        modulo: usize,
+        max_res_no: u32,
+    ) -> anyhow::Result<Option<InsertedRes>>;
+
+    async fn delete_response(&self, id: i64, modulo: usize) -> anyhow::Result<()>;
+
+    /// Raises `response_count` to `res_no` and bumps `last_modified` of the thread the
+    /// response belongs to, stopping it once `res_no` reaches `stopper`.
+    /// Returns `false` if the thread does not exist, is not on the `modulo`-th shard or is
+    /// stopped for another reason than the stopper.
+    async fn update_thread_by_response(
+        &self,
+        res: &CreatingRes<'_>,
+        res_no: u32,
+        stopper: u32,
+        modulo: usize,
+    ) -> anyhow::Result<bool>;
+
+    async fn create_authed_token(
+        &self,
+        authed_token: CreatingAuthedToken<'_>,
+    ) -> anyhow::Result<()>;
+
+    async fn update_authed_token_last_thread_creation(
+        &self,
+        token: &str,
+        unix_time: &str,
+    ) -> anyhow::Result<()>;
+
+    async fn update_authed_status(&self, token: &str, authed_time: &str) -> anyhow::Result<()>;
+
+    async fn get_cap_by_password_hash(&self, hash: &str) -> anyhow::Result<Option<Cap>>;
+
+    /// Marks the inactive (stopped) threads of the board as archived, returning how many
+    async fn archive_inactive_threads(&self, board_id: usize) -> anyhow::Result<usize>;
+
+    /// Archives the threads of the board except the `n_live_threads` most recently modified
+    /// ones, returning how many
+    async fn archive_threads_beyond(
+        &self,
+        board_id: usize,
+        n_live_threads: usize,
+    ) -> anyhow::Result<usize>;
+
+    /// Number of responses of each thread in the `modulo`-th shard, by one grouped query.
+    /// Threads without responses are omitted.
+    async fn count_responses(
+        &self,
+        board_id: usize,
+        modulo: usize,
+        thread_ids: &[String],
+    ) -> anyhow::Result<Vec<ResponseCount>>;
+
+    /// Applies every fix in one batch
+    async fn fix_response_counts(
+        &self,
+        board_id: usize,
+        fixes: &[ResponseCountFix],
+    ) -> anyhow::Result<()>;
+
+    /// Threads grouped by `modulo` and `thread_number % n_shards`
+    async fn get_shard_counts(&self, n_shards: usize) -> anyhow::Result<Vec<ShardCount>>;
+
+    /// Threads not on `thread_number % n_shards`, excluding the ones in `shard_moves`
+    async fn get_misplaced_threads(
+        &self,
+        n_shards: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ShardMove>>;
+
+    /// Moves recorded in `shard_moves`, i.e. started but not finished
+    async fn get_shard_moves(&self) -> anyhow::Result<Vec<ShardMove>>;
+
+    /// Records the move (unless it is already recorded) and stops the thread
+    async fn begin_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()>;
+
+    /// Copies the responses of the thread to the target shard, skipping the ones
+    /// already copied (same `res_no`)
+    async fn copy_responses(&self, shard_move: &ShardMove) -> anyhow::Result<()>;
+
+    async fn update_thread_modulo(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()>;
+
+    async fn delete_responses(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()>;
+
+    /// Restores `active` of the thread and removes the record
+    async fn finish_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()>;
+
+    /// Board, number and shard of every thread, for the integrity check
+    async fn get_thread_locations(&self) -> anyhow::Result<Vec<ThreadLocation>>;
+
+    /// Responses of the `modulo`-th shard grouped by thread
+    async fn get_response_groups(&self, modulo: usize) -> anyhow::Result<Vec<ResponseGroup>>;
+
+    /// Threads of the board whose title matches, newest first
+    async fn search_threads(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Thread>>;
+
+    /// Responses of the board on the `modulo`-th shard whose body matches, newest first,
+    /// excluding abone'd ones
+    async fn search_responses(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        modulo: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Res>>;
+
+    async fn get_threads_by_numbers(
+        &self,
+        board_id: usize,
+        thread_numbers: &[String],
+    ) -> anyhow::Result<Vec<Thread>>;
+
+    /// Archived threads of the board whose dat is not stored yet, oldest first
+    async fn get_threads_to_store(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>>;
+
+    /// Archived threads of the board whose dat is already stored, oldest first
+    async fn get_stored_threads(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>>;
+
+    /// Records the thread in `archives`, overwriting the previous record if any
+    async fn upsert_archive(&self, thread: &Thread) -> anyhow::Result<()>;
+
+    async fn mark_archive_stored(&self, board_id: usize, thread_id: &str) -> anyhow::Result<()>;
+
+    /// Archived threads of the board grouped by the month they were created, newest first
+    async fn get_archive_months(&self, board_id: usize) -> anyhow::Result<Vec<ArchiveMonth>>;
+
+    /// Archived threads of the board numbered `from..to`, newest first
+    async fn get_archives(
+        &self,
+        board_id: usize,
+        from: u32,
+        to: u32,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Archive>>;
+}
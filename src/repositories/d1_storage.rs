@@ -0,0 +1,1115 @@
+use worker::async_trait::async_trait;
+
+use crate::{
+    authed_cookie::AuthedCookie,
+    board::{Board, BoardSetting, CreatingBoard, UpdatingBoard},
+    board_policy::BoardPolicy,
+    cap::Cap,
+    integrity::{ResponseGroup, ThreadLocation},
+    kako::{Archive, ArchiveMonth},
+    maintenance::{ResponseCount, ResponseCountFix},
+    rebalance::{ShardCount, ShardMove},
+    repositories::{
+        bbs_repository::{
+            CreatingAuthedToken, CreatingRes, CreatingThread, ResRange, ThreadStatus,
+        },
+        bbs_storage::{
+            BbsStorage, InsertedRes, ThreadNumber, ARCHIVE_INACTIVE_THREADS_QUERY,
+            ARCHIVE_THREADS_BEYOND_QUERY, COPY_RESPONSE_QUERY, COUNT_RESPONSES_QUERY,
+            CREATE_BOARD_QUERY, FINISH_SHARD_MOVE_QUERY, FIX_RESPONSE_COUNT_QUERY,
+            GET_ARCHIVES_QUERY, GET_ARCHIVE_MONTHS_QUERY, GET_LAST_TASK_RUNS_QUERY,
+            GET_MISPLACED_THREADS_QUERY, GET_RESPONSE_GROUPS_QUERY, GET_SHARD_COUNTS_QUERY,
+            GET_TASK_RUNS_QUERY, GET_THREADS_BY_NUMBERS_QUERY, GET_THREADS_TO_STORE_QUERY,
+            GET_THREAD_LOCATIONS_QUERY, INSERT_RESPONSE_QUERY, INSERT_TASK_RUN_QUERY,
+            UPDATE_BOARD_QUERY, UPDATE_THREAD_BY_RESPONSE_QUERY, UPSERT_ARCHIVE_QUERY,
+            UPSERT_BOARD_POLICY_QUERY,
+        },
+    },
+    response::Res,
+    scheduled_tasks::{LastTaskRun, TaskRun},
+    search::SearchQuery,
+    thread::Thread,
+    DbOrchestrator,
+};
+
+/// `BbsStorage` backed by the D1 bindings of `DbOrchestrator`
+pub(crate) struct D1Storage<'a> {
+    dbo: &'a DbOrchestrator,
+}
+
+impl<'a> D1Storage<'a> {
+    pub fn new(dbo: &'a DbOrchestrator) -> D1Storage<'a> {
+        D1Storage { dbo }
+    }
+}
+
+#[async_trait(?Send)]
+impl BbsStorage for D1Storage<'_> {
+    async fn get_board_info(&self, board_id: usize) -> anyhow::Result<Option<Board>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("SELECT * FROM boards WHERE id = ?")
+            .bind(&[board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind id"));
+        };
+        let Ok(board) = stmt.first::<Board>(None).await else {
+            return Err(anyhow::anyhow!("failed to fetch board"));
+        };
+
+        Ok(board)
+    }
+
+    async fn get_boards(&self) -> anyhow::Result<Vec<Board>> {
+        let stmt = self
+            .dbo
+            .infos_db
+            .prepare("SELECT * FROM boards ORDER BY id");
+        let Ok(boards) = stmt.all().await.and_then(|res| res.results::<Board>()) else {
+            return Err(anyhow::anyhow!("failed to fetch boards"));
+        };
+
+        Ok(boards)
+    }
+
+    async fn create_board(&self, board: &CreatingBoard) -> anyhow::Result<Board> {
+        let Ok(stmt) = self.dbo.infos_db.prepare(CREATE_BOARD_QUERY).bind(&[
+            board.id.into(),
+            board.board_key.as_str().into(),
+            board.name.as_str().into(),
+            board.default_name.as_str().into(),
+            board.local_rule.as_str().into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind board"));
+        };
+        let Ok(Some(board)) = stmt.first::<Board>(None).await else {
+            return Err(anyhow::anyhow!("failed to create board"));
+        };
+
+        Ok(board)
+    }
+
+    async fn update_board(
+        &self,
+        board_id: usize,
+        board: &UpdatingBoard,
+    ) -> anyhow::Result<Option<Board>> {
+        let Ok(stmt) = self.dbo.infos_db.prepare(UPDATE_BOARD_QUERY).bind(&[
+            board_id.into(),
+            board.name.as_deref().into(),
+            board.default_name.as_deref().into(),
+            board.local_rule.as_deref().into(),
+            board.retired.map(u32::from).into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind board"));
+        };
+        let Ok(board) = stmt.first::<Board>(None).await else {
+            return Err(anyhow::anyhow!("failed to update board"));
+        };
+
+        Ok(board)
+    }
+
+    async fn get_board_policies(&self) -> anyhow::Result<Vec<BoardPolicy>> {
+        let stmt = self.dbo.infos_db.prepare("SELECT * FROM board_policies");
+        let Ok(policies) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<BoardPolicy>())
+        else {
+            return Err(anyhow::anyhow!("failed to fetch board policies"));
+        };
+
+        Ok(policies)
+    }
+
+    async fn upsert_board_policy(&self, policy: &BoardPolicy) -> anyhow::Result<()> {
+        let Ok(stmt) = self.dbo.infos_db.prepare(UPSERT_BOARD_POLICY_QUERY).bind(&[
+            policy.board_id.into(),
+            policy.max_subject_len.into(),
+            policy.max_name_len.into(),
+            policy.max_mail_len.into(),
+            policy.max_body_len.into(),
+            policy.max_body_lines.into(),
+            policy.thread_stopper.into(),
+            (policy.thread_cooldown_secs as usize).into(),
+            (policy.min_res_span_secs as usize).into(),
+            policy.n_live_threads.into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind board policy"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to set board policy"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_board_settings(&self, board_id: usize) -> anyhow::Result<Vec<BoardSetting>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("SELECT key, value FROM board_settings WHERE board_id = ? ORDER BY key")
+            .bind(&[board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id"));
+        };
+        let Ok(settings) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<BoardSetting>())
+        else {
+            return Err(anyhow::anyhow!("failed to fetch board settings"));
+        };
+
+        Ok(settings)
+    }
+
+    async fn replace_board_settings(
+        &self,
+        board_id: usize,
+        settings: &[BoardSetting],
+    ) -> anyhow::Result<()> {
+        let db = &self.dbo.infos_db;
+        let Ok(delete_stmt) = db
+            .prepare("DELETE FROM board_settings WHERE board_id = ?")
+            .bind(&[board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id"));
+        };
+        let mut stmts = vec![delete_stmt];
+        for setting in settings {
+            let Ok(stmt) = db
+                .prepare("INSERT INTO board_settings (board_id, key, value) VALUES (?, ?, ?)")
+                .bind(&[
+                    board_id.into(),
+                    setting.key.as_str().into(),
+                    setting.value.as_str().into(),
+                ])
+            else {
+                return Err(anyhow::anyhow!("failed to bind board setting"));
+            };
+            stmts.push(stmt);
+        }
+
+        if db.batch(stmts).await.is_err() {
+            Err(anyhow::anyhow!("failed to replace board settings"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_last_task_runs(&self) -> anyhow::Result<Vec<LastTaskRun>> {
+        let stmt = self.dbo.infos_db.prepare(GET_LAST_TASK_RUNS_QUERY);
+        let Ok(runs) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<LastTaskRun>())
+        else {
+            return Err(anyhow::anyhow!("failed to fetch the last task runs"));
+        };
+
+        Ok(runs)
+    }
+
+    async fn get_task_runs(&self, limit: usize) -> anyhow::Result<Vec<TaskRun>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare(GET_TASK_RUNS_QUERY)
+            .bind(&[limit.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind limit"));
+        };
+        let Ok(runs) = stmt.all().await.and_then(|res| res.results::<TaskRun>()) else {
+            return Err(anyhow::anyhow!("failed to fetch task runs"));
+        };
+
+        Ok(runs)
+    }
+
+    async fn insert_task_run(&self, run: &TaskRun) -> anyhow::Result<()> {
+        let Ok(stmt) = self.dbo.infos_db.prepare(INSERT_TASK_RUN_QUERY).bind(&[
+            run.task_name.as_str().into(),
+            (run.started_at as f64).into(),
+            (run.duration_millis as f64).into(),
+            run.succeeded.into(),
+            run.counts.as_str().into(),
+            run.error.as_deref().into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind task run"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to insert task run"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn delete_task_runs_before(&self, started_at: u64) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("DELETE FROM task_runs WHERE started_at < ?")
+            .bind(&[(started_at as f64).into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind started_at"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to delete task runs"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_thread(&self, board_id: usize, thread_id: &str) -> anyhow::Result<Option<Thread>> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare("SELECT * FROM threads WHERE thread_number = ? AND board_id = ?")
+            .bind(&[thread_id.into(), board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind thread_number and board_id"));
+        };
+        let Ok(thread) = stmt.first::<Thread>(None).await else {
+            return Err(anyhow::anyhow!("failed to fetch thread"));
+        };
+
+        Ok(thread)
+    }
+
+    async fn get_threads(
+        &self,
+        board_id: usize,
+        status: ThreadStatus,
+    ) -> anyhow::Result<Vec<Thread>> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(status.select_threads_query())
+            .bind(&[board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id"));
+        };
+        let Ok(threads) = stmt.all().await.and_then(|res| res.results::<Thread>()) else {
+            return Err(anyhow::anyhow!("failed to fetch threads"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn get_responses_in_range(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        range: ResRange,
+    ) -> anyhow::Result<Vec<Res>> {
+        let mut params = vec![thread_id.into(), board_id.into()];
+        params.extend(range.bounds().into_iter().map(Into::into));
+        let Ok(stmt) = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare(range.select_responses_query())
+            .bind(&params)
+        else {
+            return Err(anyhow::anyhow!("failed to bind thread_id and board_id"));
+        };
+        let Ok(responses) = stmt.all().await.and_then(|res| res.results::<Res>()) else {
+            return Err(anyhow::anyhow!("failed to fetch responses"));
+        };
+
+        Ok(responses)
+    }
+
+    async fn get_responses_by_authed_token_and_timestamp(
+        &self,
+        authed_token: &str,
+        min_timestamp: &str,
+    ) -> anyhow::Result<Vec<Res>> {
+        let mut responses = Vec::new();
+        for m in &self.dbo.responses_db {
+            let Ok(stmt) = m
+                .prepare("SELECT * FROM responses WHERE authed_token = ? AND timestamp > ?")
+                .bind(&[authed_token.into(), min_timestamp.into()])
+            else {
+                return Err(anyhow::anyhow!("failed to bind authed_token and timestamp"));
+            };
+            if let Ok(resps) = stmt.all().await.and_then(|res| res.results::<Res>()) {
+                responses.extend(resps);
+            } else {
+                return Err(anyhow::anyhow!("failed to fetch responses"));
+            }
+        }
+
+        Ok(responses)
+    }
+
+    async fn get_authed_token(&self, token: &str) -> anyhow::Result<Option<AuthedCookie>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("SELECT * FROM authed_cookies WHERE cookie = ?")
+            .bind(&[token.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind token"));
+        };
+
+        if let Ok(authed_cookie) = stmt.first::<AuthedCookie>(None).await {
+            Ok(authed_cookie)
+        } else {
+            Err(anyhow::anyhow!("failed to fetch authed_cookie"))
+        }
+    }
+
+    async fn get_authed_token_by_origin_ip_and_auth_code(
+        &self,
+        ip: &str,
+        auth_code: &str,
+    ) -> anyhow::Result<Option<AuthedCookie>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("SELECT * FROM authed_cookies WHERE origin_ip = ? AND auth_code = ?")
+            .bind(&[ip.into(), auth_code.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind ip and auth_code"));
+        };
+
+        if let Ok(authed_cookie) = stmt.first::<AuthedCookie>(None).await {
+            Ok(authed_cookie)
+        } else {
+            Err(anyhow::anyhow!("failed to fetch authed_cookie"))
+        }
+    }
+
+    fn n_responses_db(&self) -> usize {
+        self.dbo.responses_db.len()
+    }
+
+    async fn insert_thread(
+        &self,
+        thread: &CreatingThread<'_>,
+        thread_number: &str,
+        modulo: usize,
+    ) -> anyhow::Result<bool> {
+        let metadent: Option<&str> = thread.metadent.clone().into();
+        let metadent = metadent.unwrap_or("");
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(
+                "INSERT INTO threads
+                (thread_number, title, response_count, board_id, last_modified, authed_cookie, metadent, modulo)
+                VALUES (?, ?, 1, ?, ?, ?, ?, ?)",
+            )
+            .bind(&[
+                thread_number.into(),
+                thread.title.into(),
+                thread.board_id.into(),
+                thread.unix_time.into(),
+                thread.authed_token.into(),
+                metadent.into(),
+                modulo.into(),
+            ])
+        else {
+            return Err(anyhow::anyhow!("failed to bind thread"));
+        };
+
+        match stmt.run().await {
+            Ok(_) => Ok(true),
+            Err(e) if e.to_string().to_lowercase().contains("unique") => Ok(false),
+            Err(_) => Err(anyhow::anyhow!("failed to insert thread")),
+        }
+    }
+
+    async fn delete_thread(&self, board_id: usize, thread_id: &str) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare("DELETE FROM threads WHERE thread_number = ? AND board_id = ?")
+            .bind(&[thread_id.into(), board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind thread_number and board_id"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to delete thread"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn insert_response(
+        &self,
+        res: &CreatingRes<'_>,
+        modulo: usize,
+        max_res_no: u32,
+    ) -> anyhow::Result<Option<InsertedRes>> {
+        let Ok(stmt) = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare(INSERT_RESPONSE_QUERY)
+            .bind(&[
+                res.name.into(),
+                res.mail.into(),
+                res.date_time.into(),
+                res.author_ch5id.into(),
+                res.body.into(),
+                res.thread_id.into(),
+                res.ip_addr.into(),
+                res.authed_token.into(),
+                res.unix_time.into(),
+                res.board_id.into(),
+                max_res_no.into(),
+            ])
+        else {
+            return Err(anyhow::anyhow!("failed to bind response"));
+        };
+
+        let Ok(inserted) = stmt.first::<InsertedRes>(None).await else {
+            return Err(anyhow::anyhow!("failed to insert response"));
+        };
+
+        Ok(inserted)
+    }
+
+    async fn delete_response(&self, id: i64, modulo: usize) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare("DELETE FROM responses WHERE id = ?")
+            .bind(&[(id as f64).into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind id"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to delete response"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn update_thread_by_response(
+        &self,
+        res: &CreatingRes<'_>,
+        res_no: u32,
+        stopper: u32,
+        modulo: usize,
+    ) -> anyhow::Result<bool> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(UPDATE_THREAD_BY_RESPONSE_QUERY)
+            .bind(&[
+                res_no.into(),
+                res.unix_time.into(),
+                stopper.into(),
+                res.thread_id.into(),
+                res.board_id.into(),
+                modulo.into(),
+            ])
+        else {
+            return Err(anyhow::anyhow!("failed to bind thread update"));
+        };
+
+        match stmt.first::<u32>(Some("response_count")).await {
+            Ok(updated) => Ok(updated.is_some()),
+            Err(_) => Err(anyhow::anyhow!("failed to update thread")),
+        }
+    }
+
+    async fn create_authed_token(
+        &self,
+        authed_token: CreatingAuthedToken<'_>,
+    ) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare(
+                "INSERT INTO authed_cookies (cookie, origin_ip, authed, auth_code, writed_time)
+                VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(&[
+                authed_token.token.into(),
+                authed_token.origin_ip.into(),
+                0.into(),
+                authed_token.auth_code.into(),
+                authed_token.writed_time.into(),
+            ])
+        else {
+            return Err(anyhow::anyhow!("failed to bind authed_token"));
+        };
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to insert authed_token"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn update_authed_token_last_thread_creation(
+        &self,
+        token: &str,
+        unix_time: &str,
+    ) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("UPDATE authed_cookies SET last_thread_creation = ? WHERE cookie = ?")
+            .bind(&[unix_time.into(), token.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind token"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to update authed_token"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn update_authed_status(&self, token: &str, authed_time: &str) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("UPDATE authed_cookies SET authed = ?, authed_time = ? WHERE cookie = ?")
+            .bind(&[1.into(), authed_time.into(), token.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind token"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to update authed_token"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_cap_by_password_hash(&self, hash: &str) -> anyhow::Result<Option<Cap>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare("SELECT * FROM caps WHERE cap_password_hash = ?")
+            .bind(&[hash.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind hash"));
+        };
+
+        if let Ok(cap) = stmt.first::<Cap>(None).await {
+            Ok(cap)
+        } else {
+            Err(anyhow::anyhow!("failed to fetch cap"))
+        }
+    }
+
+    async fn archive_inactive_threads(&self, board_id: usize) -> anyhow::Result<usize> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(ARCHIVE_INACTIVE_THREADS_QUERY)
+            .bind(&[board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id"));
+        };
+        let Ok(archived) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<ThreadNumber>())
+        else {
+            return Err(anyhow::anyhow!("failed to archive inactive threads"));
+        };
+
+        Ok(archived.len())
+    }
+
+    async fn archive_threads_beyond(
+        &self,
+        board_id: usize,
+        n_live_threads: usize,
+    ) -> anyhow::Result<usize> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(ARCHIVE_THREADS_BEYOND_QUERY)
+            .bind(&[board_id.into(), n_live_threads.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id and offset"));
+        };
+        let Ok(archived) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<ThreadNumber>())
+        else {
+            return Err(anyhow::anyhow!("failed to archive threads"));
+        };
+
+        Ok(archived.len())
+    }
+
+    async fn count_responses(
+        &self,
+        board_id: usize,
+        modulo: usize,
+        thread_ids: &[String],
+    ) -> anyhow::Result<Vec<ResponseCount>> {
+        let thread_ids = serde_json::to_string(thread_ids)?;
+        let Ok(stmt) = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare(COUNT_RESPONSES_QUERY)
+            .bind(&[board_id.into(), thread_ids.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id and thread_ids"));
+        };
+        let Ok(counts) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<ResponseCount>())
+        else {
+            return Err(anyhow::anyhow!("failed to count responses"));
+        };
+
+        Ok(counts)
+    }
+
+    async fn fix_response_counts(
+        &self,
+        board_id: usize,
+        fixes: &[ResponseCountFix],
+    ) -> anyhow::Result<()> {
+        let db = &self.dbo.threads_db;
+        let mut stmts = Vec::new();
+        for fix in fixes {
+            let Ok(stmt) = db.prepare(FIX_RESPONSE_COUNT_QUERY).bind(&[
+                fix.to.into(),
+                board_id.into(),
+                fix.thread_number.as_str().into(),
+                fix.from.into(),
+            ]) else {
+                return Err(anyhow::anyhow!("failed to bind response_count"));
+            };
+            stmts.push(stmt);
+        }
+        if stmts.is_empty() {
+            return Ok(());
+        }
+
+        if db.batch(stmts).await.is_err() {
+            Err(anyhow::anyhow!("failed to fix response_count"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_shard_counts(&self, n_shards: usize) -> anyhow::Result<Vec<ShardCount>> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(GET_SHARD_COUNTS_QUERY)
+            .bind(&[n_shards.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind n_shards"));
+        };
+        let Ok(counts) = stmt.all().await.and_then(|res| res.results::<ShardCount>()) else {
+            return Err(anyhow::anyhow!("failed to count threads per shard"));
+        };
+
+        Ok(counts)
+    }
+
+    async fn get_misplaced_threads(
+        &self,
+        n_shards: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ShardMove>> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(GET_MISPLACED_THREADS_QUERY)
+            .bind(&[n_shards.into(), limit.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind n_shards and limit"));
+        };
+        let Ok(threads) = stmt.all().await.and_then(|res| res.results::<ShardMove>()) else {
+            return Err(anyhow::anyhow!("failed to fetch misplaced threads"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn get_shard_moves(&self) -> anyhow::Result<Vec<ShardMove>> {
+        let stmt = self.dbo.threads_db.prepare("SELECT * FROM shard_moves");
+        let Ok(moves) = stmt.all().await.and_then(|res| res.results::<ShardMove>()) else {
+            return Err(anyhow::anyhow!("failed to fetch shard_moves"));
+        };
+
+        Ok(moves)
+    }
+
+    async fn begin_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        let db = &self.dbo.threads_db;
+        let (Ok(insert_stmt), Ok(update_stmt)) = (
+            db.prepare(
+                "INSERT OR IGNORE INTO shard_moves
+                (board_id, thread_number, from_modulo, to_modulo, active)
+                VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(&[
+                shard_move.board_id.into(),
+                shard_move.thread_number.as_str().into(),
+                shard_move.from_modulo.into(),
+                shard_move.to_modulo.into(),
+                shard_move.active.into(),
+            ]),
+            db.prepare("UPDATE threads SET active = 0 WHERE board_id = ? AND thread_number = ?")
+                .bind(&[
+                    shard_move.board_id.into(),
+                    shard_move.thread_number.as_str().into(),
+                ]),
+        ) else {
+            return Err(anyhow::anyhow!("failed to bind shard_move"));
+        };
+
+        // Both rows are in the threads database, so they can be written atomically
+        if db.batch(vec![insert_stmt, update_stmt]).await.is_err() {
+            Err(anyhow::anyhow!("failed to begin shard_move"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn copy_responses(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        let responses = self
+            .get_responses(
+                shard_move.board_id,
+                &shard_move.thread_number,
+                shard_move.from_modulo,
+            )
+            .await?;
+        let db = self.dbo.get_responses_db(shard_move.to_modulo)?;
+
+        let mut stmts = Vec::new();
+        for r in responses {
+            let Ok(stmt) = db.prepare(COPY_RESPONSE_QUERY).bind(&[
+                r.name.into(),
+                r.mail.into(),
+                r.date.into(),
+                r.author_id.into(),
+                r.body.into(),
+                r.thread_id.into(),
+                r.ip_addr.into(),
+                r.authed_token.into(),
+                (r.timestamp as f64).into(),
+                shard_move.board_id.into(),
+                r.is_abone.into(),
+                r.res_no.into(),
+            ]) else {
+                return Err(anyhow::anyhow!("failed to bind response"));
+            };
+            stmts.push(stmt);
+        }
+        if stmts.is_empty() {
+            return Ok(());
+        }
+
+        if db.batch(stmts).await.is_err() {
+            Err(anyhow::anyhow!("failed to copy responses"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn update_thread_modulo(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare("UPDATE threads SET modulo = ? WHERE board_id = ? AND thread_number = ?")
+            .bind(&[modulo.into(), board_id.into(), thread_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind modulo"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to update modulo"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn delete_responses(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare("DELETE FROM responses WHERE board_id = ? AND thread_id = ?")
+            .bind(&[board_id.into(), thread_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id and thread_id"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to delete responses"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn finish_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        let db = &self.dbo.threads_db;
+        let (Ok(update_stmt), Ok(delete_stmt)) = (
+            db.prepare(FINISH_SHARD_MOVE_QUERY).bind(&[
+                shard_move.active.into(),
+                shard_move.board_id.into(),
+                shard_move.thread_number.as_str().into(),
+            ]),
+            db.prepare("DELETE FROM shard_moves WHERE board_id = ? AND thread_number = ?")
+                .bind(&[
+                    shard_move.board_id.into(),
+                    shard_move.thread_number.as_str().into(),
+                ]),
+        ) else {
+            return Err(anyhow::anyhow!("failed to bind shard_move"));
+        };
+
+        if db.batch(vec![update_stmt, delete_stmt]).await.is_err() {
+            Err(anyhow::anyhow!("failed to finish shard_move"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_thread_locations(&self) -> anyhow::Result<Vec<ThreadLocation>> {
+        let stmt = self.dbo.threads_db.prepare(GET_THREAD_LOCATIONS_QUERY);
+        let Ok(threads) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<ThreadLocation>())
+        else {
+            return Err(anyhow::anyhow!("failed to fetch thread locations"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn get_response_groups(&self, modulo: usize) -> anyhow::Result<Vec<ResponseGroup>> {
+        let stmt = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare(GET_RESPONSE_GROUPS_QUERY);
+        let Ok(groups) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<ResponseGroup>())
+        else {
+            return Err(anyhow::anyhow!("failed to group responses"));
+        };
+
+        Ok(groups)
+    }
+
+    async fn search_threads(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        let (sql, terms) = query.select_threads_query();
+        let mut params = vec![board_id.into(), limit.into(), offset.into()];
+        params.extend(terms.iter().map(|x| x.as_str().into()));
+        let Ok(stmt) = self.dbo.threads_db.prepare(sql).bind(&params) else {
+            return Err(anyhow::anyhow!("failed to bind search query"));
+        };
+        let Ok(threads) = stmt.all().await.and_then(|res| res.results::<Thread>()) else {
+            return Err(anyhow::anyhow!("failed to search threads"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn search_responses(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        modulo: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Res>> {
+        let (sql, terms) = query.select_responses_query();
+        let mut params = vec![board_id.into(), limit.into()];
+        params.extend(terms.iter().map(|x| x.as_str().into()));
+        let Ok(stmt) = self
+            .dbo
+            .get_responses_db(modulo)?
+            .prepare(sql)
+            .bind(&params)
+        else {
+            return Err(anyhow::anyhow!("failed to bind search query"));
+        };
+        let Ok(responses) = stmt.all().await.and_then(|res| res.results::<Res>()) else {
+            return Err(anyhow::anyhow!("failed to search responses"));
+        };
+
+        Ok(responses)
+    }
+
+    async fn get_threads_by_numbers(
+        &self,
+        board_id: usize,
+        thread_numbers: &[String],
+    ) -> anyhow::Result<Vec<Thread>> {
+        let thread_numbers = serde_json::to_string(thread_numbers)?;
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(GET_THREADS_BY_NUMBERS_QUERY)
+            .bind(&[board_id.into(), thread_numbers.into()])
+        else {
+            return Err(anyhow::anyhow!(
+                "failed to bind board_id and thread_numbers"
+            ));
+        };
+        let Ok(threads) = stmt.all().await.and_then(|res| res.results::<Thread>()) else {
+            return Err(anyhow::anyhow!("failed to fetch threads"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn get_threads_to_store(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(GET_THREADS_TO_STORE_QUERY)
+            .bind(&[board_id.into(), limit.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id and limit"));
+        };
+        let Ok(threads) = stmt.all().await.and_then(|res| res.results::<Thread>()) else {
+            return Err(anyhow::anyhow!("failed to fetch threads"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn get_stored_threads(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(
+                "SELECT * FROM threads WHERE board_id = ? AND archived = 1 AND archive_stored = 1
+                ORDER BY CAST(thread_number AS INTEGER) LIMIT ?",
+            )
+            .bind(&[board_id.into(), limit.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id and limit"));
+        };
+        let Ok(threads) = stmt.all().await.and_then(|res| res.results::<Thread>()) else {
+            return Err(anyhow::anyhow!("failed to fetch threads"));
+        };
+
+        Ok(threads)
+    }
+
+    async fn upsert_archive(&self, thread: &Thread) -> anyhow::Result<()> {
+        let Ok(stmt) = self.dbo.infos_db.prepare(UPSERT_ARCHIVE_QUERY).bind(&[
+            thread.thread_number.as_str().into(),
+            thread.title.as_str().into(),
+            thread.response_count.into(),
+            thread.board_id.into(),
+            thread.last_modified.as_str().into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind archive"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to record archive"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn mark_archive_stored(&self, board_id: usize, thread_id: &str) -> anyhow::Result<()> {
+        let Ok(stmt) = self
+            .dbo
+            .threads_db
+            .prepare(
+                "UPDATE threads SET archive_stored = 1 WHERE board_id = ? AND thread_number = ?",
+            )
+            .bind(&[board_id.into(), thread_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id and thread_number"));
+        };
+
+        if stmt.run().await.is_err() {
+            Err(anyhow::anyhow!("failed to mark thread as stored"))
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn get_archive_months(&self, board_id: usize) -> anyhow::Result<Vec<ArchiveMonth>> {
+        let Ok(stmt) = self
+            .dbo
+            .infos_db
+            .prepare(GET_ARCHIVE_MONTHS_QUERY)
+            .bind(&[board_id.into()])
+        else {
+            return Err(anyhow::anyhow!("failed to bind board_id"));
+        };
+        let Ok(months) = stmt
+            .all()
+            .await
+            .and_then(|res| res.results::<ArchiveMonth>())
+        else {
+            return Err(anyhow::anyhow!("failed to fetch archive months"));
+        };
+
+        Ok(months)
+    }
+
+    async fn get_archives(
+        &self,
+        board_id: usize,
+        from: u32,
+        to: u32,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Archive>> {
+        let Ok(stmt) = self.dbo.infos_db.prepare(GET_ARCHIVES_QUERY).bind(&[
+            board_id.into(),
+            from.into(),
+            to.into(),
+            limit.into(),
+            offset.into(),
+        ]) else {
+            return Err(anyhow::anyhow!("failed to bind board_id and range"));
+        };
+        let Ok(archives) = stmt.all().await.and_then(|res| res.results::<Archive>()) else {
+            return Err(anyhow::anyhow!("failed to fetch archives"));
+        };
+
+        Ok(archives)
+    }
+}
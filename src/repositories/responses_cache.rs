@@ -0,0 +1,461 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use crate::{
+    repositories::bbs_repository::ResRange,
+    response::{Res, SjisDat},
+};
+
+/// Cached responses are served without asking the database for this long
+const RESPONSES_CACHE_FRESH_MILLIS: u64 = 1000; // same as s-maxage=1
+
+/// Responses are reloaded from the start after this, so that rows changed in place
+/// (e.g. made あぼーん by hand) are picked up eventually
+const RESPONSES_CACHE_RELOAD_MILLIS: u64 = 1000 * 60 * 5;
+
+/// Threads are evicted in least recently used order beyond this
+const RESPONSES_CACHE_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// `(board_id, thread_id, modulo)`
+pub(crate) type ResponsesCacheKey = (usize, String, usize);
+
+/// Every response of a thread as of `checked_at`, ordered by `res_no`
+#[derive(Debug)]
+pub(crate) struct CachedThread {
+    responses: Vec<Res>,
+    /// Encoded on the first dat request, then extended by the responses appended since
+    dat: Option<CachedDat>,
+    /// When the responses were last loaded from the start
+    loaded_at: u64,
+    /// When the database was last asked for newer responses
+    checked_at: u64,
+    bytes: usize,
+    /// Larger is more recently used
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct CachedDat {
+    thread_title: String,
+    default_name: String,
+    dat: SjisDat,
+}
+
+impl CachedThread {
+    fn last_res_no(&self) -> u32 {
+        self.responses.last().map(|r| r.res_no).unwrap_or(0)
+    }
+
+    pub fn select(&self, range: ResRange) -> Vec<Res> {
+        select(&self.responses, range)
+    }
+
+    /// The whole dat in Shift_JIS, encoding only the responses not encoded yet.
+    /// The bytes are shared with the cache, not copied.
+    pub fn sjis_dat(&mut self, thread_title: &str, default_name: &str) -> Arc<Vec<u8>> {
+        let cached = match &mut self.dat {
+            // The default name of the board may have been changed
+            Some(cached)
+                if cached.thread_title == thread_title && cached.default_name == default_name =>
+            {
+                cached
+            }
+            dat => {
+                if let Some(old) = dat.take() {
+                    self.bytes -= old.dat.as_bytes().len();
+                }
+                dat.insert(CachedDat {
+                    thread_title: thread_title.to_string(),
+                    default_name: default_name.to_string(),
+                    dat: SjisDat::default(),
+                })
+            }
+        };
+        let len = cached.dat.as_bytes().len();
+        cached.dat.append(
+            &self.responses[cached.dat.n_responses()..],
+            thread_title,
+            default_name,
+        );
+        self.bytes += cached.dat.as_bytes().len() - len;
+        cached.dat.shared_bytes()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum CacheLookup<T> {
+    Fresh(T),
+    /// Responses from this `res_no` have to be fetched and appended. The response of this
+    /// `res_no` itself is fetched too, to check that it was not rolled back.
+    Stale(u32),
+    Miss,
+}
+
+/// Append-only cache of the responses of each thread, and of their dat.
+///
+/// A cached thread only fetches the responses after the last one it holds, so a hot thread
+/// costs a few rows per second instead of the whole thread. What is read from a thread is
+/// given by `read`, which runs under the lock.
+#[derive(Debug)]
+pub(crate) struct ResponsesCache {
+    threads: HashMap<ResponsesCacheKey, CachedThread>,
+    bytes: usize,
+    max_bytes: usize,
+    clock: u64,
+}
+
+/// Rough size of a response on the heap
+fn res_bytes(res: &Res) -> usize {
+    std::mem::size_of::<Res>()
+        + res.name.as_ref().map_or(0, String::len)
+        + res.mail.as_ref().map_or(0, String::len)
+        + res.date.len()
+        + res.author_id.as_ref().map_or(0, String::len)
+        + res.body.len()
+        + res.thread_id.len()
+        + res.ip_addr.len()
+        + res.authed_token.as_ref().map_or(0, String::len)
+}
+
+/// `responses` are every response of a thread, ordered by `res_no`
+fn select(responses: &[Res], range: ResRange) -> Vec<Res> {
+    let filter = |from: u32, to: u32| {
+        responses
+            .iter()
+            .filter(|r| from <= r.res_no && r.res_no <= to)
+            .cloned()
+            .collect()
+    };
+    match range {
+        ResRange::All => responses.to_vec(),
+        ResRange::Between(from, to) => filter(from, to),
+        ResRange::Last(n) => responses[responses.len().saturating_sub(n as usize)..].to_vec(),
+        ResRange::Since(from) => filter(from, u32::MAX),
+    }
+}
+
+impl ResponsesCache {
+    pub fn new(max_bytes: usize) -> ResponsesCache {
+        ResponsesCache {
+            threads: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+            clock: 0,
+        }
+    }
+
+    pub fn lookup<T>(
+        &mut self,
+        key: &ResponsesCacheKey,
+        now: u64,
+        read: impl FnOnce(&mut CachedThread) -> T,
+    ) -> CacheLookup<T> {
+        let Some(thread) = self.threads.get(key) else {
+            return CacheLookup::Miss;
+        };
+        if now.saturating_sub(thread.loaded_at) > RESPONSES_CACHE_RELOAD_MILLIS {
+            CacheLookup::Miss
+        } else if now.saturating_sub(thread.checked_at) > RESPONSES_CACHE_FRESH_MILLIS {
+            CacheLookup::Stale(thread.last_res_no())
+        } else {
+            self.read(key, read)
+                .map_or(CacheLookup::Miss, CacheLookup::Fresh)
+        }
+    }
+
+    /// Caches every response of the thread, replacing the cached ones
+    pub fn insert<T>(
+        &mut self,
+        key: ResponsesCacheKey,
+        responses: Vec<Res>,
+        now: u64,
+        read: impl FnOnce(&mut CachedThread) -> T,
+    ) -> T {
+        self.remove(&key);
+        let bytes = responses.iter().map(res_bytes).sum();
+        self.bytes += bytes;
+        let mut thread = CachedThread {
+            responses,
+            dat: None,
+            loaded_at: now,
+            checked_at: now,
+            bytes,
+            last_used: 0,
+        };
+        let value = read(&mut thread);
+        self.bytes += thread.bytes - bytes;
+        self.clock += 1;
+        thread.last_used = self.clock;
+        self.threads.insert(key, thread);
+        self.evict();
+        value
+    }
+
+    /// Appends the responses fetched for `CacheLookup::Stale(checked_res_no)`.
+    ///
+    /// The ones already appended by a concurrent request are skipped. Returns `None` if the
+    /// thread is no longer cached, the cached response of `checked_res_no` is not the one in
+    /// the database (i.e. it was rolled back and the number was taken again), or the
+    /// responses don't follow the cached ones, i.e. the whole thread has to be loaded again.
+    pub fn append<T>(
+        &mut self,
+        key: &ResponsesCacheKey,
+        checked_res_no: u32,
+        responses: Vec<Res>,
+        now: u64,
+        read: impl FnOnce(&mut CachedThread) -> T,
+    ) -> Option<T> {
+        let thread = self.threads.get_mut(key)?;
+        if checked_res_no > 0 {
+            let cached = thread
+                .responses
+                .iter()
+                .rfind(|r| r.res_no == checked_res_no);
+            let fetched = responses.iter().find(|r| r.res_no == checked_res_no);
+            if cached.is_none() || cached != fetched {
+                self.remove(key);
+                return None;
+            }
+        }
+        let last_res_no = thread.last_res_no();
+        let new_responses = responses
+            .into_iter()
+            .filter(|r| r.res_no > last_res_no)
+            .collect::<Vec<_>>();
+        if new_responses
+            .first()
+            .is_some_and(|r| r.res_no != last_res_no + 1)
+        {
+            self.remove(key);
+            return None;
+        }
+
+        let bytes = new_responses.iter().map(res_bytes).sum::<usize>();
+        thread.responses.extend(new_responses);
+        thread.checked_at = now;
+        thread.bytes += bytes;
+        self.bytes += bytes;
+        self.read(key, read)
+    }
+
+    /// Marks the thread as used, and evicts others if `read` grew it
+    fn read<T>(
+        &mut self,
+        key: &ResponsesCacheKey,
+        read: impl FnOnce(&mut CachedThread) -> T,
+    ) -> Option<T> {
+        let thread = self.threads.get_mut(key)?;
+        self.clock += 1;
+        thread.last_used = self.clock;
+        let bytes = thread.bytes;
+        let value = read(thread);
+        self.bytes = self.bytes - bytes + thread.bytes;
+        self.evict();
+        Some(value)
+    }
+
+    pub fn remove(&mut self, key: &ResponsesCacheKey) {
+        if let Some(thread) = self.threads.remove(key) {
+            self.bytes -= thread.bytes;
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let Some(key) = self
+                .threads
+                .iter()
+                .min_by_key(|(_, thread)| thread.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            log!("cache evicted {}/{} ({})", key.0, key.1, key.2);
+            self.remove(&key);
+        }
+    }
+}
+
+/// Shared by every request of the isolate
+pub(crate) fn responses_cache() -> &'static Mutex<ResponsesCache> {
+    static RESPONSES_CACHE: OnceLock<Mutex<ResponsesCache>> = OnceLock::new();
+    RESPONSES_CACHE.get_or_init(|| Mutex::new(ResponsesCache::new(RESPONSES_CACHE_MAX_BYTES)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Ch5ResponsesFormatter;
+
+    fn res(res_no: u32) -> Res {
+        Res {
+            name: None,
+            mail: None,
+            date: String::new(),
+            author_id: None,
+            body: "レス".to_string(),
+            thread_id: "1710000001".to_string(),
+            ip_addr: String::new(),
+            authed_token: None,
+            timestamp: 0,
+            is_abone: 0,
+            res_no,
+        }
+    }
+
+    fn res_nos(responses: &[Res]) -> Vec<u32> {
+        responses.iter().map(|r| r.res_no).collect()
+    }
+
+    fn key(thread_id: &str) -> ResponsesCacheKey {
+        (1, thread_id.to_string(), 0)
+    }
+
+    #[test]
+    fn test_select() {
+        let responses = (1..=5).map(res).collect::<Vec<_>>();
+        for (range, expected) in [
+            (ResRange::Between(2, 3), vec![2, 3]),
+            (ResRange::Last(2), vec![4, 5]),
+            (ResRange::Since(4), vec![4, 5]),
+            (ResRange::Last(10), vec![1, 2, 3, 4, 5]),
+            (ResRange::Between(5, 10), vec![5]),
+            (ResRange::All, vec![1, 2, 3, 4, 5]),
+        ] {
+            assert_eq!(res_nos(&select(&responses, range)), expected, "{range:?}");
+        }
+    }
+
+    fn all(thread: &mut CachedThread) -> Vec<u32> {
+        res_nos(&thread.select(ResRange::All))
+    }
+
+    #[test]
+    fn test_append_only_newer_responses() {
+        let mut cache = ResponsesCache::new(usize::MAX);
+        let key = key("1710000001");
+        assert_eq!(cache.lookup(&key, 0, all), CacheLookup::Miss);
+        cache.insert(key.clone(), (1..=3).map(res).collect(), 0, all);
+        assert_eq!(
+            cache.lookup(&key, 500, |t| res_nos(&t.select(ResRange::Last(1)))),
+            CacheLookup::Fresh(vec![3])
+        );
+        assert_eq!(cache.lookup(&key, 1500, all), CacheLookup::Stale(3));
+
+        // 4 was already appended by a concurrent request
+        cache.append(&key, 3, vec![res(3), res(4)], 1500, all);
+        let appended = cache.append(&key, 3, vec![res(3), res(4), res(5)], 1500, |t| {
+            res_nos(&t.select(ResRange::Since(4)))
+        });
+        assert_eq!(appended, Some(vec![4, 5]));
+        assert_eq!(
+            cache.lookup(&key, 2000, all),
+            CacheLookup::Fresh(vec![1, 2, 3, 4, 5])
+        );
+
+        // Reloaded from the start now and then
+        assert_eq!(
+            cache.lookup(&key, RESPONSES_CACHE_RELOAD_MILLIS + 1, all),
+            CacheLookup::Miss
+        );
+    }
+
+    #[test]
+    fn test_append_with_gap_drops_thread() {
+        let mut cache = ResponsesCache::new(usize::MAX);
+        let key = key("1710000001");
+        cache.insert(key.clone(), (1..=3).map(res).collect(), 0, all);
+        assert!(cache
+            .append(&key, 3, vec![res(3), res(5)], 1500, all)
+            .is_none());
+        assert_eq!(cache.lookup(&key, 1500, all), CacheLookup::Miss);
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn test_append_after_rollback_drops_thread() {
+        let mut cache = ResponsesCache::new(usize::MAX);
+        let key = key("1710000001");
+        // 3 was cached before it was rolled back
+        cache.insert(key.clone(), (1..=3).map(res).collect(), 0, all);
+        assert!(cache.append(&key, 3, vec![], 1500, all).is_none());
+        assert_eq!(cache.lookup(&key, 1500, all), CacheLookup::Miss);
+
+        // ... and 3 was taken by another response
+        cache.insert(key.clone(), (1..=3).map(res).collect(), 0, all);
+        let retaken = Res {
+            body: "別のレス".to_string(),
+            ..res(3)
+        };
+        assert!(cache
+            .append(&key, 3, vec![retaken, res(4)], 1500, all)
+            .is_none());
+        assert_eq!(cache.lookup(&key, 1500, all), CacheLookup::Miss);
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn test_sjis_dat_is_extended() {
+        let mut cache = ResponsesCache::new(usize::MAX);
+        let key = key("1710000001");
+        let dat = |t: &mut CachedThread| t.sjis_dat("スレ", "名無し");
+        let first = cache.insert(key.clone(), (1..=3).map(res).collect(), 0, dat);
+        let bytes = cache.bytes;
+        let appended = cache
+            .append(&key, 3, vec![res(3), res(4)], 1500, dat)
+            .unwrap();
+
+        let responses = (1..=4).map(res).collect::<Vec<_>>();
+        let formatted = responses.format_responses("スレ", "名無し");
+        assert_eq!(
+            &appended[..],
+            &encoding_rs::SHIFT_JIS.encode(&formatted).0[..]
+        );
+        assert!(appended.starts_with(&first));
+        // Not copied by requests
+        let CacheLookup::Fresh(served) = cache.lookup(&key, 1500, dat) else {
+            panic!("not fresh");
+        };
+        assert!(Arc::ptr_eq(&served, &appended));
+        assert_eq!(
+            cache.bytes,
+            bytes + res_bytes(&res(4)) + appended.len() - first.len()
+        );
+
+        // Encoded again for another default name
+        let CacheLookup::Fresh(renamed) =
+            cache.lookup(&key, 1500, |t| t.sjis_dat("スレ", "名無しさん"))
+        else {
+            panic!("not fresh");
+        };
+        assert_ne!(renamed, appended);
+        assert_eq!(
+            cache.bytes,
+            bytes + res_bytes(&res(4)) + renamed.len() - first.len()
+        );
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let thread_bytes = 10 * res_bytes(&res(1));
+        let mut cache = ResponsesCache::new(2 * thread_bytes);
+        let responses = (1..=10).map(res).collect::<Vec<_>>();
+        cache.insert(key("1"), responses.clone(), 0, all);
+        cache.insert(key("2"), responses.clone(), 0, all);
+        cache.lookup(&key("1"), 0, all);
+        cache.insert(key("3"), responses.clone(), 0, all);
+
+        assert!(matches!(
+            cache.lookup(&key("1"), 0, all),
+            CacheLookup::Fresh(_)
+        ));
+        assert_eq!(cache.lookup(&key("2"), 0, all), CacheLookup::Miss);
+        assert!(matches!(
+            cache.lookup(&key("3"), 0, all),
+            CacheLookup::Fresh(_)
+        ));
+        assert_eq!(cache.bytes, 2 * thread_bytes);
+    }
+}
@@ -0,0 +1,1024 @@
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{
+    params, params_from_iter,
+    types::{Value, ValueRef},
+    Connection, OptionalExtension, Params,
+};
+use serde::de::DeserializeOwned;
+use worker::async_trait::async_trait;
+
+use crate::{
+    authed_cookie::AuthedCookie,
+    board::{Board, BoardSetting, CreatingBoard, UpdatingBoard},
+    board_policy::BoardPolicy,
+    cap::Cap,
+    integrity::{ResponseGroup, ThreadLocation},
+    kako::{Archive, ArchiveMonth},
+    maintenance::{ResponseCount, ResponseCountFix},
+    migrations::{
+        migrations_for, DbKind, SchemaDb, CREATE_SCHEMA_MIGRATIONS_QUERY, RECORD_MIGRATION_QUERY,
+        SCHEMA_MIGRATIONS_EXISTS_QUERY,
+    },
+    rebalance::{ShardCount, ShardMove},
+    repositories::{
+        bbs_repository::{
+            CreatingAuthedToken, CreatingRes, CreatingThread, ResRange, ThreadStatus,
+        },
+        bbs_storage::{
+            BbsStorage, InsertedRes, ThreadNumber, ARCHIVE_INACTIVE_THREADS_QUERY,
+            ARCHIVE_THREADS_BEYOND_QUERY, COPY_RESPONSE_QUERY, COUNT_RESPONSES_QUERY,
+            CREATE_BOARD_QUERY, FINISH_SHARD_MOVE_QUERY, FIX_RESPONSE_COUNT_QUERY,
+            GET_ARCHIVES_QUERY, GET_ARCHIVE_MONTHS_QUERY, GET_LAST_TASK_RUNS_QUERY,
+            GET_MISPLACED_THREADS_QUERY, GET_RESPONSE_GROUPS_QUERY, GET_SHARD_COUNTS_QUERY,
+            GET_TASK_RUNS_QUERY, GET_THREADS_BY_NUMBERS_QUERY, GET_THREADS_TO_STORE_QUERY,
+            GET_THREAD_LOCATIONS_QUERY, INSERT_RESPONSE_QUERY, INSERT_TASK_RUN_QUERY,
+            UPDATE_BOARD_QUERY, UPDATE_THREAD_BY_RESPONSE_QUERY, UPSERT_ARCHIVE_QUERY,
+            UPSERT_BOARD_POLICY_QUERY,
+        },
+    },
+    response::Res,
+    scheduled_tasks::{LastTaskRun, TaskRun},
+    search::SearchQuery,
+    thread::Thread,
+};
+
+/// In-memory SQLite `BbsStorage` with the same database layout as D1
+/// (infos, threads and `n_responses_db` responses shards), so that routes and
+/// maintenance logic can be exercised by `cargo test`.
+pub(crate) struct SqliteStorage {
+    infos_db: Mutex<Connection>,
+    threads_db: Mutex<Connection>,
+    responses_db: Vec<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn new_in_memory(n_responses_db: usize) -> anyhow::Result<SqliteStorage> {
+        // Same as `migrations::apply_pending`, which is async
+        let open = |kind: DbKind| -> anyhow::Result<Mutex<Connection>> {
+            let conn = Connection::open_in_memory()?;
+            conn.execute_batch(CREATE_SCHEMA_MIGRATIONS_QUERY)?;
+            for m in migrations_for(kind) {
+                conn.execute_batch(m.up)?;
+                conn.execute(RECORD_MIGRATION_QUERY, params![m.name, 0])?;
+            }
+            Ok(Mutex::new(conn))
+        };
+
+        Ok(SqliteStorage {
+            infos_db: open(DbKind::Infos)?,
+            threads_db: open(DbKind::Threads)?,
+            responses_db: (0..n_responses_db.max(1))
+                .map(|_| open(DbKind::Responses))
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        })
+    }
+
+    /// Runs arbitrary SQL against the threads database, e.g. to make later writes fail
+    pub fn execute_on_threads_db(&self, sql: &str) -> anyhow::Result<()> {
+        Ok(self.threads_db().execute_batch(sql)?)
+    }
+
+    /// Runs arbitrary SQL against the `modulo`-th responses database
+    pub fn execute_on_responses_db(&self, modulo: usize, sql: &str) -> anyhow::Result<()> {
+        Ok(self.responses_db(modulo).execute_batch(sql)?)
+    }
+
+    /// Runs arbitrary SQL against the infos database
+    pub fn execute_on_infos_db(&self, sql: &str) -> anyhow::Result<()> {
+        Ok(self.infos_db().execute_batch(sql)?)
+    }
+
+    /// Runs a `SELECT COUNT(*)` against the infos database
+    pub fn count_on_infos_db(&self, sql: &str) -> anyhow::Result<i64> {
+        Ok(self.infos_db().query_row(sql, [], |row| row.get(0))?)
+    }
+
+    fn infos_db(&self) -> MutexGuard<'_, Connection> {
+        self.infos_db.lock().unwrap()
+    }
+
+    fn threads_db(&self) -> MutexGuard<'_, Connection> {
+        self.threads_db.lock().unwrap()
+    }
+
+    fn responses_db(&self, modulo: usize) -> MutexGuard<'_, Connection> {
+        self.responses_db[modulo].lock().unwrap()
+    }
+}
+
+/// Deserializes rows the same way D1 does, i.e. as objects keyed by column name
+fn query_all<T: DeserializeOwned, P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> anyhow::Result<Vec<T>> {
+    let mut stmt = conn.prepare(sql)?;
+    let columns = stmt
+        .column_names()
+        .into_iter()
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    let rows = stmt.query_map(params, |row| {
+        let mut map = serde_json::Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            let value = match row.get_ref(i)? {
+                ValueRef::Null => serde_json::Value::Null,
+                ValueRef::Integer(n) => n.into(),
+                ValueRef::Real(f) => f.into(),
+                ValueRef::Text(t) => String::from_utf8_lossy(t).into(),
+                ValueRef::Blob(b) => b.to_vec().into(),
+            };
+            map.insert(column.clone(), value);
+        }
+        Ok(serde_json::Value::Object(map))
+    })?;
+
+    let mut result = Vec::new();
+    for row in rows {
+        result.push(serde_json::from_value(row?)?);
+    }
+    Ok(result)
+}
+
+fn query_first<T: DeserializeOwned, P: Params>(
+    conn: &Connection,
+    sql: &str,
+    params: P,
+) -> anyhow::Result<Option<T>> {
+    Ok(query_all(conn, sql, params)?.into_iter().next())
+}
+
+#[async_trait(?Send)]
+impl BbsStorage for SqliteStorage {
+    async fn get_board_info(&self, board_id: usize) -> anyhow::Result<Option<Board>> {
+        query_first(
+            &self.infos_db(),
+            "SELECT * FROM boards WHERE id = ?",
+            params![board_id],
+        )
+    }
+
+    async fn get_boards(&self) -> anyhow::Result<Vec<Board>> {
+        query_all(&self.infos_db(), "SELECT * FROM boards ORDER BY id", [])
+    }
+
+    async fn create_board(&self, board: &CreatingBoard) -> anyhow::Result<Board> {
+        query_first(
+            &self.infos_db(),
+            CREATE_BOARD_QUERY,
+            params![
+                board.id,
+                board.board_key,
+                board.name,
+                board.default_name,
+                board.local_rule
+            ],
+        )?
+        .ok_or_else(|| anyhow::anyhow!("failed to create board"))
+    }
+
+    async fn update_board(
+        &self,
+        board_id: usize,
+        board: &UpdatingBoard,
+    ) -> anyhow::Result<Option<Board>> {
+        query_first(
+            &self.infos_db(),
+            UPDATE_BOARD_QUERY,
+            params![
+                board_id,
+                board.name,
+                board.default_name,
+                board.local_rule,
+                board.retired
+            ],
+        )
+    }
+
+    async fn get_board_policies(&self) -> anyhow::Result<Vec<BoardPolicy>> {
+        query_all(&self.infos_db(), "SELECT * FROM board_policies", [])
+    }
+
+    async fn upsert_board_policy(&self, policy: &BoardPolicy) -> anyhow::Result<()> {
+        self.infos_db().execute(
+            UPSERT_BOARD_POLICY_QUERY,
+            params![
+                policy.board_id,
+                policy.max_subject_len,
+                policy.max_name_len,
+                policy.max_mail_len,
+                policy.max_body_len,
+                policy.max_body_lines,
+                policy.thread_stopper,
+                policy.thread_cooldown_secs,
+                policy.min_res_span_secs,
+                policy.n_live_threads
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn get_board_settings(&self, board_id: usize) -> anyhow::Result<Vec<BoardSetting>> {
+        query_all(
+            &self.infos_db(),
+            "SELECT key, value FROM board_settings WHERE board_id = ? ORDER BY key",
+            params![board_id],
+        )
+    }
+
+    async fn replace_board_settings(
+        &self,
+        board_id: usize,
+        settings: &[BoardSetting],
+    ) -> anyhow::Result<()> {
+        let mut db = self.infos_db();
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM board_settings WHERE board_id = ?",
+            params![board_id],
+        )?;
+        for setting in settings {
+            tx.execute(
+                "INSERT INTO board_settings (board_id, key, value) VALUES (?, ?, ?)",
+                params![board_id, setting.key, setting.value],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn get_last_task_runs(&self) -> anyhow::Result<Vec<LastTaskRun>> {
+        query_all(&self.infos_db(), GET_LAST_TASK_RUNS_QUERY, [])
+    }
+
+    async fn get_task_runs(&self, limit: usize) -> anyhow::Result<Vec<TaskRun>> {
+        query_all(&self.infos_db(), GET_TASK_RUNS_QUERY, params![limit])
+    }
+
+    async fn insert_task_run(&self, run: &TaskRun) -> anyhow::Result<()> {
+        self.infos_db().execute(
+            INSERT_TASK_RUN_QUERY,
+            params![
+                run.task_name,
+                run.started_at,
+                run.duration_millis,
+                run.succeeded,
+                run.counts,
+                run.error
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_task_runs_before(&self, started_at: u64) -> anyhow::Result<()> {
+        self.infos_db().execute(
+            "DELETE FROM task_runs WHERE started_at < ?",
+            params![started_at],
+        )?;
+        Ok(())
+    }
+
+    async fn get_thread(&self, board_id: usize, thread_id: &str) -> anyhow::Result<Option<Thread>> {
+        query_first(
+            &self.threads_db(),
+            "SELECT * FROM threads WHERE thread_number = ? AND board_id = ?",
+            params![thread_id, board_id],
+        )
+    }
+
+    async fn get_threads(
+        &self,
+        board_id: usize,
+        status: ThreadStatus,
+    ) -> anyhow::Result<Vec<Thread>> {
+        query_all(
+            &self.threads_db(),
+            status.select_threads_query(),
+            params![board_id],
+        )
+    }
+
+    async fn get_responses_in_range(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+        range: ResRange,
+    ) -> anyhow::Result<Vec<Res>> {
+        let mut params = vec![
+            Value::from(thread_id.to_string()),
+            Value::from(board_id as i64),
+        ];
+        params.extend(range.bounds().into_iter().map(Value::from));
+        query_all(
+            &self.responses_db(modulo),
+            range.select_responses_query(),
+            params_from_iter(params),
+        )
+    }
+
+    async fn get_responses_by_authed_token_and_timestamp(
+        &self,
+        authed_token: &str,
+        min_timestamp: &str,
+    ) -> anyhow::Result<Vec<Res>> {
+        let mut responses = Vec::new();
+        for modulo in 0..self.responses_db.len() {
+            responses.extend(query_all::<Res, _>(
+                &self.responses_db(modulo),
+                "SELECT * FROM responses WHERE authed_token = ? AND timestamp > ?",
+                params![authed_token, min_timestamp],
+            )?);
+        }
+        Ok(responses)
+    }
+
+    async fn get_authed_token(&self, token: &str) -> anyhow::Result<Option<AuthedCookie>> {
+        query_first(
+            &self.infos_db(),
+            "SELECT * FROM authed_cookies WHERE cookie = ?",
+            params![token],
+        )
+    }
+
+    async fn get_authed_token_by_origin_ip_and_auth_code(
+        &self,
+        ip: &str,
+        auth_code: &str,
+    ) -> anyhow::Result<Option<AuthedCookie>> {
+        query_first(
+            &self.infos_db(),
+            "SELECT * FROM authed_cookies WHERE origin_ip = ? AND auth_code = ?",
+            params![ip, auth_code],
+        )
+    }
+
+    fn n_responses_db(&self) -> usize {
+        self.responses_db.len()
+    }
+
+    async fn insert_thread(
+        &self,
+        thread: &CreatingThread<'_>,
+        thread_number: &str,
+        modulo: usize,
+    ) -> anyhow::Result<bool> {
+        let metadent: Option<&str> = thread.metadent.clone().into();
+        let metadent = metadent.unwrap_or("");
+
+        match self.threads_db().execute(
+            "INSERT INTO threads
+            (thread_number, title, response_count, board_id, last_modified, authed_cookie, metadent, modulo)
+            VALUES (?, ?, 1, ?, ?, ?, ?, ?)",
+            params![
+                thread_number,
+                thread.title,
+                thread.board_id,
+                thread.unix_time,
+                thread.authed_token,
+                metadent,
+                modulo,
+            ],
+        ) {
+            Ok(_) => Ok(true),
+            Err(e) if e.to_string().to_lowercase().contains("unique") => Ok(false),
+            Err(_) => Err(anyhow::anyhow!("failed to insert thread")),
+        }
+    }
+
+    async fn delete_thread(&self, board_id: usize, thread_id: &str) -> anyhow::Result<()> {
+        self.threads_db()
+            .execute(
+                "DELETE FROM threads WHERE thread_number = ? AND board_id = ?",
+                params![thread_id, board_id],
+            )
+            .map_err(|_| anyhow::anyhow!("failed to delete thread"))?;
+        Ok(())
+    }
+
+    async fn insert_response(
+        &self,
+        res: &CreatingRes<'_>,
+        modulo: usize,
+        max_res_no: u32,
+    ) -> anyhow::Result<Option<InsertedRes>> {
+        query_first(
+            &self.responses_db(modulo),
+            INSERT_RESPONSE_QUERY,
+            params![
+                res.name,
+                res.mail,
+                res.date_time,
+                res.author_ch5id,
+                res.body,
+                res.thread_id,
+                res.ip_addr,
+                res.authed_token,
+                res.unix_time,
+                res.board_id,
+                max_res_no,
+            ],
+        )
+        .map_err(|_| anyhow::anyhow!("failed to insert response"))
+    }
+
+    async fn delete_response(&self, id: i64, modulo: usize) -> anyhow::Result<()> {
+        self.responses_db(modulo)
+            .execute("DELETE FROM responses WHERE id = ?", params![id])
+            .map_err(|_| anyhow::anyhow!("failed to delete response"))?;
+        Ok(())
+    }
+
+    async fn update_thread_by_response(
+        &self,
+        res: &CreatingRes<'_>,
+        res_no: u32,
+        stopper: u32,
+        modulo: usize,
+    ) -> anyhow::Result<bool> {
+        let updated: Option<u32> = self
+            .threads_db()
+            .query_row(
+                UPDATE_THREAD_BY_RESPONSE_QUERY,
+                params![
+                    res_no,
+                    res.unix_time,
+                    stopper,
+                    res.thread_id,
+                    res.board_id,
+                    modulo
+                ],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|_| anyhow::anyhow!("failed to update thread"))?;
+        Ok(updated.is_some())
+    }
+
+    async fn create_authed_token(
+        &self,
+        authed_token: CreatingAuthedToken<'_>,
+    ) -> anyhow::Result<()> {
+        self.infos_db()
+            .execute(
+                "INSERT INTO authed_cookies (cookie, origin_ip, authed, auth_code, writed_time)
+                VALUES (?, ?, ?, ?, ?)",
+                params![
+                    authed_token.token,
+                    authed_token.origin_ip,
+                    0,
+                    authed_token.auth_code,
+                    authed_token.writed_time,
+                ],
+            )
+            .map_err(|_| anyhow::anyhow!("failed to insert authed_token"))?;
+        Ok(())
+    }
+
+    async fn update_authed_token_last_thread_creation(
+        &self,
+        token: &str,
+        unix_time: &str,
+    ) -> anyhow::Result<()> {
+        self.infos_db()
+            .execute(
+                "UPDATE authed_cookies SET last_thread_creation = ? WHERE cookie = ?",
+                params![unix_time, token],
+            )
+            .map_err(|_| anyhow::anyhow!("failed to update authed_token"))?;
+        Ok(())
+    }
+
+    async fn update_authed_status(&self, token: &str, authed_time: &str) -> anyhow::Result<()> {
+        self.infos_db()
+            .execute(
+                "UPDATE authed_cookies SET authed = ?, authed_time = ? WHERE cookie = ?",
+                params![1, authed_time, token],
+            )
+            .map_err(|_| anyhow::anyhow!("failed to update authed_token"))?;
+        Ok(())
+    }
+
+    async fn get_cap_by_password_hash(&self, hash: &str) -> anyhow::Result<Option<Cap>> {
+        query_first(
+            &self.infos_db(),
+            "SELECT * FROM caps WHERE cap_password_hash = ?",
+            params![hash],
+        )
+    }
+
+    async fn archive_inactive_threads(&self, board_id: usize) -> anyhow::Result<usize> {
+        let archived: Vec<ThreadNumber> = query_all(
+            &self.threads_db(),
+            ARCHIVE_INACTIVE_THREADS_QUERY,
+            params![board_id],
+        )
+        .map_err(|_| anyhow::anyhow!("failed to archive inactive threads"))?;
+        Ok(archived.len())
+    }
+
+    async fn archive_threads_beyond(
+        &self,
+        board_id: usize,
+        n_live_threads: usize,
+    ) -> anyhow::Result<usize> {
+        let archived: Vec<ThreadNumber> = query_all(
+            &self.threads_db(),
+            ARCHIVE_THREADS_BEYOND_QUERY,
+            params![board_id, n_live_threads],
+        )
+        .map_err(|_| anyhow::anyhow!("failed to archive threads"))?;
+        Ok(archived.len())
+    }
+
+    async fn count_responses(
+        &self,
+        board_id: usize,
+        modulo: usize,
+        thread_ids: &[String],
+    ) -> anyhow::Result<Vec<ResponseCount>> {
+        query_all(
+            &self.responses_db(modulo),
+            COUNT_RESPONSES_QUERY,
+            params![board_id, serde_json::to_string(thread_ids)?],
+        )
+    }
+
+    async fn fix_response_counts(
+        &self,
+        board_id: usize,
+        fixes: &[ResponseCountFix],
+    ) -> anyhow::Result<()> {
+        let mut db = self.threads_db();
+        let tx = db.transaction()?;
+        for fix in fixes {
+            tx.execute(
+                FIX_RESPONSE_COUNT_QUERY,
+                params![fix.to, board_id, fix.thread_number, fix.from],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn get_shard_counts(&self, n_shards: usize) -> anyhow::Result<Vec<ShardCount>> {
+        query_all(
+            &self.threads_db(),
+            GET_SHARD_COUNTS_QUERY,
+            params![n_shards],
+        )
+    }
+
+    async fn get_misplaced_threads(
+        &self,
+        n_shards: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ShardMove>> {
+        query_all(
+            &self.threads_db(),
+            GET_MISPLACED_THREADS_QUERY,
+            params![n_shards, limit],
+        )
+    }
+
+    async fn get_shard_moves(&self) -> anyhow::Result<Vec<ShardMove>> {
+        query_all(&self.threads_db(), "SELECT * FROM shard_moves", [])
+    }
+
+    async fn begin_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        let mut db = self.threads_db();
+        let tx = db.transaction()?;
+        tx.execute(
+            "INSERT OR IGNORE INTO shard_moves
+            (board_id, thread_number, from_modulo, to_modulo, active)
+            VALUES (?, ?, ?, ?, ?)",
+            params![
+                shard_move.board_id,
+                shard_move.thread_number,
+                shard_move.from_modulo,
+                shard_move.to_modulo,
+                shard_move.active,
+            ],
+        )?;
+        tx.execute(
+            "UPDATE threads SET active = 0 WHERE board_id = ? AND thread_number = ?",
+            params![shard_move.board_id, shard_move.thread_number],
+        )?;
+        tx.commit()
+            .map_err(|_| anyhow::anyhow!("failed to begin shard_move"))
+    }
+
+    async fn copy_responses(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        let responses = self
+            .get_responses(
+                shard_move.board_id,
+                &shard_move.thread_number,
+                shard_move.from_modulo,
+            )
+            .await?;
+
+        let mut db = self.responses_db(shard_move.to_modulo);
+        let tx = db.transaction()?;
+        for r in responses {
+            tx.execute(
+                COPY_RESPONSE_QUERY,
+                params![
+                    r.name,
+                    r.mail,
+                    r.date,
+                    r.author_id,
+                    r.body,
+                    r.thread_id,
+                    r.ip_addr,
+                    r.authed_token,
+                    r.timestamp,
+                    shard_move.board_id,
+                    r.is_abone,
+                    r.res_no,
+                ],
+            )?;
+        }
+        tx.commit()
+            .map_err(|_| anyhow::anyhow!("failed to copy responses"))
+    }
+
+    async fn update_thread_modulo(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()> {
+        self.threads_db()
+            .execute(
+                "UPDATE threads SET modulo = ? WHERE board_id = ? AND thread_number = ?",
+                params![modulo, board_id, thread_id],
+            )
+            .map_err(|_| anyhow::anyhow!("failed to update modulo"))?;
+        Ok(())
+    }
+
+    async fn delete_responses(
+        &self,
+        board_id: usize,
+        thread_id: &str,
+        modulo: usize,
+    ) -> anyhow::Result<()> {
+        self.responses_db(modulo)
+            .execute(
+                "DELETE FROM responses WHERE board_id = ? AND thread_id = ?",
+                params![board_id, thread_id],
+            )
+            .map_err(|_| anyhow::anyhow!("failed to delete responses"))?;
+        Ok(())
+    }
+
+    async fn finish_shard_move(&self, shard_move: &ShardMove) -> anyhow::Result<()> {
+        let mut db = self.threads_db();
+        let tx = db.transaction()?;
+        tx.execute(
+            FINISH_SHARD_MOVE_QUERY,
+            params![
+                shard_move.active,
+                shard_move.board_id,
+                shard_move.thread_number
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM shard_moves WHERE board_id = ? AND thread_number = ?",
+            params![shard_move.board_id, shard_move.thread_number],
+        )?;
+        tx.commit()
+            .map_err(|_| anyhow::anyhow!("failed to finish shard_move"))
+    }
+
+    async fn get_thread_locations(&self) -> anyhow::Result<Vec<ThreadLocation>> {
+        query_all(&self.threads_db(), GET_THREAD_LOCATIONS_QUERY, [])
+    }
+
+    async fn get_response_groups(&self, modulo: usize) -> anyhow::Result<Vec<ResponseGroup>> {
+        query_all(&self.responses_db(modulo), GET_RESPONSE_GROUPS_QUERY, [])
+    }
+
+    async fn search_threads(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        let (sql, terms) = query.select_threads_query();
+        let mut params = vec![
+            Value::from(board_id as i64),
+            Value::from(limit as i64),
+            Value::from(offset as i64),
+        ];
+        params.extend(terms.into_iter().map(Value::from));
+        query_all(&self.threads_db(), &sql, params_from_iter(params))
+    }
+
+    async fn search_responses(
+        &self,
+        board_id: usize,
+        query: &SearchQuery,
+        modulo: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Res>> {
+        let (sql, terms) = query.select_responses_query();
+        let mut params = vec![Value::from(board_id as i64), Value::from(limit as i64)];
+        params.extend(terms.into_iter().map(Value::from));
+        query_all(&self.responses_db(modulo), &sql, params_from_iter(params))
+    }
+
+    async fn get_threads_by_numbers(
+        &self,
+        board_id: usize,
+        thread_numbers: &[String],
+    ) -> anyhow::Result<Vec<Thread>> {
+        query_all(
+            &self.threads_db(),
+            GET_THREADS_BY_NUMBERS_QUERY,
+            params![board_id, serde_json::to_string(thread_numbers)?],
+        )
+    }
+
+    async fn get_threads_to_store(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        query_all(
+            &self.threads_db(),
+            GET_THREADS_TO_STORE_QUERY,
+            params![board_id, limit],
+        )
+    }
+
+    async fn get_stored_threads(
+        &self,
+        board_id: usize,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Thread>> {
+        query_all(
+            &self.threads_db(),
+            "SELECT * FROM threads WHERE board_id = ? AND archived = 1 AND archive_stored = 1
+            ORDER BY CAST(thread_number AS INTEGER) LIMIT ?",
+            params![board_id, limit],
+        )
+    }
+
+    async fn upsert_archive(&self, thread: &Thread) -> anyhow::Result<()> {
+        self.infos_db().execute(
+            UPSERT_ARCHIVE_QUERY,
+            params![
+                thread.thread_number,
+                thread.title,
+                thread.response_count,
+                thread.board_id,
+                thread.last_modified
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn mark_archive_stored(&self, board_id: usize, thread_id: &str) -> anyhow::Result<()> {
+        self.threads_db().execute(
+            "UPDATE threads SET archive_stored = 1 WHERE board_id = ? AND thread_number = ?",
+            params![board_id, thread_id],
+        )?;
+        Ok(())
+    }
+
+    async fn get_archive_months(&self, board_id: usize) -> anyhow::Result<Vec<ArchiveMonth>> {
+        query_all(
+            &self.infos_db(),
+            GET_ARCHIVE_MONTHS_QUERY,
+            params![board_id],
+        )
+    }
+
+    async fn get_archives(
+        &self,
+        board_id: usize,
+        from: u32,
+        to: u32,
+        limit: usize,
+        offset: usize,
+    ) -> anyhow::Result<Vec<Archive>> {
+        query_all(
+            &self.infos_db(),
+            GET_ARCHIVES_QUERY,
+            params![board_id, from, to, limit, offset],
+        )
+    }
+}
+
+#[async_trait(?Send)]
+impl SchemaDb for Mutex<Connection> {
+    async fn applied_migrations(&self) -> anyhow::Result<Vec<String>> {
+        if !self.exists(SCHEMA_MIGRATIONS_EXISTS_QUERY).await? {
+            return Ok(Vec::new());
+        }
+        let conn = self.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name FROM schema_migrations")?;
+        let names = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(names)
+    }
+
+    async fn exists(&self, sql: &str) -> anyhow::Result<bool> {
+        Ok(self.lock().unwrap().prepare(sql)?.exists([])?)
+    }
+
+    async fn apply(&self, name: &str, statements: Vec<String>) -> anyhow::Result<()> {
+        let mut conn = self.lock().unwrap();
+        let tx = conn.transaction()?;
+        for sql in &statements {
+            tx.execute_batch(sql)?;
+        }
+        tx.execute(RECORD_MIGRATION_QUERY, params![name, 0])?;
+        Ok(tx.commit()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread::MetadentType;
+
+    fn creating_thread<'a>(unix_time: &'a str, title: &'a str) -> CreatingThread<'a> {
+        CreatingThread {
+            title,
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id: 1,
+            metadent: MetadentType::None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_thread_and_responses() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let thread = creating_thread("1700000001", "テストスレ");
+        assert!(storage
+            .insert_thread(&thread, "1700000001", 2)
+            .await
+            .unwrap());
+        let inserted = storage
+            .insert_response(&CreatingRes::from(&thread), 2, 1000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inserted.res_no, 1);
+
+        let thread = storage.get_thread(1, "1700000001").await.unwrap().unwrap();
+        assert_eq!(thread.title, "テストスレ");
+        assert_eq!(thread.response_count, 1);
+        assert_eq!(thread.modulo, 2);
+
+        let res = CreatingRes {
+            unix_time: "1700000002",
+            body: "二レス目",
+            name: "",
+            mail: "sage",
+            date_time: "2099/09/09(水) 00:00:01.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            thread_id: "1700000001",
+            board_id: 1,
+        };
+        let inserted = storage
+            .insert_response(&res, 2, 1000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inserted.res_no, 2);
+        assert!(storage
+            .update_thread_by_response(&res, inserted.res_no, 1000, 2)
+            .await
+            .unwrap());
+
+        let thread = storage.get_thread(1, "1700000001").await.unwrap().unwrap();
+        assert_eq!(thread.response_count, 2);
+        assert_eq!(thread.last_modified, "1700000002");
+        let responses = storage.get_responses(1, "1700000001", 2).await.unwrap();
+        assert_eq!(
+            responses
+                .iter()
+                .map(|r| r.body.as_str())
+                .collect::<Vec<_>>(),
+            vec!["本文", "二レス目"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_insert_thread_duplicated() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        assert!(storage
+            .insert_thread(
+                &creating_thread("1700000001", "テストスレ"),
+                "1700000001",
+                0
+            )
+            .await
+            .unwrap());
+        assert!(!storage
+            .insert_thread(
+                &creating_thread("1700000001", "テストスレ2"),
+                "1700000001",
+                0
+            )
+            .await
+            .unwrap());
+
+        // Thread numbers are unique per board
+        let thread = CreatingThread {
+            board_id: 2,
+            ..creating_thread("1700000001", "別板のスレ")
+        };
+        assert!(storage
+            .insert_thread(&thread, "1700000001", 0)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_update_missing_thread() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let thread = creating_thread("1700000001", "テストスレ");
+        assert!(!storage
+            .update_thread_by_response(&CreatingRes::from(&thread), 2, 1000, 0)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_insert_response_stops_at_max_res_no() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let thread = creating_thread("1700000001", "テストスレ");
+        let res = CreatingRes::from(&thread);
+        for res_no in 1..=3 {
+            let inserted = storage.insert_response(&res, 0, 3).await.unwrap().unwrap();
+            assert_eq!(inserted.res_no, res_no);
+        }
+        assert_eq!(storage.insert_response(&res, 0, 3).await.unwrap(), None);
+        // The numbering is per thread
+        let other = CreatingRes {
+            thread_id: "1700000002",
+            ..res
+        };
+        let inserted = storage
+            .insert_response(&other, 0, 3)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inserted.res_no, 1);
+    }
+
+    #[tokio::test]
+    async fn test_res_no_backfilled() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let responses = storage.get_responses(1, "1696233330", 0).await.unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].res_no, 1);
+    }
+
+    #[tokio::test]
+    async fn test_replace_board_settings() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let setting = |key: &str, value: &str| BoardSetting {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        storage
+            .replace_board_settings(1, &[setting("BBS_SLIP", ""), setting("BBS_DATMAX", "512")])
+            .await
+            .unwrap();
+        storage
+            .replace_board_settings(2, &[setting("BBS_SLIP", "vvv")])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_board_settings(1).await.unwrap(),
+            vec![setting("BBS_DATMAX", "512"), setting("BBS_SLIP", "")]
+        );
+
+        storage
+            .replace_board_settings(1, &[setting("BBS_SLIP", "checked")])
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_board_settings(1).await.unwrap(),
+            vec![setting("BBS_SLIP", "checked")]
+        );
+        assert_eq!(storage.get_board_settings(2).await.unwrap().len(), 1);
+    }
+}
@@ -1,6 +1,8 @@
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Res {
     pub name: Option<String>, // author name
     pub mail: Option<String>,
@@ -12,48 +14,232 @@ pub struct Res {
     pub authed_token: Option<String>,
     pub timestamp: u64,
     pub is_abone: u32,
+    /// 1-origin number of the response in the thread, 0 if unknown
+    #[serde(default)]
+    pub res_no: u32,
 }
 
 pub trait Ch5ResponsesFormatter {
     fn format_responses(&self, thread_title: &str, default_name: &str) -> String;
 }
 
+/// Where dat lines are written: a `String`, or Shift_JIS bytes through `SjisSink`
+trait DatSink {
+    fn push_str(&mut self, s: &str);
+}
+
+impl DatSink for String {
+    fn push_str(&mut self, s: &str) {
+        String::push_str(self, s);
+    }
+}
+
+/// Longest numeric character reference an unmappable character is encoded to, `&#1114111;`
+const MAX_NCR_LEN: usize = 10;
+
+/// Encodes each fragment at the end of `out`, so the dat is never built as a `String`.
+///
+/// Unmappable characters become numeric character references as by `SHIFT_JIS.encode`.
+/// The encoder keeps no state between characters, so encoding fragment by fragment gives
+/// the same bytes as encoding the whole.
+struct SjisSink<'a> {
+    out: &'a mut Vec<u8>,
+    encoder: encoding_rs::Encoder,
+}
+
+impl<'a> SjisSink<'a> {
+    fn new(out: &'a mut Vec<u8>) -> SjisSink<'a> {
+        SjisSink {
+            out,
+            encoder: encoding_rs::SHIFT_JIS.new_encoder(),
+        }
+    }
+}
+
+impl DatSink for SjisSink<'_> {
+    fn push_str(&mut self, mut s: &str) {
+        while !s.is_empty() {
+            let len = self
+                .encoder
+                .max_buffer_length_from_utf8_if_no_unmappables(s.len())
+                .unwrap_or(s.len() * 2);
+            self.out.reserve(len + MAX_NCR_LEN);
+            let (_, read, _) = self.encoder.encode_from_utf8_to_vec(s, self.out, false);
+            s = &s[read..];
+        }
+    }
+}
+
+/// Writes `s` with every `from` replaced by `to`, without allocating the replaced string
+fn push_replaced<S: DatSink>(out: &mut S, s: &str, from: &str, to: &str) {
+    let mut rest = s;
+    while let Some(i) = rest.find(from) {
+        out.push_str(&rest[..i]);
+        out.push_str(to);
+        rest = &rest[i + from.len()..];
+    }
+    out.push_str(rest);
+}
+
+/// Appends the dat line of `r`, preceded by あぼーん lines for the numbers between
+/// `last_res_no` and `r.res_no`. Returns the `res_no` of the last line appended.
+///
+/// Line N of the dat must be response N, so numbers lost by a rolled back write are
+/// filled. `thread_title` goes to the first line of the dat, i.e. while `is_first`.
+fn push_dat_lines<S: DatSink>(
+    out: &mut S,
+    r: &Res,
+    last_res_no: u32,
+    mut is_first: bool,
+    thread_title: &str,
+    default_name: &str,
+) -> u32 {
+    let mut title = || {
+        if std::mem::take(&mut is_first) {
+            thread_title
+        } else {
+            ""
+        }
+    };
+    if r.res_no > 0 {
+        for _ in last_res_no + 1..r.res_no {
+            out.push_str("あぼーん<>あぼーん<> <> あぼーん<>");
+            out.push_str(title());
+            out.push_str("\n");
+        }
+    }
+
+    if r.is_abone == 1 {
+        out.push_str("あぼーん<>あぼーん<> <> あぼーん<>");
+        out.push_str(title());
+    } else {
+        let name = r
+            .name
+            .as_deref()
+            .filter(|x| !x.is_empty())
+            .unwrap_or(default_name);
+        push_replaced(out, name, "\n", "");
+        out.push_str("<><>");
+        out.push_str(&r.date);
+        out.push_str(" ID:");
+        out.push_str(r.author_id.as_deref().unwrap_or(""));
+        out.push_str("<> ");
+        for (i, line) in r.body.split('\n').enumerate() {
+            if i > 0 {
+                out.push_str("<br>");
+            }
+            push_replaced(out, line, "edge.edgebb.workers.dev", "bbs.eddibb.cc");
+        }
+        out.push_str("<>");
+        out.push_str(title());
+    }
+    out.push_str("\n");
+
+    if r.res_no > 0 {
+        r.res_no
+    } else {
+        last_res_no
+    }
+}
+
+/// Writes the lines of `responses` after the response `last_res_no`.
+/// Returns the `res_no` of the last line written.
+fn write_dat_lines<S: DatSink>(
+    out: &mut S,
+    responses: &[Res],
+    mut last_res_no: u32,
+    is_first: bool,
+    thread_title: &str,
+    default_name: &str,
+) -> u32 {
+    let thread_title = thread_title.replace('\n', "");
+    for (i, r) in responses.iter().enumerate() {
+        last_res_no = push_dat_lines(
+            out,
+            r,
+            last_res_no,
+            is_first && i == 0,
+            &thread_title,
+            default_name,
+        );
+    }
+    last_res_no
+}
+
 impl Ch5ResponsesFormatter for Vec<Res> {
     fn format_responses(&self, thread_title: &str, default_name: &str) -> String {
-        let thread_title = thread_title.replace('\n', "");
         let mut builder = String::new();
-        for (idx, r) in self.iter().enumerate() {
-            if r.is_abone == 1 {
-                builder.push_str(&format!(
-                    "あぼーん<>あぼーん<> <> あぼーん<>{}",
-                    if idx == 0 { &thread_title } else { "" }
-                ));
-            } else {
-                builder.push_str(&format!(
-                    "{}<><>{} ID:{}<> {}<>{}",
-                    r.name
-                        .as_ref()
-                        .map(|x| if x.is_empty() { default_name } else { x })
-                        .unwrap_or(default_name)
-                        .replace('\n', ""),
-                    r.date,
-                    r.author_id.as_deref().unwrap_or(""),
-                    r.body
-                        .replace('\n', "<br>")
-                        .replace("edge.edgebb.workers.dev", "bbs.eddibb.cc"),
-                    if idx == 0 { &thread_title } else { "" }
-                ));
-            }
+        write_dat_lines(&mut builder, self, 0, true, thread_title, default_name);
+        builder
+    }
+}
 
-            builder.push('\n');
+/// Writes the dat of `responses` in Shift_JIS at the end of `out`, e.g. a buffer reused
+/// between threads. The bytes are the same as `format_responses` encoded.
+pub fn write_sjis_dat(
+    out: &mut Vec<u8>,
+    responses: &[Res],
+    thread_title: &str,
+    default_name: &str,
+) {
+    write_dat_lines(
+        &mut SjisSink::new(out),
+        responses,
+        0,
+        true,
+        thread_title,
+        default_name,
+    );
+}
+
+/// The dat of a thread in Shift_JIS, encoded response by response.
+///
+/// Responses appended later are encoded on their own, so a live thread encodes each
+/// response once instead of the whole thread on every request. The bytes are shared with
+/// the requests being served, so serving them copies nothing.
+#[derive(Debug, Clone, Default)]
+pub struct SjisDat {
+    bytes: Arc<Vec<u8>>,
+    /// Responses encoded so far
+    n_responses: usize,
+    last_res_no: u32,
+}
+
+impl SjisDat {
+    /// `responses` are the ones after the responses already appended, in order
+    pub fn append(&mut self, responses: &[Res], thread_title: &str, default_name: &str) {
+        if responses.is_empty() {
+            return;
         }
-        builder
+        let is_first = self.bytes.is_empty();
+        // Copied only if a request is still holding the bytes
+        self.last_res_no = write_dat_lines(
+            &mut SjisSink::new(Arc::make_mut(&mut self.bytes)),
+            responses,
+            self.last_res_no,
+            is_first,
+            thread_title,
+            default_name,
+        );
+        self.n_responses += responses.len();
+    }
+
+    pub fn n_responses(&self) -> usize {
+        self.n_responses
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn shared_bytes(&self) -> Arc<Vec<u8>> {
+        Arc::clone(&self.bytes)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Ch5ResponsesFormatter, Res};
+    use super::{write_sjis_dat, Ch5ResponsesFormatter, Res, SjisDat};
 
     fn make_test_res(name: Option<&str>, body: &str, sec: u32, is_abone: bool) -> Res {
         Res {
@@ -67,6 +253,7 @@ mod tests {
             authed_token: None,
             timestamp: 0,
             is_abone: if is_abone { 1 } else { 0 },
+            res_no: 0,
         }
     }
     #[test]
@@ -94,4 +281,70 @@ a0b1c2d3e4f5g6h7i8j9k10l11m12n<><>2099/9/09(金) 0:0:50.00 ID:abC/DEf50<> 認証
             formatted,
         )
     }
+
+    #[test]
+    fn test_render_dat_with_missing_res_no() {
+        let mut res_2 = make_test_res(None, "2", 20, false);
+        res_2.res_no = 2;
+        let mut res_4 = make_test_res(None, "4", 40, false);
+        res_4.res_no = 4;
+        let formatted = vec![res_2, res_4].format_responses("実況スレ", "名無し");
+        assert_eq!(
+            r"あぼーん<>あぼーん<> <> あぼーん<>実況スレ
+名無し<><>2099/9/09(金) 0:0:20.00 ID:abC/DEf20<> 2<>
+あぼーん<>あぼーん<> <> あぼーん<>
+名無し<><>2099/9/09(金) 0:0:40.00 ID:abC/DEf40<> 4<>
+",
+            formatted,
+        )
+    }
+
+    #[test]
+    fn test_sjis_dat() {
+        let mut responses = (1..=6)
+            .map(|i| {
+                let mut res = make_test_res(Some(""), "レス\n①", i * 10, i == 3);
+                res.res_no = i;
+                res
+            })
+            .collect::<Vec<_>>();
+        // Lost by a rolled back write
+        responses.remove(4);
+
+        let mut dat = SjisDat::default();
+        dat.append(&responses[..2], "実況スレ", "名無し");
+        dat.append(&responses[2..], "実況スレ", "名無し");
+        let formatted = responses.format_responses("実況スレ", "名無し");
+        assert_eq!(
+            dat.as_bytes(),
+            &encoding_rs::SHIFT_JIS.encode(&formatted).0[..]
+        );
+        assert_eq!(dat.n_responses(), 5);
+    }
+
+    #[test]
+    fn test_write_sjis_dat() {
+        let mut responses = vec![
+            make_test_res(
+                Some("名前\n改行"),
+                "https://edge.edgebb.workers.dev/\n\n",
+                10,
+                false,
+            ),
+            // Not in Shift_JIS, so written as numeric character references
+            make_test_res(None, "絵文字😀と𠮷", 20, false),
+            make_test_res(None, "あぼーん", 30, true),
+        ];
+        responses[2].res_no = 5;
+
+        let mut buffer = b"previous".to_vec();
+        buffer.clear();
+        write_sjis_dat(&mut buffer, &responses, "スレ\nタイ", "名無し");
+        let formatted = responses.format_responses("スレ\nタイ", "名無し");
+        assert!(formatted.contains("https://bbs.eddibb.cc/<br><br><>スレタイ\n"));
+        assert_eq!(
+            buffer,
+            encoding_rs::SHIFT_JIS.encode(&formatted).0.into_owned()
+        );
+    }
 }
@@ -1,10 +1,18 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, sync::Arc};
 
+use worker::{js_sys::Uint8Array, worker_sys::web_sys, Response};
+
+use self::admin::{analyze_admin_route, AdminRoute};
+use crate::repositories::bbs_repository::ResRange;
+
+pub(crate) mod admin;
 pub(crate) mod auth;
 pub(crate) mod auth_code;
 pub(crate) mod bbs_cgi;
 pub(crate) mod dat_routing;
 pub(crate) mod head_txt;
+pub(crate) mod kako_subject_txt;
+pub(crate) mod search_json;
 pub(crate) mod setting_txt;
 pub(crate) mod subject_txt;
 pub(crate) mod webui;
@@ -25,6 +33,19 @@ pub enum Route<'a> {
         board_id: usize,
         thread_id: &'a str,
     },
+    KakoIndex {
+        board_key: &'a str,
+        board_id: usize,
+    },
+    KakoSubjectTxt {
+        board_key: &'a str,
+        board_id: usize,
+    },
+    KakoThreadWebUI {
+        board_key: &'a str,
+        board_id: usize,
+        thread_id: &'a str,
+    },
     SettingTxt {
         board_key: &'a str,
         board_id: usize,
@@ -41,11 +62,21 @@ pub enum Route<'a> {
         board_key: &'a str,
         board_id: usize,
     },
+    Search {
+        board_key: &'a str,
+        board_id: usize,
+    },
+    SearchJson {
+        board_key: &'a str,
+        board_id: usize,
+    },
     ThreadWebUI {
         board_key: &'a str,
         board_id: usize,
         thread_id: &'a str,
+        range: ResRange,
     },
+    Admin(AdminRoute),
     NotFound,
 }
 
@@ -55,6 +86,9 @@ pub fn analyze_route<'a>(path: &'a str, board_keys: &'a HashMap<String, usize>)
         "/auth/" | "/auth" => Route::Auth,
         "/auth-code/" | "/auth-code" => Route::AuthCode,
         "/test/bbs.cgi" => Route::BbsCgi,
+        path if path.starts_with("/admin/") => {
+            analyze_admin_route(&path["/admin/".len()..]).map_or(Route::NotFound, Route::Admin)
+        }
         path => {
             if path.len() < 4 {
                 return Route::NotFound;
@@ -108,12 +142,22 @@ pub fn analyze_route<'a>(path: &'a str, board_keys: &'a HashMap<String, usize>)
                 }
                 ".TXT" | ".txt" => {
                     let split = path.split('/').collect::<Vec<_>>();
-                    if split.len() != 3 {
-                        return Route::NotFound;
-                    }
-                    let board_key = split[1];
+                    let (board_key, file) = match split.as_slice() {
+                        ["", board_key, file] => (*board_key, *file),
+                        ["", board_key, "kako", "subject.txt"] => {
+                            return if let Some(board_id) = board_keys.get(*board_key) {
+                                Route::KakoSubjectTxt {
+                                    board_key,
+                                    board_id: *board_id,
+                                }
+                            } else {
+                                Route::NotFound
+                            };
+                        }
+                        _ => return Route::NotFound,
+                    };
                     let board_id = board_keys.get(board_key).copied();
-                    match (split[2], board_id) {
+                    match (file, board_id) {
                         ("SETTING.TXT", Some(board_id)) => Route::SettingTxt {
                             board_key,
                             board_id,
@@ -130,55 +174,83 @@ pub fn analyze_route<'a>(path: &'a str, board_keys: &'a HashMap<String, usize>)
                     }
                 }
                 _ => {
-                    // /:board_key/:thread_id/? OR /test/read.cgi/:board_key/:thread_id/? OR /:board_key/?
+                    // /:board_key/:thread_id/:range? OR /test/read.cgi/:board_key/:thread_id/:range?
+                    // OR /:board_key/? OR /:board_key/search OR /:board_key/search.json
+                    // OR /:board_key/kako/ OR /:board_key/kako/:thread_id
                     let mut split = path.split('/').collect::<Vec<_>>();
                     if split.last() == Some(&"") {
                         split.pop();
                     }
-                    match split.len() {
-                        2 => {
-                            let board_key = split[1];
-                            if let Some(board_id) = board_keys.get(board_key) {
+                    let thread_path = match split.as_slice() {
+                        ["", board_key] => {
+                            return if let Some(board_id) = board_keys.get(*board_key) {
                                 Route::BoardIndex {
                                     board_key,
                                     board_id: *board_id,
                                 }
                             } else {
                                 Route::NotFound
-                            }
+                            };
                         }
-                        3 => {
-                            let (board_key, thread_id) = (split[1], split[2]);
-                            if thread_id.len() != 10 {
+                        ["", board_key, page @ ("search" | "search.json")] => {
+                            let Some(board_id) = board_keys.get(*board_key).copied() else {
                                 return Route::NotFound;
-                            }
-                            if let Some(board_id) = board_keys.get(board_key) {
-                                Route::ThreadWebUI {
+                            };
+                            return if *page == "search" {
+                                Route::Search {
                                     board_key,
-                                    board_id: *board_id,
-                                    thread_id,
+                                    board_id,
                                 }
                             } else {
-                                Route::NotFound
-                            }
+                                Route::SearchJson {
+                                    board_key,
+                                    board_id,
+                                }
+                            };
                         }
-                        5 => {
-                            let (board_key, thread_id) = (split[3], split[4]);
-                            if split[1] != "test" || split[2] != "read.cgi" || thread_id.len() != 10
-                            {
+                        ["", board_key, "kako", rest @ ..] if rest.len() <= 1 => {
+                            let Some(board_id) = board_keys.get(*board_key).copied() else {
                                 return Route::NotFound;
-                            }
-                            if let Some(board_id) = board_keys.get(board_key) {
-                                Route::ThreadWebUI {
+                            };
+                            return match rest {
+                                [] => Route::KakoIndex {
                                     board_key,
-                                    board_id: *board_id,
+                                    board_id,
+                                },
+                                [thread_id] if thread_id.len() == 10 => Route::KakoThreadWebUI {
+                                    board_key,
+                                    board_id,
                                     thread_id,
-                                }
-                            } else {
-                                Route::NotFound
-                            }
+                                },
+                                _ => Route::NotFound,
+                            };
                         }
-                        _ => Route::NotFound,
+                        ["", "test", "read.cgi", rest @ ..] => rest,
+                        ["", rest @ ..] => rest,
+                        _ => return Route::NotFound,
+                    };
+                    let (board_key, thread_id, range) = match thread_path {
+                        [board_key, thread_id] => (*board_key, *thread_id, Some(ResRange::All)),
+                        [board_key, thread_id, range] => {
+                            (*board_key, *thread_id, parse_res_range(range))
+                        }
+                        _ => return Route::NotFound,
+                    };
+                    let Some(range) = range else {
+                        return Route::NotFound;
+                    };
+                    if thread_id.len() != 10 {
+                        return Route::NotFound;
+                    }
+                    if let Some(board_id) = board_keys.get(board_key) {
+                        Route::ThreadWebUI {
+                            board_key,
+                            board_id: *board_id,
+                            thread_id,
+                            range,
+                        }
+                    } else {
+                        Route::NotFound
                     }
                 }
             }
@@ -186,6 +258,100 @@ pub fn analyze_route<'a>(path: &'a str, board_keys: &'a HashMap<String, usize>)
     }
 }
 
+/// A response built without `worker`, so that a route can be run under `cargo test`.
+/// The `worker::Request` wrapper of the route sends it by `into_response`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RouteResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: RouteBody,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RouteBody {
+    Bytes(Vec<u8>),
+    /// A range of bytes shared with a cache, e.g. the dat of a thread
+    Shared(Arc<Vec<u8>>, Range<usize>),
+}
+
+impl RouteBody {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            RouteBody::Bytes(bytes) => bytes,
+            RouteBody::Shared(bytes, range) => &bytes[range.clone()],
+        }
+    }
+}
+
+impl RouteResponse {
+    pub fn new(status: u16, body: RouteBody) -> RouteResponse {
+        RouteResponse {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// Same as `Response::error`
+    pub fn error(message: impl Into<String>, status: u16) -> RouteResponse {
+        RouteResponse::new(status, RouteBody::Bytes(message.into().into_bytes()))
+    }
+
+    /// A page in Shift_JIS, as the responses of bbs.cgi
+    pub fn shift_jis_html(body: &str) -> RouteResponse {
+        let body = encoding_rs::SHIFT_JIS.encode(body).0.into_owned();
+        RouteResponse::new(200, RouteBody::Bytes(body))
+            .with_header("Content-Type", "text/html; charset=x-sjis")
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> RouteResponse {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(x, _)| x.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body is copied straight to JS, not through a `Vec` as `Response::from_bytes`
+    pub fn into_response(self) -> worker::Result<Response> {
+        let bytes = self.body.as_bytes();
+        // A 304 must not have a body, not even an empty one
+        let body = (!bytes.is_empty()).then(|| Uint8Array::from(bytes));
+        let mut resp = Response::from(web_sys::Response::new_with_opt_buffer_source(
+            body.as_deref(),
+        )?);
+        for (name, value) in &self.headers {
+            resp.headers_mut().append(name, value)?;
+        }
+        Ok(resp.with_status(self.status))
+    }
+}
+
+/// Parses the 5ch style range of read.cgi, e.g. `l50`, `100-200`, `100-`, `-100` and `50`
+fn parse_res_range(range: &str) -> Option<ResRange> {
+    if let Some(n) = range.strip_prefix('l') {
+        return n.parse().ok().filter(|n| *n > 0).map(ResRange::Last);
+    }
+    let range = match range.split_once('-') {
+        Some((from, "")) => ResRange::Since(from.parse().ok()?),
+        Some(("", to)) => ResRange::Between(1, to.parse().ok()?),
+        Some((from, to)) => ResRange::Between(from.parse().ok()?, to.parse().ok()?),
+        None => {
+            let n = range.parse().ok()?;
+            ResRange::Between(n, n)
+        }
+    };
+    match range {
+        ResRange::Between(from, to) if from > to => None,
+        range => Some(range),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -206,6 +372,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_admin() {
+        let paths = [
+            "/admin/shards",
+            "/admin/shards/rebalance/",
+            "/admin/unknown",
+        ];
+        let expecteds = [
+            Route::Admin(AdminRoute::Shards),
+            Route::Admin(AdminRoute::ShardsRebalance),
+            Route::NotFound,
+        ];
+
+        for (path, expected) in paths.iter().zip(expecteds.iter()) {
+            assert_eq!(analyze_route(path, &generate_board_keys()), *expected);
+        }
+    }
+
     #[test]
     fn test_dat() {
         let paths = [
@@ -257,6 +441,78 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_search() {
+        let paths = [
+            "/liveedge/search",
+            "/liveedge/search/",
+            "/liveedge/search.json",
+            "/unknown/search",
+        ];
+        let expecteds = [
+            Route::Search {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::Search {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::SearchJson {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::NotFound,
+        ];
+
+        for (path, expected) in paths.iter().zip(expecteds.iter()) {
+            assert_eq!(analyze_route(path, &generate_board_keys()), *expected);
+        }
+    }
+
+    #[test]
+    fn test_kako() {
+        let paths = [
+            "/liveedge/kako/",
+            "/liveedge/kako",
+            "/liveedge/kako/subject.txt",
+            "/liveedge/kako/1666666666/",
+            "/liveedge/kako/16666/",
+            "/liveedge/kako/1666/16666/",
+            "/unknown/kako/",
+        ];
+        let expecteds = [
+            Route::KakoIndex {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::KakoIndex {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::KakoSubjectTxt {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::KakoThreadWebUI {
+                board_key: "liveedge",
+                board_id: 1,
+                thread_id: "1666666666",
+            },
+            Route::NotFound,
+            Route::NotFound,
+            Route::NotFound,
+        ];
+
+        for (path, expected) in paths.iter().zip(expecteds.iter()) {
+            assert_eq!(
+                analyze_route(path, &generate_board_keys()),
+                *expected,
+                "{path}"
+            );
+        }
+    }
+
     #[test]
     fn test_thread_web_ui() {
         let paths = [
@@ -270,21 +526,25 @@ mod tests {
                 board_key: "liveedge",
                 thread_id: "1666666667",
                 board_id: 1,
+                range: ResRange::All,
             },
             Route::ThreadWebUI {
                 board_key: "liveedge",
                 thread_id: "1666666668",
                 board_id: 1,
+                range: ResRange::All,
             },
             Route::ThreadWebUI {
                 board_key: "liveedge",
                 thread_id: "1666666669",
                 board_id: 1,
+                range: ResRange::All,
             },
             Route::ThreadWebUI {
                 board_key: "liveedge",
                 thread_id: "1666666666",
                 board_id: 1,
+                range: ResRange::All,
             },
         ];
 
@@ -292,4 +552,49 @@ mod tests {
             assert_eq!(analyze_route(path, &generate_board_keys()), *expected);
         }
     }
+
+    #[test]
+    fn test_thread_web_ui_with_range() {
+        let paths = [
+            "/liveedge/1666666666/l50",
+            "/test/read.cgi/liveedge/1666666666/100-200/",
+            "/test/read.cgi/liveedge/1666666666/100-",
+            "/liveedge/1666666666/-100",
+            "/liveedge/1666666666/5",
+            "/liveedge/1666666666/200-100",
+            "/liveedge/1666666666/l0",
+            "/liveedge/1666666666/abc",
+            "/liveedge/",
+            "/unknown/1666666666/l50",
+        ];
+        let thread = |range| Route::ThreadWebUI {
+            board_key: "liveedge",
+            thread_id: "1666666666",
+            board_id: 1,
+            range,
+        };
+        let expecteds = [
+            thread(ResRange::Last(50)),
+            thread(ResRange::Between(100, 200)),
+            thread(ResRange::Since(100)),
+            thread(ResRange::Between(1, 100)),
+            thread(ResRange::Between(5, 5)),
+            Route::NotFound,
+            Route::NotFound,
+            Route::NotFound,
+            Route::BoardIndex {
+                board_key: "liveedge",
+                board_id: 1,
+            },
+            Route::NotFound,
+        ];
+
+        for (path, expected) in paths.iter().zip(expecteds.iter()) {
+            assert_eq!(
+                analyze_route(path, &generate_board_keys()),
+                *expected,
+                "{path}"
+            );
+        }
+    }
 }
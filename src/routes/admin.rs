@@ -0,0 +1,319 @@
+use worker::{Env, Method, Request, Response, Result};
+
+use crate::{
+    board::{Board, BoardSetting, CreatingBoard, UpdatingBoard},
+    board_policy::BoardPolicy,
+    board_registry::invalidate_board_registry,
+    edge_cache::purge_cached_urls,
+    integrity::{check_integrity, repair_integrity, DEFAULT_REPAIR_LIMIT},
+    migrations,
+    rebalance::{rebalance_shards, shard_report, DEFAULT_REBALANCE_LIMIT},
+    repositories::bbs_repository::BbsRepository,
+    routes::setting_txt::DERIVED_KEYS,
+    scheduled_tasks::DEFAULT_TASK_RUNS_LIMIT,
+    DbOrchestrator,
+};
+
+/// Routes under `/admin/`, which require `Authorization: Bearer <ADMIN_TOKEN>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminRoute {
+    /// GET: dry-run report of the responses shards
+    Shards,
+    /// POST: moves misplaced threads to their shards (`?limit=` threads per request)
+    ShardsRebalance,
+    /// GET: dry-run report of the inconsistencies between threads and responses
+    Integrity,
+    /// POST: applies the fixes of the report (`?limit=` fixes per request)
+    IntegrityRepair,
+    /// GET: the latest runs of the scheduled tasks, newest first (`?limit=` runs)
+    Tasks,
+    /// GET: applied and pending migrations of every database
+    Migrations,
+    /// POST: applies the pending migrations
+    MigrationsApply,
+    /// GET: every board including the retired ones, POST: creates a board
+    Boards,
+    /// PATCH: renames the board or changes its settings, DELETE: retires the board
+    Board(usize),
+    /// GET: the policy of the board, PUT: replaces it (omitted fields are the defaults)
+    BoardPolicy(usize),
+    /// GET: extra SETTING.TXT keys of the board, PUT: replaces them
+    BoardSettings(usize),
+}
+
+/// `path` is the part after `/admin/`
+pub(crate) fn analyze_admin_route(path: &str) -> Option<AdminRoute> {
+    match path.trim_end_matches('/') {
+        "shards" => Some(AdminRoute::Shards),
+        "shards/rebalance" => Some(AdminRoute::ShardsRebalance),
+        "integrity" => Some(AdminRoute::Integrity),
+        "integrity/repair" => Some(AdminRoute::IntegrityRepair),
+        "tasks" => Some(AdminRoute::Tasks),
+        "migrations" => Some(AdminRoute::Migrations),
+        "migrations/apply" => Some(AdminRoute::MigrationsApply),
+        "boards" => Some(AdminRoute::Boards),
+        path => {
+            let path = path.strip_prefix("boards/")?;
+            let (id, sub) = path.split_once('/').unwrap_or((path, ""));
+            let id = id.parse().ok()?;
+            match sub {
+                "" => Some(AdminRoute::Board(id)),
+                "policy" => Some(AdminRoute::BoardPolicy(id)),
+                "settings" => Some(AdminRoute::BoardSettings(id)),
+                _ => None,
+            }
+        }
+    }
+}
+
+pub(crate) async fn route_admin(
+    req: &mut Request,
+    env: &Env,
+    route: AdminRoute,
+    dbo: &DbOrchestrator,
+    repo: &BbsRepository<'_>,
+) -> Result<Response> {
+    let admin_token = env.var("ADMIN_TOKEN").ok().map(|x| x.to_string());
+    let authorization = req.headers().get("Authorization").ok().flatten();
+    if !is_authorized(authorization.as_deref(), admin_token.as_deref()) {
+        return Response::error("Unauthorized", 401);
+    }
+
+    let limit = |default| -> Result<usize> {
+        Ok(req
+            .url()?
+            .query_pairs()
+            .find(|(k, _)| k == "limit")
+            .and_then(|(_, v)| v.parse::<usize>().ok())
+            .unwrap_or(default))
+    };
+
+    match (route, req.method()) {
+        (AdminRoute::Shards, Method::Get) => match shard_report(repo).await {
+            Ok(report) => Response::from_json(&report),
+            Err(e) => Response::error(format!("internal server error - {e}"), 500),
+        },
+        (AdminRoute::ShardsRebalance, Method::Post) => {
+            match rebalance_shards(repo, limit(DEFAULT_REBALANCE_LIMIT)?).await {
+                Ok(report) => Response::from_json(&report),
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::Integrity, Method::Get) => match check_integrity(repo).await {
+            Ok(report) => Response::from_json(&report),
+            Err(e) => Response::error(format!("internal server error - {e}"), 500),
+        },
+        (AdminRoute::IntegrityRepair, Method::Post) => {
+            match repair_integrity(repo, limit(DEFAULT_REPAIR_LIMIT)?).await {
+                Ok(report) => Response::from_json(&report),
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::Tasks, Method::Get) => {
+            match repo.get_task_runs(limit(DEFAULT_TASK_RUNS_LIMIT)?).await {
+                Ok(runs) => Response::from_json(&runs),
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::Migrations, Method::Get) => match migrations::status(dbo).await {
+            Ok(status) => Response::from_json(&status),
+            Err(e) => Response::error(format!("internal server error - {e}"), 500),
+        },
+        (AdminRoute::MigrationsApply, Method::Post) => match migrations::apply_all(dbo).await {
+            Ok(applied) => Response::from_json(&applied),
+            Err(e) => Response::error(format!("internal server error - {e}"), 500),
+        },
+        (AdminRoute::Boards, Method::Get) => match repo.get_boards().await {
+            Ok(boards) => Response::from_json(&boards),
+            Err(e) => Response::error(format!("internal server error - {e}"), 500),
+        },
+        (AdminRoute::Boards, Method::Post) => {
+            let Ok(board) = req.json::<CreatingBoard>().await else {
+                return Response::error("Bad request - invalid board", 400);
+            };
+            if let Err(e) = board.validate() {
+                return Response::error(format!("Bad request - {e}"), 400);
+            }
+            let boards = match repo.get_boards().await {
+                Ok(boards) => boards,
+                Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+            };
+            if boards.iter().any(|b| {
+                Some(b.id) == board.id || b.board_key.as_deref() == Some(board.board_key.as_str())
+            }) {
+                return Response::error("Conflict - the id or board_key is already used", 409);
+            }
+            let result = repo.create_board(&board).await;
+            invalidate_board_registry();
+            match result {
+                Ok(board) => Response::from_json(&board),
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::Board(board_id), method @ (Method::Patch | Method::Delete)) => {
+            let board = if method == Method::Delete {
+                UpdatingBoard {
+                    retired: Some(true),
+                    ..Default::default()
+                }
+            } else {
+                let Ok(board) = req.json::<UpdatingBoard>().await else {
+                    return Response::error("Bad request - invalid board", 400);
+                };
+                board
+            };
+            if let Err(e) = board.validate() {
+                return Response::error(format!("Bad request - {e}"), 400);
+            }
+            let result = repo.update_board(board_id, &board).await;
+            invalidate_board_registry();
+            match result {
+                Ok(Some(board)) => {
+                    purge_setting_txt(req, &board).await;
+                    Response::from_json(&board)
+                }
+                Ok(None) => Response::error("Not found - board", 404),
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::BoardPolicy(board_id), Method::Get) => {
+            let policies = match repo.get_board_policies().await {
+                Ok(policies) => policies,
+                Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+            };
+            let policy = policies
+                .into_iter()
+                .find(|p| p.board_id == board_id)
+                .unwrap_or_else(|| BoardPolicy::default_for(board_id));
+            Response::from_json(&policy)
+        }
+        (AdminRoute::BoardPolicy(board_id), Method::Put) => {
+            let Ok(policy) = req.json::<BoardPolicy>().await else {
+                return Response::error("Bad request - invalid policy", 400);
+            };
+            let policy = BoardPolicy { board_id, ..policy };
+            if let Err(e) = policy.validate() {
+                return Response::error(format!("Bad request - {e}"), 400);
+            }
+            let board = match repo.get_board_info(board_id).await {
+                Ok(Some(board)) => board,
+                Ok(None) => return Response::error("Not found - board", 404),
+                Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+            };
+            let result = repo.upsert_board_policy(&policy).await;
+            invalidate_board_registry();
+            match result {
+                Ok(()) => {
+                    purge_setting_txt(req, &board).await;
+                    Response::from_json(&policy)
+                }
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::BoardSettings(board_id), Method::Get) => {
+            match repo.get_board_settings(board_id).await {
+                Ok(settings) => Response::from_json(&settings),
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        (AdminRoute::BoardSettings(board_id), Method::Put) => {
+            let Ok(settings) = req.json::<Vec<BoardSetting>>().await else {
+                return Response::error("Bad request - invalid settings", 400);
+            };
+            if let Some(e) = settings.iter().find_map(|x| x.validate(DERIVED_KEYS).err()) {
+                return Response::error(format!("Bad request - {e}"), 400);
+            }
+            let board = match repo.get_board_info(board_id).await {
+                Ok(Some(board)) => board,
+                Ok(None) => return Response::error("Not found - board", 404),
+                Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+            };
+            match repo.replace_board_settings(board_id, &settings).await {
+                Ok(()) => {
+                    purge_setting_txt(req, &board).await;
+                    Response::from_json(&settings)
+                }
+                Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            }
+        }
+        _ => Response::error("Method not allowed", 405),
+    }
+}
+
+/// Purges the cached SETTING.TXT of the board, which is built from its row, policy and
+/// settings
+async fn purge_setting_txt(req: &Request, board: &Board) {
+    let (Ok(url), Some(board_key)) = (req.url(), &board.board_key) else {
+        return;
+    };
+    let origin = url.origin().ascii_serialization();
+    purge_cached_urls(&[format!("{origin}/{board_key}/SETTING.TXT")]).await;
+}
+
+/// Admin routes are disabled unless `ADMIN_TOKEN` is set
+fn is_authorized(authorization: Option<&str>, admin_token: Option<&str>) -> bool {
+    let (Some(authorization), Some(admin_token)) = (authorization, admin_token) else {
+        return false;
+    };
+    let Some(token) = authorization.strip_prefix("Bearer ") else {
+        return false;
+    };
+    if admin_token.is_empty() || token.len() != admin_token.len() {
+        return false;
+    }
+
+    // Compare in constant time
+    token
+        .bytes()
+        .zip(admin_token.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_analyze_admin_route() {
+        assert_eq!(analyze_admin_route("shards"), Some(AdminRoute::Shards));
+        assert_eq!(analyze_admin_route("shards/"), Some(AdminRoute::Shards));
+        assert_eq!(
+            analyze_admin_route("shards/rebalance"),
+            Some(AdminRoute::ShardsRebalance)
+        );
+        assert_eq!(
+            analyze_admin_route("integrity/repair"),
+            Some(AdminRoute::IntegrityRepair)
+        );
+        assert_eq!(analyze_admin_route("tasks"), Some(AdminRoute::Tasks));
+        assert_eq!(
+            analyze_admin_route("migrations/apply"),
+            Some(AdminRoute::MigrationsApply)
+        );
+        assert_eq!(analyze_admin_route("boards/"), Some(AdminRoute::Boards));
+        assert_eq!(analyze_admin_route("boards/3"), Some(AdminRoute::Board(3)));
+        assert_eq!(analyze_admin_route("boards/news"), None);
+        assert_eq!(
+            analyze_admin_route("boards/3/policy"),
+            Some(AdminRoute::BoardPolicy(3))
+        );
+        assert_eq!(
+            analyze_admin_route("boards/3/settings"),
+            Some(AdminRoute::BoardSettings(3))
+        );
+        assert_eq!(analyze_admin_route("boards/3/unknown"), None);
+        assert_eq!(analyze_admin_route("unknown"), None);
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(Some("Bearer secret"), Some("secret")));
+        assert!(!is_authorized(Some("Bearer secreT"), Some("secret")));
+        assert!(!is_authorized(Some("Bearer secret2"), Some("secret")));
+        assert!(!is_authorized(Some("secret"), Some("secret")));
+        assert!(!is_authorized(None, Some("secret")));
+        assert!(!is_authorized(Some("Bearer "), Some("")));
+        assert!(!is_authorized(Some("Bearer secret"), None));
+    }
+}
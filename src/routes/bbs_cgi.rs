@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use base64::{engine::general_purpose, Engine};
 use jwt_simple::claims::Claims;
 use jwt_simple::prelude::{HS256Key, MACLike};
@@ -9,16 +7,19 @@ use regex_lite::Regex;
 use sha1::Sha1;
 use worker::*;
 
-use crate::get_board_info;
+use crate::board_policy::BoardPolicy;
+use crate::board_registry::BoardRegistry;
+use crate::edge_cache::{purge_cached_urls, stale_urls_after_post};
 use crate::inmemory_cache::{maybe_reject_cookie, maybe_reject_ip, n_recent_auth};
 use crate::repositories::bbs_repository::{
-    BbsRepository, CreatingAuthedToken, CreatingRes, CreatingThread,
+    BbsRepository, CreatingAuthedToken, CreatingRes, CreatingThread, WriteError,
 };
+use crate::routes::RouteResponse;
 use crate::thread::MetadentType;
 use crate::tinker::Tinker;
 use crate::utils::{
     self, generate_six_digit_num, get_current_date_time, get_current_date_time_string,
-    get_reduced_ip_addr, get_unix_timestamp_sec, response_shift_jis_text_html,
+    get_reduced_ip_addr, get_unix_timestamp_sec,
 };
 
 const WRITING_SUCCESS_HTML_RESPONSE: &str =
@@ -69,25 +70,26 @@ struct BbsCgiForm {
 }
 
 impl BbsCgiForm {
-    fn validate(&self) -> std::result::Result<(), &'static str> {
-        if matches!(&self.subject, Some(subject) if subject.chars().count() > 96) {
+    fn validate(&self, policy: &BoardPolicy) -> std::result::Result<(), &'static str> {
+        if matches!(&self.subject, Some(subject) if subject.chars().count() > policy.max_subject_len)
+        {
             return Err("スレッドタイトルが長すぎます");
         }
 
-        if self.name.chars().count() > 64 {
+        if self.name.chars().count() > policy.max_name_len {
             return Err("名前が長すぎます");
         }
 
-        if self.mail.chars().count() > 64 {
+        if self.mail.chars().count() > policy.max_mail_len {
             return Err("メールアドレスが長すぎます");
         }
 
         let body_chars = self.body.chars().collect::<Vec<_>>();
-        if body_chars.len() > 4096 {
+        if body_chars.len() > policy.max_body_len {
             return Err("本文が長すぎます");
         }
 
-        if body_chars.iter().filter(|&&x| x == '\n').count() > 32 {
+        if body_chars.iter().filter(|&&x| x == '\n').count() > policy.max_body_lines {
             return Err("本文に改行が多すぎます");
         }
 
@@ -170,19 +172,109 @@ fn extract_forms(bytes: Vec<u8>) -> Option<BbsCgiForm> {
 pub async fn route_bbs_cgi(
     req: &mut Request,
     env: &Env,
-    board_keys: &HashMap<String, usize>,
+    board_registry: &BoardRegistry,
     ua: Option<String>,
     repo: &BbsRepository<'_>,
     token_cookie: Option<&str>,
     tinker_token: Option<&str>,
 ) -> Result<Response> {
-    let router =
-        match BbsCgiRouter::new(req, env, repo, board_keys, token_cookie, tinker_token, ua).await {
-            Ok(router) => router,
-            Err(resp) => return resp,
+    let (ip_addr, local_debugging) =
+        if let Ok(Some(ip_addr)) = req.headers().get("CF-Connecting-IP") {
+            (ip_addr, false)
+        } else {
+            // Use DEBUG_IP if it is set
+            if let Ok(ip_addr) = env.var("DEBUG_IP") {
+                (ip_addr.to_string(), true)
+            } else {
+                return Response::error("internal server error - cf-connecting-ip", 500);
+            }
         };
 
-    router.route().await
+    let using_hard_min_recent_res_span_cap = env
+        .var("HARD_MIN_RECENT_RES_SPAN_CAP")
+        .ok()
+        .map(|x| x.to_string() == "true")
+        .unwrap_or(false);
+
+    let Ok(body) = req.bytes().await else {
+        return Response::error("Bad request - read bytes", 400);
+    };
+    let host_url = match utils::get_host_url(req) {
+        Ok(host_url) => host_url,
+        Err(resp) => return resp,
+    };
+    let Ok(origin) = req.url().map(|url| url.origin().ascii_serialization()) else {
+        return Response::error("internal server error - failed to parse url", 500);
+    };
+
+    let bbs_req = BbsCgiRequest {
+        body,
+        ip_addr,
+        local_debugging,
+        host_url,
+        origin,
+        asn: if local_debugging {
+            0
+        } else {
+            req.cf().map(|x| x.asn()).unwrap_or_else(|| 0)
+        },
+        ua,
+        token_cookie,
+        tinker_token,
+        tinker_secret: env.var("TINKER_SECRET").ok().map(|x| x.to_string()),
+        using_hard_min_recent_res_span_cap,
+    };
+    let reply = post_bbs_cgi(bbs_req, board_registry, repo).await?;
+    purge_cached_urls(&reply.stale_urls).await;
+    reply.response.into_response()
+}
+
+/// What `post_bbs_cgi` takes from the request and the environment
+pub(crate) struct BbsCgiRequest<'a> {
+    /// The form as posted, in Shift_JIS
+    pub body: Vec<u8>,
+    pub ip_addr: String,
+    /// `ip_addr` is `DEBUG_IP`
+    pub local_debugging: bool,
+    pub host_url: String,
+    /// `scheme://host` of the request, which prefixes the cached URLs
+    pub origin: String,
+    pub asn: u32,
+    pub ua: Option<String>,
+    pub token_cookie: Option<&'a str>,
+    pub tinker_token: Option<&'a str>,
+    pub tinker_secret: Option<String>,
+    pub using_hard_min_recent_res_span_cap: bool,
+}
+
+/// The response to a post, and the cached URLs which the post made stale
+#[derive(Debug)]
+pub(crate) struct BbsCgiReply {
+    pub response: RouteResponse,
+    pub stale_urls: Vec<String>,
+}
+
+/// Creates the thread or the response posted, or requests authentication
+pub(crate) async fn post_bbs_cgi(
+    bbs_req: BbsCgiRequest<'_>,
+    board_registry: &BoardRegistry,
+    repo: &BbsRepository<'_>,
+) -> Result<BbsCgiReply> {
+    let mut router = match BbsCgiRouter::new(bbs_req, repo, board_registry) {
+        Ok(router) => router,
+        Err(response) => {
+            return Ok(BbsCgiReply {
+                response,
+                stale_urls: Vec::new(),
+            })
+        }
+    };
+
+    let response = router.route().await?;
+    Ok(BbsCgiReply {
+        response,
+        stale_urls: router.stale_urls,
+    })
 }
 
 struct BbsCgiRouter<'a> {
@@ -197,102 +289,71 @@ struct BbsCgiRouter<'a> {
     id: Option<String>,
     ua: Option<String>,
     host_url: String,
+    /// `scheme://host` of the request, which prefixes the cached URLs
+    origin: String,
     asn: u32,
     default_name: String,
+    policy: BoardPolicy,
     local_debugging: bool,
     using_hard_min_recent_res_span_cap: bool,
+    /// Set when the post is written
+    stale_urls: Vec<String>,
 }
 
 impl<'a> BbsCgiRouter<'a> {
-    async fn new(
-        req: &'a mut Request,
-        env: &Env,
+    fn new(
+        bbs_req: BbsCgiRequest<'a>,
         repo: &'a BbsRepository<'a>,
-        board_keys: &'a HashMap<String, usize>,
-        token_cookie: Option<&'a str>,
-        tinker_token: Option<&'a str>,
-        ua: Option<String>,
-    ) -> std::result::Result<BbsCgiRouter<'a>, Result<Response>> {
-        let (ip_addr, local_debugging) =
-            if let Ok(Some(ip_addr)) = req.headers().get("CF-Connecting-IP") {
-                (ip_addr, false)
-            } else {
-                // Use DEBUG_IP if it is set
-                if let Ok(ip_addr) = env.var("DEBUG_IP") {
-                    (ip_addr.to_string(), true)
-                } else {
-                    return Err(Response::error(
-                        "internal server error - cf-connecting-ip",
-                        500,
-                    ));
-                }
-            };
-
-        let using_hard_min_recent_res_span_cap = env
-            .var("HARD_MIN_RECENT_RES_SPAN_CAP")
-            .ok()
-            .map(|x| x.to_string() == "true")
-            .unwrap_or(false);
-
-        let Ok(req_bytes) = req.bytes().await else {
-            return Err(Response::error("Bad request - read bytes", 400));
-        };
-        let form = match extract_forms(req_bytes) {
+        board_registry: &'a BoardRegistry,
+    ) -> std::result::Result<BbsCgiRouter<'a>, RouteResponse> {
+        let form = match extract_forms(bbs_req.body) {
             Some(form) => form,
-            None => return Err(Response::error("Bad request - extract forms", 400)),
+            None => return Err(RouteResponse::error("Bad request - extract forms", 400)),
         };
-        let host_url = utils::get_host_url(req)?;
-
-        if let Err(e) = form.validate() {
-            return Err(response_shift_jis_text_html(
-                WRITING_FAILED_HTML_RESPONSE.replace("{reason}", e),
-            ));
-        }
 
-        let Some(board_id) = board_keys.get(&form.board_key) else {
-            return Err(response_shift_jis_text_html(
-                WRITING_FAILED_HTML_RESPONSE
-                    .replace("{reason}", "書き込もうとしている板が存在しません"),
-            ));
-        };
-        let Some(board_conf) = get_board_info(env, *board_id, &form.board_key) else {
-            return Err(response_shift_jis_text_html(
-                WRITING_FAILED_HTML_RESPONSE
+        let Some(board_conf) = board_registry
+            .board_keys()
+            .get(&form.board_key)
+            .and_then(|board_id| board_registry.get(*board_id))
+        else {
+            return Err(RouteResponse::shift_jis_html(
+                &WRITING_FAILED_HTML_RESPONSE
                     .replace("{reason}", "書き込もうとしている板が存在しません"),
             ));
         };
 
-        let tinker_secret = env.var("TINKER_SECRET").ok().map(|x| x.to_string());
+        if let Err(e) = form.validate(&board_conf.policy) {
+            return Err(RouteResponse::shift_jis_html(
+                &WRITING_FAILED_HTML_RESPONSE.replace("{reason}", e),
+            ));
+        }
 
         Ok(Self {
             repo,
-            board_id: *board_id,
-            token_cookie,
-            tinker_token,
-            tinker_secret,
-            ip_addr,
+            board_id: board_conf.board_id,
+            token_cookie: bbs_req.token_cookie,
+            tinker_token: bbs_req.tinker_token,
+            tinker_secret: bbs_req.tinker_secret,
+            ip_addr: bbs_req.ip_addr,
             default_name: board_conf.default_name.clone(),
+            policy: board_conf.policy.clone(),
             form,
             unix_time: get_unix_timestamp_sec(),
             id: None,
-            ua,
-            host_url,
-            local_debugging,
-            asn: if local_debugging {
-                0
-            } else {
-                req.cf().map(|x| x.asn()).unwrap_or_else(|| 0)
-            },
-            using_hard_min_recent_res_span_cap,
+            ua: bbs_req.ua,
+            host_url: bbs_req.host_url,
+            origin: bbs_req.origin,
+            local_debugging: bbs_req.local_debugging,
+            asn: bbs_req.asn,
+            using_hard_min_recent_res_span_cap: bbs_req.using_hard_min_recent_res_span_cap,
+            stale_urls: Vec::new(),
         })
     }
 
-    async fn route(mut self) -> Result<Response> {
+    async fn route(&mut self) -> Result<RouteResponse> {
         // Reject too fast reponses by IP here
-        if maybe_reject_ip(&self.ip_addr)? {
-            return response_shift_jis_text_html(
-                WRITING_FAILED_HTML_RESPONSE.replace("{reason}", "5秒以内の連続投稿はできません"),
-            );
+        if maybe_reject_ip(&self.ip_addr, self.policy.min_res_span_secs)? {
+            return Ok(self.too_fast_response());
         }
 
         let moderator_cap = if let Some(cap) = &self.form.cap {
@@ -341,7 +402,10 @@ impl<'a> BbsCgiRouter<'a> {
 
         let authenticated_user_cookie = if let Some(tk) = token_cookie_candidate {
             let Ok(authed_token) = self.repo.get_authed_token(tk).await else {
-                return Response::error("internal server error - check auth", 500);
+                return Ok(RouteResponse::error(
+                    "internal server error - check auth",
+                    500,
+                ));
             };
             if let Some(authed_token) = authed_token {
                 if authed_token.authed == 1 {
@@ -358,19 +422,21 @@ impl<'a> BbsCgiRouter<'a> {
 
         let Some(authenticated_user_cookie) = authenticated_user_cookie else {
             if self.host_url.contains("workers.dev") {
-                return response_shift_jis_text_html(WRITING_FAILED_HTML_RESPONSE.replace(
+                return Ok(RouteResponse::shift_jis_html(&WRITING_FAILED_HTML_RESPONSE.replace(
                     "{reason}",
                     "旧ドメインからの新規認証は終了しました。<br>新ドメインの板 https://bbs.eddibb.cc/liveedge/ を新規に外部板登録してから書き込んでください。",
-                ));
+                )));
             }
 
             // If the user is trying to get authed cookie too many times, it might be a script.
             // Even if not, it may be better to reject such access to reduce write access to db.
             let n_r_auth = n_recent_auth(&self.ip_addr)?;
             if n_r_auth >= N_MAX_RECENT_AUTH_PER_IP {
-                return response_shift_jis_text_html(WRITING_FAILED_HTML_RESPONSE.replace(
-                    "{reason}",
-                    "発行ずみの認証トークンを使うか、時間を置いて再度アクセスして下さい",
+                return Ok(RouteResponse::shift_jis_html(
+                    &WRITING_FAILED_HTML_RESPONSE.replace(
+                        "{reason}",
+                        "発行ずみの認証トークンを使うか、時間を置いて再度アクセスして下さい",
+                    ),
                 ));
             }
             let mut hasher: Md5 = Md5::new();
@@ -388,10 +454,17 @@ impl<'a> BbsCgiRouter<'a> {
                 auth_code: &auth_code,
             };
             if let Err(e) = self.repo.create_authed_token(authed_token).await {
-                return Response::error(format!("internal server error - {e}"), 500);
+                return Ok(RouteResponse::error(
+                    format!("internal server error - {e}"),
+                    500,
+                ));
             }
 
-            let is_mate = self.ua.map(|x| x.contains("Mate")).unwrap_or(false);
+            let is_mate = self
+                .ua
+                .as_ref()
+                .map(|x| x.contains("Mate"))
+                .unwrap_or(false);
 
             let auth_body = if self.local_debugging {
                 REQUEST_AUTHENTICATION_LOCAL.replace("{token}", &token)
@@ -405,17 +478,10 @@ impl<'a> BbsCgiRouter<'a> {
                     .replace("{host_url}", &self.host_url)
             };
 
-            let resp = response_shift_jis_text_html(auth_body.clone()).map(|mut x| {
-                x.headers_mut()
-                    .append(
-                        "Set-Cookie",
-                        &format!("edge-token={token}; Max-Age=31536000; Path=/"),
-                    )
-                    .unwrap();
-                x
-            });
-
-            return resp;
+            return Ok(RouteResponse::shift_jis_html(&auth_body).with_header(
+                "Set-Cookie",
+                format!("edge-token={token}; Max-Age=31536000; Path=/"),
+            ));
         };
 
         let hs256_key = if let Some(tinker_secret) = &self.tinker_secret {
@@ -450,10 +516,11 @@ impl<'a> BbsCgiRouter<'a> {
         };
 
         // Reject too fast reponses by cookie here
-        if maybe_reject_cookie(&authenticated_user_cookie.cookie)? {
-            return response_shift_jis_text_html(
-                WRITING_FAILED_HTML_RESPONSE.replace("{reason}", "5秒以内の連続投稿はできません"),
-            );
+        if maybe_reject_cookie(
+            &authenticated_user_cookie.cookie,
+            self.policy.min_res_span_secs,
+        )? {
+            return Ok(self.too_fast_response());
         }
 
         if self.using_hard_min_recent_res_span_cap {
@@ -462,21 +529,20 @@ impl<'a> BbsCgiRouter<'a> {
                 .await
             {
                 Ok(min_recent_res_span) => min_recent_res_span,
-                Err(e) => return Response::error(e, 500),
+                Err(e) => return Ok(RouteResponse::error(e, 500)),
             };
-            if min_recent_res_span < 5 {
-                return response_shift_jis_text_html(
-                    WRITING_FAILED_HTML_RESPONSE
-                        .replace("{reason}", "5秒以内の連続投稿はできません"),
-                );
+            if min_recent_res_span < self.policy.min_res_span_secs {
+                return Ok(self.too_fast_response());
             }
         }
 
         if let Some(s) = &authenticated_user_cookie.last_thread_creation {
-            if self.form.is_thread && self.unix_time - s.parse::<u64>().unwrap() < 120 {
-                return response_shift_jis_text_html(
-                    WRITING_FAILED_HTML_RESPONSE.replace("{reason}", "ちょっとスレ立てすぎ！"),
-                );
+            if self.form.is_thread
+                && self.unix_time - s.parse::<u64>().unwrap() < self.policy.thread_cooldown_secs
+            {
+                return Ok(RouteResponse::shift_jis_html(
+                    &WRITING_FAILED_HTML_RESPONSE.replace("{reason}", "ちょっとスレ立てすぎ！"),
+                ));
             }
         }
 
@@ -501,11 +567,8 @@ impl<'a> BbsCgiRouter<'a> {
         if let Some(tinker) = tinker.as_mut() {
             tinker.wrote_count += 1;
 
-            if self.unix_time - tinker.last_wrote_at <= 5 {
-                return response_shift_jis_text_html(
-                    WRITING_FAILED_HTML_RESPONSE
-                        .replace("{reason}", "5秒以内の連続投稿はできません"),
-                );
+            if self.unix_time - tinker.last_wrote_at < self.policy.min_res_span_secs {
+                return Ok(self.too_fast_response());
             }
 
             tinker.last_wrote_at = self.unix_time;
@@ -536,7 +599,7 @@ impl<'a> BbsCgiRouter<'a> {
             self.form.name.push_str(&moderator_cap.cap_name);
         }
 
-        let result = if self.form.is_thread {
+        let mut result = if self.form.is_thread {
             self.create_thread(&authenticated_user_cookie.cookie, &tinker)
                 .await
         } else {
@@ -546,29 +609,18 @@ impl<'a> BbsCgiRouter<'a> {
 
         if is_cap {
             let tk = authenticated_user_cookie.cookie;
-            result.map(|mut x| {
-                x.headers_mut()
-                    .append(
-                        "Set-Cookie",
-                        &format!("edge-token={tk}; Max-Age=31536000; Path=/"),
-                    )
-                    .unwrap();
-                x
-            })
-        } else {
-            result
+            result = result.with_header(
+                "Set-Cookie",
+                format!("edge-token={tk}; Max-Age=31536000; Path=/"),
+            );
         }
-        .map(|mut x| {
-            if let Some(tinker) = tinker_tk {
-                x.headers_mut()
-                    .append(
-                        "Set-Cookie",
-                        &format!("tinker-token={tinker}; Max-Age=31536000; Path=/"),
-                    )
-                    .unwrap();
-            }
-            x
-        })
+        if let Some(tinker) = tinker_tk {
+            result = result.with_header(
+                "Set-Cookie",
+                format!("tinker-token={tinker}; Max-Age=31536000; Path=/"),
+            );
+        }
+        Ok(result)
     }
 
     /// Returns the number of recent responses per second for this token.
@@ -605,7 +657,7 @@ impl<'a> BbsCgiRouter<'a> {
         Ok(ts_min)
     }
 
-    async fn create_thread(self, cookie: &str, tinker: &Option<Tinker>) -> Result<Response> {
+    async fn create_thread(&mut self, cookie: &str, tinker: &Option<Tinker>) -> RouteResponse {
         let BbsCgiForm {
             subject,
             name,
@@ -656,26 +708,25 @@ impl<'a> BbsCgiRouter<'a> {
 
         match self.repo.create_thread(thread).await {
             Ok(_) => {
+                self.stale_urls = stale_urls_after_post(&self.origin, &self.form.board_key, None);
                 let _ = self
                     .repo
                     .update_authed_token_last_thread_creation(cookie, &unix_time)
                     .await;
-                response_shift_jis_text_html(WRITING_SUCCESS_HTML_RESPONSE.to_string())
+                RouteResponse::shift_jis_html(WRITING_SUCCESS_HTML_RESPONSE)
             }
+            Err(WriteError::ThreadAlreadyExists) => RouteResponse::shift_jis_html(
+                &WRITING_FAILED_HTML_RESPONSE
+                    .replace("{reason}", "同じ時間に既にスレッドが立っています"),
+            ),
             Err(e) => {
-                if e.to_string().contains("thread already exists") {
-                    response_shift_jis_text_html(
-                        WRITING_FAILED_HTML_RESPONSE
-                            .replace("{reason}", "同じ時間に既にスレッドが立っています"),
-                    )
-                } else {
-                    Response::error(format!("internal server error - {e}"), 500)
-                }
+                log!("failed to create thread: {e}");
+                RouteResponse::error("internal server error - create thread", 500)
             }
         }
     }
 
-    async fn create_response(self, cookie: &str, tinker: &Option<Tinker>) -> Result<Response> {
+    async fn create_response(&mut self, cookie: &str, tinker: &Option<Tinker>) -> RouteResponse {
         let BbsCgiForm {
             name,
             mail,
@@ -689,20 +740,20 @@ impl<'a> BbsCgiRouter<'a> {
             .get_thread(self.board_id, thread_id.as_ref().unwrap())
             .await
         else {
-            return Response::error("internal server error - get thread", 500);
+            return RouteResponse::error("internal server error - get thread", 500);
         };
 
         let thread_info = if let Some(thread_info) = thread_info {
             if thread_info.active == 0 {
-                return response_shift_jis_text_html(WRITING_FAILED_HTML_RESPONSE.replace(
+                return RouteResponse::shift_jis_html(&WRITING_FAILED_HTML_RESPONSE.replace(
                     "{reason}",
                     "スレッドストッパーが働いたみたいなので書き込めません",
                 ));
             }
             thread_info
         } else {
-            return response_shift_jis_text_html(
-                WRITING_FAILED_HTML_RESPONSE.replace("{reason}", "そのようなスレは存在しません"),
+            return RouteResponse::shift_jis_html(
+                &WRITING_FAILED_HTML_RESPONSE.replace("{reason}", "そのようなスレは存在しません"),
             );
         };
 
@@ -722,14 +773,52 @@ impl<'a> BbsCgiRouter<'a> {
 
         match self
             .repo
-            .create_response(res, thread_info.modulo as usize)
+            .create_response(res, thread_info.modulo as usize, self.policy.thread_stopper)
             .await
         {
-            Ok(_) => response_shift_jis_text_html(WRITING_SUCCESS_HTML_RESPONSE.to_string()),
-            Err(e) => Response::error(format!("internal server error - {e}"), 500),
+            Ok(_) => {
+                self.stale_urls = stale_urls_after_post(
+                    &self.origin,
+                    &self.form.board_key,
+                    Some(thread_id.as_ref().unwrap()),
+                );
+                RouteResponse::shift_jis_html(WRITING_SUCCESS_HTML_RESPONSE)
+            }
+            Err(WriteError::ThreadNotFound) => {
+                RouteResponse::shift_jis_html(&WRITING_FAILED_HTML_RESPONSE.replace(
+                    "{reason}",
+                    "そのようなスレは存在しません",
+                ))
+            }
+            Err(WriteError::ThreadStopped) => {
+                RouteResponse::shift_jis_html(&WRITING_FAILED_HTML_RESPONSE.replace(
+                    "{reason}",
+                    "スレッドストッパーが働いたみたいなので書き込めません",
+                ))
+            }
+            Err(WriteError::ThreadMoving) => {
+                RouteResponse::shift_jis_html(&WRITING_FAILED_HTML_RESPONSE.replace(
+                    "{reason}",
+                    "スレッドの移動中のため書き込めませんでした。しばらくしてから再度書き込んでください",
+                ))
+            }
+            Err(e) => {
+                log!("failed to create response: {e}");
+                RouteResponse::error("internal server error - create response", 500)
+            }
         }
     }
 
+    fn too_fast_response(&self) -> RouteResponse {
+        RouteResponse::shift_jis_html(&WRITING_FAILED_HTML_RESPONSE.replace(
+            "{reason}",
+            &format!(
+                "{}秒以内の連続投稿はできません",
+                self.policy.min_res_span_secs
+            ),
+        ))
+    }
+
     fn generate_name_with_metadent(
         &self,
         name: &str,
@@ -1016,6 +1105,241 @@ fn generate_date_seed() -> u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        board::Board,
+        repositories::{
+            bbs_repository::ThreadStatus, bbs_storage::BbsStorage, sqlite_storage::SqliteStorage,
+        },
+    };
+
+    fn board_registry() -> BoardRegistry {
+        // Posts in a row are not rejected
+        board_registry_with(BoardPolicy {
+            min_res_span_secs: 0,
+            thread_cooldown_secs: 0,
+            ..BoardPolicy::default_for(1)
+        })
+    }
+
+    fn board_registry_with(policy: BoardPolicy) -> BoardRegistry {
+        let board = Board {
+            id: 1,
+            board_key: Some("liveedge".to_string()),
+            name: "エッヂ".to_string(),
+            local_rule: None,
+            default_name: None,
+            retired: 0,
+        };
+        BoardRegistry::new(vec![board], vec![policy])
+    }
+
+    /// The form as a dat reader posts it, percent-encoded Shift_JIS
+    fn bbs_cgi_request<'a>(
+        fields: &[(&str, &str)],
+        ip_addr: &str,
+        token_cookie: Option<&'a str>,
+    ) -> BbsCgiRequest<'a> {
+        let body = fields
+            .iter()
+            .map(|(key, value)| {
+                let value = encoding_rs::SHIFT_JIS.encode(value).0;
+                let value = value
+                    .iter()
+                    .map(|b| format!("%{b:02X}"))
+                    .collect::<String>();
+                format!("{key}={value}")
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        BbsCgiRequest {
+            body: body.into_bytes(),
+            ip_addr: ip_addr.to_string(),
+            local_debugging: false,
+            host_url: "bbs.eddibb.cc".to_string(),
+            origin: "https://bbs.eddibb.cc".to_string(),
+            asn: 0,
+            ua: Some("Monazilla/1.00 JaneStyle/4.23".to_string()),
+            token_cookie,
+            tinker_token: None,
+            tinker_secret: None,
+            using_hard_min_recent_res_span_cap: false,
+        }
+    }
+
+    fn decode_body(response: &RouteResponse) -> String {
+        let body = response.body.as_bytes();
+        encoding_rs::SHIFT_JIS.decode(body).0.into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_post_bbs_cgi_requests_authentication() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let form = [
+            ("submit", "書き込む"),
+            ("FROM", ""),
+            ("mail", ""),
+            ("MESSAGE", "初カキコ"),
+            ("bbs", "liveedge"),
+            ("key", "1696233330"),
+        ];
+
+        let reply = post_bbs_cgi(
+            bbs_cgi_request(&form, "192.0.2.1", None),
+            &board_registry(),
+            &repo,
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.status, 200);
+        assert!(reply.stale_urls.is_empty());
+        let token = reply
+            .response
+            .header("Set-Cookie")
+            .and_then(|x| x.strip_prefix("edge-token="))
+            .and_then(|x| x.split(';').next())
+            .unwrap();
+        let authed_token = repo.get_authed_token(token).await.unwrap().unwrap();
+        assert_eq!(authed_token.authed, 0);
+        assert!(decode_body(&reply.response).contains(&authed_token.auth_code));
+    }
+
+    #[tokio::test]
+    async fn test_post_bbs_cgi_creates_thread_and_response() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_authed_token(CreatingAuthedToken {
+            token: "routetoken",
+            origin_ip: "192.0.2.2",
+            writed_time: "0",
+            auth_code: "123456",
+        })
+        .await
+        .unwrap();
+        repo.update_authed_status("routetoken", "0").await.unwrap();
+        let board_registry = board_registry();
+        let post = |form: &[(&str, &str)]| {
+            post_bbs_cgi(
+                bbs_cgi_request(form, "192.0.2.2", Some("routetoken")),
+                &board_registry,
+                &repo,
+            )
+        };
+
+        let reply = post(&[
+            ("submit", "新規スレッド作成"),
+            ("subject", "ルートのテスト"),
+            ("FROM", ""),
+            ("mail", ""),
+            ("MESSAGE", "スレ立て"),
+            ("bbs", "liveedge"),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(decode_body(&reply.response), WRITING_SUCCESS_HTML_RESPONSE);
+        assert_eq!(
+            reply.stale_urls[0],
+            "https://bbs.eddibb.cc/liveedge/subject.txt"
+        );
+        let thread = storage
+            .get_threads(1, ThreadStatus::Active)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|x| x.title == "ルートのテスト")
+            .unwrap();
+
+        let reply = post(&[
+            ("submit", "書き込む"),
+            ("FROM", "コテ"),
+            ("mail", "sage"),
+            ("MESSAGE", "レス<br>"),
+            ("bbs", "liveedge"),
+            ("key", &thread.thread_number),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(decode_body(&reply.response), WRITING_SUCCESS_HTML_RESPONSE);
+        let dat_url = format!(
+            "https://bbs.eddibb.cc/liveedge/dat/{}.dat",
+            thread.thread_number
+        );
+        assert!(reply.stale_urls.contains(&dat_url));
+
+        let responses = storage
+            .get_responses(1, &thread.thread_number, thread.modulo as usize)
+            .await
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1].name.as_deref(), Some("コテ"));
+        assert_eq!(responses[1].mail.as_deref(), Some("sage"));
+        assert_eq!(responses[1].body, "レス&lt;br&gt;");
+        assert_eq!(responses[1].authed_token.as_deref(), Some("routetoken"));
+
+        let reply = post(&[
+            ("submit", "書き込む"),
+            ("FROM", ""),
+            ("mail", ""),
+            ("MESSAGE", "レス"),
+            ("bbs", "liveedge"),
+            ("key", "1000000000"),
+        ])
+        .await
+        .unwrap();
+        assert!(decode_body(&reply.response).contains("そのようなスレは存在しません"));
+        assert!(reply.stale_urls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_post_bbs_cgi_rejects_tinker_by_board_span() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        repo.create_authed_token(CreatingAuthedToken {
+            token: "tinkertoken",
+            origin_ip: "192.0.2.3",
+            writed_time: "0",
+            auth_code: "123456",
+        })
+        .await
+        .unwrap();
+        repo.update_authed_status("tinkertoken", "0").await.unwrap();
+        let board_registry = board_registry_with(BoardPolicy {
+            min_res_span_secs: 30,
+            ..BoardPolicy::default_for(1)
+        });
+        let key = HS256Key::from_bytes(b"tinker secret");
+        let tinker_token = key
+            .authenticate(Claims::with_custom_claims(
+                Tinker {
+                    last_wrote_at: get_unix_timestamp_sec() - 10,
+                    ..Tinker::new("tinkertoken".to_string())
+                },
+                jwt_simple::prelude::Duration::new(60 * 60, 0),
+            ))
+            .unwrap();
+        let form = [
+            ("submit", "書き込む"),
+            ("FROM", ""),
+            ("mail", ""),
+            ("MESSAGE", "連投"),
+            ("bbs", "liveedge"),
+            ("key", "1000000000"),
+        ];
+
+        let reply = post_bbs_cgi(
+            BbsCgiRequest {
+                tinker_token: Some(&tinker_token),
+                tinker_secret: Some(general_purpose::STANDARD.encode(b"tinker secret")),
+                ..bbs_cgi_request(&form, "192.0.2.3", Some("tinkertoken"))
+            },
+            &board_registry,
+            &repo,
+        )
+        .await
+        .unwrap();
+        assert!(decode_body(&reply.response).contains("30秒以内の連続投稿はできません"));
+        assert!(reply.stale_urls.is_empty());
+    }
 
     #[test]
     fn test_calculate_trip_over_12() {
@@ -1075,8 +1399,25 @@ mod tests {
         ];
 
         for (case, expected) in test_cases.into_iter() {
-            assert_eq!(expected, case.validate());
+            assert_eq!(expected, case.validate(&BoardPolicy::default()));
         }
+
+        let slow_board = BoardPolicy {
+            max_body_len: 10,
+            ..BoardPolicy::default()
+        };
+        let form = BbsCgiForm {
+            subject: None,
+            name: "".to_string(),
+            mail: "".to_string(),
+            body: "a".repeat(12),
+            board_key: "abc".to_string(),
+            is_thread: false,
+            thread_id: Some("1700000000".to_string()),
+            cap: None,
+        };
+        assert_eq!(form.validate(&BoardPolicy::default()), Ok(()));
+        assert_eq!(form.validate(&slow_board), Err("本文が長すぎます"));
     }
 
     #[test]
@@ -1,17 +1,30 @@
-use std::str::FromStr;
+use std::sync::Arc;
 
 use worker::*;
 
 use crate::{
-    board_config::BoardConfig, repositories::bbs_repository::BbsRepository,
-    response::Ch5ResponsesFormatter,
+    board_config::BoardConfig,
+    conditional::{Conditions, Validators},
+    repositories::bbs_repository::BbsRepository,
+    response::write_sjis_dat,
+    routes::{RouteBody, RouteResponse},
 };
 
+const DEFAULT_RANGE_IGNORED_UAS: &str = "Xeno";
+
 pub struct DatRoutingThreadInfo<'a> {
     pub board_conf: &'a BoardConfig<'a>,
     pub thread_id: &'a str,
 }
 
+/// The headers of a dat request which `get_dat` looks at
+pub(crate) struct DatRequest<'a> {
+    pub range: Option<String>,
+    pub conditions: Conditions,
+    pub ua: Option<&'a str>,
+    pub host: &'a str,
+}
+
 pub async fn route_dat(
     req: &Request,
     thread_info: DatRoutingThreadInfo<'_>,
@@ -19,131 +32,400 @@ pub async fn route_dat(
     repo: &BbsRepository<'_>,
     bucket: &Option<Bucket>,
     host: String,
+    range_policy: &DatRangePolicy,
 ) -> Result<Response> {
-    let range = req.headers().get("Range").ok().flatten();
-    let if_modified_since = req.headers().get("If-Modified-Since").ok().flatten();
+    let dat_req = DatRequest {
+        range: req.headers().get("Range").ok().flatten(),
+        conditions: Conditions::from_headers(req.headers()),
+        ua: ua.as_deref(),
+        host: &host,
+    };
+    let resp = get_dat(&dat_req, thread_info, repo, bucket.is_some(), range_policy).await;
+
+    let RouteBody::Shared(dat, _) = &resp.body else {
+        return resp.into_response();
+    };
+    if resp.status == 200 {
+        let mut full_resp = resp.into_response()?;
+        if let Ok(result) = full_resp.cloned() {
+            let _ = Cache::default().put(req, result).await;
+        }
+        return Ok(full_resp);
+    }
+
+    // The whole dat is cached whatever the range is, so that the next request is served
+    // from the cache
+    let full_resp = RouteResponse {
+        status: 200,
+        headers: resp
+            .headers
+            .iter()
+            .filter(|(name, _)| *name != "Content-Range")
+            .cloned()
+            .collect(),
+        body: RouteBody::Shared(dat.clone(), 0..dat.len()),
+    };
+    if let Ok(full_resp) = full_resp.into_response() {
+        let _ = Cache::default().put(req, full_resp).await;
+    }
+    resp.into_response()
+}
+
+/// The dat of the thread, or the range of it requested. A thread which is not found is
+/// redirected to the archive if `has_archive`.
+///
+/// The body is shared with the responses cache, and `route_dat` caches the whole dat
+/// whatever the range is.
+pub(crate) async fn get_dat(
+    dat_req: &DatRequest<'_>,
+    thread_info: DatRoutingThreadInfo<'_>,
+    repo: &BbsRepository<'_>,
+    has_archive: bool,
+    range_policy: &DatRangePolicy,
+) -> RouteResponse {
+    let conditions = &dat_req.conditions;
 
     let Ok(thread) = repo
         .get_thread(thread_info.board_conf.board_id, thread_info.thread_id)
         .await
     else {
-        return Response::error("internal server error - get thread", 500);
+        return RouteResponse::error("internal server error - get thread", 500);
     };
 
     let Some(thread) = thread else {
-        return if bucket.is_some() {
-            let generate_url = |thread_id: &str| {
-                Url::from_str(&format!(
-                    "http://bbs.eddibb.cc/{}/kako/{}/{}/{}.dat",
-                    thread_info.board_conf.board_key,
-                    &thread_id[0..4],
-                    &thread_id[0..5],
-                    thread_id
-                ))
-                .unwrap()
-            };
-            Response::redirect(generate_url(thread_info.thread_id))
+        return if has_archive {
+            let thread_id = thread_info.thread_id;
+            let url = format!(
+                "http://bbs.eddibb.cc/{}/kako/{}/{}/{}.dat",
+                thread_info.board_conf.board_key,
+                &thread_id[0..4],
+                &thread_id[0..5],
+                thread_id
+            );
+            RouteResponse::new(302, RouteBody::Bytes(Vec::new())).with_header("Location", url)
         } else {
-            Response::error("Not found - dat", 404)
+            RouteResponse::error("Not found - dat", 404)
         };
     };
-    if let Some(if_modified_since) = if_modified_since {
-        if let Ok(parsed_date_time) =
-            chrono::NaiveDateTime::parse_from_str(&if_modified_since, "%Y/%m/%d %H:%M:%S")
-        {
-            let remote_last_modified = parsed_date_time.and_utc().timestamp() - 32400; // fix local time
-
-            if remote_last_modified >= thread.last_modified.parse::<i64>().unwrap() {
-                return Response::empty().map(|mut r| {
-                    let _ = r.headers_mut().append("Cache-Control", "s-maxage=1");
-                    r.with_status(304)
-                });
-            }
-        }
-    }
-
-    let mut responses = match repo
-        .get_responses(
-            thread_info.board_conf.board_id,
-            thread_info.thread_id,
-            thread.modulo as usize,
-        )
-        .await
-    {
-        Ok(o) => o,
-        Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+    let validators = Validators::for_thread(&thread);
+    let cache_control = if thread.active == 0 {
+        "s-maxage=3600"
+    } else {
+        "s-maxage=1"
     };
+    if conditions.is_not_modified(&validators) {
+        // Same as `Validators::not_modified`
+        let mut resp = RouteResponse::new(304, RouteBody::Bytes(Vec::new()));
+        resp.headers = validators.headers();
+        return resp.with_header("Cache-Control", cache_control);
+    }
 
-    if host.contains("workers.dev") {
+    let board_id = thread_info.board_conf.board_id;
+    let default_name = &thread_info.board_conf.default_name;
+    let sjis_body = if dat_req.host.contains("workers.dev") {
+        let mut responses = match repo
+            .get_responses(board_id, thread_info.thread_id, thread.modulo as usize)
+            .await
+        {
+            Ok(o) => o,
+            Err(e) => return RouteResponse::error(format!("internal server error - {e}"), 500),
+        };
         if let Some(first_res) = responses.get_mut(0) {
             first_res.body
                 .push_str(
                     "<br><br> 【以下運営からのメッセージ】<br>あなたは将来的に廃止される旧ドメインを使用しています。 <br>新ドメイン https://bbs.eddibb.cc/liveedge/ に移行してください<br>旧ドメインからの新規認証は終了しました。"
                 )
         }
-    }
+        let mut body = Vec::new();
+        write_sjis_dat(&mut body, &responses, &thread.title, default_name);
+        Arc::new(body)
+    } else {
+        match repo
+            .get_sjis_dat(
+                board_id,
+                thread_info.thread_id,
+                thread.modulo as usize,
+                &thread.title,
+                default_name,
+            )
+            .await
+        {
+            Ok(o) => o,
+            Err(e) => return RouteResponse::error(format!("internal server error - {e}"), 500),
+        }
+    };
 
-    let body = responses.format_responses(&thread.title, &thread_info.board_conf.default_name);
-    let sjis_body = encoding_rs::SHIFT_JIS.encode(&body).0.into_owned();
+    let len = sjis_body.len();
+    let range = dat_req
+        .range
+        .as_deref()
+        .filter(|_| range_policy.honors_range(dat_req.ua))
+        .filter(|_| conditions.is_range_applicable(&validators))
+        .and_then(|range| parse_range(range, len));
 
-    let ranged_sjis_body = match (range, ua) {
-        (Some(range), Some(ua)) if !ua.contains("Xeno") => {
-            if let Some(range) = range.split('=').nth(1) {
-                let range = range.split('-').collect::<Vec<_>>();
-                let Some(start) = range.first().and_then(|x| x.parse::<usize>().ok()) else {
-                    return Response::error("Bad request", 400);
-                };
+    let (status, body, content_range) = match range {
+        Some(ByteRange::Satisfiable { start, end }) => (
+            206,
+            start..end + 1,
+            Some(format!("bytes {start}-{end}/{len}")),
+        ),
+        Some(ByteRange::Unsatisfiable) => (416, 0..0, Some(format!("bytes */{len}"))),
+        None => (200, 0..len, None),
+    };
+    let mut resp = RouteResponse::new(status, RouteBody::Shared(sjis_body, body))
+        .with_header("Content-Type", "text/plain")
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("Cache-Control", cache_control);
+    resp.headers.extend(validators.headers());
+    if let Some(content_range) = content_range {
+        resp = resp.with_header("Content-Range", content_range);
+    }
+    resp
+}
 
-                Some(
-                    sjis_body
-                        .clone()
-                        .into_iter()
-                        .skip(start)
-                        .collect::<Vec<_>>(),
-                )
-            } else {
-                None
-            }
+/// A `Range` of a body of known length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    /// Both ends are inclusive and within the body
+    Satisfiable {
+        start: usize,
+        end: usize,
+    },
+    Unsatisfiable,
+}
+
+/// Parses a single byte range: `bytes=start-end`, `bytes=start-` or `bytes=-suffix`.
+///
+/// `None` means the header is ignored and the whole body is served, which RFC 7233 allows
+/// for a malformed header or multiple ranges.
+pub(crate) fn parse_range(header: &str, len: usize) -> Option<ByteRange> {
+    let (unit, spec) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let parse = |x: &str| {
+        let x = x.trim();
+        if x.is_empty() || !x.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
         }
-        _ => None,
+        x.parse::<usize>().ok()
     };
-    let Ok(mut resp) = Response::from_bytes(sjis_body) else {
-        return Response::error("internal server error - converting sjis", 500);
+
+    if first.trim().is_empty() {
+        let suffix = parse(last)?;
+        if suffix == 0 || len == 0 {
+            return Some(ByteRange::Unsatisfiable);
+        }
+        return Some(ByteRange::Satisfiable {
+            start: len.saturating_sub(suffix),
+            end: len - 1,
+        });
+    }
+
+    let start = parse(first)?;
+    let end = if last.trim().is_empty() {
+        None
+    } else {
+        Some(parse(last)?)
     };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= len {
+        return Some(ByteRange::Unsatisfiable);
+    }
+    Some(ByteRange::Satisfiable {
+        start,
+        end: end.map_or(len - 1, |end| end.min(len - 1)),
+    })
+}
 
-    let _ = resp.headers_mut().delete("Content-Type");
-    let _ = resp.headers_mut().append("Content-Type", "text/plain");
-    let _ = resp.headers_mut().append(
-        "Cache-Control",
-        if thread.active == 0 {
-            "s-maxage=3600"
-        } else {
-            "s-maxage=1"
-        },
-    );
+/// Clients which are served the whole dat whatever their `Range` is
+pub struct DatRangePolicy {
+    ignored_user_agents: Vec<String>,
+}
 
-    if let Some(ranged_resp) = ranged_sjis_body {
-        let Ok(mut ranged_resp) = Response::from_bytes(ranged_resp) else {
-            return Response::error("internal server error - converting sjis", 500);
-        };
+impl DatRangePolicy {
+    /// From `DAT_RANGE_IGNORED_UAS`, comma separated substrings of `User-Agent`.
+    /// Defaults to `Xeno`, which had been excluded before, and an empty value excludes none.
+    pub fn parse(var: Option<&str>) -> DatRangePolicy {
+        DatRangePolicy {
+            ignored_user_agents: var
+                .unwrap_or(DEFAULT_RANGE_IGNORED_UAS)
+                .split(',')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(ToOwned::to_owned)
+                .collect(),
+        }
+    }
+
+    pub fn honors_range(&self, ua: Option<&str>) -> bool {
+        let ua = ua.unwrap_or_default();
+        !self
+            .ignored_user_agents
+            .iter()
+            .any(|x| ua.contains(x.as_str()))
+    }
+}
 
-        let _ = ranged_resp.headers_mut().delete("Content-Type");
-        let _ = ranged_resp
-            .headers_mut()
-            .append("Content-Type", "text/plain");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        board_policy::BoardPolicy,
+        repositories::{
+            bbs_repository::{CreatingRes, CreatingThread},
+            sqlite_storage::SqliteStorage,
+        },
+        response::Ch5ResponsesFormatter,
+        thread::MetadentType,
+    };
 
-        if resp.status_code() == 200 {
-            let _ = Cache::default().put(req, resp).await;
+    fn dat_request(range: Option<&str>, conditions: Conditions) -> DatRequest<'static> {
+        DatRequest {
+            range: range.map(ToOwned::to_owned),
+            conditions,
+            ua: Some("Monazilla/1.00 JaneStyle/4.23"),
+            host: "bbs.eddibb.cc",
         }
+    }
 
-        Ok(ranged_resp.with_status(206))
-    } else {
-        if let Ok(result) = resp.cloned() {
-            if result.status_code() == 200 {
-                let _ = Cache::default().put(req, result).await;
+    #[tokio::test]
+    async fn test_get_dat() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let thread = CreatingThread {
+            title: "実況スレ",
+            unix_time: "1890000000",
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id: 1,
+            metadent: MetadentType::None,
+        };
+        repo.create_thread(thread.clone()).await.unwrap();
+        let res = CreatingRes {
+            unix_time: "1890000001",
+            ..CreatingRes::from(&thread)
+        };
+        repo.create_response(res, 0, 1000).await.unwrap();
+
+        let board_conf = BoardConfig {
+            board_id: 1,
+            board_key: "liveedge",
+            title: "エッヂ".to_string(),
+            default_name: "名無し".to_string(),
+            policy: BoardPolicy::default_for(1),
+        };
+        let get = |thread_id, dat_req: DatRequest<'static>, has_archive| {
+            let board_conf = &board_conf;
+            let repo = &repo;
+            async move {
+                let thread_info = DatRoutingThreadInfo {
+                    board_conf,
+                    thread_id,
+                };
+                let policy = DatRangePolicy::parse(None);
+                get_dat(&dat_req, thread_info, repo, has_archive, &policy).await
             }
-        }
+        };
+
+        let resp = get(
+            "1890000000",
+            dat_request(None, Conditions::default()),
+            false,
+        )
+        .await;
+        let responses = repo.get_responses(1, "1890000000", 0).await.unwrap();
+        let dat = encoding_rs::SHIFT_JIS
+            .encode(&responses.format_responses("実況スレ", "名無し"))
+            .0
+            .into_owned();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body.as_bytes(), dat);
+        assert_eq!(resp.header("Content-Type"), Some("text/plain"));
+        let etag = resp.header("ETag").unwrap().to_string();
+
+        let resp = get(
+            "1890000000",
+            dat_request(Some("bytes=10-"), Conditions::default()),
+            false,
+        )
+        .await;
+        assert_eq!(resp.status, 206);
+        assert_eq!(resp.body.as_bytes(), &dat[10..]);
+        let content_range = format!("bytes 10-{}/{}", dat.len() - 1, dat.len());
+        assert_eq!(resp.header("Content-Range"), Some(content_range.as_str()));
+
+        let conditions = Conditions {
+            if_none_match: Some(etag),
+            ..Default::default()
+        };
+        let resp = get("1890000000", dat_request(None, conditions), false).await;
+        assert_eq!(resp.status, 304);
+        assert!(resp.body.as_bytes().is_empty());
+
+        let resp = get(
+            "1890000009",
+            dat_request(None, Conditions::default()),
+            false,
+        )
+        .await;
+        assert_eq!(resp.status, 404);
+        let resp = get("1890000009", dat_request(None, Conditions::default()), true).await;
+        assert_eq!(resp.status, 302);
+        assert_eq!(
+            resp.header("Location"),
+            Some("http://bbs.eddibb.cc/liveedge/kako/1890/18900/1890000009.dat")
+        );
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let satisfiable = |start, end| Some(ByteRange::Satisfiable { start, end });
+        assert_eq!(parse_range("bytes=0-99", 1000), satisfiable(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), satisfiable(900, 999));
+        assert_eq!(parse_range("bytes=900-2000", 1000), satisfiable(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), satisfiable(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), satisfiable(0, 999));
+        assert_eq!(parse_range("Bytes = 999-", 1000), satisfiable(999, 999));
+
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(
+            parse_range("bytes=-0", 1000),
+            Some(ByteRange::Unsatisfiable)
+        );
+        assert_eq!(parse_range("bytes=0-", 0), Some(ByteRange::Unsatisfiable));
+
+        // Ignored
+        assert_eq!(parse_range("bytes=99-0", 1000), None);
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), None);
+        assert_eq!(parse_range("bytes=+1-", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+        assert_eq!(parse_range("items=0-9", 1000), None);
+        assert_eq!(parse_range("0-9", 1000), None);
+    }
+
+    #[test]
+    fn test_dat_range_policy() {
+        let policy = DatRangePolicy::parse(None);
+        assert!(!policy.honors_range(Some("Monazilla/1.00 Xeno/1.0")));
+        assert!(policy.honors_range(Some("Monazilla/1.00 ChMate/0.8")));
+        assert!(policy.honors_range(None));
+
+        let policy = DatRangePolicy::parse(Some("ChMate, Siki"));
+        assert!(policy.honors_range(Some("Monazilla/1.00 Xeno/1.0")));
+        assert!(!policy.honors_range(Some("Monazilla/1.00 ChMate/0.8")));
 
-        Ok(resp)
+        assert!(DatRangePolicy::parse(Some("")).honors_range(Some("Xeno")));
     }
 }
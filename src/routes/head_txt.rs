@@ -1,13 +1,24 @@
 use worker::*;
 
 use crate::{
-    repositories::bbs_repository::BbsRepository, utils::response_shift_jis_text_plain_with_cache,
+    conditional::{response_shift_jis_text_plain_conditional, Conditions, Validators},
+    repositories::bbs_repository::BbsRepository,
 };
 
-pub async fn route_head_txt(board_id: usize, repo: &BbsRepository<'_>) -> Result<Response> {
+pub async fn route_head_txt(
+    board_id: usize,
+    repo: &BbsRepository<'_>,
+    conditions: &Conditions,
+) -> Result<Response> {
     let Ok(Some(board_info)) = repo.get_board_info(board_id).await else {
         return Response::error("internal server error - failed to find board", 500);
     };
 
-    response_shift_jis_text_plain_with_cache(&board_info.local_rule, 3600)
+    let local_rule = board_info.local_rule.as_deref().unwrap_or_default();
+    response_shift_jis_text_plain_conditional(
+        local_rule,
+        3600,
+        &Validators::for_body(local_rule),
+        conditions,
+    )
 }
@@ -0,0 +1,34 @@
+use worker::*;
+
+use crate::{
+    kako::{KakoMonth, KAKO_SUBJECT_LIMIT},
+    repositories::bbs_repository::BbsRepository,
+    thread::{Ch5ThreadFormatter, Thread},
+    utils::response_shift_jis_text_plain_with_cache,
+};
+
+/// Archived threads in the format of `subject.txt`, newest first, for dedicated browsers.
+/// `month` (`YYYY-MM`) narrows them down to the threads created in the month.
+pub async fn route_kako_subject_txt(
+    repo: &BbsRepository<'_>,
+    board_id: usize,
+    month: Option<&str>,
+) -> Result<Response> {
+    let month = match month.map(KakoMonth::parse) {
+        Some(Some(month)) => Some(month),
+        Some(None) => return Response::error("Bad request - invalid month", 400),
+        None => None,
+    };
+    let Ok(archives) = repo
+        .get_archives(board_id, month, KAKO_SUBJECT_LIMIT, 0)
+        .await
+    else {
+        return Response::error("internal server error", 500);
+    };
+
+    let threads = archives.iter().map(Thread::from).collect::<Vec<_>>();
+    let Ok(threads_body) = threads.format_threads() else {
+        return Response::error("internal server error - subject.txt", 500);
+    };
+    response_shift_jis_text_plain_with_cache(&threads_body, 3600)
+}
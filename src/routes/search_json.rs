@@ -0,0 +1,27 @@
+use worker::*;
+
+use crate::{
+    repositories::bbs_repository::BbsRepository,
+    search::{search, SearchQuery},
+};
+
+/// `GET /:board_key/search.json?q=...&page=...`
+pub async fn route_search_json(
+    board_id: usize,
+    repo: &BbsRepository<'_>,
+    q: &str,
+    page: usize,
+) -> Result<Response> {
+    let Some(query) = SearchQuery::parse(q) else {
+        return Response::error("Bad request - empty query", 400);
+    };
+    let result = match search(repo, board_id, &query, page).await {
+        Ok(result) => result,
+        Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+    };
+
+    Response::from_json(&result).map(|mut x| {
+        let _ = x.headers_mut().append("Cache-Control", "s-maxage=10");
+        x
+    })
+}
@@ -1,59 +1,186 @@
 use worker::*;
 
-use crate::{board_config::BoardConfig, utils::response_shift_jis_text_plain_with_cache};
+use crate::{
+    board::BoardSetting,
+    board_config::BoardConfig,
+    conditional::{response_shift_jis_text_plain_conditional, Conditions, Validators},
+};
+
+/// Keys rendered from the board and its policy, so that dedicated browsers apply the same
+/// limits as bbs.cgi. Extra settings can't override them.
+pub(crate) const DERIVED_KEYS: &[&str] = &[
+    "BBS_TITLE",
+    "BBS_TITLE_ORIG",
+    "BBS_NONAME_NAME",
+    "BBS_LINE_NUMBER",
+    "BBS_SUBJECT_COUNT",
+    "BBS_NAME_COUNT",
+    "BBS_MAIL_COUNT",
+    "BBS_MESSAGE_COUNT",
+];
+
+/// Values of the other keys unless the board overrides them, in the order of SETTING.TXT.
+/// `None` marks where a derived key goes.
+const DEFAULT_SETTINGS: &[(&str, Option<&str>)] = &[
+    ("BBS_TITLE", None),
+    ("BBS_TITLE_ORIG", None),
+    ("BBS_NONAME_NAME", None),
+    ("BBS_TITLE_COLOR", Some("#000000")),
+    ("BBS_BG_COLOR", Some("#FFFFFF")),
+    ("BBS_MAKETHREAD_COLOR", Some("#CCFFCC")),
+    ("BBS_MENU_COLOR", Some("#CCFFCC")),
+    ("BBS_THREAD_COLOR", Some("#EFEFEF")),
+    ("BBS_TEXT_COLOR", Some("#000000")),
+    ("BBS_NAME_COLOR", Some("green")),
+    ("BBS_LINK_COLOR", Some("#0000FF")),
+    ("BBS_ALINK_COLOR", Some("#FF0000")),
+    ("BBS_VLINK_COLOR", Some("#660099")),
+    ("BBS_THREAD_NUMBER", Some("10")),
+    ("BBS_CONTENTS_NUMBER", Some("10")),
+    ("BBS_LINE_NUMBER", None),
+    ("BBS_MAX_MENU_THREAD", Some("10")),
+    ("BBS_SUBJECT_COLOR", Some("#FF0000")),
+    ("BBS_UNICODE", Some("pass")),
+    ("BBS_NAMECOOKIE_CHECK", Some("checked")),
+    ("BBS_MAILCOOKIE_CHECK", Some("checked")),
+    ("BBS_SUBJECT_COUNT", None),
+    ("BBS_NAME_COUNT", None),
+    ("BBS_MAIL_COUNT", None),
+    ("BBS_MESSAGE_COUNT", None),
+    ("BBS_THREAD_TATESUGI", Some("8")),
+    ("BBS_PROXY_CHECK", Some("")),
+    ("BBS_OVERSEA_PROXY", Some("")),
+    ("BBS_RAWIP_CHECK", Some("")),
+    ("BBS_SLIP", Some("verbose")),
+    ("BBS_DISP_IP", Some("")),
+    ("BBS_FORCE_ID", Some("checked")),
+    ("BBS_BE_ID", Some("")),
+    ("BBS_BE_TYPE2", Some("")),
+    ("BBS_NO_ID", Some("")),
+    ("BBS_JP_CHECK", Some("")),
+    ("BBS_YMD_WEEKS", Some("")),
+    ("EMOTICONS", Some("checked")),
+    ("BBS_NOSUSU", Some("checked")),
+    ("BBS_USE_VIPQ2", Some("16")),
+];
+
+fn derived_value(key: &str, board: &BoardConfig) -> String {
+    let policy = &board.policy;
+    match key {
+        "BBS_TITLE" | "BBS_TITLE_ORIG" => board.title.clone(),
+        "BBS_NONAME_NAME" => board.default_name.clone(),
+        // Dedicated browsers allow twice this many lines, and bbs.cgi allows
+        // `max_body_lines` newlines, i.e. one more line than that
+        "BBS_LINE_NUMBER" => policy.max_body_lines.div_ceil(2).to_string(),
+        "BBS_SUBJECT_COUNT" => policy.max_subject_len.to_string(),
+        "BBS_NAME_COUNT" => policy.max_name_len.to_string(),
+        "BBS_MAIL_COUNT" => policy.max_mail_len.to_string(),
+        "BBS_MESSAGE_COUNT" => policy.max_body_len.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Extra settings override the default of the same key, and new keys are appended.
+/// Derived keys in `extras` are ignored.
+pub(crate) fn render_setting_txt(board: &BoardConfig, extras: &[BoardSetting]) -> String {
+    let mut settings = DEFAULT_SETTINGS
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Some(value) => value.to_string(),
+                None => derived_value(key, board),
+            };
+            (key.to_string(), value)
+        })
+        .collect::<Vec<_>>();
+    for extra in extras {
+        if DERIVED_KEYS.contains(&extra.key.as_str()) {
+            continue;
+        }
+        match settings.iter_mut().find(|(key, _)| *key == extra.key) {
+            Some((_, value)) => *value = extra.value.clone(),
+            None => settings.push((extra.key.clone(), extra.value.clone())),
+        }
+    }
+
+    let mut setting_txt = format!("{0}@{0}\n", board.board_key);
+    for (key, value) in settings {
+        setting_txt.push_str(&format!("{key}={value}\n"));
+    }
+    setting_txt
+}
 
 pub fn route_setting_txt(
-    BoardConfig {
-        board_key,
-        title,
-        default_name,
-        ..
-    }: &BoardConfig,
+    board: &BoardConfig,
+    extras: &[BoardSetting],
+    conditions: &Conditions,
 ) -> Result<Response> {
-    let builder = format!(
-        "{board_key}@{board_key}
-BBS_TITLE={title}
-BBS_TITLE_ORIG={title}
-BBS_NONAME_NAME={default_name}
-BBS_TITLE_COLOR=#000000
-BBS_BG_COLOR=#FFFFFF
-BBS_MAKETHREAD_COLOR=#CCFFCC
-BBS_MENU_COLOR=#CCFFCC
-BBS_THREAD_COLOR=#EFEFEF
-BBS_TEXT_COLOR=#000000
-BBS_NAME_COLOR=green
-BBS_LINK_COLOR=#0000FF
-BBS_ALINK_COLOR=#FF0000
-BBS_VLINK_COLOR=#660099
-BBS_THREAD_NUMBER=10
-BBS_CONTENTS_NUMBER=10
-BBS_LINE_NUMBER=32
-BBS_MAX_MENU_THREAD=10
-BBS_SUBJECT_COLOR=#FF0000
-BBS_UNICODE=pass
-BBS_NAMECOOKIE_CHECK=checked
-BBS_MAILCOOKIE_CHECK=checked
-BBS_SUBJECT_COUNT=96
-BBS_NAME_COUNT=64
-BBS_MAIL_COUNT=64
-BBS_MESSAGE_COUNT=4096
-BBS_THREAD_TATESUGI=8
-BBS_PROXY_CHECK=
-BBS_OVERSEA_PROXY=
-BBS_RAWIP_CHECK=
-BBS_SLIP=verbose
-BBS_DISP_IP=
-BBS_FORCE_ID=checked
-BBS_BE_ID=
-BBS_BE_TYPE2=
-BBS_NO_ID=
-BBS_JP_CHECK=
-BBS_YMD_WEEKS=
-EMOTICONS=checked
-BBS_NOSUSU=checked
-BBS_USE_VIPQ2=16
-                ",
-    );
-
-    response_shift_jis_text_plain_with_cache(&builder, 86400)
+    let setting_txt = render_setting_txt(board, extras);
+    // Not a day, so that the changes of the policy and the settings are picked up soon
+    response_shift_jis_text_plain_conditional(
+        &setting_txt,
+        3600,
+        &Validators::for_body(&setting_txt),
+        conditions,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board_policy::BoardPolicy;
+
+    fn board() -> BoardConfig<'static> {
+        BoardConfig {
+            board_id: 1,
+            board_key: "liveedge",
+            title: "エッヂ".to_string(),
+            default_name: "エッヂの名無し".to_string(),
+            policy: BoardPolicy {
+                max_body_len: 2048,
+                max_body_lines: 15,
+                ..BoardPolicy::default_for(1)
+            },
+        }
+    }
+
+    fn value_of<'a>(setting_txt: &'a str, key: &str) -> Option<&'a str> {
+        setting_txt
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{key}=")))
+    }
+
+    #[test]
+    fn test_render_setting_txt() {
+        let setting_txt = render_setting_txt(&board(), &[]);
+        assert!(setting_txt.starts_with("liveedge@liveedge\nBBS_TITLE=エッヂ\n"));
+        assert_eq!(
+            value_of(&setting_txt, "BBS_NONAME_NAME"),
+            Some("エッヂの名無し")
+        );
+        assert_eq!(value_of(&setting_txt, "BBS_SUBJECT_COUNT"), Some("96"));
+        assert_eq!(value_of(&setting_txt, "BBS_MESSAGE_COUNT"), Some("2048"));
+        assert_eq!(value_of(&setting_txt, "BBS_LINE_NUMBER"), Some("8"));
+        assert_eq!(value_of(&setting_txt, "BBS_SLIP"), Some("verbose"));
+        assert_eq!(setting_txt.lines().count(), DEFAULT_SETTINGS.len() + 1);
+    }
+
+    #[test]
+    fn test_render_setting_txt_with_extras() {
+        let extra = |key: &str, value: &str| BoardSetting {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        let setting_txt = render_setting_txt(
+            &board(),
+            &[
+                extra("BBS_SLIP", ""),
+                extra("BBS_MESSAGE_COUNT", "9999"),
+                extra("BBS_DATMAX", "512"),
+            ],
+        );
+        assert_eq!(value_of(&setting_txt, "BBS_SLIP"), Some(""));
+        assert_eq!(value_of(&setting_txt, "BBS_MESSAGE_COUNT"), Some("2048"));
+        assert!(setting_txt.ends_with("BBS_DATMAX=512\n"));
+    }
 }
@@ -1,18 +1,32 @@
 use worker::*;
 
 use crate::{
+    conditional::{Conditions, Validators},
     repositories::bbs_repository::{BbsRepository, ThreadStatus},
     thread::Ch5ThreadFormatter,
     utils::response_shift_jis_text_plain_with_cache,
 };
 
-pub async fn route_subject_txt(repo: &BbsRepository<'_>, board_id: usize) -> Result<Response> {
+pub async fn route_subject_txt(
+    repo: &BbsRepository<'_>,
+    board_id: usize,
+    conditions: &Conditions,
+) -> Result<Response> {
     let Ok(mut threads) = repo.get_threads(board_id, ThreadStatus::Unarchived).await else {
         return Response::error("internal server error", 500);
     };
 
     threads.sort_by_key(|x| u64::max_value() - x.last_modified.parse::<u64>().unwrap());
 
-    let threads_body = threads.format_threads();
-    response_shift_jis_text_plain_with_cache(&threads_body, 1)
+    let validators = Validators::for_threads(&threads);
+    if conditions.is_not_modified(&validators) {
+        return validators.not_modified("s-maxage=1");
+    }
+    // Checked before formatting, which is the most of the cost
+    let Ok(threads_body) = threads.format_threads() else {
+        return Response::error("internal server error - subject.txt", 500);
+    };
+    let mut resp = response_shift_jis_text_plain_with_cache(&threads_body, 1)?;
+    validators.append_to(resp.headers_mut());
+    Ok(resp)
 }
@@ -1,17 +1,16 @@
-use crate::repositories::bbs_repository::ThreadStatus;
+use crate::archiver::{dat_key, DatArchive};
+use crate::kako::{parse_dat, Archive, KakoMonth, KAKO_PAGE_SIZE};
+use crate::repositories::bbs_repository::{ResRange, ThreadStatus};
 use crate::response::Res;
-use crate::utils::into_workers_err;
+use crate::search::{search, SearchHit, SearchQuery, MAX_SEARCH_PAGE};
+use crate::templates::render_html;
+use crate::thread::Thread;
 use crate::{board_config::BoardConfig, repositories::bbs_repository::BbsRepository};
 
-use minijinja::{context, Environment};
+use minijinja::context;
 use serde::Serialize;
 use worker::{Response, Result};
 
-use super::bbs_cgi::TokenRemover;
-
-const BOARD_HTML: &str = include_str!("templates/board.html");
-const INDEX_HTML: &str = include_str!("templates/index.html");
-const THREAD_HTML: &str = include_str!("templates/thread.html");
 const WEBUI_DISABLED_HTML: &str = include_str!("templates/webui_disabled.html");
 
 pub(crate) fn webui_disabled(site_title: &str) -> Result<Response> {
@@ -25,14 +24,10 @@ pub(crate) fn route_index(
     site_description: &str,
     boards: &[BoardConfig],
 ) -> Result<Response> {
-    let mut env = Environment::new();
-    env.add_template("index.html", INDEX_HTML)
-        .map_err(into_workers_err)?;
-    let tmpl = env.get_template("index.html").map_err(into_workers_err)?;
-    let html = tmpl
-        .render(context!(site_title, site_name, site_description, boards))
-        .map_err(into_workers_err)?;
-    Response::from_html(html)
+    render_html(
+        "index.html",
+        context!(site_title, site_name, site_description, boards),
+    )
 }
 
 pub(crate) async fn route_board(
@@ -50,14 +45,61 @@ pub(crate) async fn route_board(
         return Response::error("internal server error: convertion", 500);
     };
 
-    let mut env = Environment::new();
-    env.add_template("board.html", BOARD_HTML)
-        .map_err(into_workers_err)?;
-    let tmpl = env.get_template("board.html").map_err(into_workers_err)?;
-    let html = tmpl
-        .render(context!(host_url, board, threads))
-        .map_err(into_workers_err)?;
-    Response::from_html(html).map(|mut x| {
+    render_html("board.html", context!(host_url, board, threads)).map(|mut x| {
+        let _ = x.headers_mut().append("Cache-Control", "s-maxage=10");
+        x
+    })
+}
+
+/// Shows only the search form if `q` has no terms
+pub(crate) async fn route_search(
+    host_url: &str,
+    board: &BoardConfig<'_>,
+    repo: &BbsRepository<'_>,
+    q: &str,
+    page: usize,
+) -> Result<Response> {
+    // TODO: this restriction is only for eddi. It should be removed in the future.
+    if host_url.contains("workers.dev") {
+        return webui_disabled("edgebb");
+    }
+
+    let result = match SearchQuery::parse(q) {
+        Some(query) => match search(repo, board.board_id, &query, page).await {
+            Ok(result) => Some(result),
+            Err(e) => return Response::error(format!("DB error {}", e), 500),
+        },
+        None => None,
+    };
+    let hits = result
+        .iter()
+        .flat_map(|result| &result.responses)
+        .map(|hit| SearchHitByLines {
+            hit: hit.clone(),
+            lines: hit
+                .body
+                .replace("<br>", "\n")
+                .lines()
+                .map(|x| x.to_string())
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    let query = result
+        .as_ref()
+        .map(|x| x.query.as_str())
+        .unwrap_or_default();
+
+    render_html(
+        "search.html",
+        context!(
+            board,
+            query,
+            result,
+            hits,
+            max_page => MAX_SEARCH_PAGE
+        ),
+    )
+    .map(|mut x| {
         let _ = x.headers_mut().append("Cache-Control", "s-maxage=10");
         x
     })
@@ -65,6 +107,7 @@ pub(crate) async fn route_board(
 
 pub(crate) async fn route_thread(
     thread_id: u64,
+    range: ResRange,
     board: &BoardConfig<'_>,
     repo: &BbsRepository<'_>,
     host_url: &str,
@@ -82,12 +125,51 @@ pub(crate) async fn route_thread(
         Err(e) => return Response::error(format!("DB error {}", e), 500),
     };
     let responses = match repo
-        .get_responses(board.board_id, &thread_id, thread.modulo as usize)
+        .get_responses_in_range(board.board_id, &thread_id, thread.modulo as usize, range)
         .await
     {
         Ok(responses) => responses,
         Err(e) => return Response::error(format!("DB error {}", e), 500),
     };
+    render_thread(board, &thread, &responses, 15)
+}
+
+/// Shows an archived thread from its dat in the archive, like `route_thread`
+pub(crate) async fn route_kako_thread(
+    thread_id: u64,
+    board: &BoardConfig<'_>,
+    archive: &dyn DatArchive,
+    host_url: &str,
+) -> Result<Response> {
+    // TODO: this restriction is only for eddi. It should be removed in the future.
+    if host_url.contains("workers.dev") {
+        return webui_disabled("edgebb");
+    }
+
+    let thread_id = thread_id.to_string();
+    let dat = match archive.get_dat(&dat_key(board.board_key, &thread_id)).await {
+        Ok(Some(dat)) => dat,
+        Ok(None) => return Response::error("Not found", 404),
+        Err(e) => return Response::error(format!("internal server error - {e}"), 500),
+    };
+    let (title, responses) = parse_dat(&dat, &thread_id);
+    let thread = Thread::from(&Archive {
+        thread_number: thread_id,
+        title,
+        response_count: responses.len() as u32,
+        board_id: board.board_id as u32,
+        last_modified: String::new(),
+    });
+
+    render_thread(board, &thread, &responses, 86400)
+}
+
+fn render_thread(
+    board: &BoardConfig<'_>,
+    thread: &Thread,
+    responses: &[Res],
+    s_maxage: u32,
+) -> Result<Response> {
     let res_l = responses
         .iter()
         .map(|res| {
@@ -104,18 +186,64 @@ pub(crate) async fn route_thread(
         })
         .collect::<Vec<_>>();
 
-    let token_remover = TokenRemover::new();
-
-    let mut env = Environment::new();
-    env.add_template("thread.html", THREAD_HTML)
-        .map_err(into_workers_err)?;
-    env.add_filter("remove_token", move |name| token_remover.remove(name));
-    let tmpl = env.get_template("thread.html").map_err(into_workers_err)?;
-    let html = tmpl
-        .render(context!(board, thread, res_l))
-        .map_err(into_workers_err)?;
-    Response::from_html(html).map(|mut x| {
-        let _ = x.headers_mut().append("Cache-Control", "s-maxage=15");
+    render_html("thread.html", context!(board, thread, res_l)).map(|mut x| {
+        let _ = x
+            .headers_mut()
+            .append("Cache-Control", &format!("s-maxage={s_maxage}"));
+        x
+    })
+}
+
+/// Months of the kako index, or the archived threads of `month` if it is given
+pub(crate) async fn route_kako_index(
+    host_url: &str,
+    board: &BoardConfig<'_>,
+    repo: &BbsRepository<'_>,
+    month: Option<&str>,
+    page: usize,
+) -> Result<Response> {
+    // TODO: this restriction is only for eddi. It should be removed in the future.
+    if host_url.contains("workers.dev") {
+        return webui_disabled("edgebb");
+    }
+
+    let month = match month.map(KakoMonth::parse) {
+        Some(Some(month)) => Some(month),
+        Some(None) => return Response::error("Bad request - invalid month", 400),
+        None => None,
+    };
+    let page = page.max(1);
+
+    let (months, archives, has_next) = if let Some(month) = month {
+        let mut archives = match repo
+            .get_archives(
+                board.board_id,
+                Some(month),
+                KAKO_PAGE_SIZE + 1,
+                (page - 1) * KAKO_PAGE_SIZE,
+            )
+            .await
+        {
+            Ok(archives) => archives,
+            Err(e) => return Response::error(format!("DB error {}", e), 500),
+        };
+        let has_next = archives.len() > KAKO_PAGE_SIZE;
+        archives.truncate(KAKO_PAGE_SIZE);
+        (Vec::new(), archives, has_next)
+    } else {
+        match repo.get_archive_months(board.board_id).await {
+            Ok(months) => (months, Vec::new(), false),
+            Err(e) => return Response::error(format!("DB error {}", e), 500),
+        }
+    };
+    let month = month.map(|x| x.to_string());
+
+    render_html(
+        "kako.html",
+        context!(board, month, months, archives, page, has_next),
+    )
+    .map(|mut x| {
+        let _ = x.headers_mut().append("Cache-Control", "s-maxage=600");
         x
     })
 }
@@ -125,3 +253,9 @@ struct ResByLines {
     res: Res,
     lines: Vec<String>,
 }
+
+#[derive(Debug, Clone, Serialize)]
+struct SearchHitByLines {
+    hit: SearchHit,
+    lines: Vec<String>,
+}
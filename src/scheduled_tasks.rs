@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use worker::async_trait::async_trait;
+
+use crate::{
+    archiver::{run_archiver, ArchiveConfig, DatArchive},
+    board_config::BoardConfig,
+    integrity::{check_integrity, repair_integrity, IntegrityMode, DEFAULT_REPAIR_LIMIT},
+    maintenance::run_scheduled_maintenance,
+    repositories::bbs_repository::BbsRepository,
+    utils::get_current_millis,
+};
+
+/// Cron ticks are not exactly on time, so a task is due slightly before its interval has
+/// passed. Otherwise a task with the same interval as the cron would run every other tick.
+const SCHEDULE_SLACK_MILLIS: u64 = 60 * 1000;
+
+/// Runs returned by `GET /admin/tasks` when not specified
+pub(crate) const DEFAULT_TASK_RUNS_LIMIT: usize = 100;
+
+/// Runs older than this are deleted from `task_runs`
+const TASK_RUNS_RETENTION_MILLIS: u64 = 30 * 24 * 60 * 60 * 1000;
+
+/// What a run did, e.g. `{"archived": 3}`
+pub(crate) type TaskCounts = BTreeMap<&'static str, usize>;
+
+/// A row of `task_runs`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TaskRun {
+    pub task_name: String,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    pub duration_millis: u64,
+    pub succeeded: u32,
+    /// JSON object of `TaskCounts`
+    pub counts: String,
+    pub error: Option<String>,
+}
+
+/// The latest run of a task
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct LastTaskRun {
+    pub task_name: String,
+    pub started_at: u64,
+}
+
+/// A job of the scheduled handler, run on the first cron tick after its interval has passed
+#[async_trait(?Send)]
+pub(crate) trait ScheduledTask {
+    /// Recorded in `task_runs`, which tells when the task is due next
+    fn name(&self) -> &'static str;
+
+    /// From the start of the previous run, whether it succeeded or not
+    fn interval_secs(&self) -> u64;
+
+    /// `counts` is recorded even if the run fails halfway
+    async fn run(
+        &self,
+        repo: &BbsRepository<'_>,
+        boards: &[BoardConfig<'_>],
+        counts: &mut TaskCounts,
+    ) -> anyhow::Result<()>;
+}
+
+fn is_due(last_started_at: Option<u64>, interval_secs: u64, now: u64) -> bool {
+    match last_started_at {
+        Some(last_started_at) => {
+            now + SCHEDULE_SLACK_MILLIS >= last_started_at.saturating_add(interval_secs * 1000)
+        }
+        None => true,
+    }
+}
+
+/// Runs the due tasks in order and records each run in `task_runs`.
+///
+/// A failing task is recorded and logged, and doesn't keep the others from running.
+pub(crate) async fn run_due_tasks(
+    repo: &BbsRepository<'_>,
+    boards: &[BoardConfig<'_>],
+    tasks: &[Box<dyn ScheduledTask + '_>],
+) -> Vec<TaskRun> {
+    let last_runs = match repo.get_last_task_runs().await {
+        Ok(runs) => runs
+            .into_iter()
+            .map(|x| (x.task_name, x.started_at))
+            .collect::<HashMap<_, _>>(),
+        // Running every task is better than running none
+        Err(e) => {
+            log!("failed to fetch task_runs: {e}");
+            HashMap::new()
+        }
+    };
+
+    let mut runs = Vec::new();
+    for task in tasks {
+        let started_at = get_current_millis();
+        let last_started_at = last_runs.get(task.name()).copied();
+        if !is_due(last_started_at, task.interval_secs(), started_at) {
+            continue;
+        }
+
+        let mut counts = TaskCounts::new();
+        let result = task.run(repo, boards, &mut counts).await;
+        let run = TaskRun {
+            task_name: task.name().to_string(),
+            started_at,
+            duration_millis: get_current_millis().saturating_sub(started_at),
+            succeeded: result.is_ok() as u32,
+            counts: serde_json::to_string(&counts).unwrap_or_default(),
+            error: result.err().map(|e| e.to_string()),
+        };
+        match &run.error {
+            None => log!(
+                "{}: succeeded in {}ms {}",
+                run.task_name,
+                run.duration_millis,
+                run.counts
+            ),
+            Some(e) => log!(
+                "{}: failed in {}ms {}: {e}",
+                run.task_name,
+                run.duration_millis,
+                run.counts
+            ),
+        }
+        if let Err(e) = repo.insert_task_run(&run).await {
+            log!("failed to record the run of {}: {e}", run.task_name);
+        }
+        runs.push(run);
+    }
+
+    let before = get_current_millis().saturating_sub(TASK_RUNS_RETENTION_MILLIS);
+    if let Err(e) = repo.delete_task_runs_before(before).await {
+        log!("failed to delete old task_runs: {e}");
+    }
+    runs
+}
+
+/// Archives stale threads and repairs `response_count` of every board
+pub(crate) struct MaintenanceTask;
+
+#[async_trait(?Send)]
+impl ScheduledTask for MaintenanceTask {
+    fn name(&self) -> &'static str {
+        "maintenance"
+    }
+
+    fn interval_secs(&self) -> u64 {
+        15 * 60
+    }
+
+    async fn run(
+        &self,
+        repo: &BbsRepository<'_>,
+        boards: &[BoardConfig<'_>],
+        counts: &mut TaskCounts,
+    ) -> anyhow::Result<()> {
+        let mut errors = Vec::new();
+        for board in boards {
+            match run_scheduled_maintenance(repo, board).await {
+                Ok(report) => {
+                    *counts.entry("archived").or_default() += report.archived;
+                    *counts.entry("repaired").or_default() += report.repaired;
+                }
+                // The other boards are still maintained
+                Err(e) => errors.push(format!("{}: {e}", board.board_key)),
+            }
+        }
+        *counts.entry("failed_boards").or_default() += errors.len();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(errors.join("; ")))
+        }
+    }
+}
+
+/// Checks threads against the responses shards, enabled by `INTEGRITY_CHECK`
+pub(crate) struct IntegrityTask {
+    pub mode: IntegrityMode,
+}
+
+#[async_trait(?Send)]
+impl ScheduledTask for IntegrityTask {
+    fn name(&self) -> &'static str {
+        "integrity"
+    }
+
+    /// Reads every thread and groups every shard, so not on every tick
+    fn interval_secs(&self) -> u64 {
+        24 * 60 * 60
+    }
+
+    async fn run(
+        &self,
+        repo: &BbsRepository<'_>,
+        _boards: &[BoardConfig<'_>],
+        counts: &mut TaskCounts,
+    ) -> anyhow::Result<()> {
+        let report = match self.mode {
+            IntegrityMode::Report => check_integrity(repo).await?,
+            IntegrityMode::Fix => repair_integrity(repo, DEFAULT_REPAIR_LIMIT).await?,
+        };
+        counts.insert("orphaned", report.orphaned_responses.len());
+        counts.insert("stray", report.stray_responses.len());
+        counts.insert("broken", report.broken_threads.len());
+        counts.insert("fixes", report.fixes.len());
+        counts.insert("applied", report.applied);
+        Ok(())
+    }
+}
+
+/// Stores archived threads into `ARCHIVE_BUCKET`, enabled by the binding
+pub(crate) struct ArchiverTask<'a> {
+    pub archive: &'a dyn DatArchive,
+    pub config: ArchiveConfig,
+}
+
+#[async_trait(?Send)]
+impl ScheduledTask for ArchiverTask<'_> {
+    fn name(&self) -> &'static str {
+        "archiver"
+    }
+
+    fn interval_secs(&self) -> u64 {
+        15 * 60
+    }
+
+    async fn run(
+        &self,
+        repo: &BbsRepository<'_>,
+        boards: &[BoardConfig<'_>],
+        counts: &mut TaskCounts,
+    ) -> anyhow::Result<()> {
+        let report = run_archiver(repo, self.archive, boards, self.config).await?;
+        counts.insert("stored", report.stored.len());
+        counts.insert("purged", report.purged.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::sqlite_storage::SqliteStorage;
+
+    struct CountingTask {
+        name: &'static str,
+        fails: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl ScheduledTask for CountingTask {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn interval_secs(&self) -> u64 {
+            60 * 60
+        }
+
+        async fn run(
+            &self,
+            _repo: &BbsRepository<'_>,
+            boards: &[BoardConfig<'_>],
+            counts: &mut TaskCounts,
+        ) -> anyhow::Result<()> {
+            counts.insert("boards", boards.len());
+            if self.fails {
+                return Err(anyhow::anyhow!("no such table"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_is_due() {
+        let hour = 60 * 60 * 1000;
+        assert!(is_due(None, 60 * 60, hour));
+        assert!(is_due(Some(hour), 60 * 60, 2 * hour));
+        // A tick slightly early
+        assert!(is_due(Some(hour), 60 * 60, 2 * hour - 1000));
+        assert!(!is_due(Some(hour), 60 * 60, hour + 30 * 60 * 1000));
+    }
+
+    #[tokio::test]
+    async fn test_run_due_tasks() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        let tasks: Vec<Box<dyn ScheduledTask>> = vec![
+            Box::new(CountingTask {
+                name: "failing",
+                fails: true,
+            }),
+            Box::new(CountingTask {
+                name: "counting",
+                fails: false,
+            }),
+        ];
+
+        // The failure doesn't keep the next task from running
+        let runs = run_due_tasks(&repo, &[], &tasks).await;
+        assert_eq!(
+            runs.iter()
+                .map(|x| (x.task_name.as_str(), x.succeeded))
+                .collect::<Vec<_>>(),
+            vec![("failing", 0), ("counting", 1)]
+        );
+        assert_eq!(runs[0].error.as_deref(), Some("no such table"));
+        assert_eq!(runs[1].counts, r#"{"boards":0}"#);
+
+        let mut recorded = repo.get_task_runs(10).await.unwrap();
+        recorded.sort_by(|a, b| a.task_name.cmp(&b.task_name));
+        assert_eq!(recorded, vec![runs[1].clone(), runs[0].clone()]);
+
+        // Not due until the interval has passed
+        assert!(run_due_tasks(&repo, &[], &tasks).await.is_empty());
+    }
+}
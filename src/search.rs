@@ -0,0 +1,423 @@
+use serde::Serialize;
+
+use crate::{
+    repositories::bbs_repository::BbsRepository, routes::bbs_cgi::TokenRemover, thread::Thread,
+};
+
+/// Threads and responses shown per page
+pub(crate) const SEARCH_PAGE_SIZE: usize = 20;
+/// Responses are merged over every shard, so deep pages are expensive
+pub(crate) const MAX_SEARCH_PAGE: usize = 10;
+const MAX_SEARCH_TERMS: usize = 5;
+const MAX_TERM_LEN: usize = 50;
+/// The trigram index can't be used for shorter terms, which are matched with `LIKE` instead
+const MIN_INDEXED_TERM_LEN: usize = 3;
+
+/// Whitespace separated terms, all of which must appear (case-insensitive for ASCII)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SearchQuery {
+    terms: Vec<String>,
+}
+
+impl SearchQuery {
+    /// Returns `None` if `q` has no terms. Extra terms and characters are dropped.
+    pub fn parse(q: &str) -> Option<SearchQuery> {
+        let terms = q
+            .split_whitespace()
+            .take(MAX_SEARCH_TERMS)
+            .map(|t| t.chars().take(MAX_TERM_LEN).collect::<String>())
+            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            return None;
+        }
+        Some(SearchQuery { terms })
+    }
+
+    pub fn as_text(&self) -> String {
+        self.terms.join(" ")
+    }
+
+    /// Binds `board_id`, `limit` and `offset` first, then the returned parameters
+    pub(crate) fn select_threads_query(&self) -> (String, Vec<String>) {
+        let (from_fts, condition, params) = self.condition("threads_fts", "threads.title", 4);
+        let from = if from_fts {
+            "threads_fts JOIN threads ON threads.board_id = threads_fts.board_id \
+            AND threads.thread_number = threads_fts.thread_number"
+        } else {
+            "threads"
+        };
+        let sql = format!(
+            "SELECT threads.* FROM {from}
+            WHERE threads.board_id = ?1 AND {condition}
+            ORDER BY CAST(threads.thread_number AS INTEGER) DESC
+            LIMIT ?2 OFFSET ?3"
+        );
+        (sql, params)
+    }
+
+    /// Binds `board_id` and `limit` first, then the returned parameters.
+    /// Abone'd responses are excluded.
+    pub(crate) fn select_responses_query(&self) -> (String, Vec<String>) {
+        let (from_fts, condition, params) = self.condition("responses_fts", "responses.body", 3);
+        let from = if from_fts {
+            "responses_fts JOIN responses ON responses.id = responses_fts.rowid"
+        } else {
+            "responses"
+        };
+        let sql = format!(
+            "SELECT responses.* FROM {from}
+            WHERE responses.board_id = ?1 AND responses.is_abone = 0 AND {condition}
+            ORDER BY responses.timestamp DESC, responses.id DESC
+            LIMIT ?2"
+        );
+        (sql, params)
+    }
+
+    /// Terms long enough for the trigram index are matched as phrases of `fts_table`,
+    /// and the rest with `LIKE` on `column`. Returns whether `fts_table` is used.
+    fn condition(
+        &self,
+        fts_table: &str,
+        column: &str,
+        first_param: usize,
+    ) -> (bool, String, Vec<String>) {
+        let (indexed, scanned): (Vec<_>, Vec<_>) = self
+            .terms
+            .iter()
+            .partition(|t| t.chars().count() >= MIN_INDEXED_TERM_LEN);
+
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if !indexed.is_empty() {
+            conditions.push(format!("{fts_table} MATCH ?{first_param}"));
+            params.push(
+                indexed
+                    .iter()
+                    .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            );
+        }
+        for term in scanned {
+            conditions.push(format!(
+                "{column} LIKE ?{} ESCAPE '\\'",
+                first_param + params.len()
+            ));
+            params.push(format!("%{}%", escape_like(term)));
+        }
+
+        (!indexed.is_empty(), conditions.join(" AND "), params)
+    }
+}
+
+fn escape_like(term: &str) -> String {
+    term.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// A response in the search results, without the poster's IP address and token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct SearchHit {
+    pub thread_id: String,
+    /// `None` if the thread is gone
+    pub thread_title: Option<String>,
+    pub res_no: u32,
+    pub name: Option<String>,
+    pub date: String,
+    pub author_id: Option<String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SearchResult {
+    pub query: String,
+    /// 1-origin
+    pub page: usize,
+    /// Threads whose title matches, newest first
+    pub threads: Vec<Thread>,
+    /// Responses whose body matches over every shard, newest first
+    pub responses: Vec<SearchHit>,
+    /// Whether the next page has threads or responses
+    pub has_next: bool,
+}
+
+/// Searches thread titles and response bodies of the board. `page` is 1-origin and
+/// clamped to `1..=MAX_SEARCH_PAGE`.
+pub(crate) async fn search(
+    repo: &BbsRepository<'_>,
+    board_id: usize,
+    query: &SearchQuery,
+    page: usize,
+) -> anyhow::Result<SearchResult> {
+    let page = page.clamp(1, MAX_SEARCH_PAGE);
+    let offset = (page - 1) * SEARCH_PAGE_SIZE;
+
+    let mut threads = repo
+        .search_threads(board_id, query, SEARCH_PAGE_SIZE + 1, offset)
+        .await?;
+    let threads_has_next = threads.len() > SEARCH_PAGE_SIZE;
+    threads.truncate(SEARCH_PAGE_SIZE);
+
+    // Every shard is ordered on its own, so each one may hold the whole page
+    let mut responses = Vec::new();
+    for modulo in 0..repo.n_responses_db() {
+        responses.extend(
+            repo.search_responses(board_id, query, modulo, offset + SEARCH_PAGE_SIZE + 1)
+                .await?,
+        );
+    }
+    responses.sort_by(|a, b| {
+        (b.timestamp, &b.thread_id, b.res_no).cmp(&(a.timestamp, &a.thread_id, a.res_no))
+    });
+    let mut responses = responses.into_iter().skip(offset).collect::<Vec<_>>();
+    let responses_has_next = responses.len() > SEARCH_PAGE_SIZE;
+    responses.truncate(SEARCH_PAGE_SIZE);
+
+    let mut thread_numbers = responses
+        .iter()
+        .map(|r| r.thread_id.clone())
+        .collect::<Vec<_>>();
+    thread_numbers.sort();
+    thread_numbers.dedup();
+    let titles = repo
+        .get_threads_by_numbers(board_id, &thread_numbers)
+        .await?;
+
+    let token_remover = TokenRemover::new();
+    let responses = responses
+        .into_iter()
+        .map(|r| SearchHit {
+            thread_title: titles
+                .iter()
+                .find(|t| t.thread_number == r.thread_id)
+                .map(|t| t.title.clone()),
+            thread_id: r.thread_id,
+            res_no: r.res_no,
+            name: r.name.map(|name| token_remover.remove(name)),
+            date: r.date,
+            author_id: r.author_id,
+            body: r.body,
+        })
+        .collect();
+
+    Ok(SearchResult {
+        query: query.as_text(),
+        page,
+        threads,
+        responses,
+        has_next: threads_has_next || responses_has_next,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repositories::{
+            bbs_repository::{CreatingRes, CreatingThread, THREAD_STOPPER},
+            sqlite_storage::SqliteStorage,
+        },
+        thread::MetadentType,
+    };
+
+    async fn create_thread(repo: &BbsRepository<'_>, unix_time: &str, title: &str) {
+        create_thread_on_board(repo, 1, unix_time, title).await;
+    }
+
+    async fn create_thread_on_board(
+        repo: &BbsRepository<'_>,
+        board_id: usize,
+        unix_time: &str,
+        title: &str,
+    ) {
+        repo.create_thread(CreatingThread {
+            title,
+            unix_time,
+            body: "本文",
+            name: "",
+            mail: "",
+            date_time: "2099/09/09(水) 00:00:00.000",
+            author_ch5id: "abcdefghi",
+            authed_token: "token",
+            ip_addr: "127.0.0.1",
+            board_id,
+            metadent: MetadentType::None,
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn create_res(repo: &BbsRepository<'_>, thread_id: &str, unix_time: &str, body: &str) {
+        let modulo = thread_id.parse::<usize>().unwrap() % repo.n_responses_db();
+        repo.create_response(
+            CreatingRes {
+                unix_time,
+                body,
+                name: "",
+                mail: "",
+                date_time: "2099/09/09(水) 00:00:00.000",
+                author_ch5id: "abcdefghi",
+                authed_token: "token",
+                ip_addr: "127.0.0.1",
+                thread_id,
+                board_id: 1,
+            },
+            modulo,
+            THREAD_STOPPER,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn hits(result: &SearchResult) -> Vec<(&str, u32)> {
+        result
+            .responses
+            .iter()
+            .map(|r| (r.thread_id.as_str(), r.res_no))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_search_query() {
+        assert_eq!(SearchQuery::parse(" \u{3000} "), None);
+        let query = SearchQuery::parse("エッヂ\u{3000} 実況 ").unwrap();
+        assert_eq!(query.as_text(), "エッヂ 実況");
+        let query = SearchQuery::parse("a b c d e f g").unwrap();
+        assert_eq!(query.as_text(), "a b c d e");
+    }
+
+    #[test]
+    fn test_search_query_condition() {
+        let query = SearchQuery::parse("エッヂ 実況 \"quoted\" 1%").unwrap();
+        let (from_fts, condition, params) = query.condition("responses_fts", "responses.body", 3);
+        assert!(from_fts);
+        assert_eq!(
+            condition,
+            "responses_fts MATCH ?3 AND responses.body LIKE ?4 ESCAPE '\\' \
+            AND responses.body LIKE ?5 ESCAPE '\\'"
+        );
+        assert_eq!(
+            params,
+            vec!["\"エッヂ\" \"\"\"quoted\"\"\"", "%実況%", "%1\\%%"]
+        );
+
+        let (from_fts, _, _) = SearchQuery::parse("実況").unwrap().condition("t", "c", 1);
+        assert!(!from_fts);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let storage = SqliteStorage::new_in_memory(3).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread(&repo, "1800000000", "今日の実況スレ").await;
+        create_thread(&repo, "1800000001", "雑談スレ").await;
+        create_res(&repo, "1800000000", "1800000010", "エッヂ最高").await;
+        create_res(&repo, "1800000001", "1800000020", "えっぢ<br>エッヂ民").await;
+        create_res(&repo, "1800000001", "1800000030", "関係ない").await;
+
+        let query = SearchQuery::parse("実況").unwrap();
+        let result = search(&repo, 1, &query, 1).await.unwrap();
+        assert_eq!(
+            result
+                .threads
+                .iter()
+                .map(|t| t.thread_number.as_str())
+                .collect::<Vec<_>>(),
+            vec!["1800000000"]
+        );
+        assert!(result.responses.is_empty());
+
+        // Merged over the shards, newest first
+        let query = SearchQuery::parse("エッヂ").unwrap();
+        let result = search(&repo, 1, &query, 1).await.unwrap();
+        assert!(result.threads.is_empty());
+        assert_eq!(hits(&result), vec![("1800000001", 2), ("1800000000", 2)]);
+        assert_eq!(
+            result.responses[0].thread_title.as_deref(),
+            Some("雑談スレ")
+        );
+        assert!(!result.has_next);
+
+        // Every term must match
+        let query = SearchQuery::parse("エッヂ 民").unwrap();
+        let result = search(&repo, 1, &query, 1).await.unwrap();
+        assert_eq!(hits(&result), vec![("1800000001", 2)]);
+
+        // Other boards are not searched
+        let result = search(&repo, 2, &query, 1).await.unwrap();
+        assert!(result.responses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_excludes_abone() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread(&repo, "1800000000", "スレ").await;
+        create_res(&repo, "1800000000", "1800000010", "消される書き込み").await;
+        let query = SearchQuery::parse("書き込み").unwrap();
+        assert_eq!(
+            search(&repo, 1, &query, 1).await.unwrap().responses.len(),
+            1
+        );
+
+        storage
+            .execute_on_responses_db(0, "UPDATE responses SET is_abone = 1 WHERE res_no = 2")
+            .unwrap();
+        assert!(search(&repo, 1, &query, 1)
+            .await
+            .unwrap()
+            .responses
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_pagination() {
+        let storage = SqliteStorage::new_in_memory(2).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread(&repo, "1800000000", "スレ").await;
+        create_thread(&repo, "1800000001", "スレ").await;
+        for i in 0..SEARCH_PAGE_SIZE + 5 {
+            let thread_id = if i % 2 == 0 {
+                "1800000000"
+            } else {
+                "1800000001"
+            };
+            create_res(
+                &repo,
+                thread_id,
+                &(1800000100 + i).to_string(),
+                "ページ送り",
+            )
+            .await;
+        }
+
+        let query = SearchQuery::parse("ページ送り").unwrap();
+        let first = search(&repo, 1, &query, 1).await.unwrap();
+        assert_eq!(first.responses.len(), SEARCH_PAGE_SIZE);
+        assert!(first.has_next);
+        let second = search(&repo, 1, &query, 2).await.unwrap();
+        assert_eq!(second.page, 2);
+        assert_eq!(second.responses.len(), 5);
+        assert!(!second.has_next);
+        // The first thread of the results is the newest one
+        assert_eq!(first.responses[0].thread_id, "1800000000");
+        assert_eq!(search(&repo, 1, &query, 0).await.unwrap().page, 1);
+    }
+
+    #[tokio::test]
+    async fn test_search_threads_sharing_number() {
+        let storage = SqliteStorage::new_in_memory(1).unwrap();
+        let repo = BbsRepository::new(&storage);
+        create_thread_on_board(&repo, 1, "1850000000", "実況スレ").await;
+        create_thread_on_board(&repo, 2, "1850000000", "雑談スレ").await;
+
+        let query = SearchQuery::parse("実況").unwrap();
+        assert_eq!(search(&repo, 1, &query, 1).await.unwrap().threads.len(), 1);
+        assert!(search(&repo, 2, &query, 1)
+            .await
+            .unwrap()
+            .threads
+            .is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::OnceLock;
+
+use minijinja::Environment;
+use serde::Serialize;
+use worker::Response;
+
+use crate::routes::bbs_cgi::TokenRemover;
+
+/// minijinja templates by name. `.html` ones are HTML escaped, and the others are not.
+const TEMPLATES: &[(&str, &str)] = &[
+    ("board.html", include_str!("routes/templates/board.html")),
+    ("index.html", include_str!("routes/templates/index.html")),
+    ("kako.html", include_str!("routes/templates/kako.html")),
+    ("search.html", include_str!("routes/templates/search.html")),
+    ("subject.txt", include_str!("routes/templates/subject.txt")),
+    ("thread.html", include_str!("routes/templates/thread.html")),
+];
+
+fn build_templates() -> anyhow::Result<Environment<'static>> {
+    let mut env = Environment::new();
+    for (name, source) in TEMPLATES {
+        env.add_template(name, source)
+            .map_err(|e| anyhow::anyhow!("failed to parse {name}: {e}"))?;
+    }
+    let token_remover = TokenRemover::new();
+    env.add_filter("remove_token", move |name| token_remover.remove(name));
+    Ok(env)
+}
+
+/// Every template and filter, parsed once per isolate instead of on every request
+fn templates() -> anyhow::Result<&'static Environment<'static>> {
+    static TEMPLATES_ENV: OnceLock<Result<Environment<'static>, String>> = OnceLock::new();
+    TEMPLATES_ENV
+        .get_or_init(|| build_templates().map_err(|e| e.to_string()))
+        .as_ref()
+        .map_err(|e| anyhow::anyhow!("{e}"))
+}
+
+pub(crate) fn render<S: Serialize>(name: &str, ctx: S) -> anyhow::Result<String> {
+    let tmpl = templates()?.get_template(name)?;
+    Ok(tmpl.render(ctx)?)
+}
+
+/// The rendered page, or 500 if it fails to render
+pub(crate) fn render_html<S: Serialize>(name: &str, ctx: S) -> worker::Result<Response> {
+    match render(name, ctx) {
+        Ok(html) => Response::from_html(html),
+        Err(e) => Response::error(format!("internal server error - {name}: {e}"), 500),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_templates() {
+        assert!(build_templates().is_ok());
+        assert!(render("missing.html", ()).is_err());
+
+        // Only .html is escaped
+        let threads =
+            [minijinja::context!(thread_number => "1", title => "<b>", response_count => 1)];
+        assert_eq!(
+            render("subject.txt", minijinja::context!(threads)).unwrap(),
+            "1.dat<><b> (1)\n"
+        );
+    }
+}
@@ -1,6 +1,8 @@
-use minijinja::{context, AutoEscape, Environment};
+use minijinja::context;
 use serde::{Deserialize, Serialize};
 
+use crate::templates::render;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Thread {
     pub title: String,
@@ -47,23 +49,13 @@ pub enum MetadentType {
 }
 
 pub trait Ch5ThreadFormatter {
-    fn format_threads(&self) -> String;
+    fn format_threads(&self) -> anyhow::Result<String>;
 }
 
-const SUBJECT_TEMPLATE: &str = "
-{%- for thread in threads -%}
-  {{ thread.thread_number }}.dat<>{{ thread.title | replace('\n', '') }} ({{ thread.response_count }})
-{% endfor -%}
-";
-
 impl Ch5ThreadFormatter for Vec<Thread> {
-    fn format_threads(&self) -> String {
-        let mut env = Environment::new();
+    fn format_threads(&self) -> anyhow::Result<String> {
         // TODO(kenmo-melon): Need to escape anything?
-        env.set_auto_escape_callback(|_| AutoEscape::None);
-        env.add_template("subject.txt", SUBJECT_TEMPLATE).unwrap();
-        let tmpl = env.get_template("subject.txt").unwrap();
-        tmpl.render(context!(threads => self)).unwrap()
+        render("subject.txt", context!(threads => self))
     }
 }
 
@@ -91,7 +83,7 @@ mod tests {
         let thread_1 = make_test_thread("実況スレ", 1666666666, 334);
         let thread_2 = make_test_thread("雑談 \n スレ", 1666666667, 88);
         let threads = vec![thread_1, thread_2];
-        let formatted = threads.format_threads();
+        let formatted = threads.format_threads().unwrap();
         assert_eq!(
             formatted,
             r"1666666666.dat<>実況スレ (334)
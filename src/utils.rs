@@ -2,7 +2,9 @@ use std::collections::HashMap;
 
 use chrono::DateTime;
 use rand::Rng;
-use worker::{Date, Error, Request, Response};
+#[cfg(target_arch = "wasm32")]
+use worker::Date;
+use worker::{Request, Response};
 
 pub fn get_host_url(req: &Request) -> Result<String, worker::Result<Response>> {
     let Ok(Some(host_url)) = req.url().map(|url| url.host_str().map(ToOwned::to_owned)) else {
@@ -14,10 +16,6 @@ pub fn get_host_url(req: &Request) -> Result<String, worker::Result<Response>> {
     Ok(host_url)
 }
 
-pub fn into_workers_err<E: std::fmt::Display>(e: E) -> Error {
-    Error::RustError(format!("{}", e))
-}
-
 pub fn shift_jis_url_encodeded_body_to_vec(data: &str) -> Result<HashMap<&str, String>, ()> {
     fn ascii_hex_digit_to_byte(value: u8) -> Result<u8, ()> {
         if value.is_ascii_hexdigit() {
@@ -73,8 +71,25 @@ pub fn shift_jis_url_encodeded_body_to_vec(data: &str) -> Result<HashMap<&str, S
         .collect::<Result<HashMap<_, _>, ()>>()
 }
 
+/// Current unix time in milliseconds.
+///
+/// `worker::Date` calls into JS, so native builds (e.g. `cargo test`) use the system clock instead.
+pub fn get_current_millis() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        Date::now().as_millis()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
+
 pub fn get_unix_timestamp_sec() -> u64 {
-    Date::now().as_millis() / 1000
+    get_current_millis() / 1000
 }
 
 pub fn response_shift_jis_text_plain(body: &str) -> worker::Result<Response> {
@@ -112,20 +127,8 @@ pub fn response_shift_jis_text_plain_with_cache(
     Ok(resp)
 }
 
-pub fn response_shift_jis_text_html(body: String) -> worker::Result<Response> {
-    let data = encoding_rs::SHIFT_JIS.encode(&body).0.into_owned();
-    let Ok(mut resp) = Response::from_bytes(data) else {
-        return Response::error("internal server error - converting sjis", 500);
-    };
-    let _ = resp.headers_mut().delete("Content-Type");
-    let _ = resp
-        .headers_mut()
-        .append("Content-Type", "text/html; charset=x-sjis");
-    Ok(resp)
-}
-
 pub fn get_current_date_time() -> chrono::NaiveDateTime {
-    let date = DateTime::from_timestamp_millis(Date::now().as_millis() as i64)
+    let date = DateTime::from_timestamp_millis(get_current_millis() as i64)
         .unwrap()
         .naive_utc();
     date.checked_add_signed(chrono::Duration::try_hours(9).unwrap())
@@ -171,7 +174,7 @@ fn unix_ts_to_bytes(ts: u64) -> [u8; 32] {
 }
 
 pub fn generate_six_digit_num() -> String {
-    let milli = Date::now().as_millis();
+    let milli = get_current_millis();
 
     let mut rng: rand::rngs::StdRng = rand::SeedableRng::from_seed(unix_ts_to_bytes(milli));
     let num = rng.gen_range(0..1000000);